@@ -0,0 +1,253 @@
+use core::alloc::Layout;
+use core::fmt::{self, Write};
+use core::ptr;
+
+use crate::spin::mutex::Mutex;
+use crate::arch::timer::get_uptime;
+use crate::arch::paging::translate;
+use super::{HeapAllocator, LockedHeap};
+use super::stats::{allocator_name, ScreenWriter};
+use super::tracker::CALLER_DEPTH;
+
+// デバッグ用のHeapの検査
+// - 確保したメモリの前後にレッドゾーン(決まった値)を置き、解放する時に書き換えられていないか確かめる
+// - 解放したメモリを毒(決まった値)で埋め、次に確保する時に書き換えられていないか確かめる
+// - 二重解放や、Heapが配っていないポインタの解放を見つける
+// - 定期的にHeap全体を歩いて、空きリストやレッドゾーンが壊れていないか確かめる
+// どれもHeapのロックの中で行うので、ここではHeapを使わない
+
+pub const REDZONE_SIZE: usize = 16;
+pub const REDZONE_BYTE: u8 = 0xfd;
+pub const POISON_BYTE: u8 = 0xdd;
+// ブロックの先頭(前のレッドゾーンの中)に置くヘッダ
+const HEADER_SIZE: usize = 8;
+const ALLOCATED_MAGIC: u16 = 0xa11c;
+const POISON_MAGIC: u16 = 0xdddd;
+// 空いているslabのブロックの先頭は空きリストのポインタに使われるので、毒を確かめない
+pub const FREE_LINK_SIZE: usize = 4;
+// Heap全体を確かめる間隔(timerのカウント。10ms単位)
+const WALK_INTERVAL: usize = 1000;
+const DUMP_SIZE: usize = 32;
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct Header {
+    size: u32,  // 要求されたサイズ
+    front: u16, // ブロックの先頭からユーザーに返したポインタまで
+    magic: u16,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub enum HeapCorruption {
+    // Heapが配っていないポインタの解放
+    ForeignFree { addr: usize },
+    DoubleFree { addr: usize },
+    // ブロックの先頭のヘッダが壊れている(前のブロックからのはみ出しなど)
+    BadHeader { block: usize },
+    // 解放する時のlayoutが確保した時と違う
+    SizeMismatch { addr: usize, allocated: usize, freed: usize },
+    // レッドゾーンが書き換えられている。offsetはユーザーに返したポインタからの位置
+    RedZone { addr: usize, size: usize, offset: isize },
+    // 解放した後に書き込まれている。offsetはブロックの先頭からの位置
+    UseAfterFree { block: usize, offset: usize },
+    // slabの空きリストにそのslabのものではないブロックがある、または数が合わない
+    BadFreeList { allocator: HeapAllocator, block: usize },
+    // linked list allocatorの空き(hole)が重なっている、並んでいない、またはHeapの外にある
+    BadHole { addr: usize, size: usize },
+}
+
+impl HeapCorruption {
+    pub fn reason(&self) -> &'static str {
+        match self {
+            HeapCorruption::ForeignFree { .. } => "free of a pointer not from this heap",
+            HeapCorruption::DoubleFree { .. } => "double free",
+            HeapCorruption::BadHeader { .. } => "block header overwritten",
+            HeapCorruption::SizeMismatch { .. } => "freed with a different size",
+            HeapCorruption::RedZone { .. } => "red zone overwritten",
+            HeapCorruption::UseAfterFree { .. } => "write after free",
+            HeapCorruption::BadFreeList { .. } => "slab free list corrupted",
+            HeapCorruption::BadHole { .. } => "hole list corrupted",
+        }
+    }
+
+    // 周りのメモリを見せるアドレス。壊れたポインタかもしれないものはNone
+    fn address(&self) -> Option<usize> {
+        match *self {
+            HeapCorruption::DoubleFree { addr }
+            | HeapCorruption::SizeMismatch { addr, .. } => Some(addr),
+            HeapCorruption::BadHeader { block } => Some(block),
+            HeapCorruption::RedZone { addr, offset, .. } => Some((addr as isize + offset) as usize),
+            HeapCorruption::UseAfterFree { block, offset } => Some(block + offset),
+            HeapCorruption::ForeignFree { .. }
+            | HeapCorruption::BadFreeList { .. }
+            | HeapCorruption::BadHole { .. } => None,
+        }
+    }
+}
+
+// 見つけた時の状況をまとめたもの。Heapのロックの中で作り、ロックを放してから出す
+#[derive(Copy, Clone, Debug)]
+pub struct CorruptionReport {
+    pub corruption: HeapCorruption,
+    // 見つけた時の呼び出し元
+    pub detected_at: [u32; CALLER_DEPTH],
+    // 確保した時の呼び出し元(tracker::AllocationTrackerで記録していれば)
+    pub allocated_at: Option<[u32; CALLER_DEPTH]>,
+    pub dump_addr: usize,
+    pub dump: Option<[u8; DUMP_SIZE]>,
+}
+
+impl CorruptionReport {
+    pub fn new(corruption: HeapCorruption, detected_at: [u32; CALLER_DEPTH], allocated_at: Option<[u32; CALLER_DEPTH]>) -> Self {
+        let dump_addr = corruption.address().map_or(0, |addr| addr.saturating_sub(DUMP_SIZE / 2) & !0xf);
+        let mut report = CorruptionReport { corruption, detected_at, allocated_at, dump_addr, dump: None };
+        // ページを返した後かもしれないので、マッピングされている時だけ読む
+        let readable = corruption.address().is_some()
+            && translate(dump_addr as u32).is_some()
+            && translate((dump_addr + DUMP_SIZE - 1) as u32).is_some();
+        if readable {
+            let mut dump = [0; DUMP_SIZE];
+            unsafe { ptr::copy_nonoverlapping(dump_addr as *const u8, dump.as_mut_ptr(), DUMP_SIZE) };
+            report.dump = Some(dump);
+        }
+        report
+    }
+}
+
+// レッドゾーンを付けたlayoutと、ブロックの先頭からユーザーに返すポインタまでの大きさ
+pub fn padded_layout(layout: &Layout) -> (Layout, usize) {
+    let front = REDZONE_SIZE.max(layout.align());
+    let padded = Layout::from_size_align(front + layout.size() + REDZONE_SIZE, layout.align()).unwrap();
+    (padded, front)
+}
+
+// 確保したブロック[block, block + block_len)にヘッダとレッドゾーンを書いて、ユーザーに返すアドレスを返す
+pub unsafe fn arm(block: usize, block_len: usize, layout: &Layout) -> usize {
+    let (_, front) = padded_layout(layout);
+    let addr = block + front;
+    ptr::write_bytes(block as *mut u8, REDZONE_BYTE, front);
+    ptr::write(block as *mut Header, Header { size: layout.size() as u32, front: front as u16, magic: ALLOCATED_MAGIC });
+    ptr::write_bytes((addr + layout.size()) as *mut u8, REDZONE_BYTE, block_len - front - layout.size());
+    addr
+}
+
+fn first_mismatch(start: usize, len: usize, value: u8) -> Option<usize> {
+    (0..len).find(|offset| unsafe { *((start + offset) as *const u8) } != value)
+}
+
+pub fn is_allocated(block: usize) -> bool {
+    unsafe { (*(block as *const Header)).magic == ALLOCATED_MAGIC }
+}
+
+pub fn is_poisoned_header(block: usize) -> bool {
+    unsafe { (*(block as *const Header)).magic == POISON_MAGIC }
+}
+
+// 確保中のブロックのヘッダとレッドゾーンを確かめる。layoutがあれば確保した時と同じか確かめる
+pub fn check_allocated(block: usize, block_len: usize, layout: Option<&Layout>) -> Result<(), HeapCorruption> {
+    let header = unsafe { *(block as *const Header) };
+    if header.magic == POISON_MAGIC {
+        return Err(HeapCorruption::DoubleFree { addr: block + layout.map_or(REDZONE_SIZE, |l| padded_layout(l).1) });
+    }
+    let front = header.front as usize;
+    let size = header.size as usize;
+    if header.magic != ALLOCATED_MAGIC || front < REDZONE_SIZE || !front.is_power_of_two() || front + size + REDZONE_SIZE > block_len {
+        return Err(HeapCorruption::BadHeader { block });
+    }
+    let addr = block + front;
+    if let Some(layout) = layout {
+        if size != layout.size() || front != padded_layout(layout).1 {
+            return Err(HeapCorruption::SizeMismatch { addr, allocated: size, freed: layout.size() });
+        }
+    }
+    if let Some(offset) = first_mismatch(block + HEADER_SIZE, front - HEADER_SIZE, REDZONE_BYTE) {
+        return Err(HeapCorruption::RedZone { addr, size, offset: (HEADER_SIZE + offset) as isize - front as isize });
+    }
+    if let Some(offset) = first_mismatch(addr + size, block_len - front - size, REDZONE_BYTE) {
+        return Err(HeapCorruption::RedZone { addr, size, offset: (size + offset) as isize });
+    }
+    Ok(())
+}
+
+pub unsafe fn poison(block: usize, len: usize) {
+    ptr::write_bytes(block as *mut u8, POISON_BYTE, len);
+}
+
+// 解放したslabのブロックが書き換えられていないか確かめる
+pub fn check_poison(block: usize, len: usize) -> Result<(), HeapCorruption> {
+    match first_mismatch(block + FREE_LINK_SIZE, len - FREE_LINK_SIZE, POISON_BYTE) {
+        Some(offset) => Err(HeapCorruption::UseAfterFree { block, offset: FREE_LINK_SIZE + offset }),
+        None => Ok(()),
+    }
+}
+
+fn write_callers<W: Write>(w: &mut W, callers: &[u32]) -> fmt::Result {
+    for caller in callers.iter().take_while(|c| **c != 0) {
+        write!(w, " {:08x}", caller)?;
+    }
+    Ok(())
+}
+
+pub fn format_corruption_report<W: Write>(w: &mut W, report: &CorruptionReport) -> fmt::Result {
+    writeln!(w, "heap corruption: {}", report.corruption.reason())?;
+    match report.corruption {
+        HeapCorruption::ForeignFree { addr } => writeln!(w, "  ptr {:08x} was not allocated from this heap", addr)?,
+        HeapCorruption::DoubleFree { addr } => writeln!(w, "  ptr {:08x} is already free", addr)?,
+        HeapCorruption::BadHeader { block } => writeln!(w, "  block {:08x} has no valid header", block)?,
+        HeapCorruption::SizeMismatch { addr, allocated, freed } => {
+            writeln!(w, "  ptr {:08x} allocated with {:x} bytes, freed with {:x} bytes", addr, allocated, freed)?
+        },
+        HeapCorruption::RedZone { addr, size, offset } => {
+            let side = if offset < 0 { "before" } else { "after" };
+            writeln!(w, "  ptr {:08x} size {:x}: byte at offset {} ({}) changed", addr, size, offset, side)?
+        },
+        HeapCorruption::UseAfterFree { block, offset } => {
+            writeln!(w, "  free block {:08x} written at offset {:x}", block, offset)?
+        },
+        HeapCorruption::BadFreeList { allocator, block } => {
+            writeln!(w, "  slab {} has bad free block {:08x}", allocator_name(allocator), block)?
+        },
+        HeapCorruption::BadHole { addr, size } => writeln!(w, "  hole {:08x} size {:x}", addr, size)?,
+    }
+    write!(w, "  detected at")?;
+    write_callers(w, &report.detected_at)?;
+    writeln!(w)?;
+    if let Some(callers) = report.allocated_at {
+        write!(w, "  allocated at")?;
+        write_callers(w, &callers)?;
+        writeln!(w)?;
+    }
+    if let Some(dump) = report.dump {
+        for (idx, line) in dump.chunks(16).enumerate() {
+            write!(w, "  {:08x}:", report.dump_addr + idx * 16)?;
+            for byte in line.iter() {
+                write!(w, " {:02x}", byte)?;
+            }
+            writeln!(w)?;
+        }
+    }
+    Ok(())
+}
+
+// 壊れたHeapでは続けられないので、画面に出して止める(ロックを放してから呼ぶ)
+pub fn report_corruption(report: &CorruptionReport) -> ! {
+    let _ = format_corruption_report(&mut ScreenWriter::new(0, 400), report);
+    panic!("heap corruption: {}", report.corruption.reason());
+}
+
+static NEXT_WALK_AT: Mutex<usize> = Mutex::new(0);
+
+// メインループから呼ぶ。WALK_INTERVALごとにデバッグを有効にしたHeapを全て確かめる
+pub fn poll_heap_walk(heaps: &[&LockedHeap]) {
+    let now = get_uptime();
+    {
+        let mut next_walk_at = NEXT_WALK_AT.lock();
+        if now < *next_walk_at { return; }
+        *next_walk_at = now + WALK_INTERVAL;
+    }
+    for heap in heaps.iter() {
+        if let Err(report) = heap.walk() {
+            report_corruption(&report);
+        }
+    }
+}
@@ -1,49 +1,225 @@
-use core::ptr::NonNull;
-use core::alloc::{ Allocator as Alloc, AllocError as AllocErr, Layout };
+use core::slice;
 
 use crate::spin::mutex::Mutex;
-use super::Heap;
-use core::ops::{Deref, DerefMut};
+use crate::arch::boot_info::BootInfo;
+use crate::arch::paging::{KERNEL_BASE_ADDR, KERNEL_DIRECT_MAP_SIZE};
 
-// pub struct LockedFrameHeap {
-//     heap: Mutex<Option<Heap>>,
-// }
-pub struct LockedFrameHeap(Mutex<Option<Heap>>);
+// 物理フレーム(4KiB)をビットマップで管理するアロケータ
+// BIOSのメモリマップ(E820)でusableとされた領域だけを配り、
+// カーネル本体やページテーブル、VRAMなど使用中の領域は予約しておく
 
-impl LockedFrameHeap {
-    pub fn new() -> Self {
-        Self(Mutex::new(None))
+pub const FRAME_SIZE: usize = 4096;
+// 32bitの物理アドレス空間全体(4GiB)のフレーム数
+const MAX_FRAMES: usize = 1 << 20;
+const BITS_PER_WORD: usize = 32;
+const BITMAP_WORDS: usize = MAX_FRAMES / BITS_PER_WORD;
+const ALL_USED: u32 = 0xffffffff;
+const PHYS_ADDRESS_END: u64 = MAX_FRAMES as u64 * FRAME_SIZE as u64;
+
+// ビットマップ(128KiB)はページテーブルの直後に置く
+pub const FRAME_BITMAP_ADDR: u32 = 0x00e01000;
+pub const FRAME_BITMAP_SIZE: usize = BITMAP_WORDS * 4;
+
+// BIOS・ディスクキャッシュ・GDT/IDT・カーネル本体・スタック
+const LOW_MEMORY_END: u32 = 0x00400000;
+// ページディレクトリとページテーブル(paging::PAGE_DIR_BASE_ADDRから4KiB + 4MiB)
+const PAGE_TABLES_START: u32 = 0x00a00000;
+const PAGE_TABLES_SIZE: usize = FRAME_SIZE + FRAME_SIZE * 1024;
+
+// ストレートマッピングされている範囲(0~16MiB)のフレーム数
+// ページテーブルやallocatorの作業用の領域はここからしか取れないので、普通のフレームはこの上から配る
+const DIRECT_MAP_FRAMES: usize = KERNEL_DIRECT_MAP_SIZE / FRAME_SIZE;
+
+// E820が取れなかった場合は1MiB~64MiBだけがあるものとして扱う
+const FALLBACK_USABLE_START: u64 = 0x00100000;
+const FALLBACK_USABLE_END: u64 = 0x04000000;
+
+pub struct FrameAllocator {
+    bitmap: usize, // ビットマップの先頭アドレス(bitが1なら使用中)
+    total_frames: usize,
+    free_frames: usize,
+    // 次に探し始めるフレーム
+    next_frame: usize,
+}
+
+impl FrameAllocator {
+    fn bitmap(&mut self) -> &mut [u32] {
+        unsafe { slice::from_raw_parts_mut(self.bitmap as *mut u32, BITMAP_WORDS) }
     }
 
-    pub fn init(&self, heap_addr_start: usize, size: usize) {
-        *self.0.lock() = unsafe { Some(Heap::new(heap_addr_start, size)) };
+    fn is_used(&mut self, frame: usize) -> bool {
+        self.bitmap()[frame / BITS_PER_WORD] & (1 << (frame % BITS_PER_WORD)) != 0
     }
-}
 
-impl Deref for LockedFrameHeap {
-    type Target = Mutex<Option<Heap>>;
-    fn deref(&self) -> &Mutex<Option<Heap>> { &self.0 }
-}
+    fn set_used(&mut self, frame: usize) {
+        if self.is_used(frame) { return; }
+        self.bitmap()[frame / BITS_PER_WORD] |= 1 << (frame % BITS_PER_WORD);
+        self.free_frames -= 1;
+    }
+
+    fn set_free(&mut self, frame: usize) {
+        if !self.is_used(frame) { return; }
+        self.bitmap()[frame / BITS_PER_WORD] &= !(1 << (frame % BITS_PER_WORD));
+        self.free_frames += 1;
+    }
 
-impl DerefMut for LockedFrameHeap {
-    fn deref_mut(&mut self) -> &mut Mutex<Option<Heap>> { &mut self.0 }
+    // [start, end)に完全に含まれるフレームを空きにする
+    fn add_usable(&mut self, start: u64, end: u64) {
+        let end = end.min(PHYS_ADDRESS_END);
+        let first = ((start + FRAME_SIZE as u64 - 1) / FRAME_SIZE as u64) as usize;
+        let last = (end / FRAME_SIZE as u64) as usize;
+        for frame in first..last {
+            if self.is_used(frame) {
+                self.set_free(frame);
+                self.total_frames += 1;
+            }
+        }
+    }
+
+    // [start, start + size)に掛かるフレームを使用中にする
+    fn reserve(&mut self, start: u64, size: u64) {
+        let end = (start + size).min(PHYS_ADDRESS_END);
+        let first = (start / FRAME_SIZE as u64) as usize;
+        let last = ((end + FRAME_SIZE as u64 - 1) / FRAME_SIZE as u64) as usize;
+        for frame in first..last {
+            self.set_used(frame);
+        }
+    }
+
+    // ストレートマッピングの外(16MiB~)を優先して、足りなければ下からも配る
+    fn allocate(&mut self) -> Option<u32> {
+        if self.free_frames == 0 { return None; }
+        self.allocate_between(DIRECT_MAP_FRAMES, MAX_FRAMES)
+            .or_else(|| self.allocate_between(0, DIRECT_MAP_FRAMES))
+    }
+
+    // [first, last)のフレームからnext_frameの位置(範囲外なら先頭)から探して1つ配る
+    // firstとlastはBITS_PER_WORDの倍数
+    fn allocate_between(&mut self, first: usize, last: usize) -> Option<u32> {
+        let count = last - first;
+        let start = if first <= self.next_frame && self.next_frame < last { self.next_frame - first } else { 0 };
+        let mut i = 0;
+        while i < count {
+            let frame = first + (start + i) % count;
+            // 32フレームまとめて使用中なら飛ばす
+            if frame % BITS_PER_WORD == 0 && self.bitmap()[frame / BITS_PER_WORD] == ALL_USED {
+                i += BITS_PER_WORD;
+                continue;
+            }
+            if !self.is_used(frame) {
+                self.set_used(frame);
+                self.next_frame = frame + 1;
+                return Some((frame * FRAME_SIZE) as u32);
+            }
+            i += 1;
+        }
+        None
+    }
+
+    // [min_address, max_address)の中で物理的に連続したcount個のフレームを探す
+    fn allocate_contiguous(&mut self, count: usize, min_address: u32, max_address: u32) -> Option<u32> {
+        self.allocate_aligned(count, 1, 0, min_address, max_address)
+    }
+
+    // allocate_contiguousに加えて、先頭をalignフレームの倍数にし、boundaryフレームの境界を跨がないようにする
+    // (alignとboundaryは2の累乗。boundaryが0なら制限なし)
+    fn allocate_aligned(&mut self, count: usize, align: usize, boundary: usize, min_address: u32, max_address: u32) -> Option<u32> {
+        if count == 0 || count > self.free_frames { return None; }
+        if boundary != 0 && count > boundary { return None; }
+        let align_up = |frame: usize| (frame + align - 1) & !(align - 1);
+        let first = (min_address as usize + FRAME_SIZE - 1) / FRAME_SIZE;
+        let last = (max_address as usize / FRAME_SIZE).min(MAX_FRAMES);
+        let mut start = align_up(first);
+        while start + count <= last {
+            if boundary != 0 && start / boundary != (start + count - 1) / boundary {
+                start = align_up((start / boundary + 1) * boundary);
+                continue;
+            }
+            // 使用中のフレームがあればその次から探し直す
+            match (start..(start + count)).rev().find(|frame| self.is_used(*frame)) {
+                Some(used) => start = align_up(used + 1),
+                None => {
+                    for frame in start..(start + count) {
+                        self.set_used(frame);
+                    }
+                    return Some((start * FRAME_SIZE) as u32);
+                },
+            }
+        }
+        None
+    }
 }
 
-unsafe impl<'a> Alloc for &'a LockedFrameHeap {
-    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocErr> {
-        if let Some(ref mut heap) = *self.0.lock() {
-            heap.allocate(layout)
-        } else {
-            panic!("frame heap allocate: heap not initialized");
+pub struct LockedFrameAllocator(Mutex<Option<FrameAllocator>>);
+
+pub static FRAME_ALLOCATOR: LockedFrameAllocator = LockedFrameAllocator(Mutex::new(None));
+
+impl LockedFrameAllocator {
+    // ページテーブルを作る前(secondboot.asmが作ったストレートマッピングで動いているうち)に呼ぶ
+    pub fn init(&self, boot_info: &BootInfo) {
+        let mut allocator = FrameAllocator {
+            bitmap: (KERNEL_BASE_ADDR + FRAME_BITMAP_ADDR) as usize,
+            total_frames: 0,
+            free_frames: 0,
+            next_frame: 0,
+        };
+        // 全て使用中にしてからusableな領域だけを空ける
+        for word in allocator.bitmap().iter_mut() {
+            *word = ALL_USED;
+        }
+
+        let memory_map = boot_info.memory_map();
+        if memory_map.is_empty() {
+            allocator.add_usable(FALLBACK_USABLE_START, FALLBACK_USABLE_END);
+        }
+        for entry in memory_map.iter().filter(|entry| entry.is_usable()) {
+            allocator.add_usable(entry.base, entry.end());
+        }
+        // 重なっているusableと予約の領域は予約を優先する
+        for entry in memory_map.iter().filter(|entry| !entry.is_usable()) {
+            allocator.reserve(entry.base, entry.length);
         }
+
+        allocator.reserve(0, LOW_MEMORY_END as u64);
+        allocator.reserve(PAGE_TABLES_START as u64, PAGE_TABLES_SIZE as u64);
+        allocator.reserve(FRAME_BITMAP_ADDR as u64, FRAME_BITMAP_SIZE as u64);
+        let vram_size = boot_info.scrnx as u64 * boot_info.scrny as u64;
+        allocator.reserve(boot_info.vram as u64, vram_size);
+
+        *self.0.lock() = Some(allocator);
     }
 
-    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
-        if let Some(ref mut heap) = *self.0.lock() {
-            heap.deallocate(ptr, layout)
-        } else {
-            panic!("frame heap deallocate: heap not initialized");
+    pub fn allocate_frame(&self) -> Option<u32> {
+        self.0.lock().as_mut().and_then(|allocator| allocator.allocate())
+    }
+
+    pub fn allocate_contiguous_frames(&self, count: usize, min_address: u32, max_address: u32) -> Option<u32> {
+        self.0.lock().as_mut().and_then(|allocator| allocator.allocate_contiguous(count, min_address, max_address))
+    }
+
+    pub fn allocate_aligned_frames(&self, count: usize, align: usize, boundary: usize, min_address: u32, max_address: u32) -> Option<u32> {
+        self.0.lock().as_mut().and_then(|allocator| allocator.allocate_aligned(count, align, boundary, min_address, max_address))
+    }
+
+    pub fn free_frame(&self, phys_address: u32) {
+        if let Some(ref mut allocator) = *self.0.lock() {
+            allocator.set_free(phys_address as usize / FRAME_SIZE);
         }
     }
 
+    // 既に使っている領域を配らないようにする
+    pub fn reserve_frames(&self, phys_address: u32, size: usize) {
+        if let Some(ref mut allocator) = *self.0.lock() {
+            allocator.reserve(phys_address as u64, size as u64);
+        }
+    }
+
+    pub fn free_frame_count(&self) -> usize {
+        self.0.lock().as_ref().map_or(0, |allocator| allocator.free_frames)
+    }
+
+    // E820でusableだったフレームの数(予約したものも含む)
+    pub fn total_frame_count(&self) -> usize {
+        self.0.lock().as_ref().map_or(0, |allocator| allocator.total_frames)
+    }
 }
@@ -39,11 +39,12 @@ impl HoleList {
     pub fn allocate_first_fit(&mut self, layout: Layout) -> Result<NonNull<[u8]>, AllocErr> {
         assert!(layout.size() >= Self::min_size());
         allocate_first_fit(&mut self.first, layout).map(|allocation| {
+            // 切り出した残りなので他のholeとは重ならない
             if let Some(padding) = allocation.front_padding {
-                deallocate(&mut self.first, padding.addr, padding.size);
+                let _ = deallocate(&mut self.first, padding.addr, padding.size);
             }
             if let Some(padding) = allocation.back_padding {
-                deallocate(&mut self.first, padding.addr, padding.size);
+                let _ = deallocate(&mut self.first, padding.addr, padding.size);
             }
             // MemoryBlock {
             //     ptr: NonNull::new(allocation.info.addr as *mut u8).unwrap(),
@@ -55,10 +56,30 @@ impl HoleList {
         })
     }
 
-    pub unsafe fn deallocate(&mut self, ptr: NonNull<u8>, layout: Layout) {
+    /// Frees the given block. Fails without changing the list if the block overlaps an existing
+    /// hole, which usually means it was freed twice.
+    pub unsafe fn deallocate(&mut self, ptr: NonNull<u8>, layout: Layout) -> Result<(), HoleError> {
         deallocate(&mut self.first, ptr.as_ptr() as usize, layout.size())
     }
 
+    /// Iterates over the holes in address order.
+    pub fn holes(&self) -> Holes {
+        Holes { current: self.first.next.as_ref().map(|hole| &**hole) }
+    }
+
+    /// Returns the number of holes, their total size and the size of the largest one.
+    pub fn stats(&self) -> HoleStats {
+        let mut stats = HoleStats { count: 0, free: 0, largest: 0 };
+        let mut current = self.first.next.as_ref();
+        while let Some(hole) = current {
+            stats.count += 1;
+            stats.free += hole.size;
+            stats.largest = stats.largest.max(hole.size);
+            current = hole.next.as_ref();
+        }
+        stats
+    }
+
     /// Returns the minimal allocation size. Smaller allocations or deallocations are not allowed.
     pub fn min_size() -> usize {
         size_of::<usize>() * 2
@@ -79,6 +100,35 @@ impl Hole {
     }
 }
 
+/// A deallocated block that overlaps the hole at `hole_addr`.
+#[derive(Debug, Copy, Clone)]
+pub struct HoleError {
+    pub hole_addr: usize,
+    pub hole_size: usize,
+}
+
+pub struct Holes<'a> {
+    current: Option<&'a Hole>,
+}
+
+impl<'a> Iterator for Holes<'a> {
+    /// (address, size)
+    type Item = (usize, usize);
+
+    fn next(&mut self) -> Option<(usize, usize)> {
+        let hole = self.current?;
+        self.current = hole.next.as_ref().map(|next| &**next);
+        Some((hole as *const Hole as usize, hole.size))
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct HoleStats {
+    pub count: usize,
+    pub free: usize,
+    pub largest: usize,
+}
+
 #[derive(Debug, Copy, Clone)]
 pub struct HoleInfo {
     addr: usize,
@@ -164,7 +214,7 @@ fn allocate_first_fit(mut previous: &mut Hole, layout: Layout) -> Result<Allocat
     }
 }
 
-fn deallocate(mut hole: &mut Hole, addr: usize, mut size: usize) {
+fn deallocate(mut hole: &mut Hole, addr: usize, mut size: usize) -> Result<(), HoleError> {
     loop {
         assert!(size >= HoleList::min_size());
 
@@ -174,10 +224,16 @@ fn deallocate(mut hole: &mut Hole, addr: usize, mut size: usize) {
             hole as *mut _ as usize
         };
 
-        assert!(
-            hole_addr + hole.size <= addr,
-            "invalid deallocation (probably a double free)"
-        );
+        // invalid deallocation (probably a double free)
+        if hole_addr + hole.size > addr {
+            return Err(HoleError { hole_addr, hole_size: hole.size });
+        }
+        if let Some(next) = hole.next.as_ref() {
+            let next_addr = &**next as *const Hole as usize;
+            if addr < next_addr && addr + size > next_addr {
+                return Err(HoleError { hole_addr: next_addr, hole_size: next.size });
+            }
+        }
 
         let next_hole_info = hole.next.as_ref().map(|next| next.info());
 
@@ -235,6 +291,7 @@ fn deallocate(mut hole: &mut Hole, addr: usize, mut size: usize) {
         }
         break;
     }
+    Ok(())
 }
 
 /// Identity function to ease moving of references.
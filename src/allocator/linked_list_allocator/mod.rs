@@ -5,6 +5,7 @@ use core::ptr::NonNull;
 pub mod hole;
 use hole::HoleList;
 use hole::Hole;
+pub use hole::{HoleStats, HoleError, Holes};
 
 pub struct Heap {
     bottom: usize,
@@ -46,14 +47,18 @@ impl Heap {
         self.holes.allocate_first_fit(layout)
     }
 
-    pub unsafe fn deallocate(&mut self, ptr: NonNull<u8>, layout: Layout) {
+    pub unsafe fn deallocate(&mut self, ptr: NonNull<u8>, layout: Layout) -> Result<(), HoleError> {
         let mut size = layout.size();
         if size < HoleList::min_size() {
             size = HoleList::min_size();
         }
         let size = align_up(size, mem::align_of::<Hole>());
         let layout = Layout::from_size_align(size, layout.align()).unwrap();
-        self.holes.deallocate(ptr, layout);
+        self.holes.deallocate(ptr, layout)
+    }
+
+    pub fn holes(&self) -> Holes {
+        self.holes.holes()
     }
 
     pub fn bottom(&self) -> usize {
@@ -64,15 +69,29 @@ impl Heap {
         self.size
     }
 
+    pub fn hole_stats(&self) -> HoleStats {
+        self.holes.stats()
+    }
+
     pub fn top(&self) -> usize {
         self.bottom + self.size
     }
 
-    pub unsafe fn extend(&mut self, by: usize) {
+    // 既にあるholeと重なる場合は何も変えずにErr
+    pub unsafe fn extend(&mut self, by: usize) -> Result<(), HoleError> {
         let top = self.top();
         let layout = Layout::from_size_align(by, 1).unwrap();
-        self.holes.deallocate(NonNull::new_unchecked(top as *mut u8), layout);
+        self.holes.deallocate(NonNull::new_unchecked(top as *mut u8), layout)?;
         self.size += by;
+        Ok(())
+    }
+
+    // 今の領域と連続していないメモリを空きとして加える
+    pub unsafe fn add_memory(&mut self, addr: usize, size: usize) -> Result<(), HoleError> {
+        let layout = Layout::from_size_align(size, 1).unwrap();
+        self.holes.deallocate(NonNull::new_unchecked(addr as *mut u8), layout)?;
+        self.size += size;
+        Ok(())
     }
 }
 
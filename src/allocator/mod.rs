@@ -1,7 +1,7 @@
 #![feature(const_fn)]
 
 use core::ops::Deref;
-use core::ptr::NonNull;
+use core::ptr::{NonNull, null_mut};
 use core::alloc::{ Allocator as Alloc, AllocError as AllocErr, Layout, GlobalAlloc };
 
 use super::spin::mutex::Mutex;
@@ -10,16 +10,51 @@ pub mod slab;
 
 use self::slab::Slab;
 use core::cell::RefCell;
+use core::mem::size_of;
+use core::slice;
 
 pub mod linked_list_allocator;
 pub mod frame_allocator;
+pub mod page_source;
+pub mod stats;
+pub mod tracker;
+pub mod debug;
+
+use self::page_source::PageSource;
+use self::stats::{HeapStats, SizeClassStats};
+use self::tracker::{AllocationTracker, LeakReport, caller_addresses};
+use self::debug::{HeapCorruption, CorruptionReport, report_corruption};
+use self::slab::SlabError;
+use self::linked_list_allocator::hole::HoleList;
+use self::linked_list_allocator::HoleError;
+use alloc::string::String;
+use alloc::borrow::ToOwned;
 
 pub const NUM_OF_SLABS: usize = 8;
 pub const MIN_SLAB_SIZE: usize = 4096;
 pub const MIN_HEAP_SIZE: usize = NUM_OF_SLABS * MIN_SLAB_SIZE;
 
+const PAGE_SIZE: usize = 4096;
+// PageSourceからページを借りるHeapで、slabが一度に増やすページ数
+const SLAB_GROW_PAGES: usize = 1;
+// linked list allocatorが一度に増やす最小のページ数
+const LINKED_LIST_GROW_PAGES: usize = 16;
+// 全てのブロックが空いたページをslabごとにいくつまで返さずに持っておくか
+const KEEP_EMPTY_PAGES: usize = 1;
 
-#[derive(Copy, Clone)]
+// 小さい順。allocateで足りなければ後ろのallocatorに回す
+const ALLOCATORS: [HeapAllocator; NUM_OF_SLABS] = [
+    HeapAllocator::Slab64Bytes,
+    HeapAllocator::Slab128Bytes,
+    HeapAllocator::Slab256Bytes,
+    HeapAllocator::Slab512Bytes,
+    HeapAllocator::Slab1024Bytes,
+    HeapAllocator::Slab2048Bytes,
+    HeapAllocator::Slab4096Bytes,
+    HeapAllocator::LinkedListAllocator,
+];
+
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub enum HeapAllocator {
     Slab64Bytes,
     Slab128Bytes,
@@ -31,6 +66,54 @@ pub enum HeapAllocator {
     LinkedListAllocator,
 }
 
+impl HeapAllocator {
+    fn index(&self) -> usize {
+        ALLOCATORS.iter().position(|a| a == self).unwrap()
+    }
+}
+
+// PageSourceから借りたページ1枚の情報
+#[derive(Copy, Clone)]
+struct PageInfo {
+    owner: Option<HeapAllocator>,
+    free_blocks: u16, // slabのページなら空いているブロックの数
+}
+
+// PageSourceから借りたページの管理
+struct BorrowedPages {
+    source: &'static dyn PageSource,
+    start: usize,
+    // source.area()の全てのページの情報
+    pages: &'static mut [PageInfo],
+    // 全てのブロックが空いているページの数(slabごと)
+    empty_pages: [usize; NUM_OF_SLABS],
+    // 借りているページの数
+    borrowed: usize,
+}
+
+impl BorrowedPages {
+    fn page(&mut self, addr: usize) -> Option<&mut PageInfo> {
+        if addr < self.start { return None; }
+        self.pages.get_mut((addr - self.start) / PAGE_SIZE)
+    }
+}
+
+// allocatorごとの確保の回数
+#[derive(Copy, Clone, Default)]
+struct Counters {
+    live: usize,
+    peak: usize,
+    failed: usize,
+    fallbacks: usize,
+}
+
+enum HeapMemory {
+    // 決まった領域を均等に分けている
+    Fixed { start: usize, slab_size: usize },
+    // 足りなくなったらPageSourceからページを借りて増やし、空いたページは返す
+    Borrowed(RefCell<BorrowedPages>),
+}
+
 pub struct Heap {
     slab_64_bytes: RefCell<Slab>,
     slab_128_bytes: RefCell<Slab>,
@@ -40,6 +123,23 @@ pub struct Heap {
     slab_2048_bytes: RefCell<Slab>,
     slab_4096_bytes: RefCell<Slab>,
     linked_list_allocator: RefCell<linked_list_allocator::Heap>,
+    memory: HeapMemory,
+    counters: RefCell<[Counters; NUM_OF_SLABS]>,
+    // デバッグ用。Someなら解放されていない確保を記録する
+    tracker: RefCell<Option<AllocationTracker>>,
+    // デバッグ用。trueならレッドゾーンや毒でメモリの破壊を見つける(debugモジュール)
+    debug: bool,
+}
+
+pub enum HeapError {
+    OutOfMemory,
+    Corrupted(CorruptionReport),
+}
+
+impl From<AllocErr> for HeapError {
+    fn from(_: AllocErr) -> Self {
+        HeapError::OutOfMemory
+    }
 }
 
 impl Heap {
@@ -66,48 +166,463 @@ impl Heap {
             slab_2048_bytes: RefCell::new(Slab::new(heap_start_addr + 5 * slab_size, slab_size, 2048)),
             slab_4096_bytes: RefCell::new(Slab::new(heap_start_addr + 6 * slab_size, slab_size, 4096)),
             linked_list_allocator: RefCell::new(linked_list_allocator::Heap::new(heap_start_addr + 7 * slab_size, slab_size)),
+            memory: HeapMemory::Fixed { start: heap_start_addr, slab_size },
+            counters: RefCell::new([Counters::default(); NUM_OF_SLABS]),
+            tracker: RefCell::new(None),
+            debug: false,
+        }
+    }
+
+    // 空の状態から始めて、必要になった分だけsourceからページを借りるHeap
+    pub unsafe fn with_page_source(source: &'static dyn PageSource) -> Option<Self> {
+        let (start, size) = source.area();
+        let num_of_pages = size / PAGE_SIZE;
+        let info_pages = (num_of_pages * size_of::<PageInfo>() + PAGE_SIZE - 1) / PAGE_SIZE;
+        let info_addr = source.allocate_pages(info_pages)?;
+        let pages = slice::from_raw_parts_mut(info_addr as *mut PageInfo, num_of_pages);
+        for page in pages.iter_mut() {
+            *page = PageInfo { owner: None, free_blocks: 0 };
+        }
+        let heap = Heap {
+            slab_64_bytes: RefCell::new(Slab::empty(64)),
+            slab_128_bytes: RefCell::new(Slab::empty(128)),
+            slab_256_bytes: RefCell::new(Slab::empty(256)),
+            slab_512_bytes: RefCell::new(Slab::empty(512)),
+            slab_1024_bytes: RefCell::new(Slab::empty(1024)),
+            slab_2048_bytes: RefCell::new(Slab::empty(2048)),
+            slab_4096_bytes: RefCell::new(Slab::empty(4096)),
+            linked_list_allocator: RefCell::new(linked_list_allocator::Heap::empty()),
+            memory: HeapMemory::Borrowed(RefCell::new(BorrowedPages {
+                source,
+                start,
+                pages,
+                empty_pages: [0; NUM_OF_SLABS],
+                borrowed: info_pages,
+            })),
+            counters: RefCell::new([Counters::default(); NUM_OF_SLABS]),
+            tracker: RefCell::new(None),
+            debug: false,
+        };
+        // 最初から1回分ずつ持っておく
+        for allocator in ALLOCATORS.iter() {
+            heap.borrow_pages(*allocator, &Layout::from_size_align(1, 1).unwrap()).ok()?;
         }
+        Some(heap)
     }
 
-    pub unsafe fn grow(&self, mem_start_addr: usize, mem_size: usize, slab: HeapAllocator) {
-        match slab {
-            HeapAllocator::Slab64Bytes => self.slab_64_bytes.borrow_mut().grow(mem_start_addr, mem_size),
-            HeapAllocator::Slab128Bytes => self.slab_128_bytes.borrow_mut().grow(mem_start_addr, mem_size),
-            HeapAllocator::Slab256Bytes => self.slab_256_bytes.borrow_mut().grow(mem_start_addr, mem_size),
-            HeapAllocator::Slab512Bytes => self.slab_512_bytes.borrow_mut().grow(mem_start_addr, mem_size),
-            HeapAllocator::Slab1024Bytes => self.slab_1024_bytes.borrow_mut().grow(mem_start_addr, mem_size),
-            HeapAllocator::Slab2048Bytes => self.slab_2048_bytes.borrow_mut().grow(mem_start_addr, mem_size),
-            HeapAllocator::Slab4096Bytes => self.slab_4096_bytes.borrow_mut().grow(mem_start_addr, mem_size),
-            HeapAllocator::LinkedListAllocator => self.linked_list_allocator.borrow_mut().extend(mem_size),
+    // 領域の後ろに続くメモリを足す(LinkedListAllocatorは今の領域の最後から伸ばす)
+    pub unsafe fn grow(&self, mem_start_addr: usize, mem_size: usize, slab: HeapAllocator) -> Result<(), HoleError> {
+        match self.slab(slab) {
+            Some(slab) => {
+                slab.borrow_mut().grow(mem_start_addr, mem_size);
+                Ok(())
+            },
+            None => self.linked_list_allocator.borrow_mut().extend(mem_size),
+        }
+    }
+
+    fn slab(&self, allocator: HeapAllocator) -> Option<&RefCell<Slab>> {
+        match allocator {
+            HeapAllocator::Slab64Bytes => Some(&self.slab_64_bytes),
+            HeapAllocator::Slab128Bytes => Some(&self.slab_128_bytes),
+            HeapAllocator::Slab256Bytes => Some(&self.slab_256_bytes),
+            HeapAllocator::Slab512Bytes => Some(&self.slab_512_bytes),
+            HeapAllocator::Slab1024Bytes => Some(&self.slab_1024_bytes),
+            HeapAllocator::Slab2048Bytes => Some(&self.slab_2048_bytes),
+            HeapAllocator::Slab4096Bytes => Some(&self.slab_4096_bytes),
+            HeapAllocator::LinkedListAllocator => None,
+        }
+    }
+
+    // デバッグの検査(レッドゾーン・毒・二重解放の検出・Heap全体の確認)を有効にする
+    // 確保の大きさが変わるので、まだ何も確保していない時だけできる
+    pub fn enable_debug(&mut self) -> Result<(), &'static str> {
+        if self.counters.borrow().iter().any(|counter| counter.live > 0) {
+            return Err("heap already has allocations.");
+        }
+        self.debug = true;
+        for allocator in ALLOCATORS.iter() {
+            if let Some(slab) = self.slab(*allocator) {
+                let mut slab = slab.borrow_mut();
+                slab.set_checks(true);
+                let block_size = slab.block_size();
+                for block in slab.free_block_addrs() {
+                    unsafe { debug::poison(block + debug::FREE_LINK_SIZE, block_size - debug::FREE_LINK_SIZE) };
+                }
+            }
         }
+        Ok(())
+    }
+
+    pub fn is_debug(&self) -> bool {
+        self.debug
     }
 
     pub fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocErr> {
-        match Heap::layout_to_allocator(&layout) {
-            HeapAllocator::Slab64Bytes => self.slab_64_bytes.borrow_mut().allocate(layout),
-            HeapAllocator::Slab128Bytes => self.slab_128_bytes.borrow_mut().allocate(layout),
-            HeapAllocator::Slab256Bytes => self.slab_256_bytes.borrow_mut().allocate(layout),
-            HeapAllocator::Slab512Bytes => self.slab_512_bytes.borrow_mut().allocate(layout),
-            HeapAllocator::Slab1024Bytes => self.slab_1024_bytes.borrow_mut().allocate(layout),
-            HeapAllocator::Slab2048Bytes => self.slab_2048_bytes.borrow_mut().allocate(layout),
-            HeapAllocator::Slab4096Bytes => self.slab_4096_bytes.borrow_mut().allocate(layout),
-            HeapAllocator::LinkedListAllocator => self.linked_list_allocator.borrow_mut().allocate_first_fit(layout),
+        match self.try_allocate(layout) {
+            Ok(ptr) => Ok(ptr),
+            Err(HeapError::OutOfMemory) => Err(AllocErr),
+            Err(HeapError::Corrupted(report)) => panic!("heap corruption: {}", report.corruption.reason()),
+        }
+    }
+
+    pub fn try_allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, HeapError> {
+        if !self.debug {
+            let (ptr, _) = self.allocate_block(layout)?;
+            if let Some(ref mut tracker) = *self.tracker.borrow_mut() {
+                tracker.record(ptr.as_ptr() as *mut u8 as usize, layout.size());
+            }
+            return Ok(ptr);
+        }
+        // ブロックの前後にレッドゾーンを付ける
+        let (padded, _) = debug::padded_layout(&layout);
+        let (block, allocator) = self.allocate_block(padded)?;
+        let block = block.as_ptr() as *mut u8 as usize;
+        let block_len = self.block_len(allocator, &padded);
+        if self.slab(allocator).is_some() {
+            if let Err(corruption) = debug::check_poison(block, block_len) {
+                return Err(HeapError::Corrupted(self.corruption_report(corruption)));
+            }
+        }
+        let addr = unsafe { debug::arm(block, block_len, &layout) };
+        if let Some(ref mut tracker) = *self.tracker.borrow_mut() {
+            tracker.record(addr, layout.size());
+        }
+        Ok(unsafe { NonNull::slice_from_raw_parts(NonNull::new_unchecked(addr as *mut u8), layout.size()) })
+    }
+
+    // layoutに合うallocatorから取る。空きが無ければページを借りて増やし、それもできなければ大きいallocatorに回す
+    fn allocate_block(&self, layout: Layout) -> Result<(NonNull<[u8]>, HeapAllocator), HeapError> {
+        let first = Heap::layout_to_allocator(&layout).index();
+        for allocator in ALLOCATORS[first..].iter() {
+            let result = match self.allocate_from(*allocator, layout) {
+                Ok(ptr) => Ok(ptr),
+                Err(_) => match self.borrow_pages(*allocator, &layout) {
+                    Ok(_) => self.allocate_from(*allocator, layout),
+                    Err(HeapError::Corrupted(report)) => return Err(HeapError::Corrupted(report)),
+                    Err(HeapError::OutOfMemory) => Err(AllocErr),
+                },
+            };
+            if let Ok(ptr) = result {
+                let mut counters = self.counters.borrow_mut();
+                let counter = &mut counters[allocator.index()];
+                counter.live += 1;
+                counter.peak = counter.peak.max(counter.live);
+                if allocator.index() != first {
+                    counters[first].fallbacks += 1;
+                }
+                return Ok((ptr, *allocator));
+            }
+        }
+        self.counters.borrow_mut()[first].failed += 1;
+        Err(HeapError::OutOfMemory)
+    }
+
+    // レッドゾーンの検査に使うブロックの大きさ
+    fn block_len(&self, allocator: HeapAllocator, padded: &Layout) -> usize {
+        match self.slab(allocator) {
+            Some(slab) => slab.borrow().block_size(),
+            None => padded.size(),
+        }
+    }
+
+    fn allocate_from(&self, allocator: HeapAllocator, layout: Layout) -> Result<NonNull<[u8]>, AllocErr> {
+        let slab = match self.slab(allocator) {
+            Some(slab) => slab,
+            None => return self.linked_list_allocator.borrow_mut().allocate_first_fit(layout),
+        };
+        let mut slab = slab.borrow_mut();
+        let ptr = slab.allocate(layout)?;
+        if let HeapMemory::Borrowed(ref pages) = self.memory {
+            let mut pages = pages.borrow_mut();
+            let blocks_per_page = PAGE_SIZE / slab.block_size();
+            let mut became_used = false;
+            if let Some(page) = pages.page(ptr.as_ptr() as *mut u8 as usize) {
+                became_used = page.free_blocks as usize == blocks_per_page;
+                page.free_blocks -= 1;
+            }
+            if became_used {
+                pages.empty_pages[allocator.index()] -= 1;
+            }
+        }
+        Ok(ptr)
+    }
+
+    // allocatorにPageSourceから借りたページを足す
+    // 借りたページが既にあるholeと重なる場合(hole listが壊れている)はCorrupted
+    fn borrow_pages(&self, allocator: HeapAllocator, layout: &Layout) -> Result<(), HeapError> {
+        let pages = match self.memory {
+            HeapMemory::Borrowed(ref pages) => pages,
+            HeapMemory::Fixed { .. } => return Err(HeapError::OutOfMemory),
+        };
+        let mut pages = pages.borrow_mut();
+        let (count, free_blocks) = match self.slab(allocator) {
+            Some(slab) => (SLAB_GROW_PAGES, PAGE_SIZE / slab.borrow().block_size()),
+            None => {
+                // alignのずれを詰めても入るようにする
+                let needed = (layout.size() + layout.align() + PAGE_SIZE - 1) / PAGE_SIZE;
+                (needed.max(LINKED_LIST_GROW_PAGES), 0)
+            },
+        };
+        let addr = pages.source.allocate_pages(count).ok_or(AllocErr)?;
+        pages.borrowed += count;
+        if self.debug && self.slab(allocator).is_some() {
+            unsafe { debug::poison(addr, count * PAGE_SIZE) };
+        }
+        for idx in 0..count {
+            if let Some(page) = pages.page(addr + idx * PAGE_SIZE) {
+                *page = PageInfo { owner: Some(allocator), free_blocks: free_blocks as u16 };
+            }
+        }
+        unsafe {
+            match self.slab(allocator) {
+                Some(slab) => {
+                    pages.empty_pages[allocator.index()] += count;
+                    slab.borrow_mut().grow(addr, count * PAGE_SIZE);
+                },
+                None => self.linked_list_allocator.borrow_mut().add_memory(addr, count * PAGE_SIZE)
+                    .map_err(|e| HeapError::Corrupted(self.corruption_report(HeapCorruption::BadHole { addr: e.hole_addr, size: e.hole_size })))?,
+            }
+        }
+        Ok(())
+    }
+
+    // addrを配ったallocator(小さいallocatorから溢れた分は大きいallocatorから確保している)
+    // このHeapのメモリでなければNone
+    fn owner(&self, addr: usize) -> Option<HeapAllocator> {
+        match self.memory {
+            HeapMemory::Fixed { start, slab_size } => {
+                if start <= addr && addr < start + NUM_OF_SLABS * slab_size {
+                    Some(ALLOCATORS[(addr - start) / slab_size])
+                } else {
+                    None
+                }
+            },
+            HeapMemory::Borrowed(ref pages) => pages.borrow_mut().page(addr).and_then(|page| page.owner),
         }
     }
 
     pub unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
-        match Heap::layout_to_allocator(&layout) {
-            HeapAllocator::Slab64Bytes => self.slab_64_bytes.borrow_mut().deallocate(ptr),
-            HeapAllocator::Slab128Bytes => self.slab_128_bytes.borrow_mut().deallocate(ptr),
-            HeapAllocator::Slab256Bytes => self.slab_256_bytes.borrow_mut().deallocate(ptr),
-            HeapAllocator::Slab512Bytes => self.slab_512_bytes.borrow_mut().deallocate(ptr),
-            HeapAllocator::Slab1024Bytes => self.slab_1024_bytes.borrow_mut().deallocate(ptr),
-            HeapAllocator::Slab2048Bytes => self.slab_2048_bytes.borrow_mut().deallocate(ptr),
-            HeapAllocator::Slab4096Bytes => self.slab_4096_bytes.borrow_mut().deallocate(ptr),
-            HeapAllocator::LinkedListAllocator => self.linked_list_allocator.borrow_mut().deallocate(ptr, layout),
+        if let Err(report) = self.try_deallocate(ptr, layout) {
+            panic!("heap corruption: {}", report.corruption.reason());
         }
     }
 
+    pub unsafe fn try_deallocate(&self, ptr: NonNull<u8>, layout: Layout) -> Result<(), CorruptionReport> {
+        let addr = ptr.as_ptr() as usize;
+        let (block, block_layout) = if self.debug {
+            let (padded, front) = debug::padded_layout(&layout);
+            (addr.wrapping_sub(front), padded)
+        } else {
+            (addr, layout)
+        };
+        let allocator = match self.owner(block) {
+            Some(allocator) => allocator,
+            None => return Err(self.corruption_report(HeapCorruption::ForeignFree { addr })),
+        };
+        if self.debug {
+            let block_len = self.block_len(allocator, &block_layout);
+            // linked list allocatorは解放したブロックの先頭にholeを書くので、holeの中なら二重解放
+            let in_hole = self.slab(allocator).is_none() && !debug::is_allocated(block)
+                && self.linked_list_allocator.borrow().holes().any(|(hole, size)| hole <= block && block < hole + size);
+            if in_hole {
+                return Err(self.corruption_report(HeapCorruption::DoubleFree { addr }));
+            }
+            if let Err(corruption) = debug::check_allocated(block, block_len, Some(&layout)) {
+                return Err(self.corruption_report(corruption));
+            }
+            debug::poison(block, block_len);
+        }
+        let block_ptr = NonNull::new_unchecked(block as *mut u8);
+        let result = match self.slab(allocator) {
+            Some(slab) => slab.borrow_mut().deallocate(block_ptr).map_err(|e| match e {
+                SlabError::NotBlockStart => HeapCorruption::ForeignFree { addr },
+                SlabError::DoubleFree => HeapCorruption::DoubleFree { addr },
+            }),
+            None => self.linked_list_allocator.borrow_mut().deallocate(block_ptr, block_layout)
+                .map_err(|_| HeapCorruption::DoubleFree { addr }),
+        };
+        if let Err(corruption) = result {
+            return Err(self.corruption_report(corruption));
+        }
+
+        {
+            let mut counters = self.counters.borrow_mut();
+            let counter = &mut counters[allocator.index()];
+            counter.live = counter.live.saturating_sub(1);
+        }
+        if let Some(ref mut tracker) = *self.tracker.borrow_mut() {
+            tracker.forget(addr);
+        }
+        if self.slab(allocator).is_some() {
+            self.release_empty_page(allocator, block);
+        }
+        Ok(())
+    }
+
+    fn corruption_report(&self, corruption: HeapCorruption) -> CorruptionReport {
+        let allocated_at = match corruption {
+            HeapCorruption::DoubleFree { addr }
+            | HeapCorruption::SizeMismatch { addr, .. }
+            | HeapCorruption::RedZone { addr, .. } => {
+                self.tracker.borrow().as_ref().and_then(|tracker| tracker.find(addr)).map(|record| record.callers)
+            },
+            _ => None,
+        };
+        CorruptionReport::new(corruption, caller_addresses(), allocated_at)
+    }
+
+    // Heap全体を歩いて壊れていないか確かめる(デバッグが有効な時だけ)
+    pub fn walk(&self) -> Result<(), CorruptionReport> {
+        if !self.debug { return Ok(()); }
+        self.walk_slabs().and_then(|_| self.walk_holes()).map_err(|corruption| self.corruption_report(corruption))
+    }
+
+    fn walk_slabs(&self) -> Result<(), HeapCorruption> {
+        for allocator in ALLOCATORS.iter() {
+            let slab = match self.slab(*allocator) {
+                Some(slab) => slab.borrow(),
+                None => continue,
+            };
+            let block_size = slab.block_size();
+            // 空きリストのブロックはこのslabのもので、毒が残っている
+            let mut count = 0;
+            for block in slab.free_block_addrs() {
+                if block % block_size != 0 || self.owner(block) != Some(*allocator) {
+                    return Err(HeapCorruption::BadFreeList { allocator: *allocator, block });
+                }
+                debug::check_poison(block, block_size)?;
+                count += 1;
+            }
+            if count != slab.free_blocks() {
+                return Err(HeapCorruption::BadFreeList { allocator: *allocator, block: 0 });
+            }
+            // 確保中のブロックはレッドゾーンが残っている
+            let mut result = Ok(());
+            self.for_each_slab_range(*allocator, |start, size| {
+                for block in (start..(start + size)).step_by(block_size) {
+                    if result.is_err() { return; }
+                    if debug::is_allocated(block) {
+                        result = debug::check_allocated(block, block_size, None);
+                    } else if !debug::is_poisoned_header(block) {
+                        result = Err(HeapCorruption::BadHeader { block });
+                    }
+                }
+            });
+            result?;
+        }
+        Ok(())
+    }
+
+    // allocatorのslabが持っているメモリの範囲(start, size)を順番に呼ぶ
+    fn for_each_slab_range(&self, allocator: HeapAllocator, mut f: impl FnMut(usize, usize)) {
+        match self.memory {
+            HeapMemory::Fixed { start, slab_size } => f(start + allocator.index() * slab_size, slab_size),
+            HeapMemory::Borrowed(ref pages) => {
+                let pages = pages.borrow();
+                for (idx, page) in pages.pages.iter().enumerate() {
+                    if page.owner == Some(allocator) {
+                        f(pages.start + idx * PAGE_SIZE, PAGE_SIZE);
+                    }
+                }
+            },
+        }
+    }
+
+    // holeはアドレス順に並んでいて、重ならず、linked list allocatorのメモリの中にある
+    fn walk_holes(&self) -> Result<(), HeapCorruption> {
+        let lla = self.linked_list_allocator.borrow();
+        let mut prev_end = 0;
+        for (addr, size) in lla.holes() {
+            if addr < prev_end || size < HoleList::min_size() || self.owner(addr) != Some(HeapAllocator::LinkedListAllocator) {
+                return Err(HeapCorruption::BadHole { addr, size });
+            }
+            prev_end = addr + size;
+        }
+        Ok(())
+    }
+
+    // slabのブロックが解放された時に呼ぶ。ページの全てのブロックが空き、空きページが多すぎればPageSourceに返す
+    fn release_empty_page(&self, allocator: HeapAllocator, addr: usize) {
+        let pages = match self.memory {
+            HeapMemory::Borrowed(ref pages) => pages,
+            HeapMemory::Fixed { .. } => return,
+        };
+        let mut pages = pages.borrow_mut();
+        let slab = self.slab(allocator).unwrap();
+        let blocks_per_page = PAGE_SIZE / slab.borrow().block_size();
+        let page_addr = addr & !(PAGE_SIZE - 1);
+        let became_empty = match pages.page(page_addr) {
+            Some(page) => {
+                page.free_blocks += 1;
+                page.free_blocks as usize == blocks_per_page
+            },
+            None => false,
+        };
+        if !became_empty { return; }
+        let idx = allocator.index();
+        pages.empty_pages[idx] += 1;
+        if pages.empty_pages[idx] <= KEEP_EMPTY_PAGES { return; }
+
+        slab.borrow_mut().shrink(page_addr, PAGE_SIZE);
+        pages.empty_pages[idx] -= 1;
+        if let Some(page) = pages.page(page_addr) {
+            *page = PageInfo { owner: None, free_blocks: 0 };
+        }
+        pages.source.free_pages(page_addr, 1);
+        pages.borrowed -= 1;
+    }
+
+    pub fn stats(&self) -> HeapStats {
+        let counters = self.counters.borrow();
+        let holes = self.linked_list_allocator.borrow().hole_stats();
+        let mut classes = [SizeClassStats {
+            allocator: HeapAllocator::LinkedListAllocator,
+            block_size: 0,
+            capacity: 0,
+            in_use: 0,
+            allocations: 0,
+            peak: 0,
+            failed: 0,
+            fallbacks: 0,
+        }; NUM_OF_SLABS];
+        for (idx, allocator) in ALLOCATORS.iter().enumerate() {
+            let (block_size, capacity, in_use) = match self.slab(*allocator) {
+                Some(slab) => {
+                    let slab = slab.borrow();
+                    (slab.block_size(), slab.total_blocks() * slab.block_size(), slab.used_blocks() * slab.block_size())
+                },
+                None => {
+                    let size = self.linked_list_allocator.borrow().size();
+                    (0, size, size - holes.free)
+                },
+            };
+            classes[idx] = SizeClassStats {
+                allocator: *allocator,
+                block_size,
+                capacity,
+                in_use,
+                allocations: counters[idx].live,
+                peak: counters[idx].peak,
+                failed: counters[idx].failed,
+                fallbacks: counters[idx].fallbacks,
+            };
+        }
+        let borrowed_pages = match self.memory {
+            HeapMemory::Borrowed(ref pages) => Some(pages.borrow().borrowed),
+            HeapMemory::Fixed { .. } => None,
+        };
+        HeapStats { classes, holes, borrowed_pages }
+    }
+
+    pub fn set_tracker(&self, tracker: Option<AllocationTracker>) -> Option<AllocationTracker> {
+        self.tracker.replace(tracker)
+    }
+
+    pub fn leak_report(&self) -> Option<LeakReport> {
+        self.tracker.borrow().as_ref().map(|tracker| tracker.report())
+    }
+
     pub fn usable_size(&self, layout: &Layout) -> (usize, usize) {
         match Heap::layout_to_allocator(&layout) {
             HeapAllocator::Slab64Bytes => (layout.size(), 64),
@@ -178,6 +693,81 @@ impl LockedHeap {
         *self.heap.lock() = unsafe { Some(Heap::new(heap_addr_start, size)) };
     }
 
+    pub fn init_with_page_source(&self, source: &'static dyn PageSource) {
+        match unsafe { Heap::with_page_source(source) } {
+            Some(heap) => *self.heap.lock() = Some(heap),
+            None => panic!("init_with_page_source: failed to borrow pages"),
+        }
+    }
+
+    pub fn stats(&self) -> Option<HeapStats> {
+        self.heap.lock().as_ref().map(|heap| heap.stats())
+    }
+
+    // 解放されていない確保を記録し始める(記録する表はHeapの外に取るので、ロックを持つ前に作る)
+    pub fn enable_tracking(&self) -> Result<(), String> {
+        let tracker = AllocationTracker::new()?;
+        // ロックを持ったままエラーのStringを作らない(確保でこのロックを待ってしまう)
+        let result = match *self.heap.lock() {
+            Some(ref heap) => {
+                // 既に記録していたら、それまでの記録は捨てる
+                heap.set_tracker(Some(tracker));
+                Ok(())
+            },
+            None => Err("heap not initialized."),
+        };
+        result.map_err(|e| e.to_owned())
+    }
+
+    pub fn disable_tracking(&self) {
+        let old = self.heap.lock().as_ref().and_then(|heap| heap.set_tracker(None));
+        drop(old);
+    }
+
+    pub fn is_tracking(&self) -> bool {
+        self.heap.lock().as_ref().map_or(false, |heap| heap.tracker.borrow().is_some())
+    }
+
+    pub fn leak_report(&self) -> Option<LeakReport> {
+        self.heap.lock().as_ref().and_then(|heap| heap.leak_report())
+    }
+
+    // レッドゾーン・毒・二重解放の検査を有効にする(何か確保する前に呼ぶ)
+    pub fn enable_debug(&self) -> Result<(), String> {
+        let result = match *self.heap.lock() {
+            Some(ref mut heap) => heap.enable_debug(),
+            None => Err("heap not initialized."),
+        };
+        result.map_err(|e| e.to_owned())
+    }
+
+    pub fn walk(&self) -> Result<(), CorruptionReport> {
+        self.heap.lock().as_ref().map_or(Ok(()), |heap| heap.walk())
+    }
+
+    // 壊れていた時はロックを放してから出す(画面に出す時にHeapを使うかもしれないので)
+    fn try_allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocErr> {
+        let result = match *self.heap.lock() {
+            Some(ref heap) => heap.try_allocate(layout),
+            None => panic!("allocate: heap not initialized"),
+        };
+        match result {
+            Ok(ptr) => Ok(ptr),
+            Err(HeapError::OutOfMemory) => Err(AllocErr),
+            Err(HeapError::Corrupted(report)) => report_corruption(&report),
+        }
+    }
+
+    unsafe fn try_deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        let result = match *self.heap.lock() {
+            Some(ref heap) => heap.try_deallocate(ptr, layout),
+            None => panic!("deallocate: heap not initialized"),
+        };
+        if let Err(report) = result {
+            report_corruption(&report);
+        }
+    }
+
 //    pub unsafe fn new(heap_addr_start: usize, heap_size: usize) -> Self {
 //        LockedHeap(Mutex::new(Some(Heap::new(heap_addr_start, heap_size))))
 //    }
@@ -195,21 +785,13 @@ unsafe impl<'a> Alloc for &'a LockedHeap {
     fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocErr> {
 //        if let Some(ref mut heap) = *self.0.lock() {
 //    unsafe fn alloc(&self, layout: Layout) -> Result<NonNull<u8>, AllocErr> {
-        if let Some(ref mut heap) = *self.heap.lock() {
-            heap.allocate(layout)
-        } else {
-            panic!("allocate: heap not initialized");
-        }
+        self.try_allocate(layout)
     }
 
     unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
 //        if let Some(ref mut heap) = *self.0.lock() {
 //    unsafe fn dealloc(&self, ptr: NonNull<u8>, layout: Layout) {
-        if let Some(ref mut heap) = *self.heap.lock() {
-            heap.deallocate(ptr, layout)
-        } else {
-            panic!("deallocate: heap not initialized");
-        }
+        self.try_deallocate(ptr, layout)
     }
 
 //     fn usable_size(&self, layout: &Layout) -> (usize, usize) {
@@ -225,25 +807,17 @@ unsafe impl<'a> Alloc for &'a LockedHeap {
 unsafe impl GlobalAlloc for LockedHeap {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
 //        if let Some(ref mut heap) = *self.0.lock() {
-        if let Some(ref mut heap) = *self.heap.lock() {
-            if let Ok(ref mut nnptr) = heap.allocate(layout) {
-                return nnptr.as_ptr() as *mut u8;
-            } else {
-                panic!("allocate: failed");
-            }
-        } else {
-            panic!("allocate: heap not initialized");
+        // 確保できなければnullを返してalloc_error_handlerに任せる
+        match self.try_allocate(layout) {
+            Ok(nnptr) => nnptr.as_ptr() as *mut u8,
+            Err(_) => null_mut(),
         }
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
 //        if let Some(ref mut heap) = *self.0.lock() {
-        if let Some(ref mut heap) = *self.heap.lock() {
-            if let Some(p) = NonNull::new(ptr) {
-                heap.deallocate(p, layout)
-            }
-        } else {
-            panic!("dealloc: heap not initialized");
+        if let Some(p) = NonNull::new(ptr) {
+            self.try_deallocate(p, layout)
         }
     }
 }
@@ -0,0 +1,110 @@
+use crate::spin::mutex::Mutex;
+use crate::arch::paging::{map_page, unmap_page, PTE_RW, KERNEL_HEAP_START, KERNEL_HEAP_MAX_SIZE};
+use super::frame_allocator::{FRAME_ALLOCATOR, FRAME_SIZE};
+
+// Heapが足りなくなった時にページを借りてくる先
+// 返ってくるアドレスはページ境界で、area()の範囲に入っている
+pub trait PageSource: Sync {
+    // ページを配る仮想アドレスの範囲(start, size)
+    fn area(&self) -> (usize, usize);
+    // count枚の(仮想アドレスが)連続したページを確保して先頭のアドレスを返す
+    fn allocate_pages(&self, count: usize) -> Option<usize>;
+    fn free_pages(&self, addr: usize, count: usize);
+}
+
+const HEAP_AREA_PAGES: usize = KERNEL_HEAP_MAX_SIZE / FRAME_SIZE;
+const BITS_PER_WORD: usize = 32;
+
+// カーネルのHeapの範囲(KERNEL_HEAP_START~)に物理フレームをマッピングして配る
+// Heapのロックを持ったまま呼ばれるので、ここではHeapを使ってはいけない(map_pageのエラーもMapErrorで、Heapを使わない)
+pub struct KernelHeapPages {
+    // 配ったページのビットマップ(bitが1なら使用中)
+    used: Mutex<[u32; HEAP_AREA_PAGES / BITS_PER_WORD]>,
+}
+
+impl KernelHeapPages {
+    pub const fn new() -> Self {
+        KernelHeapPages { used: Mutex::new([0; HEAP_AREA_PAGES / BITS_PER_WORD]) }
+    }
+
+    // 物理メモリより大きい範囲は使えないので、実際に使えるフレームの数で範囲を絞る
+    fn area_pages() -> usize {
+        HEAP_AREA_PAGES.min(FRAME_ALLOCATOR.total_frame_count())
+    }
+
+    fn unmap_pages(addr: usize, count: usize) {
+        for page in 0..count {
+            if let Some(phys_address) = unmap_page((addr + page * FRAME_SIZE) as u32) {
+                FRAME_ALLOCATOR.free_frame(phys_address);
+            }
+        }
+    }
+}
+
+fn is_used(used: &[u32], page: usize) -> bool {
+    used[page / BITS_PER_WORD] & (1 << (page % BITS_PER_WORD)) != 0
+}
+
+fn set_used(used: &mut [u32], page: usize, value: bool) {
+    if value {
+        used[page / BITS_PER_WORD] |= 1 << (page % BITS_PER_WORD);
+    } else {
+        used[page / BITS_PER_WORD] &= !(1 << (page % BITS_PER_WORD));
+    }
+}
+
+impl PageSource for KernelHeapPages {
+    fn area(&self) -> (usize, usize) {
+        (KERNEL_HEAP_START as usize, Self::area_pages() * FRAME_SIZE)
+    }
+
+    fn allocate_pages(&self, count: usize) -> Option<usize> {
+        if count == 0 || count > FRAME_ALLOCATOR.free_frame_count() { return None; }
+        let mut used = self.used.lock();
+        // 空いている仮想アドレスを先頭から探す
+        let area_pages = Self::area_pages();
+        let mut run_start = 0;
+        let mut run_len = 0;
+        let mut page = 0;
+        while page < area_pages && run_len < count {
+            if is_used(&*used, page) {
+                run_start = page + 1;
+                run_len = 0;
+            } else {
+                run_len += 1;
+            }
+            page += 1;
+        }
+        if run_len < count { return None; }
+
+        let addr = KERNEL_HEAP_START as usize + run_start * FRAME_SIZE;
+        for idx in 0..count {
+            let vir_address = (addr + idx * FRAME_SIZE) as u32;
+            let phys_address = match FRAME_ALLOCATOR.allocate_frame() {
+                Some(phys_address) => phys_address,
+                None => {
+                    Self::unmap_pages(addr, idx);
+                    return None;
+                },
+            };
+            if map_page(vir_address, phys_address, PTE_RW).is_err() {
+                FRAME_ALLOCATOR.free_frame(phys_address);
+                Self::unmap_pages(addr, idx);
+                return None;
+            }
+        }
+        for page in run_start..(run_start + count) {
+            set_used(&mut *used, page, true);
+        }
+        Some(addr)
+    }
+
+    fn free_pages(&self, addr: usize, count: usize) {
+        let first = (addr - KERNEL_HEAP_START as usize) / FRAME_SIZE;
+        let mut used = self.used.lock();
+        for page in first..(first + count) {
+            set_used(&mut *used, page, false);
+        }
+        Self::unmap_pages(addr, count);
+    }
+}
@@ -8,7 +8,17 @@ use core::slice::SliceIndex;
 
 pub struct Slab {
     block_size: usize,
+    total_blocks: usize,
     free_block_list: FreeBlockList,
+    // 解放の度に空きリストを探して二重解放を見つける(デバッグ用。遅い)
+    checks: bool,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum SlabError {
+    // ブロックの先頭ではない(このslabが配ったポインタではない)
+    NotBlockStart,
+    DoubleFree,
 }
 
 impl Slab {
@@ -16,22 +26,65 @@ impl Slab {
         let num_of_blocks: usize = slab_size / block_size;
         Slab {
             block_size,
+            total_blocks: num_of_blocks,
             free_block_list: unsafe { FreeBlockList::new(start_addr, block_size, num_of_blocks) },
+            checks: false,
         }
     }
 
-    pub fn used_blocks(&self) -> usize {
+    pub fn empty(block_size: usize) -> Self {
+        Slab {
+            block_size,
+            total_blocks: 0,
+            free_block_list: FreeBlockList::new_empty(),
+            checks: false,
+        }
+    }
+
+    pub fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    pub fn total_blocks(&self) -> usize {
+        self.total_blocks
+    }
+
+    pub fn free_blocks(&self) -> usize {
         self.free_block_list.len()
     }
 
+    pub fn used_blocks(&self) -> usize {
+        self.total_blocks - self.free_block_list.len()
+    }
+
+    pub fn set_checks(&mut self, checks: bool) {
+        self.checks = checks;
+    }
+
+    // 空きブロックのアドレスを順番に返す。リストが壊れて輪になっていても全ブロック数より多くは返さない
+    // 次のブロックは呼ばれた時に読むので、返ったアドレスがおかしければそこで止めれば触らずに済む
+    pub fn free_block_addrs(&self) -> FreeBlockAddrs {
+        FreeBlockAddrs {
+            next: self.free_block_list.head.as_ref().map(|block| block.addr()),
+            current: None,
+            remaining: self.total_blocks + 1,
+        }
+    }
+
     pub unsafe fn grow(&mut self, start_addr: usize, slab_size: usize) {
         let num_of_blocks: usize = slab_size / self.block_size;
-        let mut block_list: FreeBlockList = unsafe { FreeBlockList::new(start_addr, slab_size, num_of_blocks) };
+        let mut block_list: FreeBlockList = unsafe { FreeBlockList::new(start_addr, self.block_size, num_of_blocks) };
+        self.total_blocks += num_of_blocks;
         while let Some(block) = block_list.pop() {
             self.free_block_list.push(block);
         }
     }
 
+    // [start_addr, start_addr + size)にある空きブロックを全て取り除く(ページを返す時に使う)
+    pub fn shrink(&mut self, start_addr: usize, size: usize) {
+        self.total_blocks -= self.free_block_list.remove_range(start_addr, start_addr + size);
+    }
+
     pub fn allocate(&mut self, layout: Layout) -> Result<NonNull<[u8]>, AllocErr> {
         match self.free_block_list.pop() {
             // Some(block) => Ok(unsafe {
@@ -47,9 +100,39 @@ impl Slab {
         }
     }
 
-    pub fn deallocate(&mut self, ptr: NonNull<u8>) {
+    pub fn deallocate(&mut self, ptr: NonNull<u8>) -> Result<(), SlabError> {
+        let addr = ptr.as_ptr() as usize;
+        // ブロックはページ境界から並べているので、先頭ならblock_sizeで割り切れる
+        if addr % self.block_size != 0 {
+            return Err(SlabError::NotBlockStart);
+        }
+        if self.checks && self.free_block_addrs().any(|free| free == addr) {
+            return Err(SlabError::DoubleFree);
+        }
         let ptr: *mut FreeBlock = ptr.as_ptr() as *mut FreeBlock;
         unsafe { self.free_block_list.push(&mut *ptr); }
+        Ok(())
+    }
+}
+
+pub struct FreeBlockAddrs {
+    next: Option<usize>,
+    current: Option<usize>,
+    remaining: usize,
+}
+
+impl Iterator for FreeBlockAddrs {
+    type Item = usize;
+
+    fn next(&mut self) -> Option<usize> {
+        if let Some(current) = self.current.take() {
+            let block = current as *const FreeBlock;
+            self.next = unsafe { (*block).next.as_ref().map(|block| block.addr()) };
+        }
+        if self.remaining == 0 { return None; }
+        self.remaining -= 1;
+        self.current = self.next.take();
+        self.current
     }
 }
 
@@ -96,6 +179,25 @@ impl FreeBlockList {
     fn is_empty(&self) -> bool {
         self.head.is_none()
     }
+
+    // [start, end)にあるブロックをリストから外して、外した数を返す
+    fn remove_range(&mut self, start: usize, end: usize) -> usize {
+        let mut rest = self.head.take();
+        let mut kept = FreeBlockList::new_empty();
+        let mut removed = 0;
+        while let Some(block) = rest {
+            rest = block.next.take();
+            if start <= block.addr() && block.addr() < end {
+                removed += 1;
+            } else {
+                kept.push(block);
+            }
+        }
+        // 順番は変わるが、空きブロックの順番に意味はない
+        self.len = kept.len;
+        self.head = kept.head.take();
+        removed
+    }
 }
 
 impl Drop for FreeBlockList {
@@ -0,0 +1,127 @@
+use core::fmt::{self, Write};
+
+use crate::arch::graphic::Graphic;
+use super::{HeapAllocator, NUM_OF_SLABS};
+use super::linked_list_allocator::HoleStats;
+use super::tracker::LeakReport;
+
+// Heapの状態を書き出す
+// メモリが足りない時(alloc_error_handler)にも使うので、ここではHeapを使わない
+
+const LINE_HEIGHT: u32 = 15;
+
+#[derive(Copy, Clone, Debug)]
+pub struct SizeClassStats {
+    pub allocator: HeapAllocator,
+    pub block_size: usize,  // linked list allocatorは0
+    pub capacity: usize,    // 持っているバイト数
+    pub in_use: usize,      // 使われているバイト数
+    pub allocations: usize, // 解放されていない確保の数
+    pub peak: usize,        // allocationsの最大
+    pub failed: usize,      // このサイズの確保に失敗した回数
+    pub fallbacks: usize,   // 空きが無く大きいサイズから確保した回数
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct HeapStats {
+    pub classes: [SizeClassStats; NUM_OF_SLABS],
+    pub holes: HoleStats,
+    // PageSourceから借りているページの数(決まった領域のHeapならNone)
+    pub borrowed_pages: Option<usize>,
+}
+
+impl HeapStats {
+    pub fn capacity(&self) -> usize {
+        self.classes.iter().map(|c| c.capacity).sum()
+    }
+
+    pub fn in_use(&self) -> usize {
+        self.classes.iter().map(|c| c.in_use).sum()
+    }
+}
+
+// 空きの内、一番大きい空きに入っていない割合(%)。大きいほど細切れになっている
+pub fn fragmentation(holes: &HoleStats) -> usize {
+    if holes.free == 0 { return 0; }
+    100 - holes.largest * 100 / holes.free
+}
+
+pub fn allocator_name(allocator: HeapAllocator) -> &'static str {
+    match allocator {
+        HeapAllocator::Slab64Bytes => "64",
+        HeapAllocator::Slab128Bytes => "128",
+        HeapAllocator::Slab256Bytes => "256",
+        HeapAllocator::Slab512Bytes => "512",
+        HeapAllocator::Slab1024Bytes => "1024",
+        HeapAllocator::Slab2048Bytes => "2048",
+        HeapAllocator::Slab4096Bytes => "4096",
+        HeapAllocator::LinkedListAllocator => "list",
+    }
+}
+
+pub fn format_heap_stats<W: Write>(w: &mut W, name: &str, stats: &HeapStats) -> fmt::Result {
+    write!(w, "{}: {:x}/{:x} bytes in use", name, stats.in_use(), stats.capacity())?;
+    if let Some(pages) = stats.borrowed_pages {
+        write!(w, ", {} pages borrowed", pages)?;
+    }
+    writeln!(w)?;
+    writeln!(w, "class   capacity    in use  live  peak failed fallback")?;
+    for class in stats.classes.iter() {
+        writeln!(w, "{:>5} {:>10x} {:>9x} {:>5} {:>5} {:>6} {:>8}",
+                 allocator_name(class.allocator), class.capacity, class.in_use,
+                 class.allocations, class.peak, class.failed, class.fallbacks)?;
+    }
+    writeln!(w, "list holes {} free {:x} largest {:x} fragmentation {}%",
+             stats.holes.count, stats.holes.free, stats.holes.largest, fragmentation(&stats.holes))
+}
+
+pub fn format_leak_report<W: Write>(w: &mut W, name: &str, report: &LeakReport) -> fmt::Result {
+    writeln!(w, "{}: {} allocations ({:x} bytes) outstanding, {} not tracked", name, report.count, report.bytes, report.dropped)?;
+    for summary in report.callers.iter().flatten() {
+        write!(w, "  {:>4} x {:>8x} bytes from", summary.count, summary.bytes)?;
+        write_callers(w, &summary.callers)?;
+        writeln!(w)?;
+    }
+    writeln!(w, "oldest:")?;
+    for record in report.oldest.iter().flatten() {
+        write!(w, "  #{} {:08x} {:x} bytes from", record.seq, record.addr, record.size)?;
+        write_callers(w, &record.callers)?;
+        writeln!(w)?;
+    }
+    Ok(())
+}
+
+fn write_callers<W: Write>(w: &mut W, callers: &[u32]) -> fmt::Result {
+    for caller in callers.iter().take_while(|c| **c != 0) {
+        write!(w, " {:08x}", caller)?;
+    }
+    Ok(())
+}
+
+// 改行ごとに1行下に書くWriter(Stringを使わずに画面に出すため)
+// 1回のwrite!でwrite_strが何回も呼ばれるので、書いた分だけ右にずらす
+pub struct ScreenWriter {
+    x: u32,
+    y: u32,
+    column: u32, // 行の先頭からのピクセル数
+}
+
+impl ScreenWriter {
+    pub fn new(x: u32, y: u32) -> Self {
+        ScreenWriter { x, y, column: 0 }
+    }
+}
+
+impl Write for ScreenWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for (idx, line) in s.split('\n').enumerate() {
+            if idx > 0 {
+                self.y += LINE_HEIGHT;
+                self.column = 0;
+            }
+            Graphic::putfont_asc(self.x + self.column, self.y, 0, line);
+            self.column += 8 * line.len() as u32;
+        }
+        Ok(())
+    }
+}
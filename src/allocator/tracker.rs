@@ -0,0 +1,143 @@
+use core::mem::size_of;
+use core::slice;
+use alloc::string::String;
+
+use crate::arch::asmfunc::load_ebp;
+use crate::arch::paging::{allocate_direct_frames, free_direct_frames};
+use crate::exception::backtrace::walk_frames;
+use super::frame_allocator::FRAME_SIZE;
+
+// デバッグ用に、解放されていない確保(アドレス・サイズ・呼び出し元)を記録してリークを探す
+// Heapのロックの中で呼ばれるのでHeapは使えない。記録する表はストレートマッピングのフレームに置く
+
+// 記録する呼び出し元の深さ(先頭の方はallocやBox::newなどallocatorの入り口になる)
+pub const CALLER_DEPTH: usize = 6;
+const MAX_RECORDS: usize = 1024;
+const TABLE_FRAMES: usize = (MAX_RECORDS * size_of::<AllocationRecord>() + FRAME_SIZE - 1) / FRAME_SIZE;
+// leak_reportで見せる数
+pub const REPORT_OLDEST: usize = 8;
+pub const REPORT_CALLERS: usize = 8;
+
+#[derive(Copy, Clone, Debug)]
+pub struct AllocationRecord {
+    pub addr: usize, // 0なら空き
+    pub size: usize,
+    pub seq: u32,    // 何番目の確保か(小さいほど古い)
+    pub callers: [u32; CALLER_DEPTH],
+}
+
+// 同じ呼び出し元から確保されたままのもの
+#[derive(Copy, Clone, Debug)]
+pub struct CallerSummary {
+    pub callers: [u32; CALLER_DEPTH],
+    pub count: usize,
+    pub bytes: usize,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct LeakReport {
+    pub count: usize,
+    pub bytes: usize,
+    // 表が一杯で記録できなかった確保の数
+    pub dropped: usize,
+    pub oldest: [Option<AllocationRecord>; REPORT_OLDEST],
+    // 確保されたままのバイト数が多い順
+    pub callers: [Option<CallerSummary>; REPORT_CALLERS],
+}
+
+pub struct AllocationTracker {
+    records: &'static mut [AllocationRecord],
+    next_seq: u32,
+    dropped: usize,
+}
+
+// ebpを辿って呼び出し元のアドレスを集める
+#[inline(never)]
+pub fn caller_addresses() -> [u32; CALLER_DEPTH] {
+    let mut callers = [0; CALLER_DEPTH];
+    walk_frames(load_ebp(), &mut callers);
+    callers
+}
+
+impl AllocationTracker {
+    pub fn new() -> Result<Self, String> {
+        let table = allocate_direct_frames(TABLE_FRAMES)?;
+        // 0で埋めてあるので全て空き
+        let records = unsafe { slice::from_raw_parts_mut(table as *mut AllocationRecord, MAX_RECORDS) };
+        Ok(AllocationTracker { records, next_seq: 0, dropped: 0 })
+    }
+
+    pub fn record(&mut self, addr: usize, size: usize) {
+        let seq = self.next_seq;
+        self.next_seq = self.next_seq.wrapping_add(1);
+        match self.records.iter_mut().find(|r| r.addr == 0) {
+            Some(record) => *record = AllocationRecord { addr, size, seq, callers: caller_addresses() },
+            None => self.dropped += 1,
+        }
+    }
+
+    pub fn forget(&mut self, addr: usize) {
+        if let Some(record) = self.records.iter_mut().find(|r| r.addr == addr) {
+            record.addr = 0;
+        }
+    }
+
+    pub fn find(&self, addr: usize) -> Option<AllocationRecord> {
+        self.records.iter().find(|r| r.addr == addr).copied()
+    }
+
+    pub fn report(&self) -> LeakReport {
+        let mut report = LeakReport {
+            count: 0,
+            bytes: 0,
+            dropped: self.dropped,
+            oldest: [None; REPORT_OLDEST],
+            callers: [None; REPORT_CALLERS],
+        };
+        for record in self.records.iter().filter(|r| r.addr != 0) {
+            report.count += 1;
+            report.bytes += record.size;
+            insert_oldest(&mut report.oldest, *record);
+            add_caller(&mut report.callers, record);
+        }
+        report.callers.sort_unstable_by(|a, b| b.map_or(0, |c| c.bytes).cmp(&a.map_or(0, |c| c.bytes)));
+        report
+    }
+}
+
+impl Drop for AllocationTracker {
+    fn drop(&mut self) {
+        free_direct_frames(self.records.as_ptr() as u32, TABLE_FRAMES);
+    }
+}
+
+// seqの小さい順に並べて持つ
+fn insert_oldest(oldest: &mut [Option<AllocationRecord>; REPORT_OLDEST], record: AllocationRecord) {
+    let idx = match oldest.iter().position(|r| r.map_or(true, |r| record.seq < r.seq)) {
+        Some(idx) => idx,
+        None => return,
+    };
+    for i in (idx + 1..REPORT_OLDEST).rev() {
+        oldest[i] = oldest[i - 1];
+    }
+    oldest[idx] = Some(record);
+}
+
+// 同じ呼び出し元ならまとめる。入りきらなければ一番小さいものと比べて入れ替える
+fn add_caller(summaries: &mut [Option<CallerSummary>; REPORT_CALLERS], record: &AllocationRecord) {
+    if let Some(summary) = summaries.iter_mut().flatten().find(|s| s.callers == record.callers) {
+        summary.count += 1;
+        summary.bytes += record.size;
+        return;
+    }
+    let new_summary = CallerSummary { callers: record.callers, count: 1, bytes: record.size };
+    if let Some(slot) = summaries.iter_mut().find(|s| s.is_none()) {
+        *slot = Some(new_summary);
+        return;
+    }
+    if let Some(smallest) = summaries.iter_mut().min_by_key(|s| s.map_or(0, |s| s.bytes)) {
+        if smallest.map_or(0, |s| s.bytes) < new_summary.bytes {
+            *smallest = Some(new_summary);
+        }
+    }
+}
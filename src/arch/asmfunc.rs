@@ -329,6 +329,22 @@ pub fn store_cr3(cr3: u32) {
     }
 }
 
+// 今のフレームのebp(呼び出し元を辿るのに使う)
+#[inline(always)]
+pub fn load_ebp() -> u32 {
+    let mut ebp: u32 = 0;
+    unsafe {
+        llvm_asm!("
+        mov eax, ebp
+        "
+        : "={eax}"(ebp)
+        :
+        :
+        : "intel");
+    }
+    return ebp;
+}
+
 pub fn load_tr(tr: u32) {
     unsafe {
         llvm_asm!("
@@ -1,4 +1,51 @@
-pub const ADR_BOOTINFO: u32 = 0x00000ff0;
+use core::slice;
+
+use super::paging::KERNEL_BASE_ADDR;
+
+// secondboot.asmが物理アドレス0x0ff0に書き込んだ情報(カーネル空間から見たアドレス)
+pub const ADR_BOOTINFO: u32 = KERNEL_BASE_ADDR + 0x00000ff0;
+// secondboot.asmがINT 15h(E820)で取得したメモリマップの格納先
+pub const ADR_E820_MAP: u32 = KERNEL_BASE_ADDR + 0x00000500;
+pub const E820_MAX_ENTRIES: usize = 64;
+
+pub const E820_TYPE_USABLE: u32 = 1;
+pub const E820_TYPE_RESERVED: u32 = 2;
+pub const E820_TYPE_ACPI_RECLAIMABLE: u32 = 3;
+pub const E820_TYPE_ACPI_NVS: u32 = 4;
+pub const E820_TYPE_BAD: u32 = 5;
+const E820_EXT_ATTR_VALID: u32 = 1 << 0;
+
+// メモリマップの1エントリ(BIOSが書き込んだ形式そのまま)
+#[repr(C, packed)]
+#[derive(Copy, Clone)]
+pub struct E820Entry {
+    pub base: u64,
+    pub length: u64,
+    pub entry_type: u32,
+    pub ext_attr: u32,
+}
+
+impl E820Entry {
+    // 拡張属性で無効とされたエントリは使えないものとして扱う
+    pub fn is_usable(&self) -> bool {
+        self.entry_type == E820_TYPE_USABLE && self.ext_attr & E820_EXT_ATTR_VALID == E820_EXT_ATTR_VALID
+    }
+
+    pub fn end(&self) -> u64 {
+        self.base + self.length
+    }
+
+    pub fn type_name(&self) -> &'static str {
+        match self.entry_type {
+            E820_TYPE_USABLE => "usable",
+            E820_TYPE_RESERVED => "reserved",
+            E820_TYPE_ACPI_RECLAIMABLE => "ACPI data",
+            E820_TYPE_ACPI_NVS => "ACPI NVS",
+            E820_TYPE_BAD => "bad",
+            _ => "unknown",
+        }
+    }
+}
 
 // public interface
 pub struct BootInfo {
@@ -8,6 +55,7 @@ pub struct BootInfo {
     pub scrnx: u16,     /* 画像解像度 */
     pub scrny: u16,     /* 画像解像度 */
     pub vram: u32,      /* vram */
+    pub e820_count: u32, /* メモリマップのエントリ数 */
 }
 
 impl BootInfo {
@@ -19,9 +67,15 @@ impl BootInfo {
             scrnx:  unsafe   { *((ADR_BOOTINFO + 0x04) as *const u16) },
             scrny:  unsafe   { *((ADR_BOOTINFO + 0x06) as *const u16) },
             vram:   unsafe   { *((ADR_BOOTINFO + 0x08) as *mut   u32) },
+            e820_count: unsafe { *((ADR_BOOTINFO + 0x0c) as *const u32) },
         }
     }
 
+    pub fn memory_map(&self) -> &'static [E820Entry] {
+        let count = (self.e820_count as usize).min(E820_MAX_ENTRIES);
+        unsafe { slice::from_raw_parts(ADR_E820_MAP as *const E820Entry, count) }
+    }
+
     pub fn set_addr_vram(&mut self, vir_addr: u32) {
         unsafe { *((ADR_BOOTINFO + 0x08) as *mut   u32) = vir_addr };
     }
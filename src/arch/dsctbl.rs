@@ -1,6 +1,9 @@
 use super::asmfunc;
 use super::pic;
 use super::graphic::Graphic;
+use super::paging::KERNEL_BASE_ADDR;
+use super::msi::{MSI_VECTOR_BASE, MSI_VECTOR_NUM};
+use super::tss::{self, KERNEL_TSS, DOUBLE_FAULT_TSS, KERNEL_TSS_SELECTOR, DOUBLE_FAULT_TSS_SELECTOR, TSS_LIMIT};
 
 extern "C" {
     pub fn asm_inthandler02();
@@ -21,22 +24,65 @@ extern "C" {
     pub fn asm_inthandler13();
     pub fn asm_inthandler20();
     pub fn asm_inthandler21();
+    pub fn asm_inthandler23();
+    pub fn asm_inthandler24();
     pub fn asm_inthandler27();
     pub fn asm_inthandler2c();
+    pub fn asm_inthandler30();
+    pub fn asm_inthandler31();
+    pub fn asm_inthandler32();
+    pub fn asm_inthandler33();
+    pub fn asm_inthandler34();
+    pub fn asm_inthandler35();
+    pub fn asm_inthandler36();
+    pub fn asm_inthandler37();
+    pub fn asm_inthandler38();
+    pub fn asm_inthandler39();
+    pub fn asm_inthandler3a();
+    pub fn asm_inthandler3b();
+    pub fn asm_inthandler3c();
+    pub fn asm_inthandler3d();
+    pub fn asm_inthandler3e();
+    pub fn asm_inthandler3f();
 }
 
 
-const ADR_GDT: u32 = 0x00270000;
+// GDTとIDTは物理アドレス0x0026f800~0x0027ffffに置く(GDTR, IDTRにはカーネル空間のアドレスを入れる)
+const ADR_GDT: u32 = KERNEL_BASE_ADDR + 0x00270000;
 const LIMIT_GDT: usize = 0x0000ffff;
 const ADR_OSPAK: u32 = 0x00000000;
 // const LIMIT_OSPAK: u32 = 0x00280000 + 0x0007ffff;
 const LIMIT_OSPAK: u32 = 0xffffffff;
 const AR_DATA32_RW: u32 = 0x4092;
 const AR_CODE32_ER: u32 = 0x409a;
+const AR_TSS32: u32 = 0x0089;
 
-const ADR_IDT: u32 = 0x0026f800;
+const ADR_IDT: u32 = KERNEL_BASE_ADDR + 0x0026f800;
 const LIMIT_IDT: usize = 0x000007ff;
 const AR_INTGATE32: u32 = 0x008e;
+const AR_TASKGATE: u32 = 0x0085;
+
+// IDTにゲートを設定するCPU例外(INT 0x00~0x1f)のハンドラ
+// ダブルフォルト(0x08)は後でinit_double_fault_taskがタスクゲートにする
+const EXCEPTION_INTHANDLERS: [(u8, unsafe extern fn()); 15] = [
+    (0x02, asm_inthandler02), (0x04, asm_inthandler04), (0x05, asm_inthandler05), (0x06, asm_inthandler06),
+    (0x07, asm_inthandler07), (0x08, asm_inthandler08), (0x0a, asm_inthandler0a), (0x0b, asm_inthandler0b),
+    (0x0c, asm_inthandler0c), (0x0d, asm_inthandler0d), (0x0e, asm_inthandler0e), (0x10, asm_inthandler10),
+    (0x11, asm_inthandler11), (0x12, asm_inthandler12), (0x13, asm_inthandler13),
+];
+
+// vectorのCPU例外にIDTのゲートを設定しているか
+pub fn has_exception_gate(vector: u8) -> bool {
+    EXCEPTION_INTHANDLERS.iter().any(|(v, _)| *v == vector)
+}
+
+// MSIに割り当てるベクタの割り込みハンドラ(INT 0x30~0x3f)
+const MSI_INTHANDLERS: [unsafe extern fn(); MSI_VECTOR_NUM] = [
+    asm_inthandler30, asm_inthandler31, asm_inthandler32, asm_inthandler33,
+    asm_inthandler34, asm_inthandler35, asm_inthandler36, asm_inthandler37,
+    asm_inthandler38, asm_inthandler39, asm_inthandler3a, asm_inthandler3b,
+    asm_inthandler3c, asm_inthandler3d, asm_inthandler3e, asm_inthandler3f,
+];
 
 #[repr(C, packed)]
 struct SegmentDescriptorEntry {
@@ -116,27 +162,35 @@ impl DscTbl {
 
         asmfunc::load_idtr(LIMIT_IDT as u32, ADR_IDT);
 
-        gate_descriptor_table[0x04] = DscTbl::set_fn_gatedesc(0x04 as u32, asm_inthandler04, 2 * 8, AR_INTGATE32);
-        gate_descriptor_table[0x05] = DscTbl::set_fn_gatedesc(0x05 as u32, asm_inthandler05, 2 * 8, AR_INTGATE32);
-        gate_descriptor_table[0x06] = DscTbl::set_fn_gatedesc(0x06 as u32, asm_inthandler06, 2 * 8, AR_INTGATE32);
-        gate_descriptor_table[0x07] = DscTbl::set_fn_gatedesc(0x07 as u32, asm_inthandler07, 2 * 8, AR_INTGATE32);
-        gate_descriptor_table[0x08] = DscTbl::set_fn_gatedesc(0x08 as u32, asm_inthandler08, 2 * 8, AR_INTGATE32);
-        gate_descriptor_table[0x0a] = DscTbl::set_fn_gatedesc(0x0a as u32, asm_inthandler0a, 2 * 8, AR_INTGATE32);
-        gate_descriptor_table[0x0b] = DscTbl::set_fn_gatedesc(0x0b as u32, asm_inthandler0b, 2 * 8, AR_INTGATE32);
-        gate_descriptor_table[0x0c] = DscTbl::set_fn_gatedesc(0x0c as u32, asm_inthandler0c, 2 * 8, AR_INTGATE32);
-        gate_descriptor_table[0x0d] = DscTbl::set_fn_gatedesc(0x0d as u32, asm_inthandler0d, 2 * 8, AR_INTGATE32);
-        gate_descriptor_table[0x0e] = DscTbl::set_fn_gatedesc(0x0e as u32, asm_inthandler0e, 2 * 8, AR_INTGATE32);
-        gate_descriptor_table[0x10] = DscTbl::set_fn_gatedesc(0x10 as u32, asm_inthandler10, 2 * 8, AR_INTGATE32);
-        gate_descriptor_table[0x11] = DscTbl::set_fn_gatedesc(0x11 as u32, asm_inthandler11, 2 * 8, AR_INTGATE32);
-        gate_descriptor_table[0x12] = DscTbl::set_fn_gatedesc(0x12 as u32, asm_inthandler12, 2 * 8, AR_INTGATE32);
-        gate_descriptor_table[0x13] = DscTbl::set_fn_gatedesc(0x13 as u32, asm_inthandler13, 2 * 8, AR_INTGATE32);
+        for (vector, handler) in EXCEPTION_INTHANDLERS.iter() {
+            gate_descriptor_table[*vector as usize] = DscTbl::set_fn_gatedesc(*vector as u32, *handler, 2 * 8, AR_INTGATE32);
+        }
         gate_descriptor_table[0x20] = DscTbl::set_fn_gatedesc(0x20 as u32, asm_inthandler20, 2 * 8, AR_INTGATE32);
         gate_descriptor_table[0x21] = DscTbl::set_fn_gatedesc(0x21 as u32, asm_inthandler21, 2 * 8, AR_INTGATE32);
+        gate_descriptor_table[0x23] = DscTbl::set_fn_gatedesc(0x23 as u32, asm_inthandler23, 2 * 8, AR_INTGATE32);
+        gate_descriptor_table[0x24] = DscTbl::set_fn_gatedesc(0x24 as u32, asm_inthandler24, 2 * 8, AR_INTGATE32);
         gate_descriptor_table[0x27] = DscTbl::set_fn_gatedesc(0x27 as u32, asm_inthandler27, 2 * 8, AR_INTGATE32);
         gate_descriptor_table[0x2c] = DscTbl::set_fn_gatedesc(0x2c as u32, asm_inthandler2c, 2 * 8, AR_INTGATE32);
+        for (idx, handler) in MSI_INTHANDLERS.iter().enumerate() {
+            let vector = MSI_VECTOR_BASE as usize + idx;
+            gate_descriptor_table[vector] = DscTbl::set_fn_gatedesc(vector as u32, *handler, 2 * 8, AR_INTGATE32);
+        }
         return gate_descriptor_table
     }
 
+    // ダブルフォルトを専用のTSS(スタック)で処理するようにする
+    // カーネルのスタックが溢れた時は、割り込みゲートだと例外を積む場所が無くトリプルフォルトになってしまう
+    pub fn init_double_fault_task(&mut self, stack_top: u32) {
+        tss::init_double_fault_task(asm_inthandler08 as u32, stack_top, asmfunc::load_cr3());
+        unsafe {
+            self.segment_descriptor_table[3] = DscTbl::set_segmdesc(3, TSS_LIMIT, KERNEL_TSS.address(), AR_TSS32);
+            self.segment_descriptor_table[4] = DscTbl::set_segmdesc(4, TSS_LIMIT, DOUBLE_FAULT_TSS.address(), AR_TSS32);
+        }
+        // 今動いているカーネルをKERNEL_TSSのタスクにする
+        asmfunc::load_tr(KERNEL_TSS_SELECTOR as u32);
+        self.gate_descriptor_table[0x08] = DscTbl::set_gatedesc(0x08, 0, DOUBLE_FAULT_TSS_SELECTOR, AR_TASKGATE);
+    }
+
     fn set_gatedesc(idx: u32, offset: u32, selector: u16, ar: u32) -> *mut GateDescriptorEntry {
         let base_address: u32 = (ADR_IDT + (idx * 8));
         let gate_dsc_entry: *mut GateDescriptorEntry = base_address as *mut GateDescriptorEntry;
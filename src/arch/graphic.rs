@@ -6,15 +6,15 @@ use core::fmt;
 //use alloc::vec::Vec;
 
 use super::asmfunc;
-use super::boot_info::BootInfo;
+use super::boot_info::{BootInfo, ADR_BOOTINFO};
 use super::hankaku;
 #[macro_use]
 use core::fmt::{ Write, Display };
 
-const ADR_BOOTINFO: u32 = 0x00000ff0;
-static mut scrnx: u16 = (ADR_BOOTINFO + 0x04) as u16;
-static mut scrny: u16 = (ADR_BOOTINFO + 0x06) as u16;
-static mut vram: u32 = (ADR_BOOTINFO + 0x08) as u32;
+// BootInfoの各項目のアドレス(カーネル空間なのでu16には収まらない)
+static mut scrnx: u32 = ADR_BOOTINFO + 0x04;
+static mut scrny: u32 = ADR_BOOTINFO + 0x06;
+static mut vram: u32 = ADR_BOOTINFO + 0x08;
 
 fn get_scrnx() -> &'static u16 {
     return unsafe { &*(scrnx as *const u16) }
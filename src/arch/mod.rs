@@ -3,7 +3,9 @@ pub mod graphic;
 pub mod boot_info;
 pub mod hankaku;
 pub mod dsctbl;
+pub mod tss;
 pub mod pic;
+pub mod msi;
 pub mod keyboard;
 pub mod mouse;
 pub mod timer;
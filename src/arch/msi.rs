@@ -0,0 +1,95 @@
+use alloc::string::String;
+use alloc::borrow::ToOwned;
+
+use super::asmfunc::{io_cli, io_load_eflags, io_store_eflags};
+
+#[macro_use]
+use crate::lazy_static;
+use crate::spin::mutex::Mutex;
+
+// MSI(Message Signaled Interrupt)に割り当てるIDTのベクタ
+// デバイスがLocal APICのアドレスにベクタ番号を書き込むと、そのベクタの割り込みになる
+// PICのIRQ線を共有しないので、デバイスごとに専用のハンドラを持てる
+
+pub const MSI_VECTOR_BASE: u8 = 0x30;
+pub const MSI_VECTOR_NUM: usize = 16;
+
+// MSIの書き込み先(Local APIC)
+pub const MSI_ADDRESS_BASE: u32 = 0xfee00000;
+const MSI_ADDRESS_DEST_ID_SHIFT: u32 = 12;
+// BSPのLocal APIC ID
+pub const MSI_DEFAULT_DEST_APIC_ID: u8 = 0;
+
+lazy_static! {
+    static ref MSI_HANDLERS: Mutex<[Option<fn()>; MSI_VECTOR_NUM]> = Mutex::new([None; MSI_VECTOR_NUM]);
+    static ref MSI_INTERRUPT_COUNTS: Mutex<[usize; MSI_VECTOR_NUM]> = Mutex::new([0; MSI_VECTOR_NUM]);
+}
+
+// inthandler_msiも同じロックを取るので、割り込みを禁止してからロックを取る
+fn without_interrupts<R>(f: impl FnOnce() -> R) -> R {
+    let eflags = io_load_eflags();
+    io_cli();
+    let result = f();
+    io_store_eflags(eflags);
+    result
+}
+
+// 宛先のLocal APICに対応するMessage Address
+pub fn msi_address(dest_apic_id: u8) -> u32 {
+    MSI_ADDRESS_BASE | (dest_apic_id as u32) << MSI_ADDRESS_DEST_ID_SHIFT
+}
+
+// 空いているベクタにハンドラを登録して、そのベクタ番号を返す
+pub fn allocate_msi_vector(handler: fn()) -> Result<u8, String> {
+    without_interrupts(|| {
+        let mut handlers = MSI_HANDLERS.lock();
+        match handlers.iter().position(|h| h.is_none()) {
+            Some(idx) => {
+                handlers[idx] = Some(handler);
+                MSI_INTERRUPT_COUNTS.lock()[idx] = 0;
+                Ok(MSI_VECTOR_BASE + idx as u8)
+            },
+            None => Err("no free msi vector.".to_owned()),
+        }
+    })
+}
+
+pub fn free_msi_vector(vector: u8) -> Result<(), String> {
+    let idx = msi_vector_index(vector)?;
+    without_interrupts(|| {
+        let mut handlers = MSI_HANDLERS.lock();
+        if handlers[idx].is_none() { return Err("msi vector is not allocated.".to_owned()); }
+        handlers[idx] = None;
+        Ok(())
+    })
+}
+
+pub fn get_msi_interrupt_count(vector: u8) -> usize {
+    match msi_vector_index(vector) {
+        Ok(idx) => without_interrupts(|| MSI_INTERRUPT_COUNTS.lock()[idx]),
+        Err(_) => 0,
+    }
+}
+
+fn msi_vector_index(vector: u8) -> Result<usize, String> {
+    if vector < MSI_VECTOR_BASE || vector as usize >= MSI_VECTOR_BASE as usize + MSI_VECTOR_NUM {
+        return Err("not a msi vector.".to_owned());
+    }
+    Ok((vector - MSI_VECTOR_BASE) as usize)
+}
+
+// asm_inthandler30~3fから呼ばれる
+#[no_mangle]
+pub extern "C" fn inthandler_msi(vector: u32, esp: *const usize) {
+    let idx = match msi_vector_index(vector as u8) {
+        Ok(idx) => idx,
+        Err(_) => return,
+    };
+    MSI_INTERRUPT_COUNTS.lock()[idx] += 1;
+    // ハンドラの中でベクタを解放できるようにロックを外してから呼ぶ
+    let handler = MSI_HANDLERS.lock()[idx];
+    if let Some(handler) = handler {
+        handler();
+    }
+    // MSIはPICを通らないのでPICへのEOIは不要。Local APICのEOIはAPICに対応したらここで送る
+}
@@ -1,5 +1,6 @@
 #![feature(allocator_api)]
 use core::alloc::{Layout, Allocator as Alloc};
+use core::fmt;
 use core::marker::PhantomData;
 use core::mem::size_of;
 use core::ptr::{self, read_volatile, write_volatile};
@@ -15,33 +16,78 @@ use core::borrow::{Borrow, BorrowMut};
 use super::boot_info::BootInfo;
 
 use super::super::allocator::LockedHeap;
+use super::super::allocator::frame_allocator::{FRAME_ALLOCATOR, FRAME_SIZE};
 
 use crate::spin::mutex::Mutex;
+use crate::memory::vmm;
+use crate::exception;
+use crate::exception::frame::ExceptionFrame;
+use crate::exception::report::report_exception;
 
-use super::graphic::Graphic;
-use super::super::Printer;
-use core::fmt::Write;
 
 use crate::asmfunc::{load_cr0, store_cr0, load_cr3, store_cr3, set_pg_flag, flush_tlb};
 
-const PTE_PRESENT: u16 = 0x0001;   // P bit
-const PTE_RW: u16 = 0x0002;        // R bit
-const PTE_USER: u16 = 0x0004;      // U/S bit
-const PTE_PWT: u16 = 0x0008;      // Page Write Through bit
-const PTE_PCD: u16 = 0x0010;      // Page Cache Disable bit
+pub const PTE_PRESENT: u16 = 0x0001;   // P bit
+pub const PTE_RW: u16 = 0x0002;        // R bit
+pub const PTE_USER: u16 = 0x0004;      // U/S bit
+pub const PTE_PWT: u16 = 0x0008;      // Page Write Through bit
+pub const PTE_PCD: u16 = 0x0010;      // Page Cache Disable bit
+// デバイスと共有するメモリ(DMA)はキャッシュしない
+pub const PTE_UNCACHED: u16 = PTE_PCD | PTE_PWT;
 const PTE_ACCESS: u16 = 0x0020;    // A bit
 const PTE_DIRTY: u16 = 0x0040;     // D bit
 const PTE_G: u16 = 0x0100;     // Global bit
+// OSが自由に使えるビット(AVL)。AddressSpaceが確保したフレームの印(解放・fork時のコピーの対象)
+pub const PTE_OWNED: u16 = 0x0200;
 
 // const PAGE_DIR_BASE_ADDR: u32 = 0x00400000;
 const PAGE_DIR_BASE_ADDR: u32 = 0x00a00000;
 const NUM_OF_ENTRY: usize = 1024;    // 0x10_0000_0000
 const PAGE_TABLE_BASE_ADDR: u32 = PAGE_DIR_BASE_ADDR + (size_of::<u32>() * NUM_OF_ENTRY) as u32;
-const KERNEL_BASE_ADDR: u32 = 0x0000_0000;
-const ADDRESS_MSK: u32 = 0xfffff000;
+// カーネル空間の先頭。物理アドレスpはカーネル空間からKERNEL_BASE_ADDR + pで見える(ストレートマッピング)
+// これより下(0x00000000~0xbfffffff)はユーザー空間で、0番地のページはマッピングしない
+pub const KERNEL_BASE_ADDR: u32 = 0xc000_0000;
+// 起動時にストレートマッピングしておく物理メモリ(カーネル本体・スタック・ページテーブル・フレームのビットマップ)
+pub const KERNEL_DIRECT_MAP_SIZE: usize = 0x0100_0000;
+pub const KERNEL_HEAP_START: u32 = 0xd000_0000;
+pub const KERNEL_HEAP_MAX_SIZE: usize = 0x2000_0000;
+pub const KERNEL_VRAM_ADDR: u32 = 0xf000_0000;
+pub const KERNEL_VRAM_SIZE: usize = 0x0040_0000;
+// カーネル空間のページディレクトリのindex(768~1023)。どのアドレス空間でも同じページテーブルを共有する
+pub const KERNEL_DIR_START: usize = (KERNEL_BASE_ADDR >> 22) as usize;
+// 任意の物理フレームを一時的に覗くための仮想アドレス(ページディレクトリの最後のindex)
+const TEMP_MAP_ADDR: u32 = 0xffc0_0000;
+const TEMP_MAP_SLOTS: usize = 2;
+pub const ADDRESS_MSK: u32 = 0xfffff000;
+
+// map_pageなどのエラー。Heapのロックを持ったまま(Heapを広げる時に)呼ばれるので、Stringを作らない
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MapError {
+    OutOfFrames,
+    AlreadyMapped(u32),
+    NotMapped(u32),
+}
+
+impl fmt::Display for MapError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MapError::OutOfFrames => write!(f, "out of physical frames."),
+            MapError::AlreadyMapped(vir_address) => write!(f, "{:x} is already mapped.", vir_address),
+            MapError::NotMapped(vir_address) => write!(f, "{:x} is not mapped.", vir_address),
+        }
+    }
+}
+
+// Result<_, String>を返す所では?でそのまま返せるようにする
+impl From<MapError> for String {
+    fn from(e: MapError) -> String {
+        format!("{}", e)
+    }
+}
 const SIZE_OF_PAGE: usize = 4096;
 
 static KERNEL_TABLE: Mutex<Option<PageTableImpl<&LockedHeap>>> = Mutex::new(None);
+static TEMP_MAP_LOCK: Mutex<()> = Mutex::new(());
 
 pub fn set_kernel_table(table: PageTableImpl<&LockedHeap>) {
     {
@@ -131,8 +177,12 @@ where
     }
 
     pub fn set_address(&mut self, index: usize, address: u32, user_accessible: bool) -> Result<(), String> {
-        let flags = PTE_PRESENT | PTE_RW | if user_accessible { PTE_USER } else { 0 };
-        self.entries[index].set(address, flags);
+        let flags = PTE_RW | if user_accessible { PTE_USER } else { 0 };
+        self.set_address_with_flags(index, address, flags)
+    }
+
+    pub fn set_address_with_flags(&mut self, index: usize, address: u32, flags: u16) -> Result<(), String> {
+        self.entries[index].set(address, flags | PTE_PRESENT);
         Ok(())
     }
 }
@@ -141,12 +191,19 @@ impl<L> Table<L>
 where
     L: TableLevel,
 {
-    pub fn create_next_table(&mut self, index: usize, user_accessible: bool, physical_base_virtual_address: u32) -> Result<*mut Table<L::NextLevel>, String> {
+    pub fn create_next_table(&mut self, index: usize, user_accessible: bool, physical_base_virtual_address: u32) -> Result<*mut Table<L::NextLevel>, MapError> {
         let table_size: usize = size_of::<u32>() * NUM_OF_ENTRY;
-        let start_address: *mut [u32; size_of::<u32>() * NUM_OF_ENTRY] = (PAGE_TABLE_BASE_ADDR + (index * table_size) as u32) as *mut [u32; size_of::<u32>() * NUM_OF_ENTRY];
-        unsafe { *start_address = [0x00000000; size_of::<u32>() * NUM_OF_ENTRY] };
+        let table_phys_address = if index < KERNEL_DIR_START {
+            // ユーザー空間のページテーブルはアドレス空間ごとに別に持つので、フレームを確保する
+            allocate_table_frame()?
+        } else {
+            // カーネル空間のページテーブルはディレクトリのindexごとに置き場所が決まっている(物理アドレス)
+            let table_phys_address = PAGE_TABLE_BASE_ADDR + (index * table_size) as u32;
+            unsafe { ptr::write_bytes((table_phys_address + physical_base_virtual_address) as *mut u8, 0, table_size) };
+            table_phys_address
+        };
         let flags = PTE_PRESENT | PTE_RW | if user_accessible { PTE_USER } else { 0 };
-        self.entries[index].set(start_address as u32, flags);
+        self.entries[index].set(table_phys_address, flags);
         let virtual_table_address = self.entries[index].address() + physical_base_virtual_address;
         unsafe { Ok(virtual_table_address as *mut Table<L::NextLevel>) }
     }
@@ -162,26 +219,38 @@ impl<A> PageTableImpl<A>
 where
     A: 'static + Alloc,
 {
+    // secondboot.asmが作った仮のページディレクトリ(0~4MBと0xc0000000~の16MB)で動いている状態で呼ぶ
+    // 新しいディレクトリを作り終えてからCR3を切り替えるので、切り替えた時点で0~4MBのストレートマッピングは無くなる
     pub fn initialize() -> Result<Self, String> {
         Self::bury_zero();
-        store_cr3(PAGE_DIR_BASE_ADDR);
+        let page_dir_tbl = (PAGE_DIR_BASE_ADDR + KERNEL_BASE_ADDR) as *mut Table<PageDirectory>;
 
-        // 0x00000000 - 0x003fffff ⇒ direct mapping(0x00000000(dir index: 0) - 0x003fffff(dir index: 0))
-        Self::map_entry(0x00000000, 0x00000000, 0x00400000, KERNEL_BASE_ADDR);
+        // 0x00000000 - 0x00ffffff(Kernel, Stack, Page Directory, Page Table, Frame Bitmap) ⇒ 0xc0000000(dir index: 768) - 0xc0ffffff(dir index: 771)
+        Self::map_entry_in(page_dir_tbl, 0x00000000, KERNEL_BASE_ADDR, KERNEL_DIRECT_MAP_SIZE, KERNEL_BASE_ADDR, PTE_RW)?;
 
         let mut boot_info: BootInfo = BootInfo::new();
-        // 0xfd000000 - 0xfd0c0400(VRAM) ⇒ 0x00400000(dir index: 1) -  0x007fffff(dir index: 1)
-        Self::map_entry(boot_info.vram, 0x00400000, 0x00400000, KERNEL_BASE_ADDR);
-        boot_info.set_addr_vram(0x00400000);
+        // 0xfd000000 - 0xfd0c0400(VRAM) ⇒ 0xf0000000(dir index: 960) - 0xf03fffff(dir index: 960)
+        Self::map_entry_in(page_dir_tbl, boot_info.vram, KERNEL_VRAM_ADDR, KERNEL_VRAM_SIZE, KERNEL_BASE_ADDR, PTE_RW)?;
+        boot_info.set_addr_vram(KERNEL_VRAM_ADDR);
 
-        // 0xb0000000 - (DMA) 0xb0ffffff ⇒ direct mapping 0xb0000000(dir index: 704) - 0xb0ffffff(dir index: 707)
-        Self::map_entry(0xb0000000, 0xb0000000, 0x01000000, KERNEL_BASE_ADDR);
+        // DMAの領域は物理フレームを確保してからmap_directでマッピングする
 
         // 0xfe000000 - 0xfeffffff(PCI) ⇒ direct mapping 0xfe000000(dir index: 1016) - 0xfeffffff(dir index: 1019)
-        Self::map_entry(0xfe000000, 0xfe000000, 0x01000000, KERNEL_BASE_ADDR);
+        Self::map_entry_in(page_dir_tbl, 0xfe000000, 0xfe000000, 0x01000000, KERNEL_BASE_ADDR, PTE_RW)?;
 
-        // 0x00e80000 - 0x40a80000(Heap) ⇒ 0x00800000(dir index: 2) - 0x3fffffff(dir index: 255)
-        Self::map_entry(0x00e80000, 0x00800000, 0x3f800000, KERNEL_BASE_ADDR);
+        // Heapのページはallocator::page_sourceが必要になった時にマッピングする
+
+        // カーネル空間のページテーブルを全て作っておく
+        // AddressSpaceはディレクトリのエントリをコピーするので、後からテーブルが増えても全てのアドレス空間から見える
+        for dir_idx in KERNEL_DIR_START..NUM_OF_ENTRY {
+            if unsafe { !(*page_dir_tbl).entries[dir_idx].present() } {
+                unsafe { (*page_dir_tbl).create_next_table(dir_idx, false, KERNEL_BASE_ADDR)?; }
+            }
+        }
+
+        // 0番地を含むユーザー空間には何もマッピングしていないので、NULLの参照はページフォルトになる
+        store_cr3(PAGE_DIR_BASE_ADDR);
+        flush_tlb();
 
         Ok(PageTableImpl {
             physical_base_virtual_address: KERNEL_BASE_ADDR,
@@ -189,20 +258,31 @@ where
         })
     }
 
+    // ページディレクトリ(4KiB)とページテーブル(4KiB * 1024)を0で埋める
     fn bury_zero() {
-        let dir_start_address: *mut [u32; size_of::<u32>() * NUM_OF_ENTRY] = PAGE_DIR_BASE_ADDR as *mut [u32; size_of::<u32>() * NUM_OF_ENTRY];
-        unsafe { *dir_start_address = [0x00000000; size_of::<u32>() * NUM_OF_ENTRY] };
-        let mut table_start_address: *mut [u32; size_of::<u32>() * NUM_OF_ENTRY * 1024] = PAGE_TABLE_BASE_ADDR as *mut [u32; size_of::<u32>() * NUM_OF_ENTRY * 1024];
-        unsafe { *table_start_address = [0x00000000; size_of::<u32>() * NUM_OF_ENTRY * 1024] };
+        let table_size = size_of::<u32>() * NUM_OF_ENTRY;
+        unsafe {
+            ptr::write_bytes((PAGE_DIR_BASE_ADDR + KERNEL_BASE_ADDR) as *mut u8, 0, table_size);
+            ptr::write_bytes((PAGE_TABLE_BASE_ADDR + KERNEL_BASE_ADDR) as *mut u8, 0, table_size * NUM_OF_ENTRY);
+        }
     }
 
     // physical addressとvirtual addressのマッピングを行う
     // 引数はphysical & virtual addressの始点とバイト数(range)
     // page directoryのindexとpage tableの位置についてはvirtual addressから取得可能
     pub fn map_entry(start_phys_address: u32, start_vir_address: u32, range: usize, kernel_base_address: u32) -> Result<(), String> {
+        Self::map_entry_with_flags(start_phys_address, start_vir_address, range, kernel_base_address, PTE_RW | PTE_USER)
+    }
+
+    // flagsはpage tableのエントリに設定するフラグ(PTE_PRESENTは常に付ける)
+    pub fn map_entry_with_flags(start_phys_address: u32, start_vir_address: u32, range: usize, kernel_base_address: u32, flags: u16) -> Result<(), String> {
+        Self::map_entry_in(current_page_dir(), start_phys_address, start_vir_address, range, kernel_base_address, flags)
+    }
+
+    // page_dir_tblはカーネル空間から見たページディレクトリのアドレス
+    fn map_entry_in(page_dir_tbl: *mut Table<PageDirectory>, start_phys_address: u32, start_vir_address: u32, range: usize, kernel_base_address: u32, flags: u16) -> Result<(), String> {
         let phys_address: usize = start_phys_address as usize;
         let vir_address: usize = start_vir_address as usize;
-        let page_dir_tbl = asmfunc::load_cr3() as *mut Table<PageDirectory>;
         let page_size = size_of::<usize>() * NUM_OF_ENTRY;
         // let mut count: usize = 0;
         // 1 page毎なので、4096毎
@@ -214,8 +294,8 @@ where
             let dir_entry: Entry = unsafe { (*page_dir_tbl).entries[dir_idx] };
 
             if dir_entry.present() {
-                let mut page_table = dir_entry.address() as *mut Table<PageTable>;
-                unsafe { (*page_table).set_address(tbl_idx, phys_address as u32 & 0xfffff000, true); }
+                let mut page_table = (dir_entry.address() + kernel_base_address) as *mut Table<PageTable>;
+                unsafe { (*page_table).set_address_with_flags(tbl_idx, phys_address as u32 & 0xfffff000, flags); }
             } else {
                 let page_table = match unsafe { (*page_dir_tbl).create_next_table(dir_idx, false, kernel_base_address) } {
                     Ok(table) => table,
@@ -223,7 +303,7 @@ where
                         panic!("Error in PageTableImpl.allocate_frame. {:?}", e)
                     },
                 };
-                unsafe { (*page_table).set_address(tbl_idx, phys_address as u32 & 0xfffff000, true); }
+                unsafe { (*page_table).set_address_with_flags(tbl_idx, phys_address as u32 & 0xfffff000, flags); }
             }
         }
         Ok(())
@@ -234,36 +314,25 @@ where
     }
 
     pub fn get_physaddr(&self, vir_address: u32) -> u32 {
-        let cr3 = asmfunc::load_cr3();
-        let page_dir_tbl = cr3 as *mut Table<PageDirectory>;
+        let page_dir_tbl = current_page_dir();
         let position_in_dir: usize = (vir_address >> 22) as usize; // PageTable no.
-        let pte_address = unsafe { (*page_dir_tbl).entries[position_in_dir].address() as *mut Table<PageTable> };
+        let pte_address = unsafe { ((*page_dir_tbl).entries[position_in_dir].address() + self.physical_base_virtual_address) as *mut Table<PageTable> };
         let position_in_pte: usize = (vir_address >> 12 & 0x3ff) as usize;
         unsafe { (*pte_address).entries[position_in_pte].address() + vir_address & 0x00000fff }
     }
 
+    // 物理フレームを1つ確保して、その物理アドレスを返す
     pub fn allocate_frame(&mut self) -> Result<u32, String> {
-        let layout = match Layout::from_size_align(SIZE_OF_PAGE, SIZE_OF_PAGE) {
-            Ok(l) => l,
-            Err(e) => panic!("Error in PageTableImpl.allocate_frame. {:?}", e),
-        };
-        if (&self.global_allocator).is_none() { panic!("global_allocator in PageTableImpl is not set."); }
-        let ptr = self.global_allocator
-            .as_mut()
-            .unwrap()
-            .allocate(layout)
-            .or(Err("Error in PageTableImpl.allocate_frame when call self.table_allocator.alloc().".to_owned()))?
-            .as_ptr();
-        Ok(ptr as *const *mut [u8] as usize as u32)
+        FRAME_ALLOCATOR.allocate_frame().ok_or("Error in PageTableImpl.allocate_frame. out of physical frames.".to_owned())
     }
 
     pub fn map(&mut self, vir_address: u32) -> Result<(), String> {
-        let page_dir = PAGE_DIR_BASE_ADDR as *mut Table<PageDirectory>;
+        let page_dir = current_page_dir();
         let position_in_dir: usize = (vir_address >> 22) as usize; // PageTable no.
         let dir_entry = unsafe { (*page_dir).entries[position_in_dir] };
         let table_idx = (vir_address >> 12 & 0x3ff) as usize;
         if dir_entry.present() {
-            let page_table = dir_entry.address() as *mut Table<PageTable>;
+            let page_table = (dir_entry.address() + self.physical_base_virtual_address) as *mut Table<PageTable>;
             let mut table_entry = unsafe { (*page_table).entries[table_idx] };
             if table_entry.present() {
                 return Err(format!("Already Exist in {:?}.", vir_address));
@@ -296,15 +365,225 @@ where
     }
 }
 
+// カーネルのページディレクトリの物理アドレス(AddressSpaceを使っていない時のCR3)
+pub fn kernel_page_dir() -> u32 {
+    PAGE_DIR_BASE_ADDR
+}
+
+// ページディレクトリ・ページテーブル用のフレーム(0で埋めてある)
+// カーネル空間から直接触れるように、ストレートマッピングしてある範囲から取る
+pub fn allocate_table_frame() -> Result<u32, MapError> {
+    let phys_address = FRAME_ALLOCATOR.allocate_contiguous_frames(1, 0, KERNEL_DIRECT_MAP_SIZE as u32)
+        .ok_or(MapError::OutOfFrames)?;
+    unsafe { ptr::write_bytes(phys_to_virt(phys_address) as *mut u8, 0, FRAME_SIZE) };
+    Ok(phys_address)
+}
+
+// ストレートマッピングされている範囲から物理的に連続したcount個のフレームを確保して、0で埋めた仮想アドレスを返す
+// Heapを使えない所(allocatorの中など)で使う作業用の領域
+pub fn allocate_direct_frames(count: usize) -> Result<u32, MapError> {
+    let phys_address = FRAME_ALLOCATOR.allocate_contiguous_frames(count, 0, KERNEL_DIRECT_MAP_SIZE as u32)
+        .ok_or(MapError::OutOfFrames)?;
+    unsafe { ptr::write_bytes(phys_to_virt(phys_address) as *mut u8, 0, count * FRAME_SIZE) };
+    Ok(phys_to_virt(phys_address))
+}
+
+pub fn free_direct_frames(vir_address: u32, count: usize) {
+    for idx in 0..count {
+        FRAME_ALLOCATOR.free_frame(virt_to_phys(vir_address) + (idx * FRAME_SIZE) as u32);
+    }
+}
+
+// 一時的な仮想アドレスにフレームをマッピングしてfを呼ぶ
+// ストレートマッピングされていないフレーム(ユーザーのページなど)の中身を触るのに使う
+fn with_temporary_mapping<R>(phys_addresses: &[u32], f: impl FnOnce(&[*mut u8]) -> R) -> R {
+    let _lock = TEMP_MAP_LOCK.lock();
+    let mut pointers = [ptr::null_mut(); TEMP_MAP_SLOTS];
+    for (slot, phys_address) in phys_addresses.iter().enumerate().take(TEMP_MAP_SLOTS) {
+        let vir_address = TEMP_MAP_ADDR + (slot * FRAME_SIZE) as u32;
+        let entry = page_table_entry(vir_address).expect("temporary map table is not present.");
+        unsafe { (*entry).set(*phys_address & ADDRESS_MSK, PTE_RW) };
+        pointers[slot] = vir_address as *mut u8;
+    }
+    flush_tlb();
+    let result = f(&pointers[..phys_addresses.len().min(TEMP_MAP_SLOTS)]);
+    for slot in 0..phys_addresses.len().min(TEMP_MAP_SLOTS) {
+        let entry = page_table_entry(TEMP_MAP_ADDR + (slot * FRAME_SIZE) as u32).unwrap();
+        unsafe { *entry = Entry::unused() };
+    }
+    flush_tlb();
+    result
+}
+
+pub fn zero_frame(phys_address: u32) {
+    with_temporary_mapping(&[phys_address], |pointers| unsafe { ptr::write_bytes(pointers[0], 0, FRAME_SIZE) });
+}
+
+pub fn copy_frame(dst_phys_address: u32, src_phys_address: u32) {
+    with_temporary_mapping(&[dst_phys_address, src_phys_address], |pointers| unsafe {
+        ptr::copy_nonoverlapping(pointers[1], pointers[0], FRAME_SIZE)
+    });
+}
+
+// 今のページディレクトリ(CR3は物理アドレスなのでカーネル空間のアドレスにする)
+fn current_page_dir() -> *mut Table<PageDirectory> {
+    (asmfunc::load_cr3() + KERNEL_BASE_ADDR) as *mut Table<PageDirectory>
+}
+
+// カーネル空間のストレートマッピングの中のアドレスと物理アドレスの変換
+// (カーネル本体の静的変数・カーネルスタック・DMAの領域はストレートマッピングされている)
+pub fn phys_to_virt(phys_address: u32) -> u32 {
+    phys_address + KERNEL_BASE_ADDR
+}
+
+pub fn virt_to_phys(vir_address: u32) -> u32 {
+    vir_address - KERNEL_BASE_ADDR
+}
+
+fn page_table_entry(vir_address: u32) -> Option<*mut Entry> {
+    let page_dir_tbl = current_page_dir();
+    let dir_entry = unsafe { (*page_dir_tbl).entries[(vir_address >> 22) as usize] };
+    if !dir_entry.present() { return None; }
+    let page_table = (dir_entry.address() + KERNEL_BASE_ADDR) as *mut Table<PageTable>;
+    Some(unsafe { &mut (*page_table).entries[(vir_address >> 12 & 0x3ff) as usize] as *mut Entry })
+}
+
+// 1ページだけマッピングする(flagsはPTE_RW, PTE_USERなど)
+// Heapを広げる時にも使うので、成功してもエラーでもHeapを使わない
+pub fn map_page(vir_address: u32, phys_address: u32, flags: u16) -> Result<(), MapError> {
+    let page_dir_tbl = current_page_dir();
+    let dir_idx = (vir_address >> 22) as usize;
+    let user_accessible = flags & PTE_USER == PTE_USER;
+    let dir_entry = unsafe { &mut (*page_dir_tbl).entries[dir_idx] };
+    if !dir_entry.present() {
+        unsafe { (*page_dir_tbl).create_next_table(dir_idx, user_accessible, KERNEL_BASE_ADDR)?; }
+    } else if user_accessible {
+        // ユーザーのページを含むならディレクトリのエントリもユーザーからアクセスできるようにする
+        dir_entry.0 |= PTE_USER as u32;
+    }
+    let entry = page_table_entry(vir_address).ok_or(MapError::NotMapped(vir_address))?;
+    unsafe {
+        if (*entry).present() { return Err(MapError::AlreadyMapped(vir_address)); }
+        (*entry).set(phys_address & ADDRESS_MSK, flags);
+    }
+    flush_tlb();
+    Ok(())
+}
+
+// マッピングを外して、マッピングされていた物理アドレスを返す
+pub fn unmap_page(vir_address: u32) -> Option<u32> {
+    let entry = page_table_entry(vir_address)?;
+    let phys_address = unsafe {
+        if !(*entry).present() { return None; }
+        let phys_address = (*entry).address();
+        *entry = Entry::unused();
+        phys_address
+    };
+    flush_tlb();
+    Some(phys_address)
+}
+
+// マッピングされているページのフラグだけを変える
+pub fn set_page_flags(vir_address: u32, flags: u16) -> Result<(), MapError> {
+    let entry = page_table_entry(vir_address).ok_or(MapError::NotMapped(vir_address))?;
+    unsafe {
+        if !(*entry).present() { return Err(MapError::NotMapped(vir_address)); }
+        let phys_address = (*entry).address();
+        (*entry).set(phys_address, flags);
+    }
+    flush_tlb();
+    Ok(())
+}
+
+// 仮想アドレスに対応する物理アドレス。マッピングされていなければNone
+pub fn translate(vir_address: u32) -> Option<u32> {
+    let entry = page_table_entry(vir_address)?;
+    unsafe {
+        if !(*entry).present() { return None; }
+        Some((*entry).address() | vir_address & !ADDRESS_MSK)
+    }
+}
+
+// start_vir_addressからsizeバイトを、新しく確保した物理フレームでマッピングする(物理的に連続している必要はない)
+pub fn map_new_frames(start_vir_address: u32, size: usize) -> Result<(), String> {
+    for offset in (0..size).step_by(FRAME_SIZE) {
+        let phys_address = match FRAME_ALLOCATOR.allocate_frame() {
+            Some(phys_address) => phys_address,
+            None => return Err(format!("out of physical frames at {:x}.", start_vir_address as usize + offset)),
+        };
+        PageTableImpl::<&LockedHeap>::map_entry_with_flags(
+            phys_address,
+            start_vir_address + offset as u32,
+            FRAME_SIZE,
+            KERNEL_BASE_ADDR,
+            PTE_RW,
+        )?;
+    }
+    flush_tlb();
+    Ok(())
+}
+
+// 物理的に連続した領域をカーネル空間のストレートマッピング(phys_to_virt)に追加して、その仮想アドレスを返す
+// DMAなどデバイスに物理アドレスを渡す領域に使う
+pub fn map_direct(phys_address: u32, size: usize) -> Result<u32, String> {
+    let vir_address = phys_to_virt(phys_address);
+    PageTableImpl::<&LockedHeap>::map_entry_with_flags(phys_address, vir_address, size, KERNEL_BASE_ADDR, PTE_RW)?;
+    flush_tlb();
+    Ok(vir_address)
+}
+
+// 物理的に連続した領域をストレートマッピングの位置(phys_to_virt)にキャッシュしないでマッピングして、その仮想アドレスを返す
+// 起動時からストレートマッピングされている範囲はフラグだけを変える
+pub fn map_direct_uncached(phys_address: u32, size: usize) -> Result<u32, String> {
+    let vir_address = phys_to_virt(phys_address);
+    for offset in (0..size).step_by(FRAME_SIZE) {
+        let page = vir_address + offset as u32;
+        let result = if translate(page).is_some() {
+            set_page_flags(page, PTE_RW | PTE_UNCACHED)
+        } else {
+            map_page(page, phys_address + offset as u32, PTE_RW | PTE_UNCACHED)
+        };
+        if let Err(e) = result {
+            unmap_direct_uncached(vir_address, offset);
+            return Err(e.into());
+        }
+    }
+    Ok(vir_address)
+}
+
+// map_direct_uncachedを戻す。起動時からのストレートマッピングはキャッシュするように戻し、それ以外はマッピングを外す
+pub fn unmap_direct_uncached(vir_address: u32, size: usize) {
+    for offset in (0..size).step_by(FRAME_SIZE) {
+        let page = vir_address + offset as u32;
+        if (virt_to_phys(page) as usize) < KERNEL_DIRECT_MAP_SIZE {
+            let _ = set_page_flags(page, PTE_RW);
+        } else {
+            unmap_page(page);
+        }
+    }
+}
+
 #[no_mangle]
 pub extern "C" fn page_fault_handler(esp: *const usize) {
-    Graphic::putfont_asc(10, 345, 10, "Page Fault!!");
     let vir_address = asmfunc::load_cr2();
-    {
-        if let Some(ref mut table) = *KERNEL_TABLE.lock() {
-            table.map(vir_address);
-        } else {
-            panic!("There is no Kernel Table.");
-        }
+    let frame = unsafe { ExceptionFrame::from_esp_mut(esp) };
+    // 予約済みの領域ならフレームを割り当てて、フォルトした命令からやり直す
+    let e = match vmm::handle_page_fault(vir_address, frame.error_code) {
+        Ok(()) => return,
+        Err(e) => e,
+    };
+    if let Some(stack) = vmm::find_stack_guard(vir_address) {
+        exception::report_stack_overflow(frame.eip, frame.fault_esp(), vir_address, &stack);
+        exception::halt();
+    }
+    // フォルトしてもよい場所(ユーザーのポインタから写す時など)ならエラーを返す所から続ける
+    if exception::dispatch::recover(0x0e, "page fault", frame) {
+        return;
+    }
+    match e.region() {
+        Some(region) => report_exception("page fault", frame, Some(vir_address), Some(format_args!(
+            "{} region: {} {:x}-{:x}", e.reason(), region.name, region.start, region.end()))),
+        None => report_exception("page fault", frame, Some(vir_address), Some(format_args!("{}", e.reason()))),
     }
+    exception::halt();
 }
\ No newline at end of file
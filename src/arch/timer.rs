@@ -150,3 +150,8 @@ pub extern "C" fn inthandler20(esp: *const usize) {
 pub fn get_uptime() -> usize {
     return *COUNTER.lock();
 }
+
+// タイマの割り込みに割り込んだかもしれない所(例外ハンドラなど)から読む。ロックが取れなければNone
+pub fn try_get_uptime() -> Option<usize> {
+    COUNTER.try_lock().map(|counter| *counter)
+}
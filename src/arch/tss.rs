@@ -0,0 +1,91 @@
+// タスク状態セグメント(TSS)
+// ハードウェアのタスク切り替えで使う。今はダブルフォルトを別のスタックで処理するためだけに使っている
+// (カーネルのスタックが溢れてダブルフォルトになった時、同じスタックでは例外を処理できないため)
+
+pub const KERNEL_TSS_SELECTOR: u16 = 3 * 8;
+pub const DOUBLE_FAULT_TSS_SELECTOR: u16 = 4 * 8;
+pub const TSS_LIMIT: u32 = 103;
+
+pub const DOUBLE_FAULT_STACK_SIZE: usize = 0x4000;
+
+const CODE_SELECTOR: u32 = 2 * 8;
+const DATA_SELECTOR: u32 = 1 * 8;
+// IF = 0(割り込み禁止)。bit1は常に1
+const EFLAGS_DEFAULT: u32 = 0x00000002;
+// I/O許可ビットマップを使わない
+const IOMAP_NONE: u32 = 0x40000000;
+
+#[repr(C)]
+pub struct TaskStateSegment {
+    pub backlink: u32,
+    pub esp0: u32,
+    pub ss0: u32,
+    pub esp1: u32,
+    pub ss1: u32,
+    pub esp2: u32,
+    pub ss2: u32,
+    pub cr3: u32,
+    pub eip: u32,
+    pub eflags: u32,
+    pub eax: u32,
+    pub ecx: u32,
+    pub edx: u32,
+    pub ebx: u32,
+    pub esp: u32,
+    pub ebp: u32,
+    pub esi: u32,
+    pub edi: u32,
+    pub es: u32,
+    pub cs: u32,
+    pub ss: u32,
+    pub ds: u32,
+    pub fs: u32,
+    pub gs: u32,
+    pub ldtr: u32,
+    pub iomap: u32,
+}
+
+impl TaskStateSegment {
+    const fn new() -> Self {
+        TaskStateSegment {
+            backlink: 0, esp0: 0, ss0: 0, esp1: 0, ss1: 0, esp2: 0, ss2: 0,
+            cr3: 0, eip: 0, eflags: 0,
+            eax: 0, ecx: 0, edx: 0, ebx: 0, esp: 0, ebp: 0, esi: 0, edi: 0,
+            es: 0, cs: 0, ss: 0, ds: 0, fs: 0, gs: 0,
+            ldtr: 0, iomap: IOMAP_NONE,
+        }
+    }
+
+    // カーネルのセグメントで、eipからstack_topのスタックで動き始めるタスクにする
+    fn init_kernel_task(&mut self, eip: u32, stack_top: u32, cr3: u32) {
+        *self = TaskStateSegment::new();
+        self.cr3 = cr3;
+        self.eip = eip;
+        self.eflags = EFLAGS_DEFAULT;
+        self.esp = stack_top;
+        self.cs = CODE_SELECTOR;
+        self.es = DATA_SELECTOR;
+        self.ss = DATA_SELECTOR;
+        self.ds = DATA_SELECTOR;
+        self.fs = DATA_SELECTOR;
+        self.gs = DATA_SELECTOR;
+    }
+
+    pub fn address(&self) -> u32 {
+        self as *const TaskStateSegment as u32
+    }
+}
+
+// 普段カーネルが動いているタスク。タスク切り替えの時にCPUがここにレジスタを保存する
+pub static mut KERNEL_TSS: TaskStateSegment = TaskStateSegment::new();
+// ダブルフォルトを処理するタスク
+pub static mut DOUBLE_FAULT_TSS: TaskStateSegment = TaskStateSegment::new();
+
+pub fn init_double_fault_task(handler: u32, stack_top: u32, cr3: u32) {
+    unsafe { DOUBLE_FAULT_TSS.init_kernel_task(handler, stack_top, cr3) };
+}
+
+// ダブルフォルトが起きた時にカーネルが実行していた場所(EIP, ESP)
+pub fn kernel_task_state() -> (u32, u32) {
+    unsafe { (KERNEL_TSS.eip, KERNEL_TSS.esp) }
+}
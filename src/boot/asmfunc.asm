@@ -3,11 +3,17 @@ bits 32
 
 ;global far_jmp
 global asm_inthandler02, asm_inthandler04, asm_inthandler05, asm_inthandler06, asm_inthandler07, asm_inthandler08, asm_inthandler0a, asm_inthandler0b, asm_inthandler0c, asm_inthandler0d
-global asm_inthandler0e, asm_inthandler10, asm_inthandler11, asm_inthandler12, asm_inthandler13, asm_inthandler14, asm_inthandler20, asm_inthandler21, asm_inthandler27, asm_inthandler2c
+global asm_inthandler0e, asm_inthandler10, asm_inthandler11, asm_inthandler12, asm_inthandler13, asm_inthandler14, asm_inthandler20, asm_inthandler21, asm_inthandler23, asm_inthandler24, asm_inthandler27, asm_inthandler2c
+global asm_inthandler30, asm_inthandler31, asm_inthandler32, asm_inthandler33, asm_inthandler34, asm_inthandler35, asm_inthandler36, asm_inthandler37
+global asm_inthandler38, asm_inthandler39, asm_inthandler3a, asm_inthandler3b, asm_inthandler3c, asm_inthandler3d, asm_inthandler3e, asm_inthandler3f
 extern non_maskable_interrupt_handler, overflow_handler, bounds_check_handler, undefined_operation_code_instruction_handler, no_coprocessor_handler, double_fault_handler, invalid_tss_handler
 extern segment_not_present_handler, stack_segment_fault_handler, general_protection_error_handler, page_fault_handler, coprocessor_error_handler, alignment_check_error_handler, machine_check_handler
 extern simd_fpu_exception_handler
-extern inthandler20, inthandler21, inthandler27, inthandler2c
+extern inthandler20, inthandler21, inthandler23, inthandler24, inthandler27, inthandler2c
+extern inthandler_msi
+global fault_copy, fault_copy_begin, fault_copy_end, fault_copy_fixup
+global fault_read32, fault_read32_begin, fault_read32_end, fault_read32_fixup
+global fault_write32, fault_write32_begin, fault_write32_end, fault_write32_fixup
 
 section .text
 
@@ -15,7 +21,10 @@ section .text
 ;    jmp far [esp+4]     ; eip, cs. JMP FARはfar jmpさせるための命令。CPUは指定番地からまず4バイト読み込んんで、その値をEIPに入れる
 ;    ret                 ; さらにその隣の2バイトも読み込んでCSに入れる
 
-asm_inthandler02:
+; CPU例外の入口(INT 0x00~0x1f)。エラーコードを積まない例外は0を積んで、どれも同じ形のスタック
+; (pushad, ds, es, エラーコード, eip, cs, eflags = exception::frame::ExceptionFrame)にしてからhandler(esp)を呼ぶ
+%macro EXCEPTION_HANDLER_ERRCODE 2
+asm_inthandler%1:
     push es
     push ds
     pushad
@@ -24,14 +33,18 @@ asm_inthandler02:
     mov ax, ss
     mov ds, ax
     mov es, ax
-    call non_maskable_interrupt_handler
+    call %2
     pop eax
     popad
     pop ds
     pop es
+    add esp, 4          ; エラーコードを捨てる
     iretd
+%endmacro
 
-asm_inthandler04:
+%macro EXCEPTION_HANDLER 2
+asm_inthandler%1:
+    push dword 0        ; エラーコードの代わり
     push es
     push ds
     pushad
@@ -40,110 +53,40 @@ asm_inthandler04:
     mov ax, ss
     mov ds, ax
     mov es, ax
-    call overflow_handler
+    call %2
     pop eax
     popad
     pop ds
     pop es
+    add esp, 4
     iretd
+%endmacro
 
-asm_inthandler05:
-    push es
-    push ds
-    pushad
-    mov eax, esp
-    push eax
-    mov ax, ss
-    mov ds, ax
-    mov es, ax
-    call bounds_check_handler
-    pop eax
-    popad
-    pop ds
-    pop es
-    iretd
-
-asm_inthandler06:
-    push es
-    push ds
-    pushad
-    mov eax, esp
-    push eax
-    mov ax, ss
-    mov ds, ax
-    mov es, ax
-    call undefined_operation_code_instruction_handler
-    pop eax
-    popad
-    pop ds
-    pop es
-    iretd
-
-asm_inthandler07:
-    push es
-    push ds
-    pushad
-    mov eax, esp
-    push eax
-    mov ax, ss
-    mov ds, ax
-    mov es, ax
-    call no_coprocessor_handler
-    pop eax
-    popad
-    pop ds
-    pop es
-    iretd
+EXCEPTION_HANDLER 02, non_maskable_interrupt_handler
+EXCEPTION_HANDLER 04, overflow_handler
+EXCEPTION_HANDLER 05, bounds_check_handler
+EXCEPTION_HANDLER 06, undefined_operation_code_instruction_handler
+EXCEPTION_HANDLER 07, no_coprocessor_handler
 
-asm_inthandler08:
-    push es
-    push ds
-    pushad
-    mov eax, esp
+asm_inthandler08:       ; ダブルフォルト専用のタスク(TSS)として動くので、戻らない
+    mov eax, esp        ; [esp]はエラーコード
     push eax
-    mov ax, ss
-    mov ds, ax
-    mov es, ax
     call double_fault_handler
-    pop eax
-    popad
-    pop ds
-    pop es
-    iretd
+.fin:
+    hlt
+    jmp .fin
 
-asm_inthandler0a:
-    push es
-    push ds
-    pushad
-    mov eax, esp
-    push eax
-    mov ax, ss
-    mov ds, ax
-    mov es, ax
-    call invalid_tss_handler
-    pop eax
-    popad
-    pop ds
-    pop es
-    iretd
+EXCEPTION_HANDLER_ERRCODE 0a, invalid_tss_handler
+EXCEPTION_HANDLER_ERRCODE 0b, segment_not_present_handler
+EXCEPTION_HANDLER_ERRCODE 0c, stack_segment_fault_handler
+EXCEPTION_HANDLER_ERRCODE 0d, general_protection_error_handler
+EXCEPTION_HANDLER_ERRCODE 0e, page_fault_handler
+EXCEPTION_HANDLER 10, coprocessor_error_handler
+EXCEPTION_HANDLER_ERRCODE 11, alignment_check_error_handler
+EXCEPTION_HANDLER 12, machine_check_handler
+EXCEPTION_HANDLER 13, simd_fpu_exception_handler
 
-asm_inthandler0b:
-    push es
-    push ds
-    pushad
-    mov eax, esp
-    push eax
-    mov ax, ss
-    mov ds, ax
-    mov es, ax
-    call segment_not_present_handler
-    pop eax
-    popad
-    pop ds
-    pop es
-    iretd
-
-asm_inthandler0c:
+asm_inthandler20:
     push es
     push ds
     pushad
@@ -152,14 +95,14 @@ asm_inthandler0c:
     mov ax, ss
     mov ds, ax
     mov es, ax
-    call stack_segment_fault_handler
+    call inthandler20
     pop eax
     popad
     pop ds
     pop es
     iretd
 
-asm_inthandler0d:
+asm_inthandler21:
     push es
     push ds
     pushad
@@ -168,14 +111,14 @@ asm_inthandler0d:
     mov ax, ss
     mov ds, ax
     mov es, ax
-    call general_protection_error_handler
+    call inthandler21
     pop eax
     popad
     pop ds
     pop es
     iretd
 
-asm_inthandler0e:
+asm_inthandler23:
     push es
     push ds
     pushad
@@ -184,14 +127,14 @@ asm_inthandler0e:
     mov ax, ss
     mov ds, ax
     mov es, ax
-    call page_fault_handler
+    call inthandler23
     pop eax
     popad
     pop ds
     pop es
     iretd
 
-asm_inthandler10:
+asm_inthandler24:
     push es
     push ds
     pushad
@@ -200,14 +143,14 @@ asm_inthandler10:
     mov ax, ss
     mov ds, ax
     mov es, ax
-    call coprocessor_error_handler
+    call inthandler24
     pop eax
     popad
     pop ds
     pop es
     iretd
 
-asm_inthandler11:
+asm_inthandler27:
     push es
     push ds
     pushad
@@ -216,14 +159,14 @@ asm_inthandler11:
     mov ax, ss
     mov ds, ax
     mov es, ax
-    call alignment_check_error_handler
+    call inthandler27
     pop eax
     popad
     pop ds
     pop es
     iretd
 
-asm_inthandler12:
+asm_inthandler2c:
     push es
     push ds
     pushad
@@ -232,89 +175,94 @@ asm_inthandler12:
     mov ax, ss
     mov ds, ax
     mov es, ax
-    call machine_check_handler
+    call inthandler2c
     pop eax
     popad
     pop ds
     pop es
     iretd
 
-asm_inthandler13:
+; MSI用の割り込み(INT 0x30~0x3f)。ベクタ番号を付けてinthandler_msi(vector, esp)を呼ぶ
+%macro MSI_INTHANDLER 1
+asm_inthandler%1:
     push es
     push ds
     pushad
     mov eax, esp
     push eax
+    push dword 0x%1
     mov ax, ss
     mov ds, ax
     mov es, ax
-    call simd_fpu_exception_handler
+    call inthandler_msi
+    add esp, 4
     pop eax
     popad
     pop ds
     pop es
     iretd
+%endmacro
 
-asm_inthandler20:
-    push es
-    push ds
-    pushad
-    mov eax, esp
-    push eax
-    mov ax, ss
-    mov ds, ax
-    mov es, ax
-    call inthandler20
-    pop eax
-    popad
-    pop ds
-    pop es
-    iretd
+MSI_INTHANDLER 30
+MSI_INTHANDLER 31
+MSI_INTHANDLER 32
+MSI_INTHANDLER 33
+MSI_INTHANDLER 34
+MSI_INTHANDLER 35
+MSI_INTHANDLER 36
+MSI_INTHANDLER 37
+MSI_INTHANDLER 38
+MSI_INTHANDLER 39
+MSI_INTHANDLER 3a
+MSI_INTHANDLER 3b
+MSI_INTHANDLER 3c
+MSI_INTHANDLER 3d
+MSI_INTHANDLER 3e
+MSI_INTHANDLER 3f
 
-asm_inthandler21:
-    push es
-    push ds
-    pushad
-    mov eax, esp
-    push eax
-    mov ax, ss
-    mov ds, ax
-    mov es, ax
-    call inthandler21
-    pop eax
-    popad
-    pop ds
-    pop es
-    iretd
+; フォルトするかもしれないアクセス(exception/fixup.rsの表に登録してある)
+; *_begin~*_endの間でフォルトすると、例外のハンドラがEIPを*_fixupに変えて戻るので、-1を返す
+fault_copy:             ; fault_copy(dst: *mut u8, src: *const u8, len: usize) -> i32
+    push esi
+    push edi
+    mov edi, [esp+12]
+    mov esi, [esp+16]
+    mov ecx, [esp+20]
+    cld
+fault_copy_begin:
+    rep movsb
+fault_copy_end:
+    xor eax, eax
+    pop edi
+    pop esi
+    ret
+fault_copy_fixup:
+    mov eax, -1
+    pop edi
+    pop esi
+    ret
 
-asm_inthandler27:
-    push es
-    push ds
-    pushad
-    mov eax, esp
-    push eax
-    mov ax, ss
-    mov ds, ax
-    mov es, ax
-    call inthandler27
-    pop eax
-    popad
-    pop ds
-    pop es
-    iretd
+fault_read32:           ; fault_read32(address: *const u32, value: *mut u32) -> i32
+    mov ecx, [esp+4]
+    mov edx, [esp+8]
+fault_read32_begin:
+    mov eax, [ecx]
+fault_read32_end:
+    mov [edx], eax
+    xor eax, eax
+    ret
+fault_read32_fixup:
+    mov eax, -1
+    ret
 
-asm_inthandler2c:
-    push es
-    push ds
-    pushad
-    mov eax, esp
-    push eax
-    mov ax, ss
-    mov ds, ax
-    mov es, ax
-    call inthandler2c
-    pop eax
-    popad
-    pop ds
-    pop es
-    iretd
\ No newline at end of file
+fault_write32:          ; fault_write32(address: *mut u32, value: u32) -> i32
+    mov ecx, [esp+4]
+    mov edx, [esp+8]
+fault_write32_begin:
+    mov [ecx], edx
+fault_write32_end:
+    xor eax, eax
+    ret
+fault_write32_fixup:
+    mov eax, -1
+    ret
@@ -0,0 +1,54 @@
+#!/bin/sh
+# kernel.mapから.textに置かれた関数のアドレス・サイズ・名前を取り出して、
+# 例外やパニックのバックトレースで使うシンボル表(.ksymtabセクション)のnasmのソースを書き出す
+# usage: gensyms.sh kernel.map > ksymtab.asm
+#
+# 表の形式(exception/symbols.rsで読む)
+#   dd 数
+#   dd アドレス, サイズ, 名前のオフセット  (アドレスの小さい順)
+#   db 名前, 0 ...
+set -e
+
+MAP=$1
+
+awk '
+function add(addr, size, name) {
+    if (addr in seen) return
+    seen[addr] = 1
+    print addr, size, name
+}
+# 出力セクションの行(先頭が空白でない)で.textの中かどうかを切り替える
+/^[^ \t]/ { in_text = ($1 == ".text"); section = ""; next }
+!in_text { next }
+# 入力セクション: " .text.名前 アドレス サイズ ファイル"(名前が長いとアドレスからは次の行)
+/^ \.text/ {
+    section = $1
+    sub(/^\.text\.?/, "", section)
+    if (NF >= 3) {
+        if (section != "" && $3 != "0x0") add($2, $3, section)
+        section = ""
+    }
+    next
+}
+section != "" && $1 ~ /^0x/ && NF >= 3 {
+    if ($2 != "0x0") add($1, $2, section)
+    section = ""
+    next
+}
+# グローバルなシンボル(asmfunc.asmのラベルなど): "アドレス 名前"。サイズは分からないので0
+$1 ~ /^0x/ && NF == 2 { add($1, "0x0", $2) }
+' "$MAP" | sort | awk '
+{ addr[NR] = $1; size[NR] = $2; name[NR] = $3 }
+END {
+    print "section .ksymtab align=4"
+    print "    dd " NR
+    offset = 0
+    for (i = 1; i <= NR; i++) {
+        print "    dd " addr[i] ", " size[i] ", " offset
+        offset += length(name[i]) + 1
+    }
+    for (i = 1; i <= NR; i++) {
+        print "    db \"" name[i] "\", 0"
+    }
+}
+'
@@ -1,7 +1,8 @@
 ; rio-ipl
 ; TAB=4
 
-cyls    equ     0x0a             ; どこまで読み込むか
+cyls    equ     0x1e             ; どこまで読み込むか(secondboot.asmが写すカーネルの512KBが入るように。Makefileで確かめる)
+                                 ; 0x8000から読むので、0x90000(secondboot.asmがVBEの情報を置く所)を越えないように30まで
 
         org     0x7c00           ; このプログラムがどこに読み込まれるか
 
@@ -18,6 +18,13 @@ INITOS  equ     0x00280000      ; OS本体部分のロード先
 STACK  equ      0x003fffff      ; Stack領域
 ;STACK   equ     0x00310000      ; Stack領域
 DSKCAC  equ     0x00100000      ; ディスクキャッシュの場所
+KERNEL_BASE equ 0xc0000000      ; カーネル空間の先頭(物理アドレス0をここにマッピングする)
+
+; ページングの準備(カーネルに移る前の仮のページディレクトリ)
+BOOT_PAGE_DIR    equ 0x00001000 ; 0x1000 - 0x1fff
+BOOT_PAGE_TABLES equ 0x00002000 ; 0x2000 - 0x5fff(4つで物理アドレス0~16MB)
+BOOT_PAGE_TABLE_NUM equ 4
+PTE_PRESENT_RW   equ 0x003
 DSKCAC0 equ     0x00008000      ; ディスクキャッシュの場所(リアルモード)
 
 ; BOOT_INFO関係
@@ -27,9 +34,46 @@ VMODE   equ     0x0ff2          ; 色すうに関する情報。何ビットカ
 SCRNX   equ     0x0ff4          ; 解像度のX
 SCRNY   equ     0x0ff6          ; 解像度のY
 VRAM    equ     0x0ff8          ; グラフィックバッファの開始番地
+E820_COUNT equ  0x0ffc          ; メモリマップのエントリ数
+
+; メモリマップ(INT 15h, EAX=0xE820)の格納先
+E820_MAP   equ  0x0500          ; 0x0500 - 0x0aff
+E820_MAX   equ  64              ; 格納できるエントリ数
+E820_ENTRY_SIZE equ 24
+SMAP       equ  0x534d4150      ; 'SMAP'
 
         org     0xc200          ; このプログラムがどこに読み込まれるのか
 
+; BIOSからメモリマップ(E820)を取得する
+;   1回のINT 15hで1エントリ(base, length, type, 拡張属性)が返ってくる
+;   ebxが0になるかcarryが立ったら終わり
+
+        mov     DWORD [E820_COUNT], 0
+        mov     ax, 0
+        mov     es, ax
+        mov     di, E820_MAP
+        xor     ebx, ebx
+e820_next:
+        mov     eax, 0xe820
+        mov     edx, SMAP
+        mov     ecx, E820_ENTRY_SIZE
+        mov     DWORD [es:di+20], 1     ; 拡張属性を返さないBIOSのために有効の印を入れておく
+        int     0x15
+        jc      e820_done               ; 非対応か最後のエントリの次
+        cmp     eax, SMAP
+        jne     e820_done
+        mov     ecx, [es:di+8]          ; lengthが0のエントリは無視する
+        or      ecx, [es:di+12]
+        jz      e820_skip
+        inc     DWORD [E820_COUNT]
+        add     di, E820_ENTRY_SIZE
+        cmp     DWORD [E820_COUNT], E820_MAX
+        jae     e820_done
+e820_skip:
+        test    ebx, ebx
+        jnz     e820_next
+e820_done:
+
 ; VBE存在確認
 
         mov     ax, 0x9000
@@ -177,9 +221,49 @@ pipelineflush:
 ;        mov     edi, [ebx+12]       ; 転送先
 ;        call    memcpy
 ;skip:
-        mov     esp, STACK           ; スタック初期値
+; ページングを有効にしてカーネル空間(0xc0000000~)へ移る
+;   物理アドレス0~16MBを0xc0000000~にマッピングする
+;   ジャンプするまではここのコードを同じアドレスで実行するので0~4MBもそのままマッピングしておく
+;   (カーネルがページテーブルを作り直した時に無くなる)
+
+        mov     edi, BOOT_PAGE_DIR          ; ディレクトリとページテーブルを0で埋める
+        mov     ecx, 1024*(1+BOOT_PAGE_TABLE_NUM)
+        xor     eax, eax
+        rep stosd
+
+        mov     edi, BOOT_PAGE_TABLES
+        mov     eax, PTE_PRESENT_RW         ; 物理アドレス0から順番に
+        mov     ecx, 1024*BOOT_PAGE_TABLE_NUM
+boot_pte:
+        mov     [edi], eax
+        add     eax, 0x1000
+        add     edi, 4
+        sub     ecx, 1
+        jnz     boot_pte
+
+        mov     DWORD [BOOT_PAGE_DIR], BOOT_PAGE_TABLES+PTE_PRESENT_RW     ; 0~4MB
+        mov     edi, BOOT_PAGE_DIR+(KERNEL_BASE>>22)*4                      ; 0xc0000000~16MB
+        mov     eax, BOOT_PAGE_TABLES+PTE_PRESENT_RW
+        mov     ecx, BOOT_PAGE_TABLE_NUM
+boot_pde:
+        mov     [edi], eax
+        add     eax, 0x1000
+        add     edi, 4
+        sub     ecx, 1
+        jnz     boot_pde
+
+        mov     eax, BOOT_PAGE_DIR
+        mov     cr3, eax
+        mov     eax, cr0
+        or      eax, 0x80000000     ; bit31を1にする(ページング開始)
+        mov     cr0, eax
+        jmp     paging_flush
+paging_flush:
+
+        mov     esp, STACK+KERNEL_BASE      ; スタック初期値
         ; jmp     $                    ; debug
-        jmp     0x00280000     ; .sysへジャンプ
+        mov     eax, INITOS+KERNEL_BASE
+        jmp     eax                         ; .sysへジャンプ
 
 waitkbdout:
         in      al, 0x64
@@ -202,7 +286,7 @@ GDT0:
         ; resb    8                   ; フルセレクタ
         times   8 db 0
         dw      0xffff, 0x0000, 0x9200, 0x00cf  ; 読み書き可能セグメント32bit
-        dw      0xffff, 0x0000, 0x9a00, 0x0047  ; 実行可能セグメント32bit(initos用)
+        dw      0xffff, 0x0000, 0x9a00, 0x00cf  ; 実行可能セグメント32bit(0xc0000000~のカーネルも実行できるように4GB)
 
         dw      0
 GDTR0:
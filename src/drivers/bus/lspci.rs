@@ -0,0 +1,237 @@
+use core::fmt::{self, Write};
+use alloc::string::String;
+
+use super::pci::{get_pci_devices, command_flag_names, status_flag_names, BarRegion, PciDevice};
+use super::pci_capability::PciCapabilityKind;
+use crate::arch::graphic::Printer;
+
+// lspci -v 風にPCIデバイスの一覧を書き出す
+// fmt::Writeに書くので、画面にもシリアルのログにも出せる
+
+const LINE_HEIGHT: u32 = 15;
+
+const PCI_VENDOR_NAMES: [(u16, &str); 8] = [
+    (0x8086, "Intel Corporation"),
+    (0x1234, "QEMU"),
+    (0x1af4, "Red Hat, Inc. (virtio)"),
+    (0x1b36, "Red Hat, Inc. (QEMU)"),
+    (0x10ec, "Realtek Semiconductor"),
+    (0x1013, "Cirrus Logic"),
+    (0x15ad, "VMware"),
+    (0x80ee, "VirtualBox"),
+];
+
+// QEMUでよく使うデバイス
+const PCI_DEVICE_NAMES: [(u16, u16, &str); 28] = [
+    (0x8086, 0x1237, "440FX - 82441FX PMC [Natoma]"),
+    (0x8086, 0x7000, "82371SB PIIX3 ISA [Natoma/Triton II]"),
+    (0x8086, 0x7010, "82371SB PIIX3 IDE [Natoma/Triton II]"),
+    (0x8086, 0x7020, "82371SB PIIX3 USB [Natoma/Triton II]"),
+    (0x8086, 0x7113, "82371AB/EB/MB PIIX4 ACPI"),
+    (0x8086, 0x100e, "82540EM Gigabit Ethernet Controller"),
+    (0x8086, 0x100f, "82545EM Gigabit Ethernet Controller (Copper)"),
+    (0x8086, 0x10d3, "82574L Gigabit Network Connection"),
+    (0x8086, 0x29c0, "82G33/G31/P35/P31 Express DRAM Controller"),
+    (0x8086, 0x2918, "82801IB (ICH9) LPC Interface Controller"),
+    (0x8086, 0x2922, "82801IR/IO/IH (ICH9R/DO/DH) 6 port SATA Controller [AHCI mode]"),
+    (0x8086, 0x2930, "82801I (ICH9 Family) SMBus Controller"),
+    (0x8086, 0x2415, "82801AA AC'97 Audio Controller"),
+    (0x8086, 0x2668, "82801FB/FBM/FR/FW/FRW (ICH6 Family) High Definition Audio Controller"),
+    (0x1234, 0x1111, "Standard VGA"),
+    (0x1013, 0x00b8, "GD 5446"),
+    (0x10ec, 0x8139, "RTL-8100/8101L/8139 PCI Fast Ethernet Adapter"),
+    (0x1af4, 0x1000, "Virtio network device"),
+    (0x1af4, 0x1001, "Virtio block device"),
+    (0x1af4, 0x1002, "Virtio memory balloon"),
+    (0x1af4, 0x1003, "Virtio console"),
+    (0x1af4, 0x1005, "Virtio RNG"),
+    (0x1af4, 0x1041, "Virtio 1.0 network device"),
+    (0x1af4, 0x1042, "Virtio 1.0 block device"),
+    (0x1b36, 0x0001, "QEMU PCI-PCI bridge"),
+    (0x1b36, 0x0008, "QEMU PCIe Host bridge"),
+    (0x1b36, 0x000c, "QEMU PCIe Root port"),
+    (0x1b36, 0x000d, "QEMU XHCI Host Controller"),
+];
+
+// (class, subclass)。subclassがNoneならclassだけで決める
+const PCI_CLASS_NAMES: [(u8, Option<u8>, &str); 27] = [
+    (0x01, Some(0x01), "IDE interface"),
+    (0x01, Some(0x06), "SATA controller"),
+    (0x01, Some(0x08), "Non-Volatile memory controller"),
+    (0x01, None, "Mass storage controller"),
+    (0x02, Some(0x00), "Ethernet controller"),
+    (0x02, None, "Network controller"),
+    (0x03, Some(0x00), "VGA compatible controller"),
+    (0x03, None, "Display controller"),
+    (0x04, Some(0x01), "Multimedia audio controller"),
+    (0x04, Some(0x03), "Audio device"),
+    (0x04, None, "Multimedia controller"),
+    (0x05, None, "Memory controller"),
+    (0x06, Some(0x00), "Host bridge"),
+    (0x06, Some(0x01), "ISA bridge"),
+    (0x06, Some(0x04), "PCI bridge"),
+    (0x06, Some(0x80), "Bridge"),
+    (0x06, None, "Bridge"),
+    (0x07, Some(0x00), "Serial controller"),
+    (0x07, None, "Communication controller"),
+    (0x08, None, "System peripheral"),
+    (0x09, None, "Input device controller"),
+    (0x0c, Some(0x03), "USB controller"),
+    (0x0c, Some(0x05), "SMBus"),
+    (0x0c, None, "Serial bus controller"),
+    (0x0d, None, "Wireless controller"),
+    (0x10, None, "Encryption controller"),
+    (0xff, None, "Unassigned class"),
+];
+
+pub fn pci_vendor_name(vendor_id: u16) -> Option<&'static str> {
+    PCI_VENDOR_NAMES.iter().find(|(id, _)| *id == vendor_id).map(|(_, name)| *name)
+}
+
+pub fn pci_device_name(vendor_id: u16, device_id: u16) -> Option<&'static str> {
+    PCI_DEVICE_NAMES.iter().find(|(vid, did, _)| *vid == vendor_id && *did == device_id).map(|(_, _, name)| *name)
+}
+
+pub fn pci_class_name(class_code: u8, subclass: u8) -> &'static str {
+    PCI_CLASS_NAMES.iter()
+        .find(|(class, sub, _)| *class == class_code && sub.map_or(true, |sub| sub == subclass))
+        .map_or("Unknown class", |(_, _, name)| *name)
+}
+
+// 4096 -> 4K のように単位を付ける
+fn write_size<W: Write>(w: &mut W, size: u64) -> fmt::Result {
+    const UNITS: [(u64, &str); 3] = [(1 << 30, "G"), (1 << 20, "M"), (1 << 10, "K")];
+    for (unit, suffix) in UNITS.iter() {
+        if size >= *unit && size % unit == 0 {
+            return write!(w, "{}{}", size / unit, suffix);
+        }
+    }
+    write!(w, "{}", size)
+}
+
+// Interrupt Pin(1~4がINTA#~INTD#)。それ以外はconfig spaceの値がおかしい
+fn interrupt_pin_name(pin: u8) -> char {
+    match pin {
+        1..=4 => (b'A' + pin - 1) as char,
+        _ => '?',
+    }
+}
+
+fn write_flags<W: Write>(w: &mut W, flags: &[&str]) -> fmt::Result {
+    for (idx, flag) in flags.iter().enumerate() {
+        if idx > 0 { w.write_str(" ")?; }
+        w.write_str(flag)?;
+    }
+    Ok(())
+}
+
+fn enabled_mark(enabled: bool) -> char {
+    if enabled { '+' } else { '-' }
+}
+
+fn write_capabilities<W: Write>(w: &mut W, device: &PciDevice) -> fmt::Result {
+    let capabilities = device.capabilities();
+    if capabilities.is_empty() {
+        return writeln!(w, "    Capabilities: <none>");
+    }
+    for cap in capabilities.iter() {
+        let kind = cap.kind();
+        write!(w, "    Capabilities: [{:02x}] {}", cap.offset, kind.name())?;
+        match kind {
+            PciCapabilityKind::PowerManagement => {
+                if let Some(pm) = device.power_management_capability() {
+                    write!(w, " v{} D1{} D2{} state D{}", pm.version, enabled_mark(pm.d1_support), enabled_mark(pm.d2_support), pm.power_state)?;
+                }
+            },
+            PciCapabilityKind::Msi => {
+                if let Some(msi) = device.msi_capability() {
+                    write!(w, ": Enable{} Count={} 64bit{} Maskable{}", enabled_mark(msi.enabled), msi.max_vectors, enabled_mark(msi.address_64bit), enabled_mark(msi.per_vector_masking))?;
+                }
+            },
+            PciCapabilityKind::MsiX => {
+                if let Some(msix) = device.msix_capability() {
+                    write!(w, ": Enable{} Count={} Masked{} Table BAR{}+{:x} PBA BAR{}+{:x}",
+                           enabled_mark(msix.enabled), msix.table_size, enabled_mark(msix.function_masked),
+                           msix.table_bar, msix.table_offset, msix.pba_bar, msix.pba_offset)?;
+                }
+            },
+            PciCapabilityKind::PciExpress => {
+                if let Some(pcie) = device.pci_express_capability() {
+                    write!(w, " v{} type {}", pcie.version, pcie.device_type)?;
+                }
+            },
+            PciCapabilityKind::VendorSpecific => {
+                write!(w, " len={}", device.read_config8(cap.offset + 2))?;
+            },
+            PciCapabilityKind::Other(id) => {
+                write!(w, " (id {:02x})", id)?;
+            },
+        }
+        writeln!(w)?;
+    }
+    Ok(())
+}
+
+pub fn format_pci_device<W: Write>(w: &mut W, device: &PciDevice) -> fmt::Result {
+    write!(w, "{:02x}:{:02x}.{} {}: ", device.bus, device.device, device.function, pci_class_name(device.class_code, device.subclass))?;
+    match pci_vendor_name(device.vendor_id) {
+        Some(vendor) => write!(w, "{} ", vendor)?,
+        None => write!(w, "Vendor {:04x} ", device.vendor_id)?,
+    }
+    match pci_device_name(device.vendor_id, device.device_id) {
+        Some(name) => write!(w, "{} ", name)?,
+        None => write!(w, "Device {:04x} ", device.device_id)?,
+    }
+    writeln!(w, "[{:04x}:{:04x}] (rev {:02x})", device.vendor_id, device.device_id, device.revision)?;
+
+    write!(w, "    Command: ")?;
+    write_flags(w, &command_flag_names(device.command()))?;
+    writeln!(w)?;
+    write!(w, "    Status: ")?;
+    write_flags(w, &status_flag_names(device.status()))?;
+    writeln!(w)?;
+
+    match device.interrupt() {
+        Some((line, pin)) => writeln!(w, "    IRQ {} (INT{}#)", line, interrupt_pin_name(pin))?,
+        None => writeln!(w, "    IRQ none")?,
+    }
+    if let Some(secondary_bus) = device.secondary_bus {
+        writeln!(w, "    Secondary bus: {:02x}", secondary_bus)?;
+    }
+
+    for idx in 0..device.bar_num {
+        match device.bar(idx) {
+            Some(BarRegion::Mmio { base, size, prefetchable }) => {
+                write!(w, "    BAR{}: Memory at {:08x} ({}prefetchable) [size=", idx, base, if prefetchable { "" } else { "non-" })?;
+                write_size(w, size)?;
+                writeln!(w, "]")?;
+            },
+            Some(BarRegion::Io { port, size }) => {
+                write!(w, "    BAR{}: I/O ports at {:04x} [size=", idx, port)?;
+                write_size(w, size as u64)?;
+                writeln!(w, "]")?;
+            },
+            None => {},
+        }
+    }
+
+    write_capabilities(w, device)
+}
+
+// 列挙済みの全てのデバイスを書き出す
+pub fn format_pci_devices<W: Write>(w: &mut W) -> fmt::Result {
+    for device in get_pci_devices().iter() {
+        format_pci_device(w, device)?;
+    }
+    Ok(())
+}
+
+// 画面に1行ずつ表示する(Printerは改行に対応していないため)
+pub fn dump_pci_devices(x: u32, y: u32) {
+    let mut text = String::new();
+    if format_pci_devices(&mut text).is_err() { return; }
+    for (idx, line) in text.lines().enumerate() {
+        let mut printer = Printer::new(x, y + idx as u32 * LINE_HEIGHT, 0);
+        write!(printer, "{}", line).unwrap();
+    }
+}
@@ -1 +1,3 @@
-pub mod pci;
\ No newline at end of file
+pub mod pci;
+pub mod pci_capability;
+pub mod lspci;
\ No newline at end of file
@@ -1,13 +1,18 @@
 use core::fmt::Write;
-use core::mem::{size_of, transmute};
+use core::mem::size_of;
+use core::slice;
 // use core::ptr::{read_volatile, write_volatile};
 use alloc::vec::Vec;
 use alloc::borrow::ToOwned;
 
-use super::super::net::e1000::{get_mac_addr};
+use alloc::string::String;
+use super::super::net::e1000::{get_mac_addr, negotiate_features, NicFeatures, HwChecksum, RxMeta, RxFrame, TxOffload};
+use super::super::net::e1000_regs::{self as regs, Rctl, Tctl, RCTL_BSIZE_1024B};
 use crate::asmfunc::{io_in32, io_out32};
 use crate::arch::graphic::{Graphic, Printer, print_str};
-use crate::memory::dma::DmaBox;
+use crate::memory::dma::{DmaBox, DmaConstraints, DmaRegion, ScatterGatherList};
+use crate::memory::mmio::MmioRegion;
+use crate::arch::paging::{virt_to_phys, phys_to_virt};
 
 #[macro_use]
 use crate::memory::volatile::{write_mem, read_mem};
@@ -20,26 +25,30 @@ use crate::spin::mutex::{Mutex, MutexGuard};
 const CONFIG_ADDR: i32 = 0x0cf8;
 const CONFIG_DATA: i32 = 0x0cfc;
 const PCI_CONF_DID_VID: u8 = 0x00;
+const PCI_CONF_CLASS_REV: u8 = 0x08;
+const PCI_CONF_HEADER_TYPE: u8 = 0x0c;
 const PCI_CONF_BAR: u8 = 0x10;
-
-const NIC_BUS_NUM: u8 = 0x00;
-// const NIC_DEV_NUM: u8 = 0x04;
-const NIC_DEV_NUM: u8 = 0x03;
-const NIC_FN_NUM: u8 = 0x0;
-const NIC_REG_IMS: u16 = 0x00d0;
-const NIC_REG_IMC: u16 = 0x00d8;
-const NIC_REG_RCTL: u16 = 0x0100;
-const NIC_REG_RDBAL: u16 = 0x2800;
-const NIC_REG_RDBAH: u16 = 0x2804;
-const NIC_REG_RDLEN: u16 = 0x2808;
-const NIC_REG_RDH: u16 = 0x2810;
-const NIC_REG_RDT: u16 = 0x2818;
-const NIC_REG_TCTL: u16 = 0x0400;
-const NIC_REG_TDBAL: u16 = 0x3800;
-const NIC_REG_TDBAH: u16 = 0x3804;
-const NIC_REG_TDLEN: u16 = 0x3808;
-const NIC_REG_TDH: u16 = 0x3810;
-const NIC_REG_TDT: u16 = 0x3818;
+const PCI_CONF_BRIDGE_BUS_NUM: u8 = 0x18; // PCI-PCIブリッジのprimary/secondary/subordinate bus
+const PCI_CONF_INTR: u8 = 0x3c;
+
+const PCI_BUS_NUM: usize = 256;
+const PCI_DEV_NUM: u8 = 32;
+const PCI_FN_NUM: u8 = 8;
+const PCI_VENDOR_NONE: u16 = 0xffff;
+
+const PCI_HEADER_TYPE_MASK: u8 = 0x7f;
+const PCI_HEADER_TYPE_MULTI_FUNC: u8 = 0x80;
+pub const PCI_HEADER_TYPE_NORMAL: u8 = 0x00;
+pub const PCI_HEADER_TYPE_BRIDGE: u8 = 0x01;
+pub const PCI_HEADER_TYPE_CARDBUS: u8 = 0x02;
+pub const PCI_BAR_NUM: usize = 6;
+
+pub const PCI_CLASS_NETWORK: u8 = 0x02;
+pub const PCI_CLASS_DISPLAY: u8 = 0x03;
+pub const PCI_CLASS_BRIDGE: u8 = 0x06;
+pub const PCI_SUBCLASS_ETHERNET: u8 = 0x00;
+pub const PCI_SUBCLASS_HOST_BRIDGE: u8 = 0x00;
+pub const PCI_SUBCLASS_PCI_BRIDGE: u8 = 0x04;
 
 const PCI_CONF_STATUS_COMMAND: u8 = 0x04;
 
@@ -55,7 +64,7 @@ const PCI_COM_FAST_BACK2BACK_EN: u32 = 0x01 << 9;
 const PCI_COM_INTR_DIS: u32 = 0x01 << 10;
 
 const PCI_STAT_INTR: u32 = 0x01 << 3;
-const PCI_STAT_MULT_FUNC: u32 = 0x01 << 4;
+pub const PCI_STAT_CAP_LIST: u32 = 0x01 << 4;
 const PCI_STAT_66MHZ: u32 = 0x01 << 5;
 const PCI_STAT_FAST_BACK2BACK: u32 = 0x01 << 7;
 const PCI_STAT_DATA_PARITY_ERR: u32 = 0x01 << 8;
@@ -78,22 +87,6 @@ const PCI_BAR_MASK_MEM_PREFETCHABLE: u32 = 0x00000008;
 const PCI_BAR_MASK_MEM_ADDR: u32 = 0xfffffff0;
 const PCI_BAR_MASK_IO_ADDR: u32 = 0xfffffffc;
 
-const NIC_RCTL_EN: u32 = 1 << 1;
-const NIC_RCTL_SBP: u32 = 1 << 2;
-const NIC_RCTL_UPE: u32 = 1 << 3;
-const NIC_RCTL_MPE: u32 = 1 << 4;
-const NIC_RCTL_LPE: u32 = 1 << 5;
-const NIC_RCTL_BAM: u32 = 1 << 15;
-const NIC_RCTL_BSIZE_1024B: u32 = 0b01 << 16;
-const PACKET_RBSIZE_BIT: u32 = 0b01 << 16;
-
-const NIC_TCTL_EN: u32 = 1 << 1;
-const NIC_TCTL_PSP: u32 = 1 << 3;
-const NIC_TCTL_CT_SHIFT: u32 = 4;
-const NIC_TCTL_COLD_SHIFT: u32 = 12;
-const NIC_TCTL_SWXOFF: u32 = 1 << 22;
-const NIC_TCTL_RTLC: u32 = 1 << 24;
-const NIC_TCTL_NRTU: u32 = 1 << 25;
 
 
 const NIC_RDESC_STAT_DD: u8 = 1 << 0;
@@ -104,6 +97,19 @@ const NIC_RDESC_STAT_TCPCS: u8 = 1 << 5;
 const NIC_RDESC_STAT_IPCS: u8 = 1 << 6;
 const NIC_RDESC_STAT_PIF: u8 = 1 << 7;
 
+const NIC_RDESC_ERR_TCPE: u8 = 1 << 5;
+const NIC_RDESC_ERR_IPE: u8 = 1 << 6;
+
+// 拡張データディスクリプタのDTYP(lengthの上位バイトの上位4bit)
+const NIC_TDESC_DTYP_DATA: u8 = 0b0001 << 4;
+const NIC_TDESC_POPTS_IXSM: u8 = 1 << 0;
+const NIC_TDESC_POPTS_TXSM: u8 = 1 << 1;
+
+// コンテキストディスクリプタのTUCMD
+const NIC_TCTX_TUCMD_TCP: u8 = 1 << 0;
+const NIC_TCTX_TUCMD_IP: u8 = 1 << 1;
+const NIC_TCTX_TUCMD_DEXT: u8 = 1 << 5;
+
 const NIC_TDESC_CMD_EOP: u8 = 1 << 0;
 const NIC_TDESC_CMD_IFCS: u8 = 1 << 1;
 const NIC_TDESC_CMD_IC: u8 = 1 << 2;
@@ -173,12 +179,68 @@ impl PciConfiguration {
     }
 }
 
-struct BaseAddressRegister(u32);
+// BARが指している領域
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum BarRegion {
+    Mmio { base: u64, size: u64, prefetchable: bool },
+    Io { port: u32, size: u32 },
+}
+
+pub struct BaseAddressRegister(u32);
 impl BaseAddressRegister {
     fn new(addr: i32) -> Self {
         BaseAddressRegister(addr as u32)
     }
 
+    // 全bitに1を書き込んで読み返し、デコードされないbitからサイズを求める
+    // 64bitのBARは次のBARと合わせて1つの領域になるので、使ったBARの数も返す
+    // 調べている間はデバイスがおかしなアドレスに反応しないように、IO/メモリ空間のデコードを止める
+    // bar_numはヘッダタイプごとのBARの数(ブリッジの場合はBARの後ろがバス番号のレジスタになっている)
+    pub fn probe(bus: u8, device: u8, function: u8, idx: usize, bar_num: usize) -> (Option<BarRegion>, usize) {
+        let reg = PCI_CONF_BAR + (idx as u8) * 4;
+        let original = get_pci_conf_reg(bus, device, function, reg) as u32;
+        let bar = BaseAddressRegister(original);
+        let is_64bit = bar.memory_64bit_type() && idx + 1 < bar_num;
+        let used = if is_64bit { 2 } else { 1 };
+
+        let command = get_pci_conf_reg(bus, device, function, PCI_CONF_STATUS_COMMAND) as u32 & 0x0000ffff;
+        set_pci_conf_reg(bus, device, function, PCI_CONF_STATUS_COMMAND, command & !(PCI_COM_IO_EN | PCI_COM_MEM_EN));
+
+        set_pci_conf_reg(bus, device, function, reg, 0xffffffff);
+        let size_low = get_pci_conf_reg(bus, device, function, reg) as u32;
+        set_pci_conf_reg(bus, device, function, reg, original);
+
+        let (original_high, size_high) = if is_64bit {
+            let original_high = get_pci_conf_reg(bus, device, function, reg + 4) as u32;
+            set_pci_conf_reg(bus, device, function, reg + 4, 0xffffffff);
+            let size_high = get_pci_conf_reg(bus, device, function, reg + 4) as u32;
+            set_pci_conf_reg(bus, device, function, reg + 4, original_high);
+            (original_high, size_high)
+        } else {
+            (0, 0)
+        };
+
+        set_pci_conf_reg(bus, device, function, PCI_CONF_STATUS_COMMAND, command);
+
+        // 1を書き込んでも0のままのBARは使われていない
+        if size_low == 0 && size_high == 0 { return (None, used); }
+
+        if bar.io_address() {
+            // IO空間は上位16bitが実装されていないことがある
+            let mask = size_low & PCI_BAR_MASK_IO_ADDR & 0x0000ffff;
+            if mask == 0 { return (None, used); }
+            let size = (!mask & 0x0000ffff) + 1;
+            return (Some(BarRegion::Io { port: bar.base_addr(), size }), used);
+        }
+
+        let mask = (size_high as u64) << 32 | (size_low & PCI_BAR_MASK_MEM_ADDR) as u64;
+        let mask = if is_64bit { mask } else { mask | 0xffffffff_00000000 };
+        let size = (!mask).wrapping_add(1);
+        if size == 0 { return (None, used); }
+        let base = (original_high as u64) << 32 | (original & PCI_BAR_MASK_MEM_ADDR) as u64;
+        (Some(BarRegion::Mmio { base, size, prefetchable: bar.prefetchable() }), used)
+    }
+
     fn prefetchable(&self) -> bool {
         self.0 & 0x08 > 0
     }
@@ -204,9 +266,9 @@ impl BaseAddressRegister {
 
     fn base_addr(&self) -> u32 {
         if self.io_address() {
-            return self.0 & 0xfffffffc;
+            return self.0 & PCI_BAR_MASK_IO_ADDR;
         }
-        self.0 & 0xfffffff8
+        self.0 & PCI_BAR_MASK_MEM_ADDR
     }
 }
 
@@ -220,221 +282,439 @@ pub fn set_pci_conf_reg(nic_bus_num: u8, nic_dev_num: u8, nic_fn_num: u8, reg: u
     io_out32(CONFIG_DATA, val);
 }
 
-pub fn get_nic_reg_base() -> u32 {
-    BaseAddressRegister::new(get_pci_conf_reg(NIC_BUS_NUM, NIC_DEV_NUM, NIC_FN_NUM, PCI_CONF_BAR)).base_addr()
-}
-
-pub fn get_nic_reg(reg: u16) -> u32 {
-    let mut base_addr = get_nic_reg_base();
-    // let mut pointer = unsafe { *(&mut base_addr as *mut u32).offset(0 as isize) as *mut u32 as u32 };
-    // let mut printer = Printer::new(900, 305, 0);
-    // write!(printer, "{:?}", unsafe { *(*&mut pointer as *mut u32) as *mut u32 }).unwrap(); // 0xffef3 => 0xfebc0000
-    unsafe { *(*&mut (base_addr + reg as u32) as *mut u32) }
-}
-
-pub fn set_nic_reg(reg: u16, val: u32) {
-    let mut base_addr = get_nic_reg_base();
-    unsafe { *(*&mut (base_addr + reg as u32) as *mut u32) = val };
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct PciDevice {
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+    pub vendor_id: u16,
+    pub device_id: u16,
+    pub class_code: u8,
+    pub subclass: u8,
+    pub prog_if: u8,
+    pub revision: u8,
+    pub header_type: u8, // multi-functionのbitは除いたもの
+    pub multi_function: bool,
+    pub bars: [u32; PCI_BAR_NUM],
+    pub bar_num: usize, // ヘッダタイプごとのBARの数(0: 6個, 1: 2個, 2: 0個)
+    // サイズを調べたBAR。64bitのBARの上位側・使われていないBAR・画面に使っている表示デバイスのBARはNone
+    pub bar_regions: [Option<BarRegion>; PCI_BAR_NUM],
+    pub interrupt_line: u8,
+    pub interrupt_pin: u8, // 0なら割り込みを使わない、1~4がINTA#~INTD#
+    pub secondary_bus: Option<u8>, // PCI-PCIブリッジの場合の下流のバス
 }
 
-pub fn dump_vid_did() {
-    let conf_data: i32 = get_pci_conf_reg(NIC_BUS_NUM, NIC_DEV_NUM, NIC_FN_NUM, PCI_CONF_DID_VID);
-    let vendor_id: u16 = (conf_data & 0x0000ffff) as u16;
-    let device_id: u16 = (conf_data >> 16) as u16;
-    let mut printer = Printer::new(10, 200, 0);
-    write!(printer, "{:x}", vendor_id).unwrap();
-
-    let mut printer = Printer::new(10, 215, 0);
-    write!(printer, "{:x}", device_id).unwrap();
-}
+impl PciDevice {
+    fn read(bus: u8, device: u8, function: u8) -> Option<PciDevice> {
+        let did_vid = get_pci_conf_reg(bus, device, function, PCI_CONF_DID_VID) as u32;
+        let vendor_id = (did_vid & 0x0000ffff) as u16;
+        if vendor_id == PCI_VENDOR_NONE { return None; }
+        let raw_header_type = (get_pci_conf_reg(bus, device, function, PCI_CONF_HEADER_TYPE) as u32 >> 16) as u8;
+        let header_type = raw_header_type & PCI_HEADER_TYPE_MASK;
+        let bar_num = match header_type {
+            PCI_HEADER_TYPE_NORMAL => 6,
+            PCI_HEADER_TYPE_BRIDGE => 2,
+            _ => 0,
+        };
+        let mut bars = [0 as u32; PCI_BAR_NUM];
+        for idx in 0..bar_num {
+            bars[idx] = get_pci_conf_reg(bus, device, function, PCI_CONF_BAR + (idx as u8) * 4) as u32;
+        }
+        let mut bar_regions: [Option<BarRegion>; PCI_BAR_NUM] = [None; PCI_BAR_NUM];
+        let class_rev = get_pci_conf_reg(bus, device, function, PCI_CONF_CLASS_REV) as u32;
+        let command = get_pci_conf_reg(bus, device, function, PCI_CONF_STATUS_COMMAND) as u32;
+        // 画面のフレームバッファ(VBEのVRAM)は表示デバイスのBARにあるので、使っている表示デバイスはデコードを止めずにサイズを調べない
+        let decodes_framebuffer = (class_rev >> 24) as u8 == PCI_CLASS_DISPLAY && command & PCI_COM_MEM_EN != 0;
+        let mut idx = 0;
+        while idx < bar_num && !decodes_framebuffer {
+            let (region, used) = BaseAddressRegister::probe(bus, device, function, idx, bar_num);
+            bar_regions[idx] = region;
+            idx += used;
+        }
+        let secondary_bus = if header_type == PCI_HEADER_TYPE_BRIDGE {
+            Some((get_pci_conf_reg(bus, device, function, PCI_CONF_BRIDGE_BUS_NUM) as u32 >> 8) as u8)
+        } else {
+            None
+        };
+        let intr = get_pci_conf_reg(bus, device, function, PCI_CONF_INTR) as u32;
+        Some(PciDevice {
+            bus,
+            device,
+            function,
+            vendor_id,
+            device_id: (did_vid >> 16) as u16,
+            class_code: (class_rev >> 24) as u8,
+            subclass: (class_rev >> 16) as u8,
+            prog_if: (class_rev >> 8) as u8,
+            revision: class_rev as u8,
+            header_type,
+            multi_function: raw_header_type & PCI_HEADER_TYPE_MULTI_FUNC == PCI_HEADER_TYPE_MULTI_FUNC,
+            bars,
+            bar_num,
+            bar_regions,
+            interrupt_line: intr as u8,
+            interrupt_pin: (intr >> 8) as u8,
+            secondary_bus,
+        })
+    }
 
-pub fn dump_command_status() {
-    let conf_data: i32 = get_pci_conf_reg(NIC_BUS_NUM, NIC_DEV_NUM, NIC_FN_NUM, PCI_CONF_STATUS_COMMAND);
+    pub fn read_config(&self, reg: u8) -> u32 {
+        get_pci_conf_reg(self.bus, self.device, self.function, reg) as u32
+    }
 
-    let command: u32 = (conf_data & 0x0000ffff) as u32;
-    let status: u32 = (conf_data >> 16) as u32;
+    pub fn write_config(&self, reg: u8, val: u32) {
+        set_pci_conf_reg(self.bus, self.device, self.function, reg, val);
+    }
 
-    let mut height: u32 = 215;
-    if command & PCI_COM_IO_EN > 0 {
-        print_str(400, height, "IO_EN", 0);
-        height += 15;
+    pub fn read_config16(&self, reg: u8) -> u16 {
+        (self.read_config(reg & !0x03) >> ((reg & 0x02) * 8)) as u16
     }
-    if command & PCI_COM_MEM_EN > 0 {
-        print_str(400, height, "MEM_EN", 0);
-        height += 15;
+
+    pub fn read_config8(&self, reg: u8) -> u8 {
+        (self.read_config(reg & !0x03) >> ((reg & 0x03) * 8)) as u8
     }
-    if command & PCI_COM_BUS_MASTER_EN > 0 {
-        print_str(400, height, "BUS_MASTER_EN", 0);
-        height += 15;
+
+    pub fn command(&self) -> u16 {
+        self.read_config16(PCI_CONF_STATUS_COMMAND)
     }
-    if command & PCI_COM_SPECIAL_CYCLE > 0 {
-        print_str(400, height, "SPECIAL_CYCLE", 0);
-        height += 15;
+
+    pub fn status(&self) -> u16 {
+        self.read_config16(PCI_CONF_STATUS_COMMAND + 2)
     }
-    if command & PCI_COM_MEMV_INV_EN > 0 {
-        print_str(400, height, "MEMW_INV_EN", 0);
-        height += 15;
+
+    // statusの書き込みは1を書いたbitがクリアされるので、commandだけを書き換える
+    pub fn set_command(&self, command: u16) {
+        self.write_config(PCI_CONF_STATUS_COMMAND, command as u32);
     }
-    if command & PCI_COM_VGA_PAL_SNP > 0 {
-        print_str(400, height, "VGA_PAL_SNP", 0);
-        height += 15;
+
+    pub fn enable_bus_master(&self) {
+        self.set_command(self.command() | PCI_COM_BUS_MASTER_EN as u16);
     }
-    if command & PCI_COM_PARITY_ERR_RES > 0 {
-        print_str(400, height, "PARITY_ERR_RES", 0);
-        height += 15;
+
+    // デバイスからのDMAを止める(ドライバが確保した領域を返す前に呼ぶ)
+    pub fn disable_bus_master(&self) {
+        self.set_command(self.command() & !(PCI_COM_BUS_MASTER_EN as u16));
     }
-    if command & PCI_COM_SERR_EN > 0 {
-        print_str(400, height, "SERR_EN", 0);
-        height += 15;
+
+    pub fn enable_memory_space(&self) {
+        self.set_command(self.command() | PCI_COM_MEM_EN as u16);
     }
-    if command & PCI_COM_FAST_BACK2BACK_EN > 0 {
-        print_str(400, height, "FAST_BACK2BACK_EN", 0);
-        height += 15;
+
+    pub fn enable_io_space(&self) {
+        self.set_command(self.command() | PCI_COM_IO_EN as u16);
     }
-    if command & PCI_COM_INTR_DIS > 0 {
-        print_str(400, height, "INTR_DIS", 0);
-        height += 15;
+
+    // INTx#による割り込みを止める(MSIを使う場合など)
+    pub fn disable_intx(&self) {
+        self.set_command(self.command() | PCI_COM_INTR_DIS as u16);
     }
 
-    let mut height: u32 = 215;
-    if status & PCI_STAT_INTR > 0 {
-        print_str(500, height, "INTR", 0);
-        height += 15;
+    pub fn bar(&self, idx: usize) -> Option<BarRegion> {
+        if idx >= self.bar_num { return None; }
+        self.bar_regions[idx]
     }
-    if status & PCI_STAT_MULT_FUNC > 0 {
-        print_str(500, height, "MULT_FUNC", 0);
-        height += 15;
+
+    // MMIOのBARをキャッシュ無効でマッピングする
+    pub fn map_bar(&self, idx: usize) -> Result<MmioRegion, String> {
+        match self.bar(idx) {
+            Some(BarRegion::Mmio { base, size, .. }) => MmioRegion::map(base, size, "pci bar"),
+            Some(BarRegion::Io { .. }) => Err("bar is io space.".to_owned()),
+            None => Err("bar is not implemented.".to_owned()),
+        }
     }
-    if status & PCI_STAT_66MHZ > 0 {
-        print_str(500, height, "66MHZ", 0);
-        height += 15;
+
+    // (interrupt line, interrupt pin)。割り込みを使わないデバイスはNone
+    pub fn interrupt(&self) -> Option<(u8, u8)> {
+        if self.interrupt_pin == 0 { return None; }
+        Some((self.interrupt_line, self.interrupt_pin))
     }
-    if status & PCI_STAT_FAST_BACK2BACK > 0 {
-        print_str(500, height, "FAST_BACK2BACK", 0);
-        height += 15;
+
+    pub fn is_pci_bridge(&self) -> bool {
+        self.class_code == PCI_CLASS_BRIDGE && self.subclass == PCI_SUBCLASS_PCI_BRIDGE
     }
-    if status & PCI_STAT_DATA_PARITY_ERR > 0 {
-        print_str(500, height, "DATA_PARITY_ERR", 0);
-        height += 15;
+
+    fn same_location(&self, other: &PciDevice) -> bool {
+        self.bus == other.bus && self.device == other.device && self.function == other.function
     }
-    if (status as u32 & PCI_STAT_DEVSEL_MASK) == PCI_STAT_DEVSEL_FAST {
-        print_str(500, height, "DEVSEL_FAST", 0);
-        height += 15;
+}
+
+// ドライバが対応するデバイスの条件
+#[derive(Copy, Clone, Debug)]
+pub enum PciMatch {
+    Id { vendor_id: u16, device_id: u16 },
+    // prog_ifがNoneならprog_ifは問わない
+    Class { class_code: u8, subclass: u8, prog_if: Option<u8> },
+}
+
+impl PciMatch {
+    fn matches(&self, device: &PciDevice) -> bool {
+        match *self {
+            PciMatch::Id { vendor_id, device_id } => device.vendor_id == vendor_id && device.device_id == device_id,
+            PciMatch::Class { class_code, subclass, prog_if } => {
+                device.class_code == class_code && device.subclass == subclass
+                    && prog_if.map_or(true, |prog_if| device.prog_if == prog_if)
+            },
+        }
     }
-    if (status as u32 & PCI_STAT_DEVSEL_MASK) == PCI_STAT_DEVSEL_MID {
-        print_str(500, height, "DEVSEL_MID", 0);
-        height += 15;
+}
+
+pub struct PciDriver {
+    pub name: &'static str,
+    pub matches: &'static [PciMatch],
+    // デバイスを使えるようにする。Errならそのデバイスには結びつけない
+    pub probe: fn(&PciDevice) -> Result<(), String>,
+    pub remove: Option<fn(&PciDevice)>,
+}
+
+impl PciDriver {
+    fn matches(&self, device: &PciDevice) -> bool {
+        self.matches.iter().any(|m| m.matches(device))
     }
-    if (status as u32 & PCI_STAT_DEVSEL_MASK) == PCI_STAT_DEVSEL_LOW {
-        print_str(500, height, "DEVSEL_LOW", 0);
-        height += 15;
+}
+
+lazy_static! {
+    static ref PCI_DEVICES: Mutex<Vec<PciDevice>> = Mutex::new(vec![]);
+    static ref PCI_DRIVERS: Mutex<Vec<&'static PciDriver>> = Mutex::new(vec![]);
+    // ドライバが結びついているデバイス
+    static ref PCI_BINDINGS: Mutex<Vec<(PciDevice, &'static PciDriver)>> = Mutex::new(vec![]);
+}
+
+fn scan_function(bus: u8, device: u8, function: u8, visited: &mut [bool; PCI_BUS_NUM], devices: &mut Vec<PciDevice>) -> Option<PciDevice> {
+    let pci_device = PciDevice::read(bus, device, function)?;
+    devices.push(pci_device);
+    // ブリッジの先のバスも調べる
+    if let Some(secondary_bus) = pci_device.secondary_bus {
+        if pci_device.is_pci_bridge() {
+            scan_bus(secondary_bus, visited, devices);
+        }
     }
+    Some(pci_device)
+}
 
-    if status & PCI_STAT_SND_TARGET_ABORT > 0 {
-        print_str(500, height, "SND_TARGET_ABORT", 0);
-        height += 15;
+fn scan_bus(bus: u8, visited: &mut [bool; PCI_BUS_NUM], devices: &mut Vec<PciDevice>) {
+    // ブリッジの設定がおかしくても同じバスを2回調べないようにする
+    if visited[bus as usize] { return; }
+    visited[bus as usize] = true;
+    for device in 0..PCI_DEV_NUM {
+        let first = match scan_function(bus, device, 0, visited, devices) {
+            Some(first) => first,
+            None => continue,
+        };
+        if !first.multi_function { continue; }
+        for function in 1..PCI_FN_NUM {
+            scan_function(bus, device, function, visited, devices);
+        }
     }
-    if status & PCI_STAT_RCV_TARGET_ABORT > 0 {
-        print_str(500, height, "RCV_TARGET_ABORT", 0);
-        height += 15;
+}
+
+// 全てのバス・デバイス・ファンクションを調べてデバイスの一覧を作る
+pub fn scan_pci_bus() {
+    let mut visited = [false; PCI_BUS_NUM];
+    let mut devices: Vec<PciDevice> = vec![];
+    match PciDevice::read(0, 0, 0) {
+        // 00:00.0がmulti-functionならファンクションごとに別のホストブリッジで、ファンクション番号がバス番号になる
+        Some(host_bridge) if host_bridge.multi_function => {
+            for function in 0..PCI_FN_NUM {
+                if PciDevice::read(0, 0, function).is_none() { continue; }
+                scan_bus(function, &mut visited, &mut devices);
+            }
+        },
+        _ => scan_bus(0, &mut visited, &mut devices),
     }
-    if status & PCI_STAT_RCV_MASTER_ABORT > 0 {
-        print_str(500, height, "RCV_MASTER_ABORT", 0);
-        height += 15;
+    *PCI_DEVICES.lock() = devices;
+}
+
+pub fn get_pci_devices() -> Vec<PciDevice> {
+    PCI_DEVICES.lock().clone()
+}
+
+pub fn find_pci_device(vendor_id: u16, device_id: u16) -> Option<PciDevice> {
+    PCI_DEVICES.lock().iter().find(|d| d.vendor_id == vendor_id && d.device_id == device_id).map(|d| *d)
+}
+
+pub fn find_pci_device_by_class(class_code: u8, subclass: u8) -> Option<PciDevice> {
+    PCI_DEVICES.lock().iter().find(|d| d.class_code == class_code && d.subclass == subclass).map(|d| *d)
+}
+
+pub fn register_pci_driver(driver: &'static PciDriver) {
+    PCI_DRIVERS.lock().push(driver);
+}
+
+pub fn get_bound_driver(device: &PciDevice) -> Option<&'static PciDriver> {
+    PCI_BINDINGS.lock().iter().find(|(d, _)| d.same_location(device)).map(|(_, driver)| *driver)
+}
+
+// まだドライバが結びついていないデバイスに、対応する最初のドライバを結びつける
+// probeが失敗した場合は次のドライバを試す
+pub fn bind_pci_drivers() {
+    let drivers = PCI_DRIVERS.lock().clone();
+    for device in get_pci_devices().iter() {
+        if get_bound_driver(device).is_some() { continue; }
+        for driver in drivers.iter().filter(|driver| driver.matches(device)) {
+            if (driver.probe)(device).is_ok() {
+                PCI_BINDINGS.lock().push((*device, *driver));
+                break;
+            }
+        }
     }
-    if status & PCI_STAT_SYS_ERR > 0 {
-        print_str(500, height, "SYS_ERR", 0);
-        height += 15;
+}
+
+pub fn unbind_pci_device(device: &PciDevice) -> Result<(), String> {
+    let binding = {
+        let mut bindings = PCI_BINDINGS.lock();
+        match bindings.iter().position(|(d, _)| d.same_location(device)) {
+            Some(idx) => bindings.remove(idx),
+            None => return Err("no driver is bound to the device.".to_owned()),
+        }
+    };
+    if let Some(remove) = binding.1.remove {
+        remove(&binding.0);
     }
-    if status & PCI_STAT_PARITY_ERR > 0 {
-        print_str(500, height, "PARITY_ERR", 0);
-        height += 15;
+    Ok(())
+}
+
+#[derive(Copy, Clone)]
+struct NicBinding {
+    device: PciDevice,
+    regs: MmioRegion, // マッピングしたBAR0
+}
+
+lazy_static! {
+    // e1000ドライバがprobeしたデバイス
+    static ref NIC_DEVICE: Mutex<Option<NicBinding>> = Mutex::new(None);
+}
+
+// BAR0のレジスタ領域をマッピングしてNICとして使う
+pub fn attach_nic_device(device: PciDevice) -> Result<(), String> {
+    let regs = device.map_bar(0)?;
+    *NIC_DEVICE.lock() = Some(NicBinding { device, regs });
+    Ok(())
+}
+
+pub fn detach_nic_device() {
+    *NIC_DEVICE.lock() = None;
+}
+
+pub fn is_nic_attached() -> bool {
+    NIC_DEVICE.lock().is_some()
+}
+
+// BAR0のレジスタが読めるか確かめる。マッピングが正しくなくてフォルトした場合や、デバイスが応答しない(全て1)場合はErr
+pub fn probe_nic_regs() -> Result<(), String> {
+    match nic_regs().probe_read32(regs::STATUS.offset())? {
+        0xffffffff => Err("e1000 does not respond.".to_owned()),
+        _ => Ok(()),
     }
 }
 
+fn nic_binding() -> NicBinding {
+    NIC_DEVICE.lock().expect("e1000 is not bound.")
+}
+
+fn nic_device() -> PciDevice {
+    nic_binding().device
+}
+
+fn get_nic_conf_reg(reg: u8) -> i32 {
+    nic_device().read_config(reg) as i32
+}
+
+fn set_nic_conf_reg(reg: u8, val: u32) {
+    nic_device().write_config(reg, val);
+}
+
+// NICのレジスタ(e1000_regsの型付きのレジスタで読み書きする)
+pub fn nic_regs() -> MmioRegion {
+    nic_binding().regs
+}
+
+pub fn get_nic_vendor_device_id() -> (u16, u16) {
+    let conf_data: i32 = get_nic_conf_reg(PCI_CONF_DID_VID);
+    ((conf_data & 0x0000ffff) as u16, (conf_data >> 16) as u16)
+}
+
+const PCI_COMMAND_FLAGS: [(u32, &str); 10] = [
+    (PCI_COM_IO_EN, "IO_EN"),
+    (PCI_COM_MEM_EN, "MEM_EN"),
+    (PCI_COM_BUS_MASTER_EN, "BUS_MASTER_EN"),
+    (PCI_COM_SPECIAL_CYCLE, "SPECIAL_CYCLE"),
+    (PCI_COM_MEMV_INV_EN, "MEMW_INV_EN"),
+    (PCI_COM_VGA_PAL_SNP, "VGA_PAL_SNP"),
+    (PCI_COM_PARITY_ERR_RES, "PARITY_ERR_RES"),
+    (PCI_COM_SERR_EN, "SERR_EN"),
+    (PCI_COM_FAST_BACK2BACK_EN, "FAST_BACK2BACK_EN"),
+    (PCI_COM_INTR_DIS, "INTR_DIS"),
+];
+
+const PCI_STATUS_FLAGS: [(u32, &str); 10] = [
+    (PCI_STAT_INTR, "INTR"),
+    (PCI_STAT_CAP_LIST, "CAP_LIST"),
+    (PCI_STAT_66MHZ, "66MHZ"),
+    (PCI_STAT_FAST_BACK2BACK, "FAST_BACK2BACK"),
+    (PCI_STAT_DATA_PARITY_ERR, "DATA_PARITY_ERR"),
+    (PCI_STAT_SND_TARGET_ABORT, "SND_TARGET_ABORT"),
+    (PCI_STAT_RCV_TARGET_ABORT, "RCV_TARGET_ABORT"),
+    (PCI_STAT_RCV_MASTER_ABORT, "RCV_MASTER_ABORT"),
+    (PCI_STAT_SYS_ERR, "SYS_ERR"),
+    (PCI_STAT_PARITY_ERR, "PARITY_ERR"),
+];
+
+// commandレジスタで立っているbitの名前
+pub fn command_flag_names(command: u16) -> Vec<&'static str> {
+    PCI_COMMAND_FLAGS.iter().filter(|(bit, _)| command as u32 & bit > 0).map(|(_, name)| *name).collect()
+}
+
+// statusレジスタで立っているbitの名前(DEVSELのタイミングも含む)
+pub fn status_flag_names(status: u16) -> Vec<&'static str> {
+    let mut names: Vec<&'static str> = PCI_STATUS_FLAGS.iter().filter(|(bit, _)| status as u32 & bit > 0).map(|(_, name)| *name).collect();
+    names.push(match status as u32 & PCI_STAT_DEVSEL_MASK {
+        PCI_STAT_DEVSEL_FAST => "DEVSEL_FAST",
+        PCI_STAT_DEVSEL_MID => "DEVSEL_MID",
+        PCI_STAT_DEVSEL_LOW => "DEVSEL_LOW",
+        _ => "DEVSEL_UNKNOWN",
+    });
+    names
+}
+
 pub fn set_pci_intr_disable() {
-    let mut conf_data = get_pci_conf_reg(NIC_BUS_NUM, NIC_DEV_NUM, NIC_FN_NUM, PCI_CONF_STATUS_COMMAND) as u32;
+    let mut conf_data = get_nic_conf_reg(PCI_CONF_STATUS_COMMAND) as u32;
     conf_data = conf_data & 0x0000ffff;
     conf_data = conf_data | PCI_COM_INTR_DIS;
-    set_pci_conf_reg(NIC_BUS_NUM, NIC_DEV_NUM, NIC_FN_NUM, PCI_CONF_STATUS_COMMAND, conf_data);
-    // dump_command_status();
+    set_nic_conf_reg(PCI_CONF_STATUS_COMMAND, conf_data);
 }
 
 pub fn set_bus_master_en() {
-    let mut conf_data = get_pci_conf_reg(NIC_BUS_NUM, NIC_DEV_NUM, NIC_FN_NUM, PCI_CONF_STATUS_COMMAND) as u32;
+    let mut conf_data = get_nic_conf_reg(PCI_CONF_STATUS_COMMAND) as u32;
     conf_data = conf_data & 0x0000ffff;
     conf_data = conf_data | PCI_COM_BUS_MASTER_EN;
-    set_pci_conf_reg(NIC_BUS_NUM, NIC_DEV_NUM, NIC_FN_NUM, PCI_CONF_STATUS_COMMAND, conf_data);
-    // dump_command_status();
-}
-
-pub fn dump_bar() {
-    let bar: BaseAddressRegister = BaseAddressRegister::new(get_pci_conf_reg(NIC_BUS_NUM, NIC_DEV_NUM, NIC_FN_NUM, PCI_CONF_BAR));
-    let mut height = 50;
-    if bar.io_address() {
-        print_str(800, height, "IO BASE", 0);
-        height += 15;
-        let mut printer = Printer::new(800, height, 0);
-        write!(printer, "{:x}", bar.base_addr()).unwrap();
-    } else {
-        if bar.memory_32bit_type() {
-            print_str(800, height, "MEM BASE 32BIT", 0);
-            height += 15;
-            let mut printer = Printer::new(800, height, 0);
-            write!(printer, "{:x}", bar.base_addr()).unwrap();
-        }
-        if bar.memory_1m_type() {
-            print_str(800, height, "MEM BASE 1M", 0);
-            height += 15;
-            let mut printer = Printer::new(800, height, 0);
-            write!(printer, "{:x}", bar.base_addr()).unwrap();
-        }
-        if bar.memory_64bit_type() {
-            let bar_upper = get_pci_conf_reg(NIC_BUS_NUM, NIC_DEV_NUM, NIC_FN_NUM, PCI_CONF_BAR + 4) as u32;
-            print_str(800, height, "MEM BASE 64BIT UPPER", 0);
-            height += 15;
-            let mut printer = Printer::new(800, height, 0);
-            write!(printer, "{:x}", bar_upper).unwrap();
-            print_str(800, height, "MEM BASE 64BIT", 0);
-            height += 15;
-            let mut printer = Printer::new(800, height, 0);
-            write!(printer, "{:x}", bar.base_addr()).unwrap();
-        }
-        if bar.prefetchable() {
-            height += 15;
-            print_str(800, height, "PREFETCHABLE", 0);
-        } else {
-            height += 15;
-            print_str(800, height, "NON PREFETCHABLE", 0);
-        }
-    }
+    set_nic_conf_reg(PCI_CONF_STATUS_COMMAND, conf_data);
 }
 
 pub fn dump_nic_ims() {
-    let ims: u32 = get_nic_reg(NIC_REG_IMS);
+    let ims: u32 = nic_regs().read(regs::IMS);
     let mut printer = Printer::new(900, 200, 0);
     write!(printer, "{:x}", ims).unwrap();
 }
 
 pub fn test_nic_set() {
     dump_nic_ims();
-    set_nic_reg(NIC_REG_IMS, 0x0000beef);
-    let ims: u32 = get_nic_reg(NIC_REG_IMS);
+    nic_regs().write(regs::IMS, 0x0000beef);
+    let ims: u32 = nic_regs().read(regs::IMS);
     let mut printer = Printer::new(900, 215, 0);
     write!(printer, "{:x}", ims).unwrap();
-    set_nic_reg(NIC_REG_IMC, 0xffffffff);
-    let ims: u32 = get_nic_reg(NIC_REG_IMS);
+    nic_regs().write(regs::IMC, 0xffffffff);
+    let ims: u32 = nic_regs().read(regs::IMS);
     let mut printer = Printer::new(900, 230, 0);
     write!(printer, "{:x}", ims).unwrap();
 }
 
 pub fn disable_nic_interrupt() {
-    let mut conf_data: u32 = get_pci_conf_reg(NIC_BUS_NUM, NIC_DEV_NUM, NIC_FN_NUM, PCI_CONF_STATUS_COMMAND) as u32;
+    let mut conf_data: u32 = get_nic_conf_reg(PCI_CONF_STATUS_COMMAND) as u32;
     conf_data = conf_data & 0x0000ffff;
     conf_data = conf_data | PCI_COM_INTR_DIS;
-    set_pci_conf_reg(NIC_BUS_NUM, NIC_DEV_NUM, NIC_FN_NUM, PCI_CONF_STATUS_COMMAND, conf_data);
+    set_nic_conf_reg(PCI_CONF_STATUS_COMMAND, conf_data);
 
-    set_nic_reg(NIC_REG_IMC, 0xffffffff);
+    nic_regs().write(regs::IMC, 0xffffffff);
 }
 
 #[derive(Copy, Clone)]
@@ -450,12 +730,6 @@ impl BufferAddr {
             desc_base_high: 0,
         }
     }
-    const fn new_const() -> Self {
-        BufferAddr {
-            desc_base_low: 0,
-            desc_base_high: 0,
-        }
-    }
     fn low(&self) -> u32 { self.desc_base_low }
     fn high(&self) -> u32 { self.desc_base_high }
 }
@@ -463,7 +737,7 @@ impl BufferAddr {
 #[derive(Copy, Clone)]
 #[repr(align(16), C)]
 struct RxDesc {
-    // イーサネットフレーム用バッファ(RX_BUFFER内のアドレス)のアドレスを指定
+    // イーサネットフレーム用バッファ(RX_BUFFER内)の物理アドレスを指定
     recv_buf_addr: BufferAddr,
     // イーサネットフレーム用バッファの長さ
     length: u16,
@@ -484,16 +758,6 @@ impl RxDesc {
             special: 0,
         }
     }
-    const fn new_const() -> Self {
-        RxDesc {
-            recv_buf_addr: BufferAddr::new_const(),
-            length: 0,
-            packet_checksum: 0,
-            status: 0,
-            errors: 0,
-            special: 0,
-        }
-    }
 }
 
 #[derive(Copy, Clone)]
@@ -520,16 +784,54 @@ impl TxDesc {
             special: 0
         }
     }
-    const fn new_const() -> Self {
-        TxDesc {
-            tx_buf_address: BufferAddr::new_const(),
-            length: 0,
-            cso: 0,
-            cmd: NIC_TDESC_CMD_RS | NIC_TDESC_CMD_EOP,
-            sta_rsv: 0,
-            css: 0,
-            special: 0
+}
+
+// チェックサムオフロードの範囲をNICに伝えるためのディスクリプタ(TxDescと同じ位置に書き込む)
+#[derive(Copy, Clone)]
+#[repr(align(16), C)]
+struct TxContextDesc {
+    ipcss: u8,
+    ipcso: u8,
+    ipcse: u16,
+    tucss: u8,
+    tucso: u8,
+    tucse: u16,
+    paylen: u16,
+    dtyp_paylen: u8, // 上位4bitがDTYP(コンテキストは0)
+    tucmd: u8,
+    sta: u8,
+    hdrlen: u8,
+    mss: u16,
+}
+
+impl TxContextDesc {
+    fn new(offload: &TxOffload) -> Self {
+        let mut desc = TxContextDesc {
+            ipcss: 0,
+            ipcso: 0,
+            ipcse: 0,
+            tucss: 0,
+            tucso: 0,
+            tucse: 0,
+            paylen: 0,
+            dtyp_paylen: 0,
+            tucmd: NIC_TCTX_TUCMD_DEXT,
+            sta: 0,
+            hdrlen: 0,
+            mss: 0,
+        };
+        if let Some(ip) = offload.ip_checksum {
+            desc.ipcss = ip.start;
+            desc.ipcso = ip.offset;
+            desc.ipcse = ip.end;
+            desc.tucmd |= NIC_TCTX_TUCMD_IP;
+        }
+        if let Some(l4) = offload.l4_checksum {
+            desc.tucss = l4.start;
+            desc.tucso = l4.offset;
+            desc.tucse = l4.end;
         }
+        desc
     }
 }
 
@@ -537,15 +839,35 @@ const RXDESC_NUM: usize = 80;
 const TXDESC_NUM: usize = 8; // 128バイトアラインメントがある
 const PACKET_BUFFER_SIZE: u16 = 1024;
 
-// イーサネットフレームを格納するBuffer
-static mut RX_BUFFER: [[u8; PACKET_BUFFER_SIZE as usize]; RXDESC_NUM as usize] = [[0 as u8; PACKET_BUFFER_SIZE as usize]; RXDESC_NUM as usize];
-// static mut TX_BUFFER: [u8; PACKET_BUFFER_SIZE as usize] = [0x0; PACKET_BUFFER_SIZE as usize];
+const fn rx_buffer_size() -> usize { PACKET_BUFFER_SIZE as usize * RXDESC_NUM }
 const fn rx_desc_data_size() -> usize { size_of::<RxDesc>() * RXDESC_NUM }
 const fn tx_desc_data_size() -> usize { size_of::<TxDesc>() * TXDESC_NUM }
 
-// RxDescの配列を格納するリングバッファ
-static mut RX_DESC_DATA: [RxDesc; RXDESC_NUM] = [RxDesc::new_const(); RXDESC_NUM];
-static mut TX_DESC_DATA: [TxDesc; TXDESC_NUM] = [TxDesc::new_const(); TXDESC_NUM];
+// NICと共有する領域はrx_init/tx_initでDmaRegionとして確保する(物理的に連続していて、キャッシュしない)
+// イーサネットフレームを格納するBuffer
+static mut RX_BUFFER: Option<DmaRegion> = None;
+// RxDesc/TxDescの配列を格納するリングバッファ
+static mut RX_DESC_DATA: Option<DmaRegion> = None;
+static mut TX_DESC_DATA: Option<DmaRegion> = None;
+
+// NICがつながっていて、rx_init/tx_initが済んでいるか(NICが無い・probeに失敗した場合は送受信しない)
+fn rx_ready() -> bool {
+    is_nic_attached() && unsafe { RX_DESC_DATA.is_some() }
+}
+
+fn tx_ready() -> bool {
+    is_nic_attached() && unsafe { TX_DESC_DATA.is_some() }
+}
+
+unsafe fn rx_descs() -> &'static mut [RxDesc] {
+    let region = RX_DESC_DATA.as_ref().expect("e1000 rx is not initialized.");
+    slice::from_raw_parts_mut(region.as_ptr::<RxDesc>(), RXDESC_NUM)
+}
+
+unsafe fn tx_descs() -> &'static mut [TxDesc] {
+    let region = TX_DESC_DATA.as_ref().expect("e1000 tx is not initialized.");
+    slice::from_raw_parts_mut(region.as_ptr::<TxDesc>(), TXDESC_NUM)
+}
 
 // RX_DESC_DATAのベースアドレス
 // static mut RX_DESC_DATA_BASE: Option<*mut RxDesc> = None;
@@ -557,77 +879,99 @@ lazy_static! {
 }
 
 
-pub fn rx_init() {
-    let mut printer = Printer::new(700, 55, 0);
-    write!(printer, "{:?}", unsafe { &mut RX_DESC_DATA as *mut [RxDesc; 80] }).unwrap();
-
-    let mut rx_desc_data_for_initialize: [RxDesc; RXDESC_NUM] = [RxDesc::new(); RXDESC_NUM];
-    // recv_buf_addrのアドレスを指定する作業
-    for (idx, cur_rxdesc) in unsafe { RX_DESC_DATA.iter_mut().enumerate() } {
-        (*cur_rxdesc).recv_buf_addr.desc_base_low = unsafe { transmute::<*mut &[u8], u32>(&mut RX_BUFFER[idx] as *const _ as *mut &[u8]) };
-        rx_desc_data_for_initialize[idx] = *cur_rxdesc;
+pub fn rx_init() -> Result<(), String> {
+    let rx_buffer = DmaRegion::allocate(rx_buffer_size(), &DmaConstraints::DEFAULT)?;
+    let rx_desc_data = DmaRegion::allocate(rx_desc_data_size(), &DmaConstraints::DEFAULT)?;
+    let rx_desc_phys_address = rx_desc_data.phys_address();
+    unsafe {
+        // NICにはrecv_buf_addrに物理アドレスを渡す
+        let descs = slice::from_raw_parts_mut(rx_desc_data.as_ptr::<RxDesc>(), RXDESC_NUM);
+        for (idx, cur_rxdesc) in descs.iter_mut().enumerate() {
+            let mut desc = RxDesc::new();
+            desc.recv_buf_addr.desc_base_low = rx_buffer.phys_address() + (idx * PACKET_BUFFER_SIZE as usize) as u32;
+            write_mem!(cur_rxdesc as *mut RxDesc, desc);
+        }
+        RX_BUFFER = Some(rx_buffer);
+        RX_DESC_DATA = Some(rx_desc_data);
     }
-
-    unsafe { RX_DESC_DATA = rx_desc_data_for_initialize; }
+    *CURRENT_RX_IDX.lock() = 0;
 
     let mut printer = Printer::new(700, 70, 0);
-    write!(printer, "{:?}", unsafe { &mut RX_DESC_DATA as *mut [RxDesc; 80] }).unwrap();
+    write!(printer, "{:x}", unsafe { rx_descs().as_ptr() as u32 }).unwrap();
     let mut printer = Printer::new(700, 100, 0);
-    write!(printer, "{:?}", unsafe { &mut RX_DESC_DATA[0] as *mut RxDesc }).unwrap();
+    write!(printer, "{:x}", rx_desc_phys_address).unwrap();
     let mut printer = Printer::new(700, 115, 0);
-    write!(printer, "{:?}", unsafe { &mut RX_BUFFER as *const _ }).unwrap();
+    write!(printer, "{:x}", unsafe { RX_BUFFER.as_ref().map_or(0, |b| b.vir_address()) }).unwrap();
     let mut printer = Printer::new(700, 130, 0);
-    write!(printer, "{:x}", unsafe { RX_DESC_DATA[0].recv_buf_addr.low() }).unwrap();
+    write!(printer, "{:x}", unsafe { rx_descs()[0].recv_buf_addr.low() }).unwrap();
     let mut printer = Printer::new(700, 145, 0);
-    write!(printer, "{:?}", unsafe { RX_DESC_DATA[0].recv_buf_addr.high() }).unwrap();
+    write!(printer, "{:?}", unsafe { rx_descs()[0].recv_buf_addr.high() }).unwrap();
 
     /* rxdescの先頭アドレスとサイズをNICレジスタへ設定 */
-    // set_nic_reg(NIC_REG_RDBAH, unsafe { RX_DESC_DATA.unwrap()[0].recv_buf_addr.desc_base_high });
-    // set_nic_reg(NIC_REG_RDBAL, unsafe { RX_DESC_DATA.unwrap()[0].recv_buf_addr.desc_base_low });
-    set_nic_reg(NIC_REG_RDBAH, 0x00);
-    set_nic_reg(NIC_REG_RDBAL, unsafe { &mut RX_DESC_DATA as *mut [RxDesc; RXDESC_NUM] as u32 });
-    set_nic_reg(NIC_REG_RDLEN, rx_desc_data_size() as u32 ); // 1280 = 0x500
+    nic_regs().write(regs::RDBAH, 0x00);
+    nic_regs().write(regs::RDBAL, rx_desc_phys_address);
+    nic_regs().write(regs::RDLEN, rx_desc_data_size() as u32 ); // 1280 = 0x500
 
-    set_nic_reg(NIC_REG_RDH, unsafe { *CURRENT_RX_IDX.lock() as u32 }); // 0
-    set_nic_reg(NIC_REG_RDT, (RXDESC_NUM - 1) as u32); // 79 = 0x4f
+    nic_regs().write(regs::RDH, unsafe { *CURRENT_RX_IDX.lock() as u32 }); // 0
+    nic_regs().write(regs::RDT, (RXDESC_NUM - 1) as u32); // 79 = 0x4f
 
     /* NICの受信動作設定 */
-    // 0b01 << 16 | 1 << 15 | 1 << 4 | 1 << 3 | 1 << 2 | 1 << 1
-    // 0b1_1000_0000_0001_1110 = 0x1801e
-    set_nic_reg(NIC_REG_RCTL, (PACKET_RBSIZE_BIT | NIC_RCTL_BAM | NIC_RCTL_MPE | NIC_RCTL_UPE | NIC_RCTL_SBP | NIC_RCTL_EN) as u32);
+    // 0b01 << 16 | 1 << 15 | 1 << 3 | 1 << 2 | 1 << 1
+    // 0b1_1000_0000_0000_1110 = 0x1800e
+    // マルチキャストはMTAに登録したグループだけを受信する(MPEは立てない)
+    nic_regs().write(regs::RCTL, Rctl::default().set_bsize(RCTL_BSIZE_1024B).set_bam(1).set_upe(1).set_sbp(1).set_en(1));
     dump_nic_reg_for_net();
+    Ok(())
 }
 
 pub fn dump_nic_reg_for_net() {
-    let rdbah = get_nic_reg(NIC_REG_RDBAH);
+    let rdbah = nic_regs().read(regs::RDBAH);
     let mut printer = Printer::new(700, 175, 0);
     write!(printer, "{:?}", rdbah).unwrap();
 
-    let rdbal = get_nic_reg(NIC_REG_RDBAL);
+    let rdbal = nic_regs().read(regs::RDBAL);
     let mut printer = Printer::new(700, 190, 0);
     write!(printer, "{:x}", rdbal).unwrap();
 
-    let rdlen = get_nic_reg(NIC_REG_RDLEN);
+    let rdlen = nic_regs().read(regs::RDLEN);
     let mut printer = Printer::new(700, 205, 0);
     write!(printer, "{:x}", rdlen).unwrap();
 
-    let rdh = get_nic_reg(NIC_REG_RDH);
+    let rdh = nic_regs().read(regs::RDH);
     let mut printer = Printer::new(700, 220, 0);
     write!(printer, "{:x}", rdh).unwrap();
 
-    let rdt = get_nic_reg(NIC_REG_RDT);
+    let rdt = nic_regs().read(regs::RDT);
     let mut printer = Printer::new(700, 235, 0);
     write!(printer, "{:x}", rdt).unwrap();
 
-    let rctl = get_nic_reg(NIC_REG_RCTL);
+    let rctl = nic_regs().read(regs::RCTL).0;
     let mut printer = Printer::new(700, 250, 0);
     write!(printer, "{:x}", rctl).unwrap();
 }
 
 pub fn receive_frame() -> Vec<u8> {
+    receive_rx_frame().data
+}
+
+fn decode_checksum(status: u8, errors: u8, calculated: u8, error: u8) -> HwChecksum {
+    if status & NIC_RDESC_STAT_IXSM == NIC_RDESC_STAT_IXSM || status & calculated != calculated {
+        HwChecksum::Unchecked
+    } else if errors & error == error {
+        HwChecksum::Bad
+    } else {
+        HwChecksum::Good
+    }
+}
+
+// フレームと一緒に、NICが行ったチェックサム検証の結果と取り除いたVLANタグを返す
+// NICが使えない場合は空のフレームを返す
+pub fn receive_rx_frame() -> RxFrame {
     let mut buf: Vec<u8> = vec![];
+    let mut meta = RxMeta::none();
+    if !rx_ready() { return RxFrame { data: buf, meta }; }
 
-    let mut current_rxdesc: RxDesc = unsafe { RX_DESC_DATA[*CURRENT_RX_IDX.lock()] };
+    let mut current_rxdesc: RxDesc = unsafe { rx_descs()[*CURRENT_RX_IDX.lock()] };
 
     // let mut printer = Printer::new(100, 130, 0);
     // write!(printer, "{:?}", current_rxdesc.status & NIC_RDESC_STAT_DD == NIC_RDESC_STAT_DD).unwrap();
@@ -654,12 +998,17 @@ pub fn receive_frame() -> Vec<u8> {
         let mut printer = Printer::new(100, 190, 0);
         write!(printer, "{:?}", current_rxdesc.length).unwrap(); // この時点でICMPは4バイト多くなってる
         for idx in 0..current_rxdesc.length {
-            let byte = unsafe { *(current_rxdesc.recv_buf_addr.desc_base_low as *mut u8).offset(idx as isize) };
+            let byte = unsafe { *(phys_to_virt(current_rxdesc.recv_buf_addr.desc_base_low) as *mut u8).offset(idx as isize) };
             buf.push(byte);
         }
+        meta.ip_checksum = decode_checksum(current_rxdesc.status, current_rxdesc.errors, NIC_RDESC_STAT_IPCS, NIC_RDESC_ERR_IPE);
+        meta.l4_checksum = decode_checksum(current_rxdesc.status, current_rxdesc.errors, NIC_RDESC_STAT_TCPCS, NIC_RDESC_ERR_TCPE);
+        if current_rxdesc.status & NIC_RDESC_STAT_VP == NIC_RDESC_STAT_VP {
+            meta.vlan_tci = Some(current_rxdesc.special);
+        }
         // current_rxdesc.status = 0;
-        unsafe { write_mem!(&mut RX_DESC_DATA[*CURRENT_RX_IDX.lock()].status as *mut u8, 0) };
-        set_nic_reg(NIC_REG_RDT, unsafe { *CURRENT_RX_IDX.lock() as u32 });
+        unsafe { write_mem!(&mut rx_descs()[*CURRENT_RX_IDX.lock()].status as *mut u8, 0) };
+        nic_regs().write(regs::RDT, unsafe { *CURRENT_RX_IDX.lock() as u32 });
         unsafe {
             let idx = {
                 (*CURRENT_RX_IDX.lock()).clone()
@@ -667,44 +1016,50 @@ pub fn receive_frame() -> Vec<u8> {
             *CURRENT_RX_IDX.lock() = (idx + 1) % RXDESC_NUM;
         }
     }
-    return buf;
+    return RxFrame { data: buf, meta };
 }
 
-pub fn tx_init() {
-    let mut tx_desc_data_for_initialization: [TxDesc; TXDESC_NUM] = [TxDesc::new(); TXDESC_NUM];
-    for (idx, cur_rxdesc) in unsafe { TX_DESC_DATA.iter_mut().enumerate() } {
-        (*cur_rxdesc).tx_buf_address.desc_base_low = 0x00000000;
-        (*cur_rxdesc).tx_buf_address.desc_base_high = 0x00000000;
-        tx_desc_data_for_initialization[idx] = *cur_rxdesc;
+pub fn tx_init() -> Result<(), String> {
+    let tx_desc_data = DmaRegion::allocate(tx_desc_data_size(), &DmaConstraints::DEFAULT)?;
+    let tx_desc_phys_address = tx_desc_data.phys_address();
+    unsafe {
+        let descs = slice::from_raw_parts_mut(tx_desc_data.as_ptr::<TxDesc>(), TXDESC_NUM);
+        for cur_txdesc in descs.iter_mut() {
+            write_mem!(cur_txdesc as *mut TxDesc, TxDesc::new());
+        }
+        TX_DESC_DATA = Some(tx_desc_data);
     }
-    unsafe { TX_DESC_DATA = tx_desc_data_for_initialization; }
+    *CURRENT_TX_IDX.lock() = 0;
 
     /* txdescの先頭アドレスとサイズをNICレジスタへ設定 */
-    set_nic_reg(NIC_REG_TDBAH, 0x00);
-    set_nic_reg(NIC_REG_TDBAL, unsafe { &mut TX_DESC_DATA as *mut [TxDesc; TXDESC_NUM] as u32 });
-    set_nic_reg(NIC_REG_TDLEN, tx_desc_data_size() as u32);
+    nic_regs().write(regs::TDBAH, 0x00);
+    nic_regs().write(regs::TDBAL, tx_desc_phys_address);
+    nic_regs().write(regs::TDLEN, tx_desc_data_size() as u32);
 
-    set_nic_reg(NIC_REG_TDH, unsafe { *CURRENT_TX_IDX.lock() as u32 });
-    set_nic_reg(NIC_REG_TDT, unsafe { *CURRENT_TX_IDX.lock() as u32 });
+    nic_regs().write(regs::TDH, unsafe { *CURRENT_TX_IDX.lock() as u32 });
+    nic_regs().write(regs::TDT, unsafe { *CURRENT_TX_IDX.lock() as u32 });
 
-    set_nic_reg(NIC_REG_TCTL, (0x40 << NIC_TCTL_COLD_SHIFT) | (0x0f << NIC_TCTL_CT_SHIFT) | NIC_TCTL_PSP | NIC_TCTL_EN);
+    nic_regs().write(regs::TCTL, Tctl::default().set_cold(0x40).set_ct(0x0f).set_psp(1).set_en(1));
 
     let mut printer = Printer::new(700, 400, 0);
-    write!(printer, "{:x}", unsafe { &mut TX_DESC_DATA as *mut [TxDesc; TXDESC_NUM] as u32 }).unwrap();
+    write!(printer, "{:x}", tx_desc_phys_address).unwrap();
     let mut printer = Printer::new(700, 415, 0);
-    write!(printer, "{:?}", unsafe { &mut TX_DESC_DATA[0] as *mut TxDesc }).unwrap();
+    write!(printer, "{:?}", unsafe { &mut tx_descs()[0] as *mut TxDesc }).unwrap();
     let mut printer = Printer::new(700, 430, 0);
-    write!(printer, "{:x}", unsafe { TX_DESC_DATA[0].tx_buf_address.low() }).unwrap();
+    write!(printer, "{:x}", unsafe { tx_descs()[0].tx_buf_address.low() }).unwrap();
     let mut printer = Printer::new(700, 445, 0);
-    write!(printer, "{:x}", unsafe { TX_DESC_DATA[0].tx_buf_address.high() }).unwrap();
+    write!(printer, "{:x}", unsafe { tx_descs()[0].tx_buf_address.high() }).unwrap();
     let mut printer = Printer::new(700, 460, 0);
-    write!(printer, "{:x}", unsafe { &mut TX_DESC_DATA[0].tx_buf_address as *mut BufferAddr as u32 }).unwrap();
+    write!(printer, "{:x}", unsafe { &mut tx_descs()[0].tx_buf_address as *mut BufferAddr as u32 }).unwrap();
     let mut printer = Printer::new(700, 475, 0);
-    write!(printer, "{:x}", unsafe { &mut TX_DESC_DATA[1].tx_buf_address as *mut BufferAddr as u32 }).unwrap();
+    write!(printer, "{:x}", unsafe { &mut tx_descs()[1].tx_buf_address as *mut BufferAddr as u32 }).unwrap();
+    Ok(())
 }
 
 
+// 送信関数はNICが使えない場合は何もせずに0を返す
 pub fn send_frame(mut buf: Vec<u8>) -> u8 {
+    if !tx_ready() { return 0; }
     unsafe {
         let current_idx = unsafe { *CURRENT_TX_IDX.lock() };
         let mut slice: &[u8] = &[&buf[..]].concat();
@@ -713,36 +1068,106 @@ pub fn send_frame(mut buf: Vec<u8>) -> u8 {
         let idx = { (*CURRENT_TX_IDX.lock()).clone() };
         *CURRENT_TX_IDX.lock() = (idx + 1) % TXDESC_NUM;
 
-        TX_DESC_DATA[current_idx].tx_buf_address.desc_base_low = dma_buf.as_ptr() as u32;
-        TX_DESC_DATA[current_idx].length = buf.len() as u16;
-        TX_DESC_DATA[current_idx].sta_rsv = 0x00;
-        set_nic_reg(NIC_REG_TDT, unsafe { *CURRENT_TX_IDX.lock() as u32 });
+        reset_legacy_desc(current_idx);
+        tx_descs()[current_idx].tx_buf_address.desc_base_low = dma_buf.phys_address();
+        tx_descs()[current_idx].length = buf.len() as u16;
+        tx_descs()[current_idx].sta_rsv = 0x00;
+        nic_regs().write(regs::TDT, unsafe { *CURRENT_TX_IDX.lock() as u32 });
 
         let mut send_status: u8 = 0;
-        while send_status == 0 { send_status = read_mem!(&TX_DESC_DATA[current_idx]).sta_rsv & 0x0f; }
+        while send_status == 0 { send_status = read_mem!(&tx_descs()[current_idx]).sta_rsv & 0x0f; }
         let mut printer = Printer::new(500, 705, 0);
         write!(printer, "{:x}", send_status).unwrap();
         return send_status;
     };
 }
 
+// コンテキストディスクリプタで上書きされたスロットを通常のディスクリプタに戻す
+unsafe fn reset_legacy_desc(idx: usize) {
+    tx_descs()[idx].cso = 0;
+    tx_descs()[idx].cmd = NIC_TDESC_CMD_RS | NIC_TDESC_CMD_EOP;
+    tx_descs()[idx].css = 0;
+    tx_descs()[idx].special = 0;
+}
+
+// 現在のindexを返して、次のディスクリプタに進める
+fn advance_tx_idx() -> usize {
+    let mut idx = CURRENT_TX_IDX.lock();
+    let current_idx = *idx;
+    *idx = (current_idx + 1) % TXDESC_NUM;
+    current_idx
+}
+
 pub fn send_buf_frame(buf: DmaBox<[u8]>) -> u8 {
+    send_buf_frame_with_offload(buf, TxOffload::none())
+}
+
+// チェックサムの計算やVLANタグの挿入をNICに任せて送信する
+// チェックサムを任せる場合はコンテキストディスクリプタと拡張データディスクリプタの2つを使う
+pub fn send_buf_frame_with_offload(buf: DmaBox<[u8]>, offload: TxOffload) -> u8 {
+    if !tx_ready() { return 0; }
     unsafe {
-        let current_idx = unsafe { *CURRENT_TX_IDX.lock() };
-        let idx = { (*CURRENT_TX_IDX.lock()).clone() };
-        *CURRENT_TX_IDX.lock() = (idx + 1) % TXDESC_NUM;
+        let mut popts: u8 = 0;
+        if offload.needs_checksum() {
+            let context_idx = advance_tx_idx();
+            let context = TxContextDesc::new(&offload);
+            write_mem!(&mut tx_descs()[context_idx] as *mut TxDesc as *mut TxContextDesc, context);
+            if offload.ip_checksum.is_some() { popts |= NIC_TDESC_POPTS_IXSM; }
+            if offload.l4_checksum.is_some() { popts |= NIC_TDESC_POPTS_TXSM; }
+        }
 
-        TX_DESC_DATA[current_idx].length = buf.len() as u16;
-        TX_DESC_DATA[current_idx].tx_buf_address.desc_base_low = buf.as_ptr() as u32;
-        TX_DESC_DATA[current_idx].sta_rsv = 0;
+        let current_idx = advance_tx_idx();
+        reset_legacy_desc(current_idx);
+        tx_descs()[current_idx].length = buf.len() as u16;
+        tx_descs()[current_idx].tx_buf_address.desc_base_low = buf.phys_address();
+        tx_descs()[current_idx].sta_rsv = 0;
+        if offload.needs_checksum() {
+            tx_descs()[current_idx].cso = NIC_TDESC_DTYP_DATA;
+            tx_descs()[current_idx].cmd |= NIC_TDESC_CMD_DEXT;
+            tx_descs()[current_idx].css = popts;
+        }
+        if let Some(tci) = offload.vlan_tci {
+            tx_descs()[current_idx].cmd |= NIC_TDESC_CMD_VLE;
+            tx_descs()[current_idx].special = tci;
+        }
 
-        set_nic_reg(NIC_REG_TDT, unsafe { *CURRENT_TX_IDX.lock() as u32 });
+        nic_regs().write(regs::TDT, unsafe { *CURRENT_TX_IDX.lock() as u32 });
         let mut send_status: u8 = 0;
-        while send_status == 0 { send_status = read_mem!(&TX_DESC_DATA[current_idx]).sta_rsv & 0x0f; }
+        while send_status == 0 { send_status = read_mem!(&tx_descs()[current_idx]).sta_rsv & 0x0f; }
         return send_status;
     };
 }
 
+// 複数のバッファをディスクリプタに並べて1つのフレームとして送る(最後のディスクリプタだけにEOPを立てる)
+// ディスクリプタが足りない場合は1つのバッファにつなげて送る
+pub fn send_sg_frame(sg: ScatterGatherList) -> u8 {
+    let count = sg.segment_count();
+    if count == 0 || !tx_ready() { return 0; }
+    if count >= TXDESC_NUM {
+        return send_buf_frame(sg.to_boxed_slice());
+    }
+    unsafe {
+        let mut last_idx = 0;
+        for (n, segment) in sg.segments().enumerate() {
+            let idx = advance_tx_idx();
+            reset_legacy_desc(idx);
+            if n + 1 < count {
+                tx_descs()[idx].cmd = 0;
+            }
+            tx_descs()[idx].length = segment.len as u16;
+            tx_descs()[idx].tx_buf_address.desc_base_low = segment.phys_address;
+            tx_descs()[idx].sta_rsv = 0;
+            last_idx = idx;
+        }
+
+        nic_regs().write(regs::TDT, *CURRENT_TX_IDX.lock() as u32);
+        // 送り終わるまでsgのバッファを解放しない
+        let mut send_status: u8 = 0;
+        while send_status == 0 { send_status = read_mem!(&tx_descs()[last_idx]).sta_rsv & 0x0f; }
+        send_status
+    }
+}
+
 pub fn send_test_frame() -> u8 {
     let buf: [u8; 590]  = [
         0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x08, 0x00, 0x27, 0x66, 0x10, 0x65, 0x08, 0x00, 0x45, 0x00,
@@ -784,19 +1209,21 @@ pub fn send_test_frame() -> u8 {
         0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
     ];
 
+    if !tx_ready() { return 0; }
     unsafe {
         let current_idx = unsafe { *CURRENT_TX_IDX.lock() };
-        TX_DESC_DATA[current_idx].length = buf.len() as u16;
-        TX_DESC_DATA[current_idx].tx_buf_address.desc_base_low = buf.as_ptr() as u32;
+        reset_legacy_desc(current_idx);
+        tx_descs()[current_idx].length = buf.len() as u16;
+        tx_descs()[current_idx].tx_buf_address.desc_base_low = virt_to_phys(buf.as_ptr() as u32);
 
-        TX_DESC_DATA[current_idx].sta_rsv = 0;
+        tx_descs()[current_idx].sta_rsv = 0;
 
         let idx = { (*CURRENT_TX_IDX.lock()).clone() };
         *CURRENT_TX_IDX.lock() = (idx + 1) % TXDESC_NUM;
 
-        set_nic_reg(NIC_REG_TDT, unsafe { *CURRENT_TX_IDX.lock() as u32 });
+        nic_regs().write(regs::TDT, unsafe { *CURRENT_TX_IDX.lock() as u32 });
         let mut send_status: u8 = 0;
-        while send_status == 0 { send_status = read_mem!(&TX_DESC_DATA[current_idx]).sta_rsv & 0x0f; }
+        while send_status == 0 { send_status = read_mem!(&tx_descs()[current_idx]).sta_rsv & 0x0f; }
         return send_status;
     };
 }
@@ -813,9 +1240,24 @@ pub fn dump_frame() -> usize {
     return receive_buf.len();
 }
 
-pub fn nic_init() {
+pub fn nic_init() -> Result<(), String> {
     disable_nic_interrupt();
-    rx_init();
+    rx_init()?;
     get_mac_addr();
-    tx_init();
+    tx_init()?;
+    negotiate_features(NicFeatures::all());
+    Ok(())
+}
+
+// 送受信を止めて割り込みをマスクする
+pub fn nic_shutdown() {
+    nic_regs().write(regs::RCTL, Rctl::default());
+    nic_regs().write(regs::TCTL, Tctl::default());
+    nic_regs().write(regs::IMC, 0xffffffff);
+    // NICが止まったのでディスクリプタとバッファを返す
+    unsafe {
+        RX_DESC_DATA = None;
+        RX_BUFFER = None;
+        TX_DESC_DATA = None;
+    }
 }
\ No newline at end of file
@@ -0,0 +1,304 @@
+use alloc::vec::Vec;
+use alloc::string::String;
+use alloc::borrow::ToOwned;
+
+use super::pci::{PciDevice, PCI_HEADER_TYPE_CARDBUS, PCI_STAT_CAP_LIST};
+use crate::arch::msi::{allocate_msi_vector, free_msi_vector, msi_address, MSI_DEFAULT_DEST_APIC_ID};
+
+// PCIのCapabilityリスト
+// statusのCapabilities Listのbitが立っていれば、0x34(CardBusは0x14)から各Capabilityが連結リストで並んでいる
+
+const PCI_CONF_CAP_PTR: u8 = 0x34;
+const PCI_CONF_CARDBUS_CAP_PTR: u8 = 0x14;
+const PCI_CAP_PTR_MASK: u8 = 0xfc;
+// ヘッダの後ろ(0x40~0xff)に入る数以上は辿らない(ループしているデバイス対策)
+const PCI_CAP_MAX_NUM: usize = 48;
+
+pub const PCI_CAP_ID_PM: u8 = 0x01;
+pub const PCI_CAP_ID_MSI: u8 = 0x05;
+pub const PCI_CAP_ID_VENDOR: u8 = 0x09;
+pub const PCI_CAP_ID_PCIE: u8 = 0x10;
+pub const PCI_CAP_ID_MSIX: u8 = 0x11;
+
+// Power Management
+const PCI_PM_PMC: u8 = 0x02;
+const PCI_PM_PMCSR: u8 = 0x04;
+const PCI_PM_PMC_VERSION_MASK: u16 = 0x0007;
+const PCI_PM_PMC_D1: u16 = 1 << 9;
+const PCI_PM_PMC_D2: u16 = 1 << 10;
+const PCI_PM_PMCSR_STATE_MASK: u32 = 0x0003;
+
+// MSI
+const PCI_MSI_CTRL: u8 = 0x02;
+const PCI_MSI_ADDR_LO: u8 = 0x04;
+const PCI_MSI_ADDR_HI: u8 = 0x08;
+const PCI_MSI_DATA_32: u8 = 0x08;
+const PCI_MSI_DATA_64: u8 = 0x0c;
+const PCI_MSI_MASK_32: u8 = 0x0c;
+const PCI_MSI_MASK_64: u8 = 0x10;
+const PCI_MSI_CTRL_ENABLE: u16 = 1 << 0;
+const PCI_MSI_CTRL_MMC_SHIFT: u16 = 1; // Multiple Message Capable
+const PCI_MSI_CTRL_MME_MASK: u16 = 0b111 << 4; // Multiple Message Enable
+const PCI_MSI_CTRL_64BIT: u16 = 1 << 7;
+const PCI_MSI_CTRL_PER_VECTOR_MASK: u16 = 1 << 8;
+
+// MSI-X
+const PCI_MSIX_CTRL: u8 = 0x02;
+const PCI_MSIX_TABLE: u8 = 0x04;
+const PCI_MSIX_PBA: u8 = 0x08;
+const PCI_MSIX_CTRL_TABLE_SIZE_MASK: u16 = 0x07ff;
+const PCI_MSIX_CTRL_FUNCTION_MASK: u16 = 1 << 14;
+const PCI_MSIX_CTRL_ENABLE: u16 = 1 << 15;
+const PCI_MSIX_BIR_MASK: u32 = 0x07;
+
+// PCI Express
+const PCI_EXP_FLAGS: u8 = 0x02;
+const PCI_EXP_FLAGS_VERSION_MASK: u16 = 0x000f;
+const PCI_EXP_FLAGS_TYPE_SHIFT: u16 = 4;
+const PCI_EXP_FLAGS_TYPE_MASK: u16 = 0x000f;
+
+// Vendor Specific
+const PCI_VNDR_LEN: u8 = 0x02;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum PciCapabilityKind {
+    PowerManagement,
+    Msi,
+    VendorSpecific,
+    PciExpress,
+    MsiX,
+    Other(u8),
+}
+
+impl PciCapabilityKind {
+    pub fn from_id(id: u8) -> Self {
+        match id {
+            PCI_CAP_ID_PM => PciCapabilityKind::PowerManagement,
+            PCI_CAP_ID_MSI => PciCapabilityKind::Msi,
+            PCI_CAP_ID_VENDOR => PciCapabilityKind::VendorSpecific,
+            PCI_CAP_ID_PCIE => PciCapabilityKind::PciExpress,
+            PCI_CAP_ID_MSIX => PciCapabilityKind::MsiX,
+            _ => PciCapabilityKind::Other(id),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            PciCapabilityKind::PowerManagement => "Power Management",
+            PciCapabilityKind::Msi => "MSI",
+            PciCapabilityKind::VendorSpecific => "Vendor Specific",
+            PciCapabilityKind::PciExpress => "PCI Express",
+            PciCapabilityKind::MsiX => "MSI-X",
+            PciCapabilityKind::Other(_) => "Unknown",
+        }
+    }
+}
+
+// コンフィギュレーション空間の中のCapabilityの位置
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct PciCapability {
+    pub id: u8,
+    pub offset: u8,
+}
+
+impl PciCapability {
+    pub fn kind(&self) -> PciCapabilityKind {
+        PciCapabilityKind::from_id(self.id)
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct PowerManagementCapability {
+    pub offset: u8,
+    pub version: u8,
+    pub d1_support: bool,
+    pub d2_support: bool,
+    pub power_state: u8, // 0~3がD0~D3hot
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct MsiCapability {
+    pub offset: u8,
+    pub enabled: bool,
+    pub address_64bit: bool,
+    pub per_vector_masking: bool,
+    pub max_vectors: usize, // Multiple Message Capableで要求しているベクタ数
+}
+
+impl MsiCapability {
+    fn data_offset(&self) -> u8 {
+        self.offset + if self.address_64bit { PCI_MSI_DATA_64 } else { PCI_MSI_DATA_32 }
+    }
+
+    fn mask_offset(&self) -> u8 {
+        self.offset + if self.address_64bit { PCI_MSI_MASK_64 } else { PCI_MSI_MASK_32 }
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct MsiXCapability {
+    pub offset: u8,
+    pub enabled: bool,
+    pub function_masked: bool,
+    pub table_size: usize,
+    pub table_bar: usize,
+    pub table_offset: u32,
+    pub pba_bar: usize,
+    pub pba_offset: u32,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct PciExpressCapability {
+    pub offset: u8,
+    pub version: u8,
+    pub device_type: u8, // 0: Endpoint, 4: Root Port, 5: Upstream Port, 6: Downstream Port など
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct VendorSpecificCapability {
+    pub offset: u8,
+    pub length: u8,
+}
+
+impl PciDevice {
+    fn write_config16(&self, reg: u8, val: u16) {
+        let shift = (reg & 0x02) as u32 * 8;
+        let dword = self.read_config(reg & !0x03) & !(0xffff << shift);
+        self.write_config(reg & !0x03, dword | (val as u32) << shift);
+    }
+
+    pub fn has_capabilities(&self) -> bool {
+        self.status() as u32 & PCI_STAT_CAP_LIST == PCI_STAT_CAP_LIST
+    }
+
+    // Capabilityリストを先頭から辿る
+    pub fn capabilities(&self) -> Vec<PciCapability> {
+        let mut capabilities: Vec<PciCapability> = vec![];
+        if !self.has_capabilities() { return capabilities; }
+        let cap_ptr_reg = if self.header_type == PCI_HEADER_TYPE_CARDBUS { PCI_CONF_CARDBUS_CAP_PTR } else { PCI_CONF_CAP_PTR };
+        let mut offset = self.read_config8(cap_ptr_reg) & PCI_CAP_PTR_MASK;
+        while offset != 0 && capabilities.len() < PCI_CAP_MAX_NUM {
+            let header = self.read_config16(offset);
+            capabilities.push(PciCapability { id: header as u8, offset });
+            offset = (header >> 8) as u8 & PCI_CAP_PTR_MASK;
+        }
+        capabilities
+    }
+
+    pub fn find_capability(&self, id: u8) -> Option<PciCapability> {
+        self.capabilities().into_iter().find(|cap| cap.id == id)
+    }
+
+    pub fn power_management_capability(&self) -> Option<PowerManagementCapability> {
+        let offset = self.find_capability(PCI_CAP_ID_PM)?.offset;
+        let pmc = self.read_config16(offset + PCI_PM_PMC);
+        let pmcsr = self.read_config(offset + PCI_PM_PMCSR);
+        Some(PowerManagementCapability {
+            offset,
+            version: (pmc & PCI_PM_PMC_VERSION_MASK) as u8,
+            d1_support: pmc & PCI_PM_PMC_D1 == PCI_PM_PMC_D1,
+            d2_support: pmc & PCI_PM_PMC_D2 == PCI_PM_PMC_D2,
+            power_state: (pmcsr & PCI_PM_PMCSR_STATE_MASK) as u8,
+        })
+    }
+
+    pub fn msi_capability(&self) -> Option<MsiCapability> {
+        let offset = self.find_capability(PCI_CAP_ID_MSI)?.offset;
+        let ctrl = self.read_config16(offset + PCI_MSI_CTRL);
+        Some(MsiCapability {
+            offset,
+            enabled: ctrl & PCI_MSI_CTRL_ENABLE == PCI_MSI_CTRL_ENABLE,
+            address_64bit: ctrl & PCI_MSI_CTRL_64BIT == PCI_MSI_CTRL_64BIT,
+            per_vector_masking: ctrl & PCI_MSI_CTRL_PER_VECTOR_MASK == PCI_MSI_CTRL_PER_VECTOR_MASK,
+            max_vectors: 1 << ((ctrl >> PCI_MSI_CTRL_MMC_SHIFT) & 0b111),
+        })
+    }
+
+    pub fn msix_capability(&self) -> Option<MsiXCapability> {
+        let offset = self.find_capability(PCI_CAP_ID_MSIX)?.offset;
+        let ctrl = self.read_config16(offset + PCI_MSIX_CTRL);
+        let table = self.read_config(offset + PCI_MSIX_TABLE);
+        let pba = self.read_config(offset + PCI_MSIX_PBA);
+        Some(MsiXCapability {
+            offset,
+            enabled: ctrl & PCI_MSIX_CTRL_ENABLE == PCI_MSIX_CTRL_ENABLE,
+            function_masked: ctrl & PCI_MSIX_CTRL_FUNCTION_MASK == PCI_MSIX_CTRL_FUNCTION_MASK,
+            table_size: (ctrl & PCI_MSIX_CTRL_TABLE_SIZE_MASK) as usize + 1,
+            table_bar: (table & PCI_MSIX_BIR_MASK) as usize,
+            table_offset: table & !PCI_MSIX_BIR_MASK,
+            pba_bar: (pba & PCI_MSIX_BIR_MASK) as usize,
+            pba_offset: pba & !PCI_MSIX_BIR_MASK,
+        })
+    }
+
+    pub fn pci_express_capability(&self) -> Option<PciExpressCapability> {
+        let offset = self.find_capability(PCI_CAP_ID_PCIE)?.offset;
+        let flags = self.read_config16(offset + PCI_EXP_FLAGS);
+        Some(PciExpressCapability {
+            offset,
+            version: (flags & PCI_EXP_FLAGS_VERSION_MASK) as u8,
+            device_type: ((flags >> PCI_EXP_FLAGS_TYPE_SHIFT) & PCI_EXP_FLAGS_TYPE_MASK) as u8,
+        })
+    }
+
+    pub fn vendor_specific_capabilities(&self) -> Vec<VendorSpecificCapability> {
+        self.capabilities().into_iter()
+            .filter(|cap| cap.id == PCI_CAP_ID_VENDOR)
+            .map(|cap| VendorSpecificCapability { offset: cap.offset, length: self.read_config8(cap.offset + PCI_VNDR_LEN) })
+            .collect()
+    }
+
+    // MSIでvectorの割り込みをdest_apic_idのLocal APICに届けるように設定する
+    // ベクタは1つだけ使う(Multiple Message Enableは0)
+    pub fn enable_msi(&self, vector: u8, dest_apic_id: u8) -> Result<(), String> {
+        let msi = match self.msi_capability() {
+            Some(msi) => msi,
+            None => return Err("device does not support msi.".to_owned()),
+        };
+        // 設定中に割り込みが飛ばないように一旦止める
+        let ctrl = self.read_config16(msi.offset + PCI_MSI_CTRL) & !(PCI_MSI_CTRL_ENABLE | PCI_MSI_CTRL_MME_MASK);
+        self.write_config16(msi.offset + PCI_MSI_CTRL, ctrl);
+
+        self.write_config(msi.offset + PCI_MSI_ADDR_LO, msi_address(dest_apic_id));
+        if msi.address_64bit {
+            self.write_config(msi.offset + PCI_MSI_ADDR_HI, 0);
+        }
+        // Message Dataの下位8bitがベクタ、それ以外の0はFixed/Edgeトリガ
+        self.write_config16(msi.data_offset(), vector as u16);
+        if msi.per_vector_masking {
+            self.write_config(msi.mask_offset(), 0);
+        }
+
+        self.write_config16(msi.offset + PCI_MSI_CTRL, ctrl | PCI_MSI_CTRL_ENABLE);
+        // MSIを使っている間はINTx#を止める
+        self.disable_intx();
+        Ok(())
+    }
+
+    pub fn disable_msi(&self) -> Result<(), String> {
+        let msi = match self.msi_capability() {
+            Some(msi) => msi,
+            None => return Err("device does not support msi.".to_owned()),
+        };
+        let ctrl = self.read_config16(msi.offset + PCI_MSI_CTRL);
+        self.write_config16(msi.offset + PCI_MSI_CTRL, ctrl & !PCI_MSI_CTRL_ENABLE);
+        Ok(())
+    }
+
+    // 専用のIDTベクタを確保してhandlerを登録し、MSIを有効にする。確保したベクタを返す
+    pub fn configure_msi(&self, handler: fn()) -> Result<u8, String> {
+        let vector = allocate_msi_vector(handler)?;
+        if let Err(e) = self.enable_msi(vector, MSI_DEFAULT_DEST_APIC_ID) {
+            // 直前に確保したベクタなので解放は失敗しない
+            let _ = free_msi_vector(vector);
+            return Err(e);
+        }
+        Ok(vector)
+    }
+
+    // configure_msiで確保したベクタを解放する
+    pub fn release_msi(&self, vector: u8) -> Result<(), String> {
+        self.disable_msi()?;
+        free_msi_vector(vector)
+    }
+}
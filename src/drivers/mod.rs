@@ -1,2 +1,20 @@
 pub mod bus;
 pub mod net;
+pub mod serial;
+
+use bus::pci::{self, PciDriver};
+use net::e1000::E1000_DRIVER;
+
+// ブート時にPCIデバイスに結びつけるドライバ(新しいドライバはここに追加する)
+static PCI_DRIVERS: [&PciDriver; 1] = [
+    &E1000_DRIVER,
+];
+
+// PCIバスを調べて、見つかったデバイスにドライバを結びつける
+pub fn init() {
+    pci::scan_pci_bus();
+    for driver in PCI_DRIVERS.iter() {
+        pci::register_pci_driver(driver);
+    }
+    pci::bind_pci_drivers();
+}
@@ -2,11 +2,15 @@
 
 use core::mem::size_of;
 use alloc::string::String;
+use alloc::borrow::ToOwned;
 use alloc::vec::Vec;
 use super::e1000::get_mac_addr;
-use super::ethernet::{ETHERNET_TYPE_ARP, ETHERNET_TYPE_IP, HARDWARE_TYPE_ETHERNET, EthernetHdr, send_ethernet_packet};
+use super::e1000::TxOffload;
+use super::ethernet::{ETHERNET_TYPE_ARP, ETHERNET_TYPE_IP, HARDWARE_TYPE_ETHERNET, EthernetHdr, send_vlan_ethernet_packet};
 use super::net_util::{switch_endian16, switch_endian32, any_as_u8_vec, any_as_u8_slice, push_to_vec};
 use super::ip::DEFAULT_MY_IP;
+use super::net_stats::{count_rx_drop, RxDropReason};
+use super::vlan::{self, VlanTag};
 
 use crate::arch::graphic::{Graphic, Printer, print_str};
 use core::fmt::Write;
@@ -124,6 +128,7 @@ const ARP_TABLE_NUM: usize = 512;
 static mut ARP_TABLE: ArpTable = ArpTable::new();
 
 const BROADCAST_MAC_ADDR: [u8; 6] = [0xff, 0xff, 0xff, 0xff, 0xff, 0xff];
+const ARP_PACKET_LEN: usize = 28;
 
 #[repr(C)]
 pub struct Arp {
@@ -155,6 +160,10 @@ impl Arp {
     }
 
     pub fn parse_buf(data: DmaBox<[u8]>) -> Option<Arp> {
+        if data.len() < ARP_PACKET_LEN {
+            count_rx_drop(RxDropReason::ArpTooShort);
+            return None;
+        }
         let protocol = (data[2] as u16) << 8 | data[3] as u16;
         let opcode =  if ArpType::is_reply((data[6] as u16) << 8 | data[7] as u16) { ArpType::ArpReply } else { ArpType::ArpRequest };
 
@@ -181,10 +190,30 @@ impl Arp {
 }
 
 
+// インターフェースに設定されているIPアドレス(vlanがNoneなら物理NIC)
+fn get_interface_ip_addr(vlan: Option<VlanTag>) -> Option<[u8; 4]> {
+    match vlan {
+        Some(tag) => vlan::get_vlan_interface(tag.vid).map(|interface| interface.get_ip_addr()),
+        None => Some(DEFAULT_MY_IP),
+    }
+}
+
+// 送信時に付けるタグ(受信したタグのプライオリティではなくインターフェースの設定を使う)
+fn get_interface_tag(vlan: Option<VlanTag>) -> Option<VlanTag> {
+    vlan.and_then(|tag| vlan::get_vlan_interface(tag.vid)).map(|interface| interface.get_tag())
+}
+
 pub fn send_arp_packet(dst_hardware_addr: &[u8; 6], dst_protocol_addr: &[u8; 4]) -> Result<(), String> {
+    send_vlan_arp_packet(None, dst_hardware_addr, dst_protocol_addr)
+}
+
+pub fn send_vlan_arp_packet(vlan: Option<VlanTag>, dst_hardware_addr: &[u8; 6], dst_protocol_addr: &[u8; 4]) -> Result<(), String> {
     let src_mac_addr: [u8; 6] = get_mac_addr();
     // let src_protocol_addr: [u8; 4] = [10, 0, 2, 14];
-    let src_protocol_addr: [u8; 4] = DEFAULT_MY_IP;
+    let src_protocol_addr: [u8; 4] = match get_interface_ip_addr(vlan) {
+        Some(ip_addr) => ip_addr,
+        None => return Err("vlan interface not found.".to_owned()),
+    };
     let hardware_addr_len: u8 = 6;
     let protocol_addr_len: u8 = 4;
     let arp_opcode = ArpType::ArpRequest; // 1
@@ -206,16 +235,17 @@ pub fn send_arp_packet(dst_hardware_addr: &[u8; 6], dst_protocol_addr: &[u8; 4])
         dst_protocol_addr: [dst_protocol_addr[0], dst_protocol_addr[1], dst_protocol_addr[2], dst_protocol_addr[3]],
     };
     let v = arp_packet.to_slice();
-    send_ethernet_packet(BROADCAST_MAC_ADDR, v, size_of::<Arp>(), ETHERNET_TYPE_ARP)
+    send_vlan_ethernet_packet(BROADCAST_MAC_ADDR, get_interface_tag(vlan), v, size_of::<Arp>(), ETHERNET_TYPE_ARP, TxOffload::none())
 }
 
-pub fn receive_arp_packet(buf: DmaBox<[u8]>) -> Option<ArpTableEntry> {
+// vlanは受信したフレームに付いていたタグ
+pub fn receive_arp_packet(buf: DmaBox<[u8]>, vlan: Option<VlanTag>) -> Option<ArpTableEntry> {
     let parsed_arp = Arp::parse_buf(buf);
     if let Some(arp) = parsed_arp {
         match arp.opcode {
             ArpType::ArpReply => receive_arp_reply(arp),
             ArpType::ArpRequest => {
-                send_reply_arp(arp);
+                send_reply_arp(arp, vlan);
                 None
             },
         }
@@ -224,12 +254,16 @@ pub fn receive_arp_packet(buf: DmaBox<[u8]>) -> Option<ArpTableEntry> {
     }
 }
 
-pub fn send_reply_arp(arp: Arp) -> Result<(), String> {
-    // 自分のIPじゃなかったらそのまま終了
-    if arp.dst_protocol_addr != DEFAULT_MY_IP { return Ok(()); }
+pub fn send_reply_arp(arp: Arp, vlan: Option<VlanTag>) -> Result<(), String> {
+    // 受信したインターフェースのIPじゃなかったらそのまま終了
+    let my_ip_addr = match get_interface_ip_addr(vlan) {
+        Some(ip_addr) => ip_addr,
+        None => return Ok(()),
+    };
+    if arp.dst_protocol_addr != my_ip_addr { return Ok(()); }
 
     let src_mac_addr: [u8; 6] = get_mac_addr();
-    let src_protocol_addr: [u8; 4] = DEFAULT_MY_IP;
+    let src_protocol_addr: [u8; 4] = my_ip_addr;
     let hardware_addr_len: u8 = 6;
     let protocol_addr_len: u8 = 4;
     let arp_opcode = ArpType::ArpReply; // 1
@@ -248,7 +282,7 @@ pub fn send_reply_arp(arp: Arp) -> Result<(), String> {
         dst_protocol_addr: [arp.src_protocol_addr[0], arp.src_protocol_addr[1], arp.src_protocol_addr[2], arp.src_protocol_addr[3]],
     };
     let v = arp_packet.to_slice();
-    send_ethernet_packet(arp_packet.dst_hardware_addr, v, size_of::<Arp>(), ETHERNET_TYPE_ARP)
+    send_vlan_ethernet_packet(arp_packet.dst_hardware_addr, get_interface_tag(vlan), v, size_of::<Arp>(), ETHERNET_TYPE_ARP, TxOffload::none())
 }
 
 pub fn receive_arp_reply(arp: Arp) -> Option<ArpTableEntry> {
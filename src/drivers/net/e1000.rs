@@ -1,47 +1,326 @@
+use core::convert::TryFrom;
 use core::fmt::Write;
 use alloc::vec::Vec;
 use crate::arch::graphic::{Graphic, Printer, print_str};
-use super::super::bus::pci::{send_frame, send_buf_frame, receive_frame};
-use crate::memory::dma::DmaBox;
+use super::super::bus::pci::{send_frame, send_buf_frame, send_buf_frame_with_offload, send_sg_frame, receive_frame, get_nic_vendor_device_id};
+use super::super::bus::pci::{BarRegion, PciDevice, PciDriver, PciMatch, attach_nic_device, detach_nic_device, is_nic_attached, nic_init, nic_shutdown, probe_nic_regs};
+use crate::memory::dma::{DmaBox, ScatterGatherList};
 
+#[macro_use]
+use crate::lazy_static;
+use crate::spin::mutex::Mutex;
 
-use super::super::bus::pci::{
-  get_nic_reg,
-  set_nic_reg,
-};
+
+use super::super::bus::pci::nic_regs;
+use super::e1000_regs::{self as regs, Eerd};
+use super::net_util::calc_internet_checksum;
 use alloc::string::String;
+use alloc::borrow::ToOwned;
+
+const EERD_TIMEOUT: usize = 1000000;
 
-const NIC_REG_EERD: u16 = 0x0014;
+const NIC_VET_VLAN_TYPE: u32 = 0x8100;
 
-const NIC_EERD_START: u32 = 1 << 0;
-const NIC_EERD_DONE: u32 = 1 << 4;
-const NIC_EERD_ADDRESS_SHIFT: u32 = 8;
-const NIC_EERD_NIC_DATA_SHIFT: u32 = 16;
+const INTEL_VENDOR_ID: u16 = 0x8086;
+// チェックサムオフロードとVLANのstrip/insertに対応しているデバイス
+const OFFLOAD_CAPABLE_DEVICE_IDS: [u16; 4] = [
+    0x100e, // 82540EM(QEMUのe1000)
+    0x100f, // 82545EM
+    0x1011, // 82545EM(fiber)
+    0x10d3, // 82574L
+];
 
+const E1000_MATCHES: [PciMatch; 4] = [
+    PciMatch::Id { vendor_id: INTEL_VENDOR_ID, device_id: 0x100e },
+    PciMatch::Id { vendor_id: INTEL_VENDOR_ID, device_id: 0x100f },
+    PciMatch::Id { vendor_id: INTEL_VENDOR_ID, device_id: 0x1011 },
+    PciMatch::Id { vendor_id: INTEL_VENDOR_ID, device_id: 0x10d3 },
+];
 
-const EERD_TIMEOUT: usize = 1000000;
+pub static E1000_DRIVER: PciDriver = PciDriver {
+    name: "e1000",
+    matches: &E1000_MATCHES,
+    probe: e1000_probe,
+    remove: Some(e1000_remove),
+};
+
+// NICは1つだけ扱うので、2つ目以降のデバイスは結びつけない
+fn e1000_probe(device: &PciDevice) -> Result<(), String> {
+    if is_nic_attached() { return Err("e1000 is already bound.".to_owned()); }
+    match device.bar(0) {
+        Some(BarRegion::Mmio { .. }) => {},
+        _ => return Err("e1000 has no mmio bar.".to_owned()),
+    }
+    attach_nic_device(*device)?;
+    device.enable_memory_space();
+    if let Err(message) = probe_nic_regs() {
+        detach_nic_device();
+        return Err(message);
+    }
+    device.enable_bus_master();
+    if let Err(message) = nic_init() {
+        // 途中まで設定したNICが、返した領域にDMAしないようにする
+        device.disable_bus_master();
+        nic_shutdown();
+        detach_nic_device();
+        return Err(message);
+    }
+    Ok(())
+}
+
+fn e1000_remove(device: &PciDevice) {
+    device.disable_bus_master();
+    nic_shutdown();
+    *NIC_FEATURES.lock() = NicFeatures::none();
+    detach_nic_device();
+}
+
+// NICが受信時に行ったチェックサム検証の結果
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum HwChecksum {
+    Unchecked, // NICは検証していないのでソフトウェアで確認する
+    Good,
+    Bad,
+}
+
+// 受信ディスクリプタから取り出した付加情報
+#[derive(Copy, Clone, Debug)]
+pub struct RxMeta {
+    pub ip_checksum: HwChecksum,
+    pub l4_checksum: HwChecksum,
+    // NICがVLANタグを取り除いた場合のTCI
+    pub vlan_tci: Option<u16>,
+}
+
+impl RxMeta {
+    pub const fn none() -> Self {
+        RxMeta {
+            ip_checksum: HwChecksum::Unchecked,
+            l4_checksum: HwChecksum::Unchecked,
+            vlan_tci: None,
+        }
+    }
+
+    pub fn invalidate_checksum(&mut self) {
+        self.ip_checksum = HwChecksum::Unchecked;
+        self.l4_checksum = HwChecksum::Unchecked;
+    }
+}
+
+#[derive(Clone)]
+pub struct RxFrame {
+    pub data: Vec<u8>,
+    pub meta: RxMeta,
+}
+
+impl RxFrame {
+    pub fn new(data: Vec<u8>) -> Self {
+        RxFrame {
+            data,
+            meta: RxMeta::none(),
+        }
+    }
+}
+
+// チェックサムを計算させる範囲(送信するバッファ先頭からのバイト位置)
+#[derive(Copy, Clone, Debug)]
+pub struct ChecksumOffload {
+    pub start: u8,  // 計算を始める位置
+    pub offset: u8, // 計算結果を書き込む位置
+    pub end: u16,   // 計算を終える位置(この位置を含む)。0なら末尾まで
+}
+
+impl ChecksumOffload {
+    // ディスクリプタの範囲(start/offsetは8bit)に収まらなければNone
+    fn shifted(&self, by: usize) -> Option<Self> {
+        let by_u8 = u8::try_from(by).ok()?;
+        Some(ChecksumOffload {
+            start: self.start.checked_add(by_u8)?,
+            offset: self.offset.checked_add(by_u8)?,
+            end: if self.end == 0 { 0 } else { self.end.checked_add(u16::try_from(by).ok()?)? },
+        })
+    }
+
+    // NICと同じ計算をソフトウェアで行う(offsetの位置に入っている値も含めて計算して、そこに書き込む)
+    fn calc_in_software(&self, buf: &mut [u8]) {
+        let start = self.start as usize;
+        let end = if self.end == 0 { buf.len() } else { (self.end as usize + 1).min(buf.len()) };
+        let offset = self.offset as usize;
+        if start >= end || offset + 2 > buf.len() { return; }
+        let checksum = calc_internet_checksum(&buf[start..end]);
+        buf[offset] = (checksum >> 8) as u8;
+        buf[offset + 1] = checksum as u8;
+    }
+}
+
+// 送信時にNICへ任せる処理
+#[derive(Copy, Clone, Debug)]
+pub struct TxOffload {
+    pub ip_checksum: Option<ChecksumOffload>,
+    pub l4_checksum: Option<ChecksumOffload>,
+    // NICに挿入させるVLANタグのTCI
+    pub vlan_tci: Option<u16>,
+}
+
+impl TxOffload {
+    pub const fn none() -> Self {
+        TxOffload {
+            ip_checksum: None,
+            l4_checksum: None,
+            vlan_tci: None,
+        }
+    }
+
+    pub fn needs_checksum(&self) -> bool {
+        self.ip_checksum.is_some() || self.l4_checksum.is_some()
+    }
+
+    // 上位層からのオフセットを、前に付くヘッダの分だけずらす
+    // ずらした位置がNICに渡せる範囲に収まらなければNone(呼び出し側はソフトウェアで計算する)
+    pub fn shifted(&self, by: usize) -> Option<Self> {
+        let shift = |checksum: Option<ChecksumOffload>| match checksum {
+            Some(c) => c.shifted(by).map(Some),
+            None => Some(None),
+        };
+        Some(TxOffload {
+            ip_checksum: shift(self.ip_checksum)?,
+            l4_checksum: shift(self.l4_checksum)?,
+            vlan_tci: self.vlan_tci,
+        })
+    }
+
+    // チェックサムをbuf(オフセットの基準になっているバッファ)の上で計算して、NICに任せるのはVLANタグの挿入だけにしたものを返す
+    pub fn calc_checksum_in_software(&self, buf: &mut [u8]) -> Self {
+        if let Some(c) = self.ip_checksum { c.calc_in_software(buf); }
+        if let Some(c) = self.l4_checksum { c.calc_in_software(buf); }
+        TxOffload { ip_checksum: None, l4_checksum: None, vlan_tci: self.vlan_tci }
+    }
+}
+
+#[derive(Clone)]
+pub struct TxFrame {
+    pub data: DmaBox<[u8]>,
+    pub offload: TxOffload,
+}
+
+// fault層でMutexの中に保留しておくため
+unsafe impl Send for TxFrame {}
+
+// NICの機能のうち、有効にしたもの
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct NicFeatures {
+    pub tx_checksum: bool,
+    pub rx_checksum: bool,
+    pub vlan_strip: bool,
+    pub vlan_insert: bool,
+}
+
+impl NicFeatures {
+    pub const fn none() -> Self {
+        NicFeatures {
+            tx_checksum: false,
+            rx_checksum: false,
+            vlan_strip: false,
+            vlan_insert: false,
+        }
+    }
+
+    pub const fn all() -> Self {
+        NicFeatures {
+            tx_checksum: true,
+            rx_checksum: true,
+            vlan_strip: true,
+            vlan_insert: true,
+        }
+    }
+
+    fn intersect(&self, other: &NicFeatures) -> Self {
+        NicFeatures {
+            tx_checksum: self.tx_checksum && other.tx_checksum,
+            rx_checksum: self.rx_checksum && other.rx_checksum,
+            vlan_strip: self.vlan_strip && other.vlan_strip,
+            vlan_insert: self.vlan_insert && other.vlan_insert,
+        }
+    }
+}
+
+lazy_static! {
+    static ref NIC_FEATURES: Mutex<NicFeatures> = Mutex::new(NicFeatures::none());
+}
 
 pub fn get_eeprom_data(eeprom_addr: u8) -> i32 {
-    set_nic_reg(NIC_REG_EERD, ((eeprom_addr as u32) << NIC_EERD_ADDRESS_SHIFT) | NIC_EERD_START);
+    nic_regs().write(regs::EERD, Eerd::default().set_address(eeprom_addr as u32).set_start(1));
 
     let mut wait = EERD_TIMEOUT.clone();
     while wait != 0 {
-        let eerd: u32 = get_nic_reg(NIC_REG_EERD);
-        if eerd & NIC_EERD_DONE == NIC_EERD_DONE {
+        let eerd = nic_regs().read(regs::EERD);
+        if eerd.done() == 1 {
             print_str(300, 315, "fetch eerd.", 0);
-            return (eerd as i32) >> NIC_EERD_NIC_DATA_SHIFT;
+            return eerd.data() as i32;
         }
         wait -= 1;
         if wait == 0 {
             let mut printer = Printer::new(300, 315, 0);
-            write!(printer, "{:x}", eerd).unwrap();
+            write!(printer, "{:x}", eerd.0).unwrap();
             print_str(300, 330, "TIMEOUT.", 0);
         }
     }
     return -1;
 }
 
+// デバイスIDからNICが対応している機能を調べる
+pub fn supported_features() -> NicFeatures {
+    let (vendor_id, device_id) = get_nic_vendor_device_id();
+    if vendor_id == INTEL_VENDOR_ID && OFFLOAD_CAPABLE_DEVICE_IDS.contains(&device_id) {
+        NicFeatures::all()
+    } else {
+        NicFeatures::none()
+    }
+}
+
+// 要求された機能のうちNICが対応しているものだけを有効にして、有効になったものを返す
+pub fn negotiate_features(requested: NicFeatures) -> NicFeatures {
+    let mut enabled = requested.intersect(&supported_features());
+
+    let rx_checksum = enabled.rx_checksum as u32;
+    nic_regs().modify(regs::RXCSUM, |rxcsum| rxcsum.set_ipofld(rx_checksum).set_tuofld(rx_checksum));
+
+    // VLANタグのstripとinsertはどちらもCTRL.VMEで有効になるので、片方だけを有効にはできない
+    let vlan = enabled.vlan_strip || enabled.vlan_insert;
+    enabled.vlan_strip = vlan;
+    enabled.vlan_insert = vlan;
+    nic_regs().write(regs::VET, NIC_VET_VLAN_TYPE);
+    nic_regs().modify(regs::CTRL, |ctrl| ctrl.set_vme(vlan as u32));
+
+    *NIC_FEATURES.lock() = enabled;
+    enabled
+}
+
+pub fn get_nic_features() -> NicFeatures {
+    *NIC_FEATURES.lock()
+}
+
+// RCTL.MO = 00の場合のハッシュ値(宛先MACアドレスの上位12bit)
+fn multicast_hash(mac_addr: &[u8; 6]) -> u16 {
+    ((mac_addr[4] as u16) >> 4 | (mac_addr[5] as u16) << 4) & 0x0fff
+}
+
+// Multicast Table Arrayを作り直して、渡されたアドレス宛のフレームだけを受信させる
+pub fn set_multicast_table(mac_addrs: &[[u8; 6]]) {
+    if !is_nic_attached() { return; }
+    let mut table = [0 as u32; regs::MTA_NUM];
+    for mac_addr in mac_addrs.iter() {
+        let hash = multicast_hash(mac_addr);
+        table[(hash >> 5) as usize] |= 1 << (hash & 0x1f);
+    }
+    for (idx, bits) in table.iter().enumerate() {
+        nic_regs().write(regs::MTA.at(idx), *bits);
+    }
+}
+
+// NICがつながっていない場合は0を返す(送信の方でErrになる)
 pub fn get_mac_addr() -> [u8; 6] {
+    if !is_nic_attached() { return [0; 6]; }
     let eeprom_accessible = get_eeprom_data(0x00);
     // let mut printer = Printer::new(300, 230, 0);
     // write!(printer, "{:?}", eeprom_accessible).unwrap();
@@ -77,9 +356,33 @@ pub fn get_mac_addr() -> [u8; 6] {
 // }
 
 pub fn e1000_send_packet(mut buf: DmaBox<[u8]>) -> Result<(), String> {
+    e1000_send_packet_with_offload(buf, TxOffload::none())
+}
+
+// 複数のバッファ(ヘッダとペイロードなど)をつなげずにそのまま送る
+pub fn e1000_send_sg_packet(sg: ScatterGatherList) -> Result<(), String> {
+    if !is_nic_attached() { return Err("e1000 is not attached.".to_owned()); }
+    let status = send_sg_frame(sg);
+    if status != 0 {
+        let mut printer = Printer::new(0, 600, 0);
+        write!(printer, "{:x}", status).unwrap();
+    }
+    Ok(())
+}
+
+// 有効になっていない機能のオフロードを要求された場合はErrを返す(呼び出し側がソフトウェアで処理すること)
+pub fn e1000_send_packet_with_offload(mut buf: DmaBox<[u8]>, offload: TxOffload) -> Result<(), String> {
+    if !is_nic_attached() { return Err("e1000 is not attached.".to_owned()); }
+    let features = get_nic_features();
+    if offload.needs_checksum() && !features.tx_checksum {
+        return Err("checksum offload is not enabled.".to_owned());
+    }
+    if offload.vlan_tci.is_some() && !features.vlan_insert {
+        return Err("vlan insertion is not enabled.".to_owned());
+    }
     let mut printer = Printer::new(700, 605, 0);
     write!(printer, "{:x}", &buf as *const DmaBox<[u8]> as u32).unwrap();
-    let status = send_buf_frame(buf);
+    let status = send_buf_frame_with_offload(buf, offload);
     if status != 0 {
         let mut printer = Printer::new(0, 600, 0);
         write!(printer, "{:x}", status).unwrap();
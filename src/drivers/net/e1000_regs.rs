@@ -0,0 +1,84 @@
+use crate::memory::mmio::{Register, mmio_bitfield};
+
+// e1000(8254x)のBAR0のレジスタ
+// ref: PCI/PCI-X Family of Gigabit Ethernet Controllers Software Developer's Manual 13章
+
+mmio_bitfield! {
+    // Device Control
+    pub struct Ctrl {
+        vme, set_vme: 30, 1; // VLANタグのstrip/insertを有効にする
+    }
+}
+
+mmio_bitfield! {
+    // EEPROM Read
+    pub struct Eerd {
+        start, set_start: 0, 1;
+        done, set_done: 4, 1;
+        address, set_address: 8, 8;
+        data, set_data: 16, 16;
+    }
+}
+
+mmio_bitfield! {
+    // Receive Control
+    pub struct Rctl {
+        en, set_en: 1, 1;
+        sbp, set_sbp: 2, 1;   // 壊れたフレームも受信する
+        upe, set_upe: 3, 1;   // ユニキャストを全て受信する
+        mpe, set_mpe: 4, 1;   // マルチキャストを全て受信する
+        lpe, set_lpe: 5, 1;
+        bam, set_bam: 15, 1;  // ブロードキャストを受信する
+        bsize, set_bsize: 16, 2;
+    }
+}
+
+mmio_bitfield! {
+    // Transmit Control
+    pub struct Tctl {
+        en, set_en: 1, 1;
+        psp, set_psp: 3, 1;   // 短いフレームをパディングする
+        ct, set_ct: 4, 8;     // 衝突した時の再送回数
+        cold, set_cold: 12, 10;
+        swxoff, set_swxoff: 22, 1;
+        rtlc, set_rtlc: 24, 1;
+        nrtu, set_nrtu: 25, 1;
+    }
+}
+
+mmio_bitfield! {
+    // Receive Checksum Control
+    pub struct Rxcsum {
+        ipofld, set_ipofld: 8, 1;
+        tuofld, set_tuofld: 9, 1;
+    }
+}
+
+// RCTL.BSIZEの値
+pub const RCTL_BSIZE_2048B: u32 = 0b00;
+pub const RCTL_BSIZE_1024B: u32 = 0b01;
+pub const RCTL_BSIZE_512B: u32 = 0b10;
+pub const RCTL_BSIZE_256B: u32 = 0b11;
+
+pub const CTRL: Register<Ctrl> = Register::new(0x0000);
+pub const STATUS: Register<u32> = Register::new(0x0008);
+pub const EERD: Register<Eerd> = Register::new(0x0014);
+pub const VET: Register<u32> = Register::new(0x0038);
+pub const IMS: Register<u32> = Register::new(0x00d0);
+pub const IMC: Register<u32> = Register::new(0x00d8);
+pub const RCTL: Register<Rctl> = Register::new(0x0100);
+pub const TCTL: Register<Tctl> = Register::new(0x0400);
+pub const RDBAL: Register<u32> = Register::new(0x2800);
+pub const RDBAH: Register<u32> = Register::new(0x2804);
+pub const RDLEN: Register<u32> = Register::new(0x2808);
+pub const RDH: Register<u32> = Register::new(0x2810);
+pub const RDT: Register<u32> = Register::new(0x2818);
+pub const TDBAL: Register<u32> = Register::new(0x3800);
+pub const TDBAH: Register<u32> = Register::new(0x3804);
+pub const TDLEN: Register<u32> = Register::new(0x3808);
+pub const TDH: Register<u32> = Register::new(0x3810);
+pub const TDT: Register<u32> = Register::new(0x3818);
+pub const RXCSUM: Register<Rxcsum> = Register::new(0x5000);
+// Multicast Table Array(MTA.at(idx)でidx番目)
+pub const MTA: Register<u32> = Register::new(0x5200);
+pub const MTA_NUM: usize = 128; // 4096bitのハッシュテーブル
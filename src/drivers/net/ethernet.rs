@@ -1,38 +1,56 @@
 use alloc::vec::Vec;
 use alloc::string::String;
 
-use super::e1000::{get_mac_addr, e1000_send_packet};
+use super::e1000::{get_mac_addr, e1000_send_packet, e1000_send_sg_packet, get_nic_features, RxFrame, RxMeta, TxOffload};
+use super::fault;
+use super::net_stats::{count_rx_drop, RxDropReason};
+use super::vlan::{self, VlanTag, VLAN_TAG_LEN};
 use super::net_util::{switch_endian16, any_as_u8_vec, push_to_vec};
 use crate::arch::graphic::{Graphic, Printer, print_str};
 use core::fmt::Write;
-use crate::memory::dma::DmaBox;
+use crate::memory::dma::{DmaBox, DmaVec, ScatterGatherList};
 
 pub const ETHERNET_TYPE_ARP: u16 = 0x0806;
 pub const ETHERNET_TYPE_IP: u16 = 0x0800;
+pub const ETHERNET_TYPE_VLAN: u16 = 0x8100; // 802.1QのTPID
 
 pub const HARDWARE_TYPE_ETHERNET: u16 = 0x01;
 
+pub const ETHERNET_HEADER_LEN: usize = 14;
+
 pub const DEFAULT_ETHERNET_ADDRESS: [u8; 6] = [0x52, 0x54, 0x00, 0x12, 0x34, 0x56];
 
 #[repr(C)]
 pub struct EthernetHdr {
     dst_mac_addr: [u8; 6],
     src_mac_addr: [u8; 6],
+    // 802.1Qタグ(タグ無しのフレームならNone)
+    vlan: Option<VlanTag>,
     ether_type: u16,
     payload: DmaBox<[u8]>,
+    // NICから受け取った時の付加情報(送信時は使わない)
+    rx_meta: RxMeta,
 }
 
 impl EthernetHdr {
+    // ヘッダ部分だけを組み立てる。後ろにreserveバイト足せる大きさで確保しておく
+    fn header_to_vec(&self, reserve: usize) -> DmaVec<u8> {
+        // Vec<u8>で組み立ててからコピーせず、DMAのメモリに直接組み立てる
+        let mut v = DmaVec::with_capacity(ETHERNET_HEADER_LEN + VLAN_TAG_LEN + reserve);
+        v.extend_from_slice(&self.dst_mac_addr);
+        v.extend_from_slice(&self.src_mac_addr);
+        if let Some(vlan) = self.vlan {
+            v.extend_from_slice(&ETHERNET_TYPE_VLAN.to_be_bytes());
+            v.extend_from_slice(&vlan.to_tci().to_be_bytes());
+        }
+        v.extend_from_slice(&self.ether_type.to_be_bytes());
+        v
+    }
+
     fn to_slice(&self) -> DmaBox<[u8]> {
-        let slice: &[u8] = &[
-            &self.dst_mac_addr[..],
-            // 何故か `&self.src_mac_addr[..]` だと先頭に `0xffff` が付加されるのでこれで回避する・・・
-            // ToDo 普通に `&self.src_mac_addr[..]` だとだめな理由を調べる(networkがarpのみの時代は問題なく動いていたはず・・)
-            self.src_mac_addr.clone().as_ref()[..].as_ref(),
-            &self.ether_type.to_be_bytes()[..],
-            &self.payload[..]
-        ].concat();
-        DmaBox::from(slice)
+        let mut v = self.header_to_vec(self.payload.len());
+        v.extend_from_slice(&self.payload);
+        v.into_boxed_slice()
     }
 
     pub fn get_src_mac_addr(&self) -> &[u8; 6] {
@@ -47,6 +65,10 @@ impl EthernetHdr {
         self.ether_type == ETHERNET_TYPE_IP
     }
 
+    pub fn get_vlan(&self) -> Option<VlanTag> {
+        self.vlan
+    }
+
     pub fn get_type(&self) -> u16 {
         self.ether_type
     }
@@ -55,8 +77,40 @@ impl EthernetHdr {
         self.payload.clone()
     }
 
+    pub fn get_rx_meta(&self) -> &RxMeta {
+        &self.rx_meta
+    }
+
     pub fn parse_from_frame(frame: Vec<u8>) -> Option<Self> {
-        let ether_type = (frame[12] as u16) << 8 | frame[13] as u16;
+        Self::parse_from_rx_frame(RxFrame::new(frame))
+    }
+
+    pub fn parse_from_rx_frame(rx_frame: RxFrame) -> Option<Self> {
+        let frame = rx_frame.data;
+        if frame.len() < ETHERNET_HEADER_LEN {
+            count_rx_drop(RxDropReason::EthernetTooShort);
+            return None;
+        }
+        let mut ether_type = (frame[12] as u16) << 8 | frame[13] as u16;
+        let mut header_len = ETHERNET_HEADER_LEN;
+        // NICがタグを取り除いていればディスクリプタの値を使う
+        let mut vlan = rx_frame.meta.vlan_tci.map(VlanTag::from_tci);
+        if ether_type == ETHERNET_TYPE_VLAN {
+            if frame.len() < ETHERNET_HEADER_LEN + VLAN_TAG_LEN {
+                count_rx_drop(RxDropReason::EthernetTooShort);
+                return None;
+            }
+            vlan = Some(VlanTag::from_tci((frame[14] as u16) << 8 | frame[15] as u16));
+            ether_type = (frame[16] as u16) << 8 | frame[17] as u16;
+            header_len += VLAN_TAG_LEN;
+        }
+        let vlan = vlan.filter(|tag| !tag.is_priority_only());
+        if let Some(tag) = vlan {
+            if !vlan::is_known_vid(tag.vid) {
+                count_rx_drop(RxDropReason::VlanUnknownVid);
+                return None;
+            }
+        }
         let mut printer = Printer::new(30, 75, 0);
         write!(printer, "{:x}", frame.len() as u32).unwrap();
         let mut printer = Printer::new(30, 90, 0);
@@ -70,8 +124,10 @@ impl EthernetHdr {
             Some(Self {
                 dst_mac_addr: [frame[0], frame[1], frame[2], frame[3], frame[4], frame[5]],
                 src_mac_addr: [frame[6], frame[7], frame[8], frame[9], frame[10], frame[11]],
+                vlan,
                 ether_type,
-                payload: DmaBox::from(&frame[14..]),
+                payload: DmaBox::from(&frame[header_len..]),
+                rx_meta: rx_frame.meta,
             })
         } else {
             None
@@ -85,14 +141,49 @@ impl EthernetHdr {
 
 
 pub fn send_ethernet_packet(dst_mac_addr: [u8; 6], data: DmaBox<[u8]>, len: usize, protocol: u16) -> Result<(), String> {
+    send_ethernet_packet_with_offload(dst_mac_addr, data, len, protocol, TxOffload::none())
+}
+
+// offloadのオフセットはdata先頭からの位置で指定する
+pub fn send_ethernet_packet_with_offload(dst_mac_addr: [u8; 6], data: DmaBox<[u8]>, len: usize, protocol: u16, offload: TxOffload) -> Result<(), String> {
+    send_vlan_ethernet_packet(dst_mac_addr, None, data, len, protocol, offload)
+}
+
+// vlanを指定した場合はタグを付けて送る。NICが挿入できるならNICに任せる
+pub fn send_vlan_ethernet_packet(dst_mac_addr: [u8; 6], vlan: Option<VlanTag>, data: DmaBox<[u8]>, len: usize, protocol: u16, offload: TxOffload) -> Result<(), String> {
     let src_mac_addr = get_mac_addr();
+    let mut offload = offload;
+    let mut header_len = ETHERNET_HEADER_LEN;
+    let mut tag = None;
+    if let Some(vlan) = vlan {
+        if get_nic_features().vlan_insert {
+            offload.vlan_tci = Some(vlan.to_tci());
+        } else {
+            tag = Some(vlan);
+            header_len += VLAN_TAG_LEN;
+        }
+    }
     let ethernet_hdr = EthernetHdr {
         dst_mac_addr,
         src_mac_addr,
+        vlan: tag,
         // ether_type: switch_endian16(protocol),
         ether_type: protocol,
         payload: data,
+        rx_meta: RxMeta::none(),
+    };
+    // オフロードもフォルト注入も無ければ、ペイロードをコピーせずにヘッダと並べて送る
+    if !offload.needs_checksum() && offload.vlan_tci.is_none() && !fault::is_tx_fault_enabled() {
+        let mut sg = ScatterGatherList::new();
+        sg.push_vec(ethernet_hdr.header_to_vec(0));
+        sg.push(ethernet_hdr.payload);
+        return e1000_send_sg_packet(sg);
+    }
+    let mut v = ethernet_hdr.to_slice();
+    let offload = match offload.shifted(header_len) {
+        Some(offload) => offload,
+        // ヘッダの分ずらすとNICに渡せる位置に収まらないので、ここで計算する
+        None => offload.calc_checksum_in_software(&mut v[header_len..]),
     };
-    let v = ethernet_hdr.to_slice();
-    e1000_send_packet(v)
+    fault::send_frame(v, offload)
 }
\ No newline at end of file
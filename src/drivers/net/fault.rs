@@ -0,0 +1,299 @@
+use alloc::vec::Vec;
+use alloc::string::String;
+use alloc::collections::vec_deque::VecDeque;
+
+use super::e1000::{e1000_send_packet_with_offload, RxFrame, TxFrame, TxOffload};
+use crate::memory::dma::DmaBox;
+use crate::arch::timer::get_uptime;
+
+#[macro_use]
+use crate::lazy_static;
+use crate::spin::mutex::Mutex;
+
+// NICドライバとethernet層の間に挟むフォールトインジェクション層
+// drop / duplicate / reorder / delay / bit-flip を確率(‰)で発生させる
+// 乱数はseedから決まるので、同じ設定・同じ入力なら毎回同じ結果になる
+
+const RATE_BASE: u32 = 1000; // 確率は1000分率で指定する
+const DEFAULT_SEED: u32 = 0x2545f491;
+const MAX_DELAYED_FRAMES: usize = 64;
+
+#[derive(Copy, Clone, Debug)]
+pub struct FaultConfig {
+    pub rx_enabled: bool,
+    pub tx_enabled: bool,
+    pub seed: u32,
+    pub drop_rate: u16,
+    pub duplicate_rate: u16,
+    pub reorder_rate: u16,
+    pub delay_rate: u16,
+    pub delay_ticks: usize, // timerの割り込み回数(10ms単位)
+    pub corrupt_rate: u16,
+}
+
+impl FaultConfig {
+    pub const fn disabled() -> Self {
+        FaultConfig {
+            rx_enabled: false,
+            tx_enabled: false,
+            seed: DEFAULT_SEED,
+            drop_rate: 0,
+            duplicate_rate: 0,
+            reorder_rate: 0,
+            delay_rate: 0,
+            delay_ticks: 0,
+            corrupt_rate: 0,
+        }
+    }
+
+    fn is_enabled(&self) -> bool {
+        self.rx_enabled || self.tx_enabled
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default)]
+pub struct FaultStats {
+    pub passed: usize,
+    pub dropped: usize,
+    pub duplicated: usize,
+    pub reordered: usize,
+    pub delayed: usize,
+    pub corrupted: usize,
+}
+
+// xorshift32
+struct FaultRng(u32);
+
+impl FaultRng {
+    fn new(seed: u32) -> Self {
+        FaultRng(if seed == 0 { DEFAULT_SEED } else { seed })
+    }
+
+    fn next(&mut self) -> u32 {
+        let mut x = self.0;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.0 = x;
+        x
+    }
+
+    fn hit(&mut self, rate: u16) -> bool {
+        if rate == 0 { return false; }
+        self.next() % RATE_BASE < rate as u32
+    }
+}
+
+// フォールトインジェクション層を通すフレーム
+trait FaultFrame: Clone {
+    fn len(&self) -> usize;
+    fn bytes_mut(&mut self) -> &mut [u8];
+    // 中身を書き換えた後に呼ばれる
+    fn invalidate_checksum(&mut self);
+}
+
+impl FaultFrame for RxFrame {
+    fn len(&self) -> usize { self.data.len() }
+    fn bytes_mut(&mut self) -> &mut [u8] { &mut self.data[..] }
+    // NICが検証したチェックサムの結果は書き換え前のものなので使えない
+    fn invalidate_checksum(&mut self) { self.meta.invalidate_checksum(); }
+}
+
+impl FaultFrame for TxFrame {
+    fn len(&self) -> usize { self.data.len() }
+    fn bytes_mut(&mut self) -> &mut [u8] { &mut self.data[..] }
+    // NICにチェックサムを計算させると壊したフレームが正しいものになってしまうのでオフロードをやめる
+    fn invalidate_checksum(&mut self) {
+        self.offload = TxOffload {
+            vlan_tci: self.offload.vlan_tci,
+            ..TxOffload::none()
+        };
+    }
+}
+
+// 送信・受信それぞれの方向ごとに保留中のフレームを持つ
+struct FaultChannel<F: FaultFrame> {
+    // reorderのために1つだけ後回しにしているフレーム(保留し始めたtick)
+    held: Option<(usize, F)>,
+    // delayされているフレーム(解放するtick)
+    delayed: VecDeque<(usize, F)>,
+}
+
+impl<F: FaultFrame> FaultChannel<F> {
+    fn new() -> Self {
+        FaultChannel {
+            held: None,
+            delayed: VecDeque::new(),
+        }
+    }
+
+    fn clear(&mut self) {
+        self.held = None;
+        self.delayed.clear();
+    }
+
+    fn release_due(&mut self, now: usize, delay_ticks: usize, out: &mut Vec<F>) {
+        while let Some(&(release_at, _)) = self.delayed.front() {
+            if release_at > now { break; }
+            if let Some((_, frame)) = self.delayed.pop_front() {
+                out.push(frame);
+            }
+        }
+        // 次のフレームが来ないままreorder用のフレームが残り続けないようにする
+        let expired = match self.held {
+            Some((held_at, _)) => now >= held_at + delay_ticks.max(1),
+            None => false,
+        };
+        if expired {
+            if let Some((_, frame)) = self.held.take() {
+                out.push(frame);
+            }
+        }
+    }
+
+    // 1フレームを処理して、今すぐ流すフレームを返す
+    fn inject(&mut self, config: &FaultConfig, rng: &mut FaultRng, stats: &mut FaultStats, frame: Option<F>) -> Vec<F> {
+        let now = get_uptime();
+        let mut out: Vec<F> = vec![];
+
+        if let Some(mut frame) = frame {
+            if rng.hit(config.drop_rate) {
+                stats.dropped += 1;
+            } else {
+                if rng.hit(config.corrupt_rate) && frame.len() > 0 {
+                    let bit = rng.next() as usize % (frame.len() * 8);
+                    frame.bytes_mut()[bit / 8] ^= 1 << (bit % 8);
+                    frame.invalidate_checksum();
+                    stats.corrupted += 1;
+                }
+                let mut frames: Vec<F> = vec![];
+                if rng.hit(config.duplicate_rate) {
+                    frames.push(frame.clone());
+                    stats.duplicated += 1;
+                }
+                frames.push(frame);
+                let delay = rng.hit(config.delay_rate);
+                let reorder = rng.hit(config.reorder_rate);
+
+                for f in frames.into_iter() {
+                    if delay && self.delayed.len() < MAX_DELAYED_FRAMES {
+                        self.delayed.push_back((now + config.delay_ticks, f));
+                        stats.delayed += 1;
+                    } else if reorder && self.held.is_none() {
+                        self.held = Some((now, f));
+                        stats.reordered += 1;
+                    } else {
+                        out.push(f);
+                        stats.passed += 1;
+                        // 後回しにしていたフレームはこのフレームの後ろに流す
+                        if let Some((_, held)) = self.held.take() {
+                            out.push(held);
+                        }
+                    }
+                }
+            }
+        }
+
+        self.release_due(now, config.delay_ticks, &mut out);
+        out
+    }
+}
+
+struct FaultInjector {
+    config: FaultConfig,
+    rng: FaultRng,
+    stats: FaultStats,
+    rx: FaultChannel<RxFrame>,
+    tx: FaultChannel<TxFrame>,
+}
+
+impl FaultInjector {
+    fn new(config: FaultConfig) -> Self {
+        FaultInjector {
+            config,
+            rng: FaultRng::new(config.seed),
+            stats: FaultStats::default(),
+            rx: FaultChannel::new(),
+            tx: FaultChannel::new(),
+        }
+    }
+
+    fn inject_rx(&mut self, frame: Option<RxFrame>) -> Vec<RxFrame> {
+        self.rx.inject(&self.config, &mut self.rng, &mut self.stats, frame)
+    }
+
+    fn inject_tx(&mut self, frame: Option<TxFrame>) -> Vec<TxFrame> {
+        self.tx.inject(&self.config, &mut self.rng, &mut self.stats, frame)
+    }
+}
+
+lazy_static! {
+    static ref FAULT_INJECTOR: Mutex<FaultInjector> = Mutex::new(FaultInjector::new(FaultConfig::disabled()));
+}
+
+// 設定を差し替える。乱数はseedから作り直し、保留中のフレームは捨てる
+pub fn set_fault_config(config: FaultConfig) {
+    let mut injector = FAULT_INJECTOR.lock();
+    injector.rx.clear();
+    injector.tx.clear();
+    injector.rng = FaultRng::new(config.seed);
+    injector.stats = FaultStats::default();
+    injector.config = config;
+}
+
+pub fn get_fault_config() -> FaultConfig {
+    FAULT_INJECTOR.lock().config
+}
+
+pub fn get_fault_stats() -> FaultStats {
+    FAULT_INJECTOR.lock().stats
+}
+
+pub fn is_fault_enabled() -> bool {
+    FAULT_INJECTOR.lock().config.is_enabled()
+}
+
+pub fn is_tx_fault_enabled() -> bool {
+    FAULT_INJECTOR.lock().config.tx_enabled
+}
+
+// NICから受け取ったフレームを通して、ethernet層に渡すフレームの一覧を返す
+// 空のフレームを渡した場合もdelayされていたフレームの解放は行う
+pub fn receive_frames(frame: RxFrame) -> Vec<RxFrame> {
+    let frame = if frame.data.is_empty() { None } else { Some(frame) };
+    let mut injector = FAULT_INJECTOR.lock();
+    if !injector.config.rx_enabled {
+        return frame.into_iter().collect();
+    }
+    injector.inject_rx(frame)
+}
+
+// ethernet層から送られてきたフレームを通して、NICに渡す
+pub fn send_frame(frame: DmaBox<[u8]>, offload: TxOffload) -> Result<(), String> {
+    let frames = {
+        let mut injector = FAULT_INJECTOR.lock();
+        if !injector.config.tx_enabled {
+            drop(injector);
+            return e1000_send_packet_with_offload(frame, offload);
+        }
+        injector.inject_tx(Some(TxFrame { data: frame, offload }))
+    };
+    send_all(frames)
+}
+
+// delayされている送信フレームのうち、時間が来たものを送る
+pub fn poll_tx() -> Result<(), String> {
+    let frames = {
+        let mut injector = FAULT_INJECTOR.lock();
+        if !injector.config.tx_enabled { return Ok(()); }
+        injector.inject_tx(None)
+    };
+    send_all(frames)
+}
+
+fn send_all(frames: Vec<TxFrame>) -> Result<(), String> {
+    for frame in frames.into_iter() {
+        e1000_send_packet_with_offload(frame.data, frame.offload)?;
+    }
+    Ok(())
+}
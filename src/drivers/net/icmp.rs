@@ -5,11 +5,17 @@ use crate::memory::dma::DmaBox;
 use crate::memory::volatile::{write_mem};
 
 use super::ip::{send_ip_packet, reply_ip_packet, IpProtocol};
+use super::net_util::calc_internet_checksum;
+use super::net_stats::{count_rx_drop, RxDropReason};
+use alloc::borrow::ToOwned;
 
 use crate::arch::graphic::{Graphic, Printer, print_str};
 use core::fmt::Write;
 use crate::EthernetHdr;
 
+const ICMP_HEADER_LEN: usize = 4;
+const ICMP_ECHO_HEADER_LEN: usize = 8;
+
 #[repr(u8)]
 #[derive(Copy, Clone)]
 enum IcmpEchoType {
@@ -62,6 +68,18 @@ impl IcmpHeader {
         else { IcmpEchoType::Traceroute }
     }
 
+    // 受信したICMPメッセージの長さとチェックサムを確認する
+    fn validate(buf: &[u8]) -> Result<(), RxDropReason> {
+        if buf.len() < ICMP_HEADER_LEN { return Err(RxDropReason::IcmpTooShort); }
+        let required_len = match Self::check_type(buf[0]) {
+            IcmpEchoType::EchoMessage | IcmpEchoType::EchoReplyMessage => ICMP_ECHO_HEADER_LEN,
+            _ => ICMP_HEADER_LEN,
+        };
+        if buf.len() < required_len { return Err(RxDropReason::IcmpTooShort); }
+        if calc_internet_checksum(buf) != 0 { return Err(RxDropReason::IcmpBadChecksum); }
+        Ok(())
+    }
+
     fn parse_from_buf(buf: &[u8]) -> IcmpHeader {
         IcmpHeader {
             icmp_type: Self::check_type(buf[0]),
@@ -140,7 +158,7 @@ impl EchoMessage {
             icmp_header: IcmpHeader::parse_from_buf(&buf[0..=3]),
             identifier: (buf[4] as u16) << 8 | (buf[5] as u16),
             sequence_num: (buf[6] as u16) << 8 | (buf[7] as u16),
-            data: DmaBox::from(&buf[8..&buf.len() - 4]),
+            data: DmaBox::from(&buf[ICMP_ECHO_HEADER_LEN..]),
         }
     }
 }
@@ -154,7 +172,12 @@ pub fn send_icmp(dst_ip_addr: &[u8; 4]) -> Result<(), String> {
 }
 
 pub fn receive_icmp(parsed_ethernet_header: EthernetHdr) -> Result<(), String> {
-    let parsed_ip_header = IpHdr::parsed_from_buf(parsed_ethernet_header.get_data());
+    let parsed_ip_header = IpHdr::parsed_from_buf(parsed_ethernet_header.get_data())
+        .map_err(|reason| reason.name().to_owned())?;
+    if let Err(reason) = IcmpHeader::validate(&parsed_ip_header.get_data()) {
+        count_rx_drop(reason);
+        return Err(reason.name().to_owned());
+    }
     match IcmpHeader::check_type_from_payload(parsed_ip_header.get_data()) {
         IcmpEchoType::EchoMessage => {
             let echo_message = EchoMessage::parse_from_buf(parsed_ip_header.get_data());
@@ -0,0 +1,239 @@
+use alloc::vec::Vec;
+use alloc::string::String;
+use alloc::borrow::ToOwned;
+
+use super::e1000::set_multicast_table;
+use super::ip::{IpHdr, IpProtocol, send_ip_packet_with_ttl};
+use super::net_util::{calc_internet_checksum, is_multicast_ip_addr, multicast_mac_addr};
+use super::net_stats::{count_rx_drop, RxDropReason};
+use crate::memory::dma::DmaBox;
+use crate::arch::timer::get_uptime;
+
+#[macro_use]
+use crate::lazy_static;
+use crate::spin::mutex::Mutex;
+
+// IGMPv2(RFC 2236)
+// ルーターからのQueryにはmax resp timeまでのランダムな時間の後にReportを返す
+// Router Alertオプションは付けない(IpHdrがオプションに対応していないため)
+
+const IGMP_MESSAGE_LEN: usize = 8;
+const IGMP_TTL: u8 = 1;
+
+const IGMP_TYPE_MEMBERSHIP_QUERY: u8 = 0x11;
+const IGMP_TYPE_V1_MEMBERSHIP_REPORT: u8 = 0x12;
+const IGMP_TYPE_V2_MEMBERSHIP_REPORT: u8 = 0x16;
+const IGMP_TYPE_LEAVE_GROUP: u8 = 0x17;
+
+pub const ALL_HOSTS_GROUP: [u8; 4] = [224, 0, 0, 1];
+const ALL_ROUTERS_GROUP: [u8; 4] = [224, 0, 0, 2];
+const ANY_GROUP: [u8; 4] = [0, 0, 0, 0];
+
+// max resp timeの単位は0.1秒、timerの割り込みは10ms
+const TICKS_PER_RESP_TIME_UNIT: usize = 10;
+// IGMPv1のQueryはmax resp timeが0なので10秒として扱う
+const V1_MAX_RESP_TIME: u8 = 100;
+// 参加時のReportの再送間隔(10秒)
+const UNSOLICITED_REPORT_INTERVAL: usize = 1000;
+
+struct IgmpMessage {
+    igmp_type: u8,
+    max_resp_time: u8,
+    checksum: u16,
+    group_addr: [u8; 4],
+}
+
+impl IgmpMessage {
+    fn new(igmp_type: u8, group_addr: [u8; 4]) -> Self {
+        IgmpMessage {
+            igmp_type,
+            max_resp_time: 0,
+            checksum: 0,
+            group_addr,
+        }
+    }
+
+    fn to_slice(&self) -> DmaBox<[u8]> {
+        let mut s: Vec<u8> = vec![self.igmp_type, self.max_resp_time];
+        s.extend_from_slice(&self.checksum.to_be_bytes());
+        s.extend_from_slice(&self.group_addr);
+        DmaBox::from(&s[..])
+    }
+
+    fn calc_checksum(&mut self) {
+        self.checksum = 0;
+        self.checksum = calc_internet_checksum(&self.to_slice());
+    }
+
+    fn parse_from_buf(buf: &[u8]) -> Result<IgmpMessage, RxDropReason> {
+        if buf.len() < IGMP_MESSAGE_LEN { return Err(RxDropReason::IgmpTooShort); }
+        if calc_internet_checksum(buf) != 0 { return Err(RxDropReason::IgmpBadChecksum); }
+        Ok(IgmpMessage {
+            igmp_type: buf[0],
+            max_resp_time: buf[1],
+            checksum: (buf[2] as u16) << 8 | buf[3] as u16,
+            group_addr: [buf[4], buf[5], buf[6], buf[7]],
+        })
+    }
+}
+
+struct Membership {
+    group_addr: [u8; 4],
+    // join_multicast_groupが呼ばれた回数(0になったらLeaveする)
+    refs: usize,
+    // Reportを送る予定のtick
+    report_at: Option<usize>,
+    // 最後にReportを送ったのが自分ならLeaveを送る
+    last_reporter: bool,
+}
+
+lazy_static! {
+    static ref MEMBERSHIPS: Mutex<Vec<Membership>> = Mutex::new(vec![]);
+    static ref REPORT_DELAY_SEED: Mutex<u32> = Mutex::new(0x1d872b41);
+}
+
+// 0..max_ticksのランダムな待ち時間(xorshift32)
+fn random_delay(max_ticks: usize) -> usize {
+    if max_ticks == 0 { return 0; }
+    let mut seed = REPORT_DELAY_SEED.lock();
+    let mut x = *seed ^ get_uptime() as u32;
+    if x == 0 { x = 0x1d872b41; }
+    x ^= x << 13;
+    x ^= x >> 17;
+    x ^= x << 5;
+    *seed = x;
+    x as usize % max_ticks
+}
+
+// 参加しているグループ宛てのフレームだけをNICに受信させる
+fn update_multicast_filter(memberships: &Vec<Membership>) {
+    let mut mac_addrs: Vec<[u8; 6]> = vec![multicast_mac_addr(&ALL_HOSTS_GROUP)];
+    for membership in memberships.iter() {
+        mac_addrs.push(multicast_mac_addr(&membership.group_addr));
+    }
+    set_multicast_table(&mac_addrs);
+}
+
+fn send_igmp_message(igmp_type: u8, group_addr: [u8; 4], dst_ip_addr: [u8; 4]) -> Result<(), String> {
+    let mut message = IgmpMessage::new(igmp_type, group_addr);
+    message.calc_checksum();
+    send_ip_packet_with_ttl(IpProtocol::Igmp, &dst_ip_addr, IGMP_TTL, message.to_slice())
+}
+
+fn send_report(group_addr: [u8; 4]) -> Result<(), String> {
+    send_igmp_message(IGMP_TYPE_V2_MEMBERSHIP_REPORT, group_addr, group_addr)
+}
+
+pub fn init() {
+    update_multicast_filter(&MEMBERSHIPS.lock());
+}
+
+pub fn join_multicast_group(group_addr: [u8; 4]) -> Result<(), String> {
+    if !is_multicast_ip_addr(&group_addr) { return Err("not a multicast address.".to_owned()); }
+    // all-hostsグループには常に参加している
+    if group_addr == ALL_HOSTS_GROUP { return Ok(()); }
+    {
+        let mut memberships = MEMBERSHIPS.lock();
+        if let Some(membership) = memberships.iter_mut().find(|m| m.group_addr == group_addr) {
+            membership.refs += 1;
+            return Ok(());
+        }
+        memberships.push(Membership {
+            group_addr,
+            refs: 1,
+            report_at: Some(get_uptime() + UNSOLICITED_REPORT_INTERVAL),
+            last_reporter: true,
+        });
+        update_multicast_filter(&memberships);
+    }
+    send_report(group_addr)
+}
+
+pub fn leave_multicast_group(group_addr: [u8; 4]) -> Result<(), String> {
+    if group_addr == ALL_HOSTS_GROUP { return Ok(()); }
+    let send_leave = {
+        let mut memberships = MEMBERSHIPS.lock();
+        let idx = match memberships.iter().position(|m| m.group_addr == group_addr) {
+            Some(idx) => idx,
+            None => return Err("not a member of the group.".to_owned()),
+        };
+        memberships[idx].refs -= 1;
+        if memberships[idx].refs > 0 { return Ok(()); }
+        let membership = memberships.remove(idx);
+        update_multicast_filter(&memberships);
+        membership.last_reporter
+    };
+    if send_leave {
+        send_igmp_message(IGMP_TYPE_LEAVE_GROUP, group_addr, ALL_ROUTERS_GROUP)
+    } else {
+        Ok(())
+    }
+}
+
+pub fn is_member(group_addr: &[u8; 4]) -> bool {
+    if group_addr == &ALL_HOSTS_GROUP { return true; }
+    MEMBERSHIPS.lock().iter().any(|m| &m.group_addr == group_addr)
+}
+
+pub fn get_joined_groups() -> Vec<[u8; 4]> {
+    MEMBERSHIPS.lock().iter().map(|m| m.group_addr).collect()
+}
+
+pub fn receive_igmp(ip_header: &IpHdr) {
+    let message = match IgmpMessage::parse_from_buf(&ip_header.get_data()) {
+        Ok(message) => message,
+        Err(reason) => {
+            count_rx_drop(reason);
+            return;
+        },
+    };
+    let now = get_uptime();
+    let mut memberships = MEMBERSHIPS.lock();
+    match message.igmp_type {
+        IGMP_TYPE_MEMBERSHIP_QUERY => {
+            let max_resp_time = if message.max_resp_time == 0 { V1_MAX_RESP_TIME } else { message.max_resp_time };
+            let max_ticks = max_resp_time as usize * TICKS_PER_RESP_TIME_UNIT;
+            for membership in memberships.iter_mut() {
+                if message.group_addr != ANY_GROUP && message.group_addr != membership.group_addr { continue; }
+                // 既に予定しているReportの方が早ければそれを使う
+                let report_at = now + random_delay(max_ticks);
+                membership.report_at = match membership.report_at {
+                    Some(scheduled) if scheduled <= report_at => Some(scheduled),
+                    _ => Some(report_at),
+                };
+            }
+        },
+        IGMP_TYPE_V1_MEMBERSHIP_REPORT | IGMP_TYPE_V2_MEMBERSHIP_REPORT => {
+            // 他のホストがReportを送ったので自分の分は送らない
+            if let Some(membership) = memberships.iter_mut().find(|m| m.group_addr == message.group_addr) {
+                membership.report_at = None;
+                membership.last_reporter = false;
+            }
+        },
+        _ => {},
+    }
+}
+
+// 送る時間になったReportを送る
+pub fn poll() -> Result<(), String> {
+    let now = get_uptime();
+    let due: Vec<[u8; 4]> = {
+        let mut memberships = MEMBERSHIPS.lock();
+        let mut due = vec![];
+        for membership in memberships.iter_mut() {
+            match membership.report_at {
+                Some(report_at) if report_at <= now => {
+                    membership.report_at = None;
+                    membership.last_reporter = true;
+                    due.push(membership.group_addr);
+                },
+                _ => {},
+            }
+        }
+        due
+    };
+    for group_addr in due.into_iter() {
+        send_report(group_addr)?;
+    }
+    Ok(())
+}
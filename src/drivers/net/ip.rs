@@ -2,11 +2,14 @@ use alloc::borrow::ToOwned;
 use alloc::vec::Vec;
 use alloc::string::String;
 
-use super::e1000::{get_mac_addr, e1000_send_packet};
-use super::net_util::{switch_endian16, any_as_u8_vec, push_to_vec};
+use super::e1000::{get_mac_addr, e1000_send_packet, get_nic_features, ChecksumOffload, HwChecksum, TxOffload};
+use super::net_util::{switch_endian16, any_as_u8_vec, push_to_vec, calc_internet_checksum, is_multicast_ip_addr, multicast_mac_addr};
+use super::igmp;
+use super::net_stats::{count_rx_drop, RxDropReason};
 use crate::memory::dma::DmaBox;
 use crate::arp;
-use crate::drivers::net::ethernet::{send_ethernet_packet, ETHERNET_TYPE_IP, DEFAULT_ETHERNET_ADDRESS, EthernetHdr};
+use super::vlan::{self, VlanTag};
+use crate::drivers::net::ethernet::{send_vlan_ethernet_packet, ETHERNET_TYPE_IP, DEFAULT_ETHERNET_ADDRESS, EthernetHdr};
 use crate::memory::volatile::{read_mem, write_mem};
 
 use crate::arch::graphic::{Graphic, Printer, print_str};
@@ -16,6 +19,11 @@ use crate::arch::asmfunc::jmp_stop;
 
 pub const DEFAULT_MY_IP: [u8; 4] = [192, 168, 56, 103];
 
+const IP_VERSION_4: u8 = 4;
+const IP_HEADER_MIN_LEN: usize = 20;
+const IP_CHECKSUM_OFFSET: u8 = 10;
+const DEFAULT_TTL: u8 = 30;
+
 #[repr(u8)]
 #[derive(Clone, Copy)]
 enum VersionIhl {
@@ -54,22 +62,23 @@ impl VersionIhl {
 #[derive(Clone, Copy)]
 pub enum IpProtocol {
     Icmp = 0x01,
+    Igmp = 0x02,
     Tcp = 0x06,
-    Udp = 0x07,
+    Udp = 0x11,
+    Unknown = 0xff, // 対応していないプロトコル(255は予約済みの番号)
 }
 
 impl IpProtocol {
     fn equals(&self, other: IpProtocol) -> bool {
-        match self {
-            other => true,
-            _ => false,
-        }
+        *self as u8 == other as u8
     }
 
     fn parse(ip_protocol: u8) -> IpProtocol {
         if ip_protocol == IpProtocol::Icmp as u8 { IpProtocol::Icmp }
+        else if ip_protocol == IpProtocol::Igmp as u8 { IpProtocol::Igmp }
         else if ip_protocol == IpProtocol::Tcp as u8 { IpProtocol::Tcp }
-        else { IpProtocol::Udp }
+        else if ip_protocol == IpProtocol::Udp as u8 { IpProtocol::Udp }
+        else { IpProtocol::Unknown }
     }
 }
 
@@ -110,10 +119,38 @@ impl IpHdr {
     pub fn is_tcp(&self) -> bool { self.protocol.equals(IpProtocol::Tcp) }
     pub fn is_udp(&self) -> bool { self.protocol.equals(IpProtocol::Udp) }
     pub fn is_icmp(&self) -> bool { self.protocol.equals(IpProtocol::Icmp) }
+    pub fn is_igmp(&self) -> bool { self.protocol.equals(IpProtocol::Igmp) }
 
-    pub fn parsed_from_buf(buf: DmaBox<[u8]>) -> IpHdr {
-        IpHdr {
-            version_ihl: VersionIhl::parse(buf[0]),
+    pub fn get_src_ip_addr(&self) -> [u8; 4] { self.src_ip_addr }
+    pub fn get_dst_ip_addr(&self) -> [u8; 4] { self.dst_ip_addr }
+
+    // バッファを検証してからパースする
+    // bufはethernetのpayloadなので、末尾にパディングやFCSが付いていることがある
+    pub fn parsed_from_buf(buf: DmaBox<[u8]>) -> Result<IpHdr, RxDropReason> {
+        Self::parse_checked(buf, true)
+    }
+
+    // NICがチェックサムを検証済みの場合はverify_checksumをfalseにする
+    fn parse_checked(buf: DmaBox<[u8]>, verify_checksum: bool) -> Result<IpHdr, RxDropReason> {
+        if buf.len() < IP_HEADER_MIN_LEN { return Err(RxDropReason::IpTooShort); }
+        if buf[0] >> 4 != IP_VERSION_4 { return Err(RxDropReason::IpBadVersion); }
+
+        let header_len = ((buf[0] & 0x0f) as usize) * 4;
+        if header_len < IP_HEADER_MIN_LEN || header_len > buf.len() {
+            return Err(RxDropReason::IpBadHeaderLength);
+        }
+        let total_len = (buf[2] as usize) << 8 | buf[3] as usize;
+        if total_len < header_len || total_len > buf.len() {
+            return Err(RxDropReason::IpBadTotalLength);
+        }
+        if verify_checksum && calc_internet_checksum(&buf[..header_len]) != 0 {
+            return Err(RxDropReason::IpBadChecksum);
+        }
+        if buf[8] == 0 { return Err(RxDropReason::IpTtlExpired); }
+
+        Ok(IpHdr {
+            // オプションは保持しないので、IHLは常に5として扱う
+            version_ihl: VersionIhl::Ip,
             dscp_ecn: buf[1],
             length: (buf[2] as u16) << 8 | buf[3] as u16,
             identifier: (buf[4] as u16) << 8 | buf[5] as u16,
@@ -123,8 +160,8 @@ impl IpHdr {
             checksum: (buf[10] as u16) << 8 | buf[11] as u16,
             src_ip_addr: [buf[12], buf[13], buf[14], buf[15]],
             dst_ip_addr: [buf[16], buf[17], buf[18], buf[19]],
-            payload: DmaBox::from(&buf[20..]),
-        }
+            payload: DmaBox::from(&buf[header_len..total_len]),
+        })
     }
 }
 
@@ -204,6 +241,23 @@ impl IpHdr {
         self.checksum = checksum as u16
     }
 
+    // NICにチェックサムを計算させられる場合はそのためのTxOffloadを返し、できない場合はここで計算する
+    fn prepare_checksum(&mut self) -> TxOffload {
+        if !get_nic_features().tx_checksum {
+            self.calc_checksum();
+            return TxOffload::none();
+        }
+        self.checksum = 0x00;
+        TxOffload {
+            ip_checksum: Some(ChecksumOffload {
+                start: 0,
+                offset: IP_CHECKSUM_OFFSET,
+                end: (IP_HEADER_MIN_LEN - 1) as u16,
+            }),
+            ..TxOffload::none()
+        }
+    }
+
     pub fn calc_length(&mut self) {
         self.length = (20 + self.payload.len()) as u16;
     }
@@ -213,12 +267,69 @@ impl IpHdr {
     }
 }
 
-pub fn send_ip_packet(protocol: IpProtocol, dst_ip_addr: &[u8; 4], payload: DmaBox<[u8]>) -> Result<(), String> {
+// 受信したIPパケットを検証する。不正なものは理由ごとに数えて捨てる
+// NICがチェックサムを検証していればその結果を使い、していなければソフトウェアで検証する
+pub fn receive_ip_packet(ethernet_header: &EthernetHdr) -> Option<IpHdr> {
+    let verify_checksum = match ethernet_header.get_rx_meta().ip_checksum {
+        HwChecksum::Good => false,
+        HwChecksum::Bad => {
+            count_rx_drop(RxDropReason::IpBadChecksum);
+            return None;
+        },
+        HwChecksum::Unchecked => true,
+    };
+    match IpHdr::parse_checked(ethernet_header.get_data(), verify_checksum) {
+        // 参加していないマルチキャストグループ宛のものはNICのMTAのハッシュが衝突して届いたもの
+        Ok(ip_header) if is_multicast_ip_addr(&ip_header.dst_ip_addr) && !igmp::is_member(&ip_header.dst_ip_addr) => {
+            count_rx_drop(RxDropReason::IpNotJoinedGroup);
+            None
+        },
+        Ok(ip_header) => Some(ip_header),
+        Err(reason) => {
+            count_rx_drop(reason);
+            None
+        },
+    }
+}
+
+// インターフェースに設定されているIPアドレス(vlanがNoneなら物理NIC)
+pub fn get_my_ip_addr(vlan: Option<VlanTag>) -> Option<[u8; 4]> {
+    if let Some(tag) = vlan {
+        return vlan::get_vlan_interface(tag.vid).map(|interface| interface.get_ip_addr());
+    }
     let (_, my_ip_addr) = match arp::get_my_hard_and_ip_addr() {
         (hardware_addr, Some(ip_addr)) => (hardware_addr, ip_addr),
         (hardware_addr, None) => (hardware_addr, DEFAULT_MY_IP),
         _ => (DEFAULT_ETHERNET_ADDRESS, DEFAULT_MY_IP),
     };
+    Some(my_ip_addr)
+}
+
+pub fn send_ip_packet(protocol: IpProtocol, dst_ip_addr: &[u8; 4], payload: DmaBox<[u8]>) -> Result<(), String> {
+    send_vlan_ip_packet(None, protocol, dst_ip_addr, payload)
+}
+
+// vidを指定した場合はそのVLANサブインターフェースから送信する
+pub fn send_vlan_ip_packet(vid: Option<u16>, protocol: IpProtocol, dst_ip_addr: &[u8; 4], payload: DmaBox<[u8]>) -> Result<(), String> {
+    let vlan = match vid {
+        Some(vid) => match vlan::get_vlan_interface(vid) {
+            Some(interface) => Some(interface.get_tag()),
+            None => return Err("vlan interface not found.".to_owned()),
+        },
+        None => None,
+    };
+    send_ip_datagram(vlan, protocol, dst_ip_addr, DEFAULT_TTL, payload)
+}
+
+pub fn send_ip_packet_with_ttl(protocol: IpProtocol, dst_ip_addr: &[u8; 4], ttl: u8, payload: DmaBox<[u8]>) -> Result<(), String> {
+    send_ip_datagram(None, protocol, dst_ip_addr, ttl, payload)
+}
+
+fn send_ip_datagram(vlan: Option<VlanTag>, protocol: IpProtocol, dst_ip_addr: &[u8; 4], ttl: u8, payload: DmaBox<[u8]>) -> Result<(), String> {
+    let my_ip_addr = match get_my_ip_addr(vlan) {
+        Some(ip_addr) => ip_addr,
+        None => return Err("vlan interface not found.".to_owned()),
+    };
     let mut ip = IpHdr::new();
     write_mem!(
         &mut ip as *mut IpHdr,
@@ -228,7 +339,7 @@ pub fn send_ip_packet(protocol: IpProtocol, dst_ip_addr: &[u8; 4], payload: DmaB
             length: 0x00,
             identifier: 0x00,
             flag_flagment_offset: 0x00,
-            ttl: 30,
+            ttl,
             protocol,
             checksum: 0x00,
             src_ip_addr: my_ip_addr,
@@ -236,10 +347,11 @@ pub fn send_ip_packet(protocol: IpProtocol, dst_ip_addr: &[u8; 4], payload: DmaB
             payload,
     });
     ip.calc_length();
-    ip.calc_checksum();
+    let offload = ip.prepare_checksum();
 
     // dst_mac_addrをdst_ipからARP_TABLEから取得 or ARPで取得する
     let dst_mac_addr = match protocol {
+        _ if is_multicast_ip_addr(dst_ip_addr) => multicast_mac_addr(dst_ip_addr),
         IpProtocol::Icmp => [0xff, 0xff, 0xff, 0xff, 0xff, 0xff],
         _ => {
             match arp::get_hardware_addr_from_ip_addr(dst_ip_addr) {
@@ -251,15 +363,19 @@ pub fn send_ip_packet(protocol: IpProtocol, dst_ip_addr: &[u8; 4], payload: DmaB
     let data = ip.to_slice();
 
     let len = data.len();
-    send_ethernet_packet(dst_mac_addr, data, len, ETHERNET_TYPE_IP)
+    send_vlan_ethernet_packet(dst_mac_addr, vlan, data, len, ETHERNET_TYPE_IP, offload)
 }
 
 pub fn reply_ip_packet(sent_ethernet_header: EthernetHdr, payload: DmaBox<[u8]>) -> Result<(), String> {
-    let sent_ip_header = IpHdr::parsed_from_buf(sent_ethernet_header.get_data());
-    let (_, my_ip_addr) = match arp::get_my_hard_and_ip_addr() {
-        (hardware_addr, Some(ip_addr)) => (hardware_addr, ip_addr),
-        (hardware_addr, None) => (hardware_addr, DEFAULT_MY_IP),
-        _ => (DEFAULT_ETHERNET_ADDRESS, DEFAULT_MY_IP),
+    let sent_ip_header = IpHdr::parsed_from_buf(sent_ethernet_header.get_data())
+        .map_err(|reason| reason.name().to_owned())?;
+    // 受信したVLANサブインターフェースから返信する
+    let vlan = sent_ethernet_header.get_vlan()
+        .and_then(|tag| vlan::get_vlan_interface(tag.vid))
+        .map(|interface| interface.get_tag());
+    let my_ip_addr = match get_my_ip_addr(sent_ethernet_header.get_vlan()) {
+        Some(ip_addr) => ip_addr,
+        None => return Ok(()),
     };
     if my_ip_addr != sent_ip_header.dst_ip_addr { return Ok(()); }
 
@@ -281,11 +397,11 @@ pub fn reply_ip_packet(sent_ethernet_header: EthernetHdr, payload: DmaBox<[u8]>)
         }
     );
     reply_ip_header.calc_length();
-    reply_ip_header.calc_checksum();
+    let offload = reply_ip_header.prepare_checksum();
 
     let dst_mac_addr = sent_ethernet_header.get_src_mac_addr();
     let dst_mac_addr = [dst_mac_addr[0], dst_mac_addr[1], dst_mac_addr[2], dst_mac_addr[3], dst_mac_addr[4], dst_mac_addr[5]];
     let data = reply_ip_header.to_slice();
     let len = data.len();
-    send_ethernet_packet(dst_mac_addr, data, len, ETHERNET_TYPE_IP)
+    send_vlan_ethernet_packet(dst_mac_addr, vlan, data, len, ETHERNET_TYPE_IP, offload)
 }
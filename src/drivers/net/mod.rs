@@ -1,6 +1,12 @@
 pub mod e1000;
+pub mod e1000_regs;
 pub mod arp;
 pub mod icmp;
 pub mod ethernet;
 pub mod ip;
 pub mod net_util;
+pub mod fault;
+pub mod net_stats;
+pub mod vlan;
+pub mod igmp;
+pub mod udp;
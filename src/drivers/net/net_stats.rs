@@ -0,0 +1,128 @@
+use core::fmt::Write;
+
+use crate::arch::graphic::{Printer, print_str};
+
+#[macro_use]
+use crate::lazy_static;
+use crate::spin::mutex::Mutex;
+
+// 受信したパケットを捨てた理由
+#[repr(usize)]
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum RxDropReason {
+    EthernetTooShort = 0,
+    VlanUnknownVid,
+    ArpTooShort,
+    IpTooShort,
+    IpBadVersion,
+    IpBadHeaderLength,
+    IpBadTotalLength,
+    IpBadChecksum,
+    IpTtlExpired,
+    IpNotJoinedGroup,
+    IcmpTooShort,
+    IcmpBadChecksum,
+    IgmpTooShort,
+    IgmpBadChecksum,
+    UdpTooShort,
+    UdpBadLength,
+    UdpBadChecksum,
+    UdpNoSocket,
+}
+
+// 全ての理由(カウンタの数と表示する順番はこれで決まる)
+// 理由を追加したらここにも同じ順番で追加する。順番が違うと下の検査でコンパイルできない
+const RX_DROP_REASONS: &[RxDropReason] = &[
+    RxDropReason::EthernetTooShort,
+    RxDropReason::VlanUnknownVid,
+    RxDropReason::ArpTooShort,
+    RxDropReason::IpTooShort,
+    RxDropReason::IpBadVersion,
+    RxDropReason::IpBadHeaderLength,
+    RxDropReason::IpBadTotalLength,
+    RxDropReason::IpBadChecksum,
+    RxDropReason::IpTtlExpired,
+    RxDropReason::IpNotJoinedGroup,
+    RxDropReason::IcmpTooShort,
+    RxDropReason::IcmpBadChecksum,
+    RxDropReason::IgmpTooShort,
+    RxDropReason::IgmpBadChecksum,
+    RxDropReason::UdpTooShort,
+    RxDropReason::UdpBadLength,
+    RxDropReason::UdpBadChecksum,
+    RxDropReason::UdpNoSocket,
+];
+
+const RX_DROP_REASON_NUM: usize = RX_DROP_REASONS.len();
+
+// reason as usizeをカウンタのindexに使うので、RX_DROP_REASONSの順番と値が一致しているか確かめる
+const _: () = {
+    let mut idx = 0;
+    while idx < RX_DROP_REASON_NUM {
+        assert!(RX_DROP_REASONS[idx] as usize == idx, "RX_DROP_REASONS is out of order.");
+        idx += 1;
+    }
+};
+
+impl RxDropReason {
+    pub fn name(&self) -> &'static str {
+        match self {
+            RxDropReason::EthernetTooShort => "ETHER_TOO_SHORT",
+            RxDropReason::VlanUnknownVid => "VLAN_UNKNOWN_VID",
+            RxDropReason::ArpTooShort => "ARP_TOO_SHORT",
+            RxDropReason::IpTooShort => "IP_TOO_SHORT",
+            RxDropReason::IpBadVersion => "IP_BAD_VERSION",
+            RxDropReason::IpBadHeaderLength => "IP_BAD_HDR_LEN",
+            RxDropReason::IpBadTotalLength => "IP_BAD_TOTAL_LEN",
+            RxDropReason::IpBadChecksum => "IP_BAD_CHECKSUM",
+            RxDropReason::IpTtlExpired => "IP_TTL_EXPIRED",
+            RxDropReason::IpNotJoinedGroup => "IP_NOT_JOINED_GROUP",
+            RxDropReason::IcmpTooShort => "ICMP_TOO_SHORT",
+            RxDropReason::IcmpBadChecksum => "ICMP_BAD_CHECKSUM",
+            RxDropReason::IgmpTooShort => "IGMP_TOO_SHORT",
+            RxDropReason::IgmpBadChecksum => "IGMP_BAD_CHECKSUM",
+            RxDropReason::UdpTooShort => "UDP_TOO_SHORT",
+            RxDropReason::UdpBadLength => "UDP_BAD_LENGTH",
+            RxDropReason::UdpBadChecksum => "UDP_BAD_CHECKSUM",
+            RxDropReason::UdpNoSocket => "UDP_NO_SOCKET",
+        }
+    }
+}
+
+lazy_static! {
+    static ref RX_DROP_COUNTS: Mutex<[usize; RX_DROP_REASON_NUM]> = Mutex::new([0; RX_DROP_REASON_NUM]);
+}
+
+// RX_DROP_REASONSに追加し忘れた理由は数えない
+pub fn count_rx_drop(reason: RxDropReason) {
+    if let Some(count) = RX_DROP_COUNTS.lock().get_mut(reason as usize) {
+        *count += 1;
+    }
+}
+
+pub fn get_rx_drop_count(reason: RxDropReason) -> usize {
+    RX_DROP_COUNTS.lock().get(reason as usize).copied().unwrap_or(0)
+}
+
+pub fn get_rx_drop_total() -> usize {
+    RX_DROP_COUNTS.lock().iter().sum()
+}
+
+pub fn clear_rx_drop_counts() {
+    *RX_DROP_COUNTS.lock() = [0; RX_DROP_REASON_NUM];
+}
+
+const DUMP_ROWS_PER_COLUMN: usize = 12;
+const DUMP_COLUMN_WIDTH: u32 = 200;
+
+pub fn dump_rx_drop_counts(x: u32, y: u32) {
+    let counts = *RX_DROP_COUNTS.lock();
+    for (idx, reason) in RX_DROP_REASONS.iter().enumerate() {
+        // 画面からはみ出さないように列を分ける
+        let left = x + (idx / DUMP_ROWS_PER_COLUMN) as u32 * DUMP_COLUMN_WIDTH;
+        let height = y + (idx % DUMP_ROWS_PER_COLUMN) as u32 * 15;
+        print_str(left, height, reason.name(), 0);
+        let mut printer = Printer::new(left + 160, height, 0);
+        write!(printer, "{:?}", counts[*reason as usize]).unwrap();
+    }
+}
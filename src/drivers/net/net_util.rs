@@ -43,3 +43,32 @@ pub fn push_to_vec(mut src: Vec<u8>, mut dst: Vec<u8>) -> Vec<u8> {
     }
     dst
 }
+
+
+// インターネットチェックサム(RFC 1071)
+// 受信したヘッダをチェックサムフィールドごと計算して0になれば正しい
+pub fn calc_internet_checksum(buf: &[u8]) -> u16 {
+    let mut sum: u32 = 0;
+    for chunk in buf.chunks(2) {
+        let word = if chunk.len() == 2 {
+            (chunk[0] as u32) << 8 | chunk[1] as u32
+        } else {
+            (chunk[0] as u32) << 8
+        };
+        sum += word;
+    }
+    while sum >> 16 != 0 {
+        sum = (sum & 0x0000ffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+// 224.0.0.0/4
+pub fn is_multicast_ip_addr(ip_addr: &[u8; 4]) -> bool {
+    ip_addr[0] & 0xf0 == 0xe0
+}
+
+// IPv4マルチキャストアドレスの下位23bitを01:00:5e:00:00:00に載せたMACアドレス(RFC 1112)
+pub fn multicast_mac_addr(ip_addr: &[u8; 4]) -> [u8; 6] {
+    [0x01, 0x00, 0x5e, ip_addr[1] & 0x7f, ip_addr[2], ip_addr[3]]
+}
@@ -0,0 +1,232 @@
+use alloc::vec::Vec;
+use alloc::string::String;
+use alloc::borrow::ToOwned;
+use alloc::collections::vec_deque::VecDeque;
+
+use super::e1000::HwChecksum;
+use super::ethernet::EthernetHdr;
+use super::igmp;
+use super::ip::{IpHdr, IpProtocol, send_ip_packet, get_my_ip_addr};
+use super::net_util::{calc_internet_checksum, is_multicast_ip_addr};
+use super::net_stats::{count_rx_drop, RxDropReason};
+use crate::memory::dma::DmaBox;
+
+#[macro_use]
+use crate::lazy_static;
+use crate::spin::mutex::Mutex;
+
+pub const UDP_HEADER_LEN: usize = 8;
+
+const MAX_UDP_SOCKETS: usize = 16;
+// ソケットごとに溜めておく受信データグラムの数(超えたら古いものから捨てる)
+const MAX_QUEUED_DATAGRAMS: usize = 32;
+
+// 受信したデータグラム
+pub struct UdpDatagram {
+    pub src_ip_addr: [u8; 4],
+    pub src_port: u16,
+    pub dst_ip_addr: [u8; 4],
+    pub data: Vec<u8>,
+}
+
+struct UdpHdr {
+    src_port: u16,
+    dst_port: u16,
+    length: u16,
+    checksum: u16,
+    payload: DmaBox<[u8]>,
+}
+
+impl UdpHdr {
+    fn to_slice(&self) -> DmaBox<[u8]> {
+        let s: &[u8] = &[
+            &self.src_port.to_be_bytes()[..],
+            &self.dst_port.to_be_bytes()[..],
+            &self.length.to_be_bytes()[..],
+            &self.checksum.to_be_bytes()[..],
+            &self.payload[..],
+        ].concat();
+        DmaBox::from(s)
+    }
+
+    // bufはIPのpayload(total_lengthで切り詰め済み)
+    fn parse_from_buf(buf: &[u8]) -> Result<UdpHdr, RxDropReason> {
+        if buf.len() < UDP_HEADER_LEN { return Err(RxDropReason::UdpTooShort); }
+        let length = (buf[4] as usize) << 8 | buf[5] as usize;
+        if length < UDP_HEADER_LEN || length > buf.len() { return Err(RxDropReason::UdpBadLength); }
+        Ok(UdpHdr {
+            src_port: (buf[0] as u16) << 8 | buf[1] as u16,
+            dst_port: (buf[2] as u16) << 8 | buf[3] as u16,
+            length: length as u16,
+            checksum: (buf[6] as u16) << 8 | buf[7] as u16,
+            payload: DmaBox::from(&buf[UDP_HEADER_LEN..length]),
+        })
+    }
+
+    fn calc_checksum(&mut self, src_ip_addr: &[u8; 4], dst_ip_addr: &[u8; 4]) {
+        self.checksum = 0;
+        let checksum = calc_pseudo_header_checksum(src_ip_addr, dst_ip_addr, &self.to_slice());
+        // 0は「チェックサム無し」の意味なので0xffffにする
+        self.checksum = if checksum == 0 { 0xffff } else { checksum };
+    }
+}
+
+// 疑似ヘッダ(送信元IP, 宛先IP, 0, プロトコル番号, UDP長)を含めたチェックサム
+fn calc_pseudo_header_checksum(src_ip_addr: &[u8; 4], dst_ip_addr: &[u8; 4], udp: &[u8]) -> u16 {
+    let length = udp.len() as u16;
+    let buf: &[u8] = &[
+        &src_ip_addr[..],
+        &dst_ip_addr[..],
+        &[0x00, IpProtocol::Udp as u8][..],
+        &length.to_be_bytes()[..],
+        udp,
+    ].concat();
+    calc_internet_checksum(buf)
+}
+
+struct UdpSocketEntry {
+    port: u16,
+    // このソケットが参加しているマルチキャストグループ
+    groups: Vec<[u8; 4]>,
+    queue: VecDeque<UdpDatagram>,
+}
+
+lazy_static! {
+    static ref UDP_SOCKETS: Mutex<Vec<UdpSocketEntry>> = Mutex::new(vec![]);
+}
+
+// ポートにbindしたソケット。dropするとunbindしてマルチキャストグループからも抜ける
+pub struct UdpSocket {
+    port: u16,
+}
+
+impl UdpSocket {
+    pub fn bind(port: u16) -> Result<UdpSocket, String> {
+        if port == 0 { return Err("invalid port.".to_owned()); }
+        let mut sockets = UDP_SOCKETS.lock();
+        if sockets.iter().any(|entry| entry.port == port) { return Err("port already in use.".to_owned()); }
+        if sockets.len() >= MAX_UDP_SOCKETS { return Err("too many udp sockets.".to_owned()); }
+        sockets.push(UdpSocketEntry {
+            port,
+            groups: vec![],
+            queue: VecDeque::new(),
+        });
+        Ok(UdpSocket { port })
+    }
+
+    pub fn get_port(&self) -> u16 {
+        self.port
+    }
+
+    pub fn join_multicast_group(&self, group_addr: [u8; 4]) -> Result<(), String> {
+        {
+            let mut sockets = UDP_SOCKETS.lock();
+            let entry = match sockets.iter_mut().find(|entry| entry.port == self.port) {
+                Some(entry) => entry,
+                None => return Err("socket not found.".to_owned()),
+            };
+            if entry.groups.contains(&group_addr) { return Ok(()); }
+            entry.groups.push(group_addr);
+        }
+        igmp::join_multicast_group(group_addr)
+    }
+
+    pub fn leave_multicast_group(&self, group_addr: [u8; 4]) -> Result<(), String> {
+        {
+            let mut sockets = UDP_SOCKETS.lock();
+            let entry = match sockets.iter_mut().find(|entry| entry.port == self.port) {
+                Some(entry) => entry,
+                None => return Err("socket not found.".to_owned()),
+            };
+            match entry.groups.iter().position(|g| g == &group_addr) {
+                Some(idx) => { entry.groups.remove(idx); },
+                None => return Err("not a member of the group.".to_owned()),
+            }
+        }
+        igmp::leave_multicast_group(group_addr)
+    }
+
+    pub fn recv_from(&self) -> Option<UdpDatagram> {
+        let mut sockets = UDP_SOCKETS.lock();
+        sockets.iter_mut().find(|entry| entry.port == self.port).and_then(|entry| entry.queue.pop_front())
+    }
+
+    pub fn send_to(&self, dst_ip_addr: &[u8; 4], dst_port: u16, data: &[u8]) -> Result<(), String> {
+        let src_ip_addr = match get_my_ip_addr(None) {
+            Some(ip_addr) => ip_addr,
+            None => return Err("ip address is not configured.".to_owned()),
+        };
+        let mut udp = UdpHdr {
+            src_port: self.port,
+            dst_port,
+            length: (UDP_HEADER_LEN + data.len()) as u16,
+            checksum: 0,
+            payload: DmaBox::from(data),
+        };
+        udp.calc_checksum(&src_ip_addr, dst_ip_addr);
+        send_ip_packet(IpProtocol::Udp, dst_ip_addr, udp.to_slice())
+    }
+}
+
+impl Drop for UdpSocket {
+    fn drop(&mut self) {
+        let groups = {
+            let mut sockets = UDP_SOCKETS.lock();
+            match sockets.iter().position(|entry| entry.port == self.port) {
+                Some(idx) => sockets.remove(idx).groups,
+                None => vec![],
+            }
+        };
+        // dropでは返せないので、Leaveを送れなかった場合はログに残す
+        for group_addr in groups.into_iter() {
+            if let Err(message) = igmp::leave_multicast_group(group_addr) {
+                warn!("failed to leave {:?}: {}", group_addr, message);
+            }
+        }
+    }
+}
+
+// 受信したデータグラムを宛先ポートにbindしているソケットに渡す
+// マルチキャストの場合は、そのグループに参加しているソケットにだけ渡す
+pub fn receive_udp(ethernet_header: &EthernetHdr, ip_header: &IpHdr) {
+    let buf = ip_header.get_data();
+    let udp = match UdpHdr::parse_from_buf(&buf) {
+        Ok(udp) => udp,
+        Err(reason) => {
+            count_rx_drop(reason);
+            return;
+        },
+    };
+    let src_ip_addr = ip_header.get_src_ip_addr();
+    let dst_ip_addr = ip_header.get_dst_ip_addr();
+    // チェックサムが0なら送信元が計算していない
+    if udp.checksum != 0 {
+        let valid = match ethernet_header.get_rx_meta().l4_checksum {
+            HwChecksum::Good => true,
+            HwChecksum::Bad => false,
+            HwChecksum::Unchecked => calc_pseudo_header_checksum(&src_ip_addr, &dst_ip_addr, &buf[..udp.length as usize]) == 0,
+        };
+        if !valid {
+            count_rx_drop(RxDropReason::UdpBadChecksum);
+            return;
+        }
+    }
+
+    let multicast = is_multicast_ip_addr(&dst_ip_addr);
+    let mut sockets = UDP_SOCKETS.lock();
+    let entry = sockets.iter_mut().find(|entry| {
+        entry.port == udp.dst_port && (!multicast || entry.groups.contains(&dst_ip_addr))
+    });
+    match entry {
+        Some(entry) => {
+            if entry.queue.len() >= MAX_QUEUED_DATAGRAMS { entry.queue.pop_front(); }
+            entry.queue.push_back(UdpDatagram {
+                src_ip_addr,
+                src_port: udp.src_port,
+                dst_ip_addr,
+                data: udp.payload.to_vec(),
+            });
+        },
+        None => count_rx_drop(RxDropReason::UdpNoSocket),
+    }
+}
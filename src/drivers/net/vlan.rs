@@ -0,0 +1,126 @@
+use alloc::vec::Vec;
+use alloc::string::String;
+use alloc::borrow::ToOwned;
+
+#[macro_use]
+use crate::lazy_static;
+use crate::spin::mutex::Mutex;
+
+// 802.1Q VLAN
+// 物理NICの上にVIDごとのサブインターフェースを作り、それぞれにIPアドレスを持たせる
+// サブインターフェースが無いVIDのフレームは受信時に捨てる
+
+pub const VLAN_TAG_LEN: usize = 4;
+
+const VLAN_VID_MASK: u16 = 0x0fff;
+const VLAN_DEI_BIT: u16 = 1 << 12;
+const VLAN_PCP_SHIFT: u16 = 13;
+const VLAN_PCP_MAX: u8 = 7;
+// 0はプライオリティタグ用、4095は予約
+const VLAN_VID_MIN: u16 = 1;
+const VLAN_VID_MAX: u16 = 4094;
+const MAX_VLAN_INTERFACES: usize = 16;
+
+// TCI(Tag Control Information)を分解したもの
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct VlanTag {
+    pub pcp: u8,   // Priority Code Point(3bit)
+    pub dei: bool, // Drop Eligible Indicator
+    pub vid: u16,  // VLAN ID(12bit)
+}
+
+impl VlanTag {
+    pub fn new(vid: u16, pcp: u8) -> Self {
+        VlanTag {
+            pcp: pcp & VLAN_PCP_MAX,
+            dei: false,
+            vid: vid & VLAN_VID_MASK,
+        }
+    }
+
+    pub fn from_tci(tci: u16) -> Self {
+        VlanTag {
+            pcp: (tci >> VLAN_PCP_SHIFT) as u8,
+            dei: tci & VLAN_DEI_BIT == VLAN_DEI_BIT,
+            vid: tci & VLAN_VID_MASK,
+        }
+    }
+
+    pub fn to_tci(&self) -> u16 {
+        ((self.pcp & VLAN_PCP_MAX) as u16) << VLAN_PCP_SHIFT
+            | if self.dei { VLAN_DEI_BIT } else { 0 }
+            | (self.vid & VLAN_VID_MASK)
+    }
+
+    // VID 0はプライオリティだけを運ぶタグなので、タグ無しのフレームとして扱う
+    pub fn is_priority_only(&self) -> bool {
+        self.vid == 0
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct VlanInterface {
+    vid: u16,
+    pcp: u8, // 送信時に付けるプライオリティ
+    ip_addr: [u8; 4],
+}
+
+impl VlanInterface {
+    pub fn get_vid(&self) -> u16 { self.vid }
+    pub fn get_ip_addr(&self) -> [u8; 4] { self.ip_addr }
+
+    // このインターフェースから送信するフレームに付けるタグ
+    pub fn get_tag(&self) -> VlanTag {
+        VlanTag::new(self.vid, self.pcp)
+    }
+}
+
+lazy_static! {
+    static ref VLAN_INTERFACES: Mutex<Vec<VlanInterface>> = Mutex::new(vec![]);
+}
+
+pub fn add_vlan_interface(vid: u16, ip_addr: [u8; 4], pcp: u8) -> Result<(), String> {
+    if vid < VLAN_VID_MIN || vid > VLAN_VID_MAX { return Err("invalid vlan id.".to_owned()); }
+    if pcp > VLAN_PCP_MAX { return Err("invalid vlan priority.".to_owned()); }
+    let mut interfaces = VLAN_INTERFACES.lock();
+    if interfaces.iter().any(|interface| interface.vid == vid) {
+        return Err("vlan interface already exists.".to_owned());
+    }
+    if interfaces.len() >= MAX_VLAN_INTERFACES { return Err("too many vlan interfaces.".to_owned()); }
+    interfaces.push(VlanInterface { vid, pcp, ip_addr });
+    Ok(())
+}
+
+pub fn remove_vlan_interface(vid: u16) -> Result<(), String> {
+    let mut interfaces = VLAN_INTERFACES.lock();
+    match interfaces.iter().position(|interface| interface.vid == vid) {
+        Some(idx) => {
+            interfaces.remove(idx);
+            Ok(())
+        },
+        None => Err("vlan interface not found.".to_owned()),
+    }
+}
+
+pub fn set_vlan_ip_addr(vid: u16, ip_addr: [u8; 4]) -> Result<(), String> {
+    let mut interfaces = VLAN_INTERFACES.lock();
+    match interfaces.iter_mut().find(|interface| interface.vid == vid) {
+        Some(interface) => {
+            interface.ip_addr = ip_addr;
+            Ok(())
+        },
+        None => Err("vlan interface not found.".to_owned()),
+    }
+}
+
+pub fn get_vlan_interface(vid: u16) -> Option<VlanInterface> {
+    VLAN_INTERFACES.lock().iter().find(|interface| interface.vid == vid).map(|interface| *interface)
+}
+
+pub fn get_vlan_interfaces() -> Vec<VlanInterface> {
+    VLAN_INTERFACES.lock().clone()
+}
+
+pub fn is_known_vid(vid: u16) -> bool {
+    VLAN_INTERFACES.lock().iter().any(|interface| interface.vid == vid)
+}
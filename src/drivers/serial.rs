@@ -0,0 +1,366 @@
+use core::fmt;
+use alloc::string::String;
+
+use crate::arch::asmfunc::{io_in8, io_out8, io_cli, io_load_eflags, io_store_eflags};
+use crate::arch::pic::{PIC0_IMR, PIC0_OCW2};
+use crate::spin::mutex::Mutex;
+
+// 16550 UART(COM1/COM2)のドライバ
+// 送信はポーリングか、割り込みを有効にした後はTHREの割り込みでリングバッファから送り出す
+// 受信は割り込みを有効にした後はリングバッファに溜め、それまではポーリングで読む
+// 割り込みハンドラと同じロックを取るので、ロックは割り込みを禁止してから取る
+
+const COM1_BASE: u16 = 0x03f8;
+const COM2_BASE: u16 = 0x02f8;
+const COM1_IRQ: u8 = 4;
+const COM2_IRQ: u8 = 3;
+
+const REG_DATA: u16 = 0; // DLAB=1の時はDLL
+const REG_IER: u16 = 1;  // DLAB=1の時はDLM
+const REG_IIR: u16 = 2;  // 読むとIIR、書くとFCR
+const REG_FCR: u16 = 2;
+const REG_LCR: u16 = 3;
+const REG_MCR: u16 = 4;
+const REG_LSR: u16 = 5;
+const REG_SCR: u16 = 7;
+
+const IER_RX_AVAILABLE: u8 = 1 << 0;
+const IER_TX_EMPTY: u8 = 1 << 1;
+const FCR_ENABLE: u8 = 1 << 0;
+const FCR_CLEAR_RX: u8 = 1 << 1;
+const FCR_CLEAR_TX: u8 = 1 << 2;
+const FCR_TRIGGER_14: u8 = 0b11 << 6;
+const LCR_PARITY_ENABLE: u8 = 1 << 3;
+const LCR_PARITY_EVEN: u8 = 1 << 4;
+const LCR_DLAB: u8 = 1 << 7;
+const MCR_DTR: u8 = 1 << 0;
+const MCR_RTS: u8 = 1 << 1;
+const MCR_OUT2: u8 = 1 << 3; // 割り込みをPICに通す
+const MCR_LOOPBACK: u8 = 1 << 4;
+const LSR_DATA_READY: u8 = 1 << 0;
+const LSR_THR_EMPTY: u8 = 1 << 5;
+const IIR_NO_INTERRUPT: u8 = 1 << 0;
+const IIR_ID_MASK: u8 = 0x0e;
+const IIR_LINE_STATUS: u8 = 0x06;
+const IIR_RX_AVAILABLE: u8 = 0x04;
+const IIR_RX_TIMEOUT: u8 = 0x0c;
+const IIR_TX_EMPTY: u8 = 0x02;
+
+const UART_CLOCK: u32 = 115200;
+const FIFO_SIZE: usize = 16;
+const BUFFER_SIZE: usize = 1024;
+// THRが空くのを待つ回数(繋がっていないポートで止まらないように)
+const POLL_TIMEOUT: usize = 100000;
+const LOOPBACK_TEST_BYTE: u8 = 0xae;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Parity {
+    None,
+    Odd,
+    Even,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct LineConfig {
+    pub baud: u32,
+    pub data_bits: u8, // 5~8
+    pub stop_bits: u8, // 1か2
+    pub parity: Parity,
+}
+
+impl LineConfig {
+    // 115200bps 8N1
+    pub const DEFAULT: LineConfig = LineConfig { baud: 115200, data_bits: 8, stop_bits: 1, parity: Parity::None };
+
+    fn divisor(&self) -> Result<u16, String> {
+        if self.baud == 0 || UART_CLOCK % self.baud != 0 || UART_CLOCK / self.baud > 0xffff {
+            return Err(format!("baud rate {} is not supported.", self.baud));
+        }
+        Ok((UART_CLOCK / self.baud) as u16)
+    }
+
+    fn lcr(&self) -> Result<u8, String> {
+        if self.data_bits < 5 || self.data_bits > 8 {
+            return Err(format!("{} data bits is not supported.", self.data_bits));
+        }
+        if self.stop_bits != 1 && self.stop_bits != 2 {
+            return Err(format!("{} stop bits is not supported.", self.stop_bits));
+        }
+        let mut lcr = self.data_bits - 5;
+        if self.stop_bits == 2 { lcr |= 1 << 2; }
+        match self.parity {
+            Parity::None => {},
+            Parity::Odd => lcr |= LCR_PARITY_ENABLE,
+            Parity::Even => lcr |= LCR_PARITY_ENABLE | LCR_PARITY_EVEN,
+        }
+        Ok(lcr)
+    }
+}
+
+struct RingBuffer {
+    buf: [u8; BUFFER_SIZE],
+    head: usize,
+    len: usize,
+}
+
+impl RingBuffer {
+    const fn new() -> Self {
+        RingBuffer { buf: [0; BUFFER_SIZE], head: 0, len: 0 }
+    }
+
+    fn push(&mut self, byte: u8) -> bool {
+        if self.len == BUFFER_SIZE { return false; }
+        self.buf[(self.head + self.len) % BUFFER_SIZE] = byte;
+        self.len += 1;
+        true
+    }
+
+    fn pop(&mut self) -> Option<u8> {
+        if self.len == 0 { return None; }
+        let byte = self.buf[self.head];
+        self.head = (self.head + 1) % BUFFER_SIZE;
+        self.len -= 1;
+        Some(byte)
+    }
+}
+
+struct PortState {
+    initialized: bool,
+    interrupt_driven: bool,
+    tx: RingBuffer,
+    rx: RingBuffer,
+    // rxが一杯で捨てたバイト数
+    rx_dropped: usize,
+}
+
+pub struct SerialPort {
+    base: u16,
+    irq: u8,
+    state: Mutex<PortState>,
+}
+
+pub static COM1: SerialPort = SerialPort::new(COM1_BASE, COM1_IRQ);
+pub static COM2: SerialPort = SerialPort::new(COM2_BASE, COM2_IRQ);
+
+impl SerialPort {
+    const fn new(base: u16, irq: u8) -> Self {
+        SerialPort {
+            base,
+            irq,
+            state: Mutex::new(PortState {
+                initialized: false,
+                interrupt_driven: false,
+                tx: RingBuffer::new(),
+                rx: RingBuffer::new(),
+                rx_dropped: 0,
+            }),
+        }
+    }
+
+    fn read_reg(&self, reg: u16) -> u8 {
+        io_in8((self.base + reg) as i32) as u8
+    }
+
+    fn write_reg(&self, reg: u16, value: u8) {
+        io_out8((self.base + reg) as i32, value);
+    }
+
+    // 割り込みを禁止してロックを取る(割り込みハンドラとのデッドロックを避ける)
+    fn with_state<R>(&self, f: impl FnOnce(&mut PortState) -> R) -> R {
+        let eflags = io_load_eflags();
+        io_cli();
+        let result = f(&mut self.state.lock());
+        io_store_eflags(eflags);
+        result
+    }
+
+    // ボーレートと回線の設定をしてFIFOを有効にする。ループバックで確かめて、ポートが無ければErr
+    pub fn init(&self, config: &LineConfig) -> Result<(), String> {
+        let divisor = config.divisor()?;
+        let lcr = config.lcr()?;
+        self.with_state(|state| {
+            self.write_reg(REG_IER, 0);
+            self.write_reg(REG_LCR, LCR_DLAB);
+            self.write_reg(REG_DATA, (divisor & 0xff) as u8);
+            self.write_reg(REG_IER, (divisor >> 8) as u8);
+            self.write_reg(REG_LCR, lcr);
+            self.write_reg(REG_FCR, FCR_ENABLE | FCR_CLEAR_RX | FCR_CLEAR_TX | FCR_TRIGGER_14);
+
+            self.write_reg(REG_MCR, MCR_LOOPBACK | MCR_RTS);
+            self.write_reg(REG_DATA, LOOPBACK_TEST_BYTE);
+            let echoed = (0..POLL_TIMEOUT).any(|_| self.read_reg(REG_LSR) & LSR_DATA_READY != 0)
+                && self.read_reg(REG_DATA) == LOOPBACK_TEST_BYTE;
+            if !echoed {
+                return Err(format!("no uart at {:x}.", self.base));
+            }
+            self.write_reg(REG_MCR, MCR_DTR | MCR_RTS | MCR_OUT2);
+            state.initialized = true;
+            Ok(())
+        })
+    }
+
+    pub fn is_initialized(&self) -> bool {
+        self.with_state(|state| state.initialized)
+    }
+
+    // 受信の割り込みを有効にして、PICのマスクを外す(送信の割り込みはtxに溜まった時だけ有効にする)
+    // キーボードの初期化でPIC0のマスクが書き換えられるので、その後に呼ぶ
+    pub fn enable_interrupts(&self) -> Result<(), String> {
+        self.with_state(|state| {
+            if !state.initialized {
+                return Err(format!("uart at {:x} is not initialized.", self.base));
+            }
+            state.interrupt_driven = true;
+            self.write_reg(REG_IER, IER_RX_AVAILABLE);
+            let imr = io_in8(PIC0_IMR) as u8;
+            io_out8(PIC0_IMR, imr & !(1 << self.irq));
+            Ok(())
+        })
+    }
+
+    fn wait_thr_empty(&self) -> bool {
+        (0..POLL_TIMEOUT).any(|_| self.read_reg(REG_LSR) & LSR_THR_EMPTY != 0)
+    }
+
+    // FIFOが空いていれば、txに溜まっている分を最大FIFO_SIZEバイト送る。送り切ったらTHREの割り込みを止める
+    fn fill_fifo(&self, state: &mut PortState) {
+        if self.read_reg(REG_LSR) & LSR_THR_EMPTY != 0 {
+            for _ in 0..FIFO_SIZE {
+                match state.tx.pop() {
+                    Some(byte) => self.write_reg(REG_DATA, byte),
+                    None => break,
+                }
+            }
+        }
+        let ier = if state.tx.len > 0 { IER_RX_AVAILABLE | IER_TX_EMPTY } else { IER_RX_AVAILABLE };
+        self.write_reg(REG_IER, ier);
+    }
+
+    // ロックを取った状態で呼ぶ
+    fn write_locked(&self, state: &mut PortState, bytes: &[u8]) {
+        if !state.initialized { return; }
+        for byte in bytes.iter() {
+            if !state.interrupt_driven {
+                if !self.wait_thr_empty() { return; }
+                self.write_reg(REG_DATA, *byte);
+                continue;
+            }
+            // バッファが一杯なら割り込みを待たずに送り出して空ける
+            while !state.tx.push(*byte) {
+                if !self.wait_thr_empty() { return; }
+                self.fill_fifo(state);
+            }
+        }
+        if state.interrupt_driven {
+            self.fill_fifo(state);
+        }
+    }
+
+    pub fn write_bytes(&self, bytes: &[u8]) {
+        self.with_state(|state| self.write_locked(state, bytes))
+    }
+
+    // 例外ハンドラなど、ポートのロックを持っている所に割り込んだかもしれない所から書く
+    // ロックが取れなければ待たずに捨ててfalse
+    pub fn try_write_bytes(&self, bytes: &[u8]) -> bool {
+        let eflags = io_load_eflags();
+        io_cli();
+        let written = match self.state.try_lock() {
+            Some(mut state) => {
+                self.write_locked(&mut state, bytes);
+                true
+            },
+            None => false,
+        };
+        io_store_eflags(eflags);
+        written
+    }
+
+    // 受信したバイト。無ければNone
+    pub fn read_byte(&self) -> Option<u8> {
+        self.with_state(|state| {
+            if !state.initialized { return None; }
+            if state.interrupt_driven { return state.rx.pop(); }
+            if self.read_reg(REG_LSR) & LSR_DATA_READY != 0 {
+                Some(self.read_reg(REG_DATA))
+            } else {
+                None
+            }
+        })
+    }
+
+    // パニックした時に呼ぶ。割り込みを止めてtxに残っている分を送り、以降はポーリングで送る
+    // ロックを持ったままパニックした場合でも書けるように、取れなければ外してから取る
+    pub fn enter_panic_mode(&self) {
+        if self.state.try_lock().is_none() {
+            self.state.force_unlock();
+        }
+        let mut state = self.state.lock();
+        if !state.initialized { return; }
+        self.write_reg(REG_IER, 0);
+        while let Some(byte) = state.tx.pop() {
+            if !self.wait_thr_empty() { break; }
+            self.write_reg(REG_DATA, byte);
+        }
+        state.interrupt_driven = false;
+    }
+
+    pub fn rx_dropped(&self) -> usize {
+        self.with_state(|state| state.rx_dropped)
+    }
+
+    fn handle_interrupt(&self) {
+        let mut state = self.state.lock();
+        loop {
+            let iir = self.read_reg(REG_IIR);
+            if iir & IIR_NO_INTERRUPT != 0 { break; }
+            match iir & IIR_ID_MASK {
+                IIR_RX_AVAILABLE | IIR_RX_TIMEOUT => {
+                    while self.read_reg(REG_LSR) & LSR_DATA_READY != 0 {
+                        let byte = self.read_reg(REG_DATA);
+                        if !state.rx.push(byte) {
+                            state.rx_dropped += 1;
+                        }
+                    }
+                },
+                IIR_TX_EMPTY => self.fill_fifo(&mut state),
+                // LSRを読めば解除される
+                IIR_LINE_STATUS => { self.read_reg(REG_LSR); },
+                _ => break,
+            }
+        }
+    }
+
+    pub fn writer(&'static self) -> SerialWriter {
+        SerialWriter(self)
+    }
+}
+
+// fmt::Writeで書くためのもの(改行はCRLFにする)
+pub struct SerialWriter(&'static SerialPort);
+
+impl fmt::Write for SerialWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for (idx, line) in s.split('\n').enumerate() {
+            if idx > 0 {
+                self.0.write_bytes(b"\r\n");
+            }
+            self.0.write_bytes(line.as_bytes());
+        }
+        Ok(())
+    }
+}
+
+// COM2(IRQ3)
+#[no_mangle]
+pub extern "C" fn inthandler23(esp: *const u32) {
+    io_out8(PIC0_OCW2, 0x60 + COM2_IRQ);
+    COM2.handle_interrupt();
+}
+
+// COM1(IRQ4)
+#[no_mangle]
+pub extern "C" fn inthandler24(esp: *const u32) {
+    io_out8(PIC0_OCW2, 0x60 + COM1_IRQ);
+    COM1.handle_interrupt();
+}
@@ -0,0 +1,51 @@
+use core::fmt::{self, Write};
+
+use crate::arch::paging::{translate, KERNEL_BASE_ADDR};
+use super::symbols::{lookup, Demangle};
+
+// ebpを辿って呼び出し元を調べる(ターゲットの設定でフレームポインタを消さないようにしている)
+// 各フレームは[ebp]に呼び出し元のebp、[ebp+4]に戻り先のアドレスを持つ
+
+pub const BACKTRACE_DEPTH: usize = 16;
+
+// ebpのフレームから順に戻り先のアドレスをreturn_addressesに入れて、入れた数を返す
+// 壊れたスタックでフォルトしないように、マッピングされていないフレームに着いたら止める
+pub fn walk_frames(mut ebp: u32, return_addresses: &mut [u32]) -> usize {
+    let mut count = 0;
+    for return_address in return_addresses.iter_mut() {
+        if ebp < KERNEL_BASE_ADDR || ebp % 4 != 0 || translate(ebp).is_none() || translate(ebp + 4).is_none() {
+            break;
+        }
+        let (next_ebp, address) = unsafe { (*(ebp as *const u32), *((ebp + 4) as *const u32)) };
+        if address == 0 { break; }
+        *return_address = address;
+        count += 1;
+        // 呼び出し元のフレームはスタックの上(大きいアドレス)にある
+        if next_ebp <= ebp { break; }
+        ebp = next_ebp;
+    }
+    count
+}
+
+// 戻り先のアドレスは呼び出し命令の次なので、1つ前で関数を探す(関数の最後がcallの場合に次の関数にならないように)
+pub struct ReturnAddress(pub u32);
+
+impl fmt::Display for ReturnAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:08x}", self.0)?;
+        if let Some(symbol) = lookup(self.0.wrapping_sub(1)) {
+            write!(f, " {}+{:#x}", Demangle(symbol.name), self.0 - symbol.address)?;
+        }
+        Ok(())
+    }
+}
+
+pub fn write_backtrace<W: Write>(w: &mut W, ebp: u32) -> fmt::Result {
+    let mut return_addresses = [0; BACKTRACE_DEPTH];
+    let count = walk_frames(ebp, &mut return_addresses);
+    writeln!(w, "backtrace:")?;
+    for (idx, address) in return_addresses[..count].iter().enumerate() {
+        writeln!(w, "  #{:<2} {}", idx, ReturnAddress(*address))?;
+    }
+    Ok(())
+}
@@ -0,0 +1,104 @@
+use alloc::string::String;
+
+use crate::arch::asmfunc::{io_cli, io_load_eflags, io_store_eflags};
+use crate::arch::dsctbl::has_exception_gate;
+use crate::log::{try_log, Level};
+use crate::spin::mutex::Mutex;
+use super::fixup::apply_fixup;
+use super::frame::ExceptionFrame;
+use super::symbols::SymbolName;
+
+// CPU例外の振り分け
+// 1. ベクタごとに登録されたハンドラを順に呼ぶ(Resumeを返したものがあればそこで戻る)
+// 2. フォルトしてもよい場所(fixup.rs)なら、エラーを返す所から続ける
+// 3. ユーザーのタスクで起きた例外なら、タスクを止めるハンドラ(set_user_fault_handler)に任せる
+// 4. どれでもなければ報告を書いて止める
+// ハンドラから戻ると例外の入口(asmfunc.asm)がframeのレジスタを戻してiretdする
+// フォルトの場合はEIPを変えなければ同じ命令をやり直すので、原因を直すかEIPを変えてからResumeを返す
+
+pub const NUM_OF_EXCEPTIONS: usize = 32;
+const DOUBLE_FAULT: u8 = 0x08;
+const MAX_HANDLERS_PER_VECTOR: usize = 4;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Disposition {
+    Resume, // 処理したので、frameの状態で続ける
+    Pass,   // 処理していないので、次のハンドラに任せる
+}
+
+pub type ExceptionHandler = fn(vector: u8, frame: &mut ExceptionFrame) -> Disposition;
+
+static HANDLERS: Mutex<[[Option<ExceptionHandler>; MAX_HANDLERS_PER_VECTOR]; NUM_OF_EXCEPTIONS]> =
+    Mutex::new([[None; MAX_HANDLERS_PER_VECTOR]; NUM_OF_EXCEPTIONS]);
+static USER_FAULT_HANDLER: Mutex<Option<ExceptionHandler>> = Mutex::new(None);
+
+// 例外の中からも読むので、割り込みを禁止してからロックを取る
+fn without_interrupts<R>(f: impl FnOnce() -> R) -> R {
+    let eflags = io_load_eflags();
+    io_cli();
+    let result = f();
+    io_store_eflags(eflags);
+    result
+}
+
+// IDTにゲートの無い例外や、戻れないダブルフォルト(専用のタスクで処理する)には登録できない
+pub fn register_handler(vector: u8, handler: ExceptionHandler) -> Result<(), String> {
+    if vector as usize >= NUM_OF_EXCEPTIONS {
+        return Err(format!("vector {:x} is not an exception.", vector));
+    }
+    if !has_exception_gate(vector) || vector == DOUBLE_FAULT {
+        return Err(format!("exception {:x} is not dispatched to handlers.", vector));
+    }
+    without_interrupts(|| {
+        let mut handlers = HANDLERS.lock();
+        let slot = handlers[vector as usize].iter_mut().find(|h| h.is_none())
+            .ok_or(format!("too many handlers for vector {:x}.", vector))?;
+        *slot = Some(handler);
+        Ok(())
+    })
+}
+
+pub fn unregister_handler(vector: u8, handler: ExceptionHandler) {
+    if vector as usize >= NUM_OF_EXCEPTIONS { return; }
+    without_interrupts(|| {
+        let mut handlers = HANDLERS.lock();
+        for slot in handlers[vector as usize].iter_mut() {
+            if slot.map_or(false, |h| h as usize == handler as usize) {
+                *slot = None;
+            }
+        }
+    })
+}
+
+// ユーザーのタスク(CS の RPL が 3)で起きた例外を処理するハンドラ
+// タスクを切り替えるもの(スケジューラ)が、フォルトしたタスクだけを止めて別のタスクに切り替えるために設定する
+pub fn set_user_fault_handler(handler: Option<ExceptionHandler>) {
+    without_interrupts(|| *USER_FAULT_HANDLER.lock() = handler);
+}
+
+// 例外から戻って続けられるようにできればtrue
+// ハンドラを登録している最中の例外(ロックが取れない)では登録されたハンドラは呼ばない
+pub fn recover(vector: u8, name: &str, frame: &mut ExceptionFrame) -> bool {
+    let handlers = match HANDLERS.try_lock() {
+        Some(handlers) => handlers.get(vector as usize).copied().unwrap_or([None; MAX_HANDLERS_PER_VECTOR]),
+        None => [None; MAX_HANDLERS_PER_VECTOR],
+    };
+    for handler in handlers.iter().flatten() {
+        if handler(vector, frame) == Disposition::Resume {
+            return true;
+        }
+    }
+    let eip = frame.eip;
+    if let Some(fixup) = apply_fixup(frame) {
+        // ログを書いている途中のフォルトかもしれないので、ロックを待たないtry_logで書く
+        try_log(Level::Warn, module_path!(), format_args!("{} at {} recovered by {}", name, SymbolName(eip), fixup));
+        return true;
+    }
+    if frame.from_user() {
+        let user_fault_handler = USER_FAULT_HANDLER.try_lock().and_then(|h| *h);
+        if let Some(handler) = user_fault_handler {
+            return handler(vector, frame) == Disposition::Resume;
+        }
+    }
+    false
+}
@@ -0,0 +1,69 @@
+use alloc::string::String;
+
+use super::frame::ExceptionFrame;
+
+// フォルトしても止めずにエラーを返すコード(asmfunc.asmのfault_*)の表
+// begin~endの間の命令でフォルトしたら、EIPをfixupに変えて例外から戻る
+// (fixupはフォルトした時のスタックのまま続きを実行して、エラーを返す)
+
+extern "C" {
+    fn fault_copy(dst: *mut u8, src: *const u8, len: usize) -> i32;
+    fn fault_copy_begin();
+    fn fault_copy_end();
+    fn fault_copy_fixup();
+    fn fault_read32(address: *const u32, value: *mut u32) -> i32;
+    fn fault_read32_begin();
+    fn fault_read32_end();
+    fn fault_read32_fixup();
+    fn fault_write32(address: *mut u32, value: u32) -> i32;
+    fn fault_write32_begin();
+    fn fault_write32_end();
+    fn fault_write32_fixup();
+}
+
+struct Fixup {
+    name: &'static str,
+    begin: unsafe extern "C" fn(),
+    end: unsafe extern "C" fn(),
+    fixup: unsafe extern "C" fn(),
+}
+
+// 新しくフォルトしてもよい場所を作ったらここに追加する
+static FIXUPS: [Fixup; 3] = [
+    Fixup { name: "fault_copy", begin: fault_copy_begin, end: fault_copy_end, fixup: fault_copy_fixup },
+    Fixup { name: "fault_read32", begin: fault_read32_begin, end: fault_read32_end, fixup: fault_read32_fixup },
+    Fixup { name: "fault_write32", begin: fault_write32_begin, end: fault_write32_end, fixup: fault_write32_fixup },
+];
+
+// カーネルのEIPが表の範囲に入っていれば、fixupから続けるようにしてその名前を返す
+pub fn apply_fixup(frame: &mut ExceptionFrame) -> Option<&'static str> {
+    if frame.from_user() { return None; }
+    let fixup = FIXUPS.iter().find(|f| f.begin as usize as u32 <= frame.eip && frame.eip < f.end as usize as u32)?;
+    frame.eip = fixup.fixup as usize as u32;
+    Some(fixup.name)
+}
+
+// srcからlen bytesをdstに写す。srcやdstがマッピングされていない(ページフォルト)などでフォルトしたらErr
+// ユーザーから渡されたポインタなど、正しいか分からないアドレスから写す時に使う
+pub fn copy_checked(dst: &mut [u8], src: *const u8) -> Result<(), String> {
+    match unsafe { fault_copy(dst.as_mut_ptr(), src, dst.len()) } {
+        0 => Ok(()),
+        _ => Err(format!("fault while copying from {:x}.", src as usize)),
+    }
+}
+
+// デバイスが無いかもしれないアドレスを読む(デバイスを探す時など)
+pub fn probe_read32(address: u32) -> Result<u32, String> {
+    let mut value = 0;
+    match unsafe { fault_read32(address as *const u32, &mut value) } {
+        0 => Ok(value),
+        _ => Err(format!("fault while reading {:x}.", address)),
+    }
+}
+
+pub fn probe_write32(address: u32, value: u32) -> Result<(), String> {
+    match unsafe { fault_write32(address as *mut u32, value) } {
+        0 => Ok(()),
+        _ => Err(format!("fault while writing {:x}.", address)),
+    }
+}
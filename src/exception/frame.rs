@@ -0,0 +1,84 @@
+use core::fmt;
+use core::mem::size_of;
+
+// asmfunc.asmのEXCEPTION_HANDLERが積んだスタックの形(ハンドラにはこの先頭のアドレスが渡される)
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct ExceptionFrame {
+    // pushad
+    pub edi: u32,
+    pub esi: u32,
+    pub ebp: u32,
+    pub esp_at_pushad: u32, // popadでは使われない
+    pub ebx: u32,
+    pub edx: u32,
+    pub ecx: u32,
+    pub eax: u32,
+    pub ds: u32,
+    pub es: u32,
+    // エラーコードを積まない例外では0
+    pub error_code: u32,
+    // CPUが積んだもの
+    pub eip: u32,
+    pub cs: u32,
+    pub eflags: u32,
+}
+
+impl ExceptionFrame {
+    pub unsafe fn from_esp<'a>(esp: *const usize) -> &'a ExceptionFrame {
+        &*(esp as *const ExceptionFrame)
+    }
+
+    // 書き換えた値は例外から戻る時にレジスタに戻される
+    pub unsafe fn from_esp_mut<'a>(esp: *const usize) -> &'a mut ExceptionFrame {
+        &mut *(esp as *mut ExceptionFrame)
+    }
+
+    pub fn from_user(&self) -> bool {
+        self.cs & 0x3 == 0x3
+    }
+
+    // 例外が起きた時のESP
+    // 特権レベルが変わらない例外ではSSとESPは積まれないので、EFLAGSの次がフォルトした時のESP
+    pub fn fault_esp(&self) -> u32 {
+        let after_eflags = self as *const ExceptionFrame as u32 + size_of::<ExceptionFrame>() as u32;
+        if self.from_user() {
+            unsafe { *(after_eflags as *const u32) }
+        } else {
+            after_eflags
+        }
+    }
+}
+
+impl fmt::Display for ExceptionFrame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "EIP={:08x} CS={:04x} EFLAGS={:08x} err={:x}", self.eip, self.cs, self.eflags, self.error_code)?;
+        writeln!(f, "EAX={:08x} EBX={:08x} ECX={:08x} EDX={:08x}", self.eax, self.ebx, self.ecx, self.edx)?;
+        write!(f, "ESI={:08x} EDI={:08x} EBP={:08x} ESP={:08x} DS={:04x} ES={:04x}",
+               self.esi, self.edi, self.ebp, self.fault_esp(), self.ds, self.es)
+    }
+}
+
+// ページフォルトのエラーコード
+#[derive(Copy, Clone, Debug)]
+pub struct PageFaultError(pub u32);
+
+impl PageFaultError {
+    pub fn present(&self) -> bool { self.0 & 0x1 != 0 } // 0ならページが無い、1なら保護違反
+    pub fn write(&self) -> bool { self.0 & 0x2 != 0 }
+    pub fn user(&self) -> bool { self.0 & 0x4 != 0 }
+    pub fn reserved(&self) -> bool { self.0 & 0x8 != 0 }
+}
+
+impl fmt::Display for PageFaultError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {} {}",
+               if self.present() { "protection-violation" } else { "not-present" },
+               if self.write() { "write" } else { "read" },
+               if self.user() { "user" } else { "kernel" })?;
+        if self.reserved() {
+            write!(f, " reserved-bit")?;
+        }
+        Ok(())
+    }
+}
@@ -1,145 +1,138 @@
-use core::fmt::Write;
+use core::fmt::{self, Write};
 
-use super::Graphic;
-use super::Printer;
 use super::asmfunc;
+use crate::allocator::stats::ScreenWriter;
+use crate::arch::tss;
+use crate::drivers::serial::COM1;
+use crate::memory::vmm::{self, VmRegion};
 
-#[no_mangle]
-pub extern "C" fn non_maskable_interrupt_handler(esp: *const usize) {
-    Graphic::putfont_asc(0, 180, 0, "non_maskable_interrupt_handler!!!!!");
-    let mut printer = Printer::new(0, 200, 0);
-    write!(printer, "{:?}", unsafe { esp.offset(11) }).unwrap();
+pub mod frame;
+pub mod symbols;
+pub mod backtrace;
+pub mod report;
+pub mod fixup;
+pub mod dispatch;
+
+use frame::ExceptionFrame;
+use backtrace::write_backtrace;
+use symbols::SymbolName;
+
+// 例外を振り分けて(dispatch.rs)、続けられなければ報告を書いて止める
+pub fn handle_exception(vector: u8, name: &str, esp: *const usize) {
+    let frame = unsafe { ExceptionFrame::from_esp_mut(esp) };
+    if dispatch::recover(vector, name, frame) {
+        return;
+    }
+    report::report_exception(name, frame, None, None);
+    halt()
+}
+
+pub fn halt() -> ! {
     loop {
         asmfunc::io_hlt();
     }
 }
 
+#[no_mangle]
+pub extern "C" fn non_maskable_interrupt_handler(esp: *const usize) {
+    handle_exception(0x02, "non maskable interrupt", esp);
+}
+
 #[no_mangle]
 pub extern "C" fn overflow_handler(esp: *const usize) {
-    Graphic::putfont_asc(0, 180, 0, "overflow_handler!!!!!");
-    let mut printer = Printer::new(0, 200, 0);
-    write!(printer, "{:?}", unsafe { esp.offset(11) }).unwrap();
-    loop {
-        asmfunc::io_hlt();
-    }
+    handle_exception(0x04, "overflow", esp);
 }
 
 #[no_mangle]
 pub extern "C" fn bounds_check_handler(esp: *const usize) {
-    Graphic::putfont_asc(0, 180, 0, "bounds_check_handler!!!!!");
-    let mut printer = Printer::new(0, 200, 0);
-    write!(printer, "{:?}", unsafe { esp.offset(11) }).unwrap();
-    loop {
-        asmfunc::io_hlt();
-    }
+    handle_exception(0x05, "bounds check", esp);
 }
 
 #[no_mangle]
 pub extern "C" fn undefined_operation_code_instruction_handler(esp: *const usize) {
-    Graphic::putfont_asc(0, 180, 0, "undefined_operation_code_instruction_handler!!!!!");
-    let mut printer = Printer::new(0, 200, 0);
-    write!(printer, "{:?}", unsafe { esp.offset(11) }).unwrap();
-    loop {
-        asmfunc::io_hlt();
-    }
+    handle_exception(0x06, "undefined operation code", esp);
 }
 
 #[no_mangle]
 pub extern "C" fn no_coprocessor_handler(esp: *const usize) {
-    Graphic::putfont_asc(0, 180, 0, "no_coprocessor_handler!!!!!");
-    let mut printer = Printer::new(0, 200, 0);
-    write!(printer, "{:?}", unsafe { esp.offset(11) }).unwrap();
-    loop {
-        asmfunc::io_hlt();
-    }
+    handle_exception(0x07, "no coprocessor", esp);
 }
 
+// ダブルフォルト専用のタスク(asm_inthandler08)から呼ばれる
+// 割り込みゲートではないので、esp[0]がエラーコードで、フォルトした時のレジスタはKERNEL_TSSに保存されている
 #[no_mangle]
 pub extern "C" fn double_fault_handler(esp: *const usize) {
-    Graphic::putfont_asc(0, 180, 0, "double_fault_handler!!!!!");
-    let mut printer = Printer::new(0, 200, 0);
-    write!(printer, "{:?}", unsafe { esp.offset(11) }).unwrap();
-    loop {
-        asmfunc::io_hlt();
+    let (eip, kernel_esp) = tss::kernel_task_state();
+    let fault_address = asmfunc::load_cr2();
+    // スタックのガードページに触れてページフォルトを積めなかった場合
+    // (handle_page_faultなどでVM_REGIONSのロックを持ったままかもしれないので、ロックは待たない)
+    if let Some(stack) = vmm::try_find_stack_guard(fault_address).or_else(|| vmm::try_find_stack_guard(kernel_esp)) {
+        report_stack_overflow(eip, kernel_esp, fault_address, &stack);
+    } else {
+        let error_code = unsafe { *esp } as u32;
+        COM1.enter_panic_mode();
+        let _ = write_double_fault_report(&mut COM1.writer(), error_code, fault_address);
+        let _ = write_double_fault_report(&mut ScreenWriter::new(0, 180), error_code, fault_address);
     }
+    halt()
+}
+
+fn write_double_fault_report<W: Write>(w: &mut W, error_code: u32, fault_address: u32) -> fmt::Result {
+    let task = unsafe { &tss::KERNEL_TSS };
+    writeln!(w, "double fault at {}", SymbolName(task.eip))?;
+    writeln!(w, "EIP={:08x} CS={:04x} EFLAGS={:08x} err={:x} CR2={:08x}", task.eip, task.cs, task.eflags, error_code, fault_address)?;
+    writeln!(w, "EAX={:08x} EBX={:08x} ECX={:08x} EDX={:08x}", task.eax, task.ebx, task.ecx, task.edx)?;
+    writeln!(w, "ESI={:08x} EDI={:08x} EBP={:08x} ESP={:08x}", task.esi, task.edi, task.ebp, task.esp)?;
+    write_backtrace(w, task.ebp)
+}
+
+pub fn report_stack_overflow(eip: u32, esp: u32, fault_address: u32, stack: &VmRegion) {
+    COM1.enter_panic_mode();
+    let _ = write_stack_overflow_report(&mut COM1.writer(), eip, esp, fault_address, stack);
+    let _ = write_stack_overflow_report(&mut ScreenWriter::new(0, 180), eip, esp, fault_address, stack);
+}
+
+fn write_stack_overflow_report<W: Write>(w: &mut W, eip: u32, esp: u32, fault_address: u32, stack: &VmRegion) -> fmt::Result {
+    writeln!(w, "kernel stack overflow at EIP={:x} {}", eip, SymbolName(eip))?;
+    writeln!(w, "ESP={:x} CR2={:x} stack: {} {:x}-{:x}", esp, fault_address, stack.name, stack.start, stack.end())
 }
 
 #[no_mangle]
 pub extern "C" fn invalid_tss_handler(esp: *const usize) {
-    Graphic::putfont_asc(0, 180, 0, "invalid_tss_handler!!!!!");
-    let mut printer = Printer::new(0, 200, 0);
-    write!(printer, "{:?}", unsafe { esp.offset(11) }).unwrap();
-    loop {
-        asmfunc::io_hlt();
-    }
+    handle_exception(0x0a, "invalid tss", esp);
 }
 
 #[no_mangle]
 pub extern "C" fn segment_not_present_handler(esp: *const usize) {
-    Graphic::putfont_asc(0, 180, 0, "segment_not_present_handler!!!!!");
-    let mut printer = Printer::new(0, 200, 0);
-    write!(printer, "{:?}", unsafe { esp.offset(11) }).unwrap();
-    loop {
-        asmfunc::io_hlt();
-    }
+    handle_exception(0x0b, "segment not present", esp);
 }
 
 #[no_mangle]
 pub extern "C" fn stack_segment_fault_handler(esp: *const usize) {
-    Graphic::putfont_asc(0, 180, 0, "stack_segment_fault_handler!!!!!");
-    let mut printer = Printer::new(0, 200, 0);
-    write!(printer, "{:?}", unsafe { esp.offset(11) }).unwrap();
-    loop {
-        asmfunc::io_hlt();
-    }
+    handle_exception(0x0c, "stack segment fault", esp);
 }
 
 #[no_mangle]
 pub extern "C" fn general_protection_error_handler(esp: *const usize) {
-    Graphic::putfont_asc(0, 180, 0, "general_protection_error_handler!!!!!");
-    let mut printer = Printer::new(0, 200, 0);
-    write!(printer, "{:?}", unsafe { esp.offset(11) }).unwrap();
-    loop {
-        asmfunc::io_hlt();
-    }
+    handle_exception(0x0d, "general protection", esp);
 }
 
 #[no_mangle]
 pub extern "C" fn coprocessor_error_handler(esp: *const usize) {
-    Graphic::putfont_asc(0, 180, 0, "coprocessor_error_handler!!!!!");
-    let mut printer = Printer::new(0, 200, 0);
-    write!(printer, "{:?}", unsafe { esp.offset(11) }).unwrap();
-    loop {
-        asmfunc::io_hlt();
-    }
+    handle_exception(0x10, "coprocessor error", esp);
 }
 
 #[no_mangle]
 pub extern "C" fn alignment_check_error_handler(esp: *const usize) {
-    Graphic::putfont_asc(0, 180, 0, "alignment_check_error_handler!!!!!");
-    let mut printer = Printer::new(0, 200, 0);
-    write!(printer, "{:?}", unsafe { esp.offset(11) }).unwrap();
-    loop {
-        asmfunc::io_hlt();
-    }
+    handle_exception(0x11, "alignment check", esp);
 }
 
 #[no_mangle]
 pub extern "C" fn machine_check_handler(esp: *const usize) {
-    Graphic::putfont_asc(0, 180, 0, "machine_check_handler!!!!!");
-    let mut printer = Printer::new(0, 200, 0);
-    write!(printer, "{:?}", unsafe { esp.offset(11) }).unwrap();
-    loop {
-        asmfunc::io_hlt();
-    }
+    handle_exception(0x12, "machine check", esp);
 }
 
 #[no_mangle]
 pub extern "C" fn simd_fpu_exception_handler(esp: *const usize) {
-    Graphic::putfont_asc(0, 180, 0, "simd_fpu_exception_handler!!!!!");
-    let mut printer = Printer::new(0, 200, 0);
-    write!(printer, "{:?}", unsafe { esp.offset(11) }).unwrap();
-    loop {
-        asmfunc::io_hlt();
-    }
+    handle_exception(0x13, "simd fpu exception", esp);
 }
@@ -0,0 +1,61 @@
+use core::fmt::{self, Write};
+use core::panic::PanicInfo;
+
+use crate::allocator::stats::ScreenWriter;
+use crate::arch::asmfunc::load_ebp;
+use crate::drivers::serial::COM1;
+use crate::log::ring::dmesg_on_panic;
+use super::backtrace::write_backtrace;
+use super::frame::{ExceptionFrame, PageFaultError};
+use super::symbols::SymbolName;
+
+// 続けられない例外やパニックの報告を画面とシリアルに書く
+// 止まる直前に呼ぶので、Heapは使わず、シリアルはポーリングで送るように切り替える
+
+// シリアルに書き出すログの行数
+const FATAL_LOG_LINES: usize = 32;
+// 画面に書き出すログの行数
+const FATAL_SCREEN_LOG_LINES: usize = 10;
+
+pub fn write_exception_report<W: Write>(
+    w: &mut W,
+    name: &str,
+    frame: &ExceptionFrame,
+    cr2: Option<u32>,
+    detail: Option<fmt::Arguments>,
+) -> fmt::Result {
+    writeln!(w, "{} in {} at {}", name, if frame.from_user() { "user" } else { "kernel" }, SymbolName(frame.eip))?;
+    writeln!(w, "{}", frame)?;
+    if let Some(cr2) = cr2 {
+        writeln!(w, "CR2={:08x} {}", cr2, PageFaultError(frame.error_code))?;
+    }
+    if let Some(detail) = detail {
+        writeln!(w, "{}", detail)?;
+    }
+    write_backtrace(w, frame.ebp)
+}
+
+// 例外の報告。cr2はページフォルトの場合だけ渡す
+pub fn report_exception(name: &str, frame: &ExceptionFrame, cr2: Option<u32>, detail: Option<fmt::Arguments>) {
+    COM1.enter_panic_mode();
+    let mut writer = COM1.writer();
+    let _ = write_exception_report(&mut writer, name, frame, cr2, detail);
+    let _ = writeln!(writer, "last {} log lines:", FATAL_LOG_LINES);
+    let _ = dmesg_on_panic(&mut writer, FATAL_LOG_LINES);
+    let _ = write_exception_report(&mut ScreenWriter::new(0, 180), name, frame, cr2, detail);
+}
+
+pub fn report_panic(info: &PanicInfo) {
+    COM1.enter_panic_mode();
+    let ebp = load_ebp();
+    let mut writer = COM1.writer();
+    let _ = writeln!(writer, "panic: {}", info);
+    let _ = write_backtrace(&mut writer, ebp);
+    let _ = writeln!(writer, "last {} log lines:", FATAL_LOG_LINES);
+    let _ = dmesg_on_panic(&mut writer, FATAL_LOG_LINES);
+
+    let mut screen = ScreenWriter::new(0, 100);
+    let _ = writeln!(screen, "panic!!!!! {}", info);
+    let _ = write_backtrace(&mut screen, ebp);
+    let _ = dmesg_on_panic(&mut screen, FATAL_SCREEN_LOG_LINES);
+}
@@ -0,0 +1,169 @@
+use core::fmt;
+use core::mem::size_of;
+use core::slice;
+use core::str;
+
+// ビルドの時にkernel.mapから作って埋め込んだシンボル表(src/boot/gensyms.sh)でアドレスを関数名にする
+// 1回目のリンクでは空なので、その場合はいつもNone
+
+extern "C" {
+    static __ksymtab_start: u8;
+    static __ksymtab_end: u8;
+}
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct SymbolEntry {
+    address: u32,
+    size: u32, // 分からない場合(アセンブラのラベル)は0
+    name: u32, // 名前の表の中のオフセット
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct Symbol {
+    pub name: &'static str,
+    pub address: u32,
+    pub offset: u32, // 関数の先頭から
+}
+
+fn table() -> Option<(&'static [SymbolEntry], &'static [u8])> {
+    let start = unsafe { &__ksymtab_start as *const u8 as usize };
+    let end = unsafe { &__ksymtab_end as *const u8 as usize };
+    if end - start < size_of::<u32>() { return None; }
+    let count = unsafe { *(start as *const u32) } as usize;
+    let entries_start = start + size_of::<u32>();
+    let names_start = entries_start + count * size_of::<SymbolEntry>();
+    if names_start > end { return None; }
+    unsafe {
+        Some((
+            slice::from_raw_parts(entries_start as *const SymbolEntry, count),
+            slice::from_raw_parts(names_start as *const u8, end - names_start),
+        ))
+    }
+}
+
+fn name_at(names: &'static [u8], offset: u32) -> &'static str {
+    let names = names.get(offset as usize..).unwrap_or(&[]);
+    let len = names.iter().position(|b| *b == 0).unwrap_or(names.len());
+    str::from_utf8(&names[..len]).unwrap_or("?")
+}
+
+// addressを含む関数(サイズが分からない場合は、address以下で一番近いもの)
+pub fn lookup(address: u32) -> Option<Symbol> {
+    let (entries, names) = table()?;
+    let idx = match entries.binary_search_by_key(&address, |e| e.address) {
+        Ok(idx) => idx,
+        Err(0) => return None,
+        Err(idx) => idx - 1,
+    };
+    let entry = entries[idx];
+    let offset = address - entry.address;
+    if entry.size != 0 && offset >= entry.size {
+        return None;
+    }
+    Some(Symbol { name: name_at(names, entry.name), address: entry.address, offset })
+}
+
+// <関数名+オフセット>。見つからなければ"?"
+pub struct SymbolName(pub u32);
+
+impl fmt::Display for SymbolName {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match lookup(self.0) {
+            Some(symbol) => write!(f, "{}+{:#x}", Demangle(symbol.name), symbol.offset),
+            None => write!(f, "?"),
+        }
+    }
+}
+
+// Rustの(legacyの)マングリングを戻して書く: _ZN4rios9exception4main17h0123456789abcdefE -> rios::exception::main
+// 形が違うものはそのまま書く
+pub struct Demangle<'a>(pub &'a str);
+
+// 長さ付きの名前を順に返す
+fn next_ident(rest: &str) -> Option<(&str, &str)> {
+    let digits = rest.bytes().take_while(|b| b.is_ascii_digit()).count();
+    let len: usize = rest[..digits].parse().ok()?;
+    let rest = &rest[digits..];
+    if rest.len() < len || !rest.is_char_boundary(len) { return None; }
+    Some((&rest[..len], &rest[len..]))
+}
+
+fn is_hash(ident: &str) -> bool {
+    ident.len() == 17 && ident.starts_with('h') && ident[1..].bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+fn is_legacy(inner: &str) -> bool {
+    let mut rest = inner;
+    while !rest.is_empty() {
+        match next_ident(rest) {
+            Some((_, next)) => rest = next,
+            None => return false,
+        }
+    }
+    !inner.is_empty()
+}
+
+fn write_ident(f: &mut fmt::Formatter, ident: &str) -> fmt::Result {
+    let mut rest = if ident.starts_with("_$") { &ident[1..] } else { ident };
+    while !rest.is_empty() {
+        if rest.starts_with("..") {
+            f.write_str("::")?;
+            rest = &rest[2..];
+        } else if rest.starts_with('$') {
+            let end = match rest[1..].find('$') {
+                Some(end) => end + 1,
+                None => return f.write_str(rest),
+            };
+            let escaped = match &rest[1..end] {
+                "SP" => "@",
+                "BP" => "*",
+                "RF" => "&",
+                "LT" => "<",
+                "GT" => ">",
+                "LP" => "(",
+                "RP" => ")",
+                "C" => ",",
+                code if code.starts_with('u') => {
+                    match u32::from_str_radix(&code[1..], 16).ok().and_then(core::char::from_u32) {
+                        Some(ch) => {
+                            write!(f, "{}", ch)?;
+                            rest = &rest[end + 1..];
+                            continue;
+                        },
+                        None => &rest[..end + 1],
+                    }
+                },
+                _ => &rest[..end + 1],
+            };
+            f.write_str(escaped)?;
+            rest = &rest[end + 1..];
+        } else {
+            let len = rest.find(|c| c == '$' || c == '.').unwrap_or(rest.len()).max(1);
+            f.write_str(&rest[..len])?;
+            rest = &rest[len..];
+        }
+    }
+    Ok(())
+}
+
+impl<'a> fmt::Display for Demangle<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let inner = match self.0.strip_prefix("_ZN").and_then(|s| s.strip_suffix('E')) {
+            Some(inner) if is_legacy(inner) => inner,
+            _ => return f.write_str(self.0),
+        };
+        let mut rest = inner;
+        let mut first = true;
+        while let Some((ident, next)) = next_ident(rest) {
+            rest = next;
+            if rest.is_empty() && is_hash(ident) { break; }
+            if !first {
+                f.write_str("::")?;
+            }
+            first = false;
+            write_ident(f, ident)?;
+        }
+        Ok(())
+    }
+}
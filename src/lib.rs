@@ -30,12 +30,15 @@ use alloc::string::ToString;
 #[allow(unused_imports)]
 #[macro_use]
 pub mod arch;
+#[macro_use]
+pub mod log;
 use arch::boot_info::BootInfo;
 use arch::graphic::Graphic;
 use arch::graphic::MouseGraphic;
 use arch::graphic::Printer;
 use arch::asmfunc;
 use arch::dsctbl::DscTbl;
+use arch::tss::DOUBLE_FAULT_STACK_SIZE;
 use arch::keyboard;
 use arch::mouse;
 use arch::timer::{ timer_init, get_uptime };
@@ -51,12 +54,15 @@ use sync::queue;
 #[allow(unused_imports)]
 pub mod allocator;
 use allocator::LockedHeap;
-use allocator::frame_allocator::LockedFrameHeap;
+use allocator::page_source::{PageSource, KernelHeapPages};
+use allocator::frame_allocator::FRAME_ALLOCATOR;
+use allocator::stats::{format_heap_stats, format_leak_report, ScreenWriter};
 
 #[global_allocator]
 static mut ALLOCATOR: LockedHeap = LockedHeap {
     heap: Mutex::new(None),
 };
+static KERNEL_HEAP_PAGES: KernelHeapPages = KernelHeapPages::new();
 
 #[allow(unused_imports)]
 pub mod spin;
@@ -75,10 +81,13 @@ use core::borrow::Borrow;
 pub mod exception;
 
 pub mod drivers;
-use drivers::bus::pci;
-use drivers::net::{e1000, arp, ethernet, net_util, icmp};
+use drivers::bus::{pci, lspci};
+use drivers::net::{e1000, arp, ethernet, net_util, icmp, ip, fault, net_stats, vlan, igmp, udp};
+use drivers::net::fault::FaultConfig;
+use drivers::serial::{self, COM1};
 
 pub mod memory;
+use memory::vmm;
 use memory::dma::{
     init_dma,
     DMA_ALLOCATOR,
@@ -91,16 +100,43 @@ use crate::drivers::net::ethernet::EthernetHdr;
 use crate::drivers::net::ip::IpHdr;
 use crate::drivers::net::icmp::{IcmpHeader, send_icmp, receive_icmp};
 
-fn init_heap() {
-    // let heap_start: usize = 0x00e80000;
-    // let heap_end: usize = 0x3fff0000;
-    let heap_start: usize = 0x00800000; // 物理アドレスは0x00e80000
-    let heap_end: usize = 0x3e970000; // 物理アドレスは0x3fff0000;
+// キーボードの`5`で有効にするフォールトインジェクションの設定
+const FAULT_TEST_CONFIG: FaultConfig = FaultConfig {
+    rx_enabled: true,
+    tx_enabled: true,
+    seed: 0x00c0ffee,
+    drop_rate: 50,
+    duplicate_rate: 20,
+    reorder_rate: 20,
+    delay_rate: 20,
+    delay_ticks: 50,
+    corrupt_rate: 20,
+};
+
+// テスト用ネットワークでtapにtrunkされているVLAN(VID, IPアドレス)
+const VLAN_TEST_INTERFACES: [(u16, [u8; 4]); 2] = [
+    (10, [192, 168, 10, 103]),
+    (20, [192, 168, 20, 103]),
+];
+
+// キーボードの`7`で参加するmDNSのグループとポート
+const MDNS_GROUP: [u8; 4] = [224, 0, 0, 251];
+const MDNS_PORT: u16 = 5353;
 
-    let heap_size: usize = heap_end - heap_start;
+// キーボードの`-`で画面に書き出すログの行数
+const DMESG_SCREEN_LINES: usize = 10;
+
+// trueならカーネルのHeapでレッドゾーン・毒・二重解放の検査と定期的なHeap全体の確認をする(遅くなる)
+const HEAP_DEBUG: bool = false;
+
+fn init_heap() {
+    // Heapは空の状態から始めて、足りなくなったら物理フレームを借りてKERNEL_HEAP_START~にマッピングする
+    unsafe { ALLOCATOR.init_with_page_source(&KERNEL_HEAP_PAGES) };
+    if HEAP_DEBUG {
+        unsafe { ALLOCATOR.enable_debug() }.unwrap();
+    }
     let mut printer = Printer::new(0, 300, 0);
-    write!(printer, "{:x}", heap_size).unwrap();
-    unsafe { ALLOCATOR.init(heap_start, heap_size) };
+    write!(printer, "{:x}", KERNEL_HEAP_PAGES.area().1).unwrap();
 }
 
 #[cfg(not(test))]
@@ -108,20 +144,30 @@ fn init_heap() {
 #[no_mangle]
 pub extern fn init_os(argc: isize, argv: *const *const u8) -> isize {
     pic::init_pic();
-    let dsc_tbl: DscTbl = DscTbl::init_gdt_idt();
+    // ログはCOM1に出す(割り込みはPICのマスクが決まってから有効にするので、それまではポーリングで送る)
+    let serial_result = COM1.init(&serial::LineConfig::DEFAULT);
+    let mut dsc_tbl: DscTbl = DscTbl::init_gdt_idt();
     asmfunc::io_sti();
 
+    // E820のメモリマップから使える物理フレームを調べる(VRAMのアドレスが書き換えられる前に行う)
+    FRAME_ALLOCATOR.init(&BootInfo::new());
+
     let mut page_tmpl_impl_result = unsafe { PageTableImpl::initialize() };
     let mut page_tmpl_impl: PageTableImpl<&LockedHeap> =  if page_tmpl_impl_result.is_err() { panic!("page_tmpl_impl is error.") } else { page_tmpl_impl_result.unwrap() };
     init_paging(page_tmpl_impl);
 
     Graphic::init();
     Graphic::putfont_asc(210, 150, 0, "rio-os, rio-os , rio-os");
+    // Direct Memory Access用のHeapを取得(物理的に連続した領域が必要なのでHeapより先に確保する)
+    init_dma();
+
     init_heap();
     unsafe { set_kernel_table_allocator(&ALLOCATOR) };
-
-    // Direct Memory Access用のHeapを取得
-    init_dma();
+    // ここまでに作ったマッピングを仮想メモリの領域として登録する
+    vmm::init();
+    // ダブルフォルト用のスタック(ガードページ付き)を用意して、専用のタスクで処理するようにする
+    let double_fault_stack = vmm::allocate_kernel_stack(DOUBLE_FAULT_STACK_SIZE, "double fault stack").unwrap();
+    dsc_tbl.init_double_fault_task(double_fault_stack.top);
 
     Graphic::putfont_asc(210, 85, 0, "-1-1-1-1");
     Graphic::putfont_asc(210, 100, 0, "0000");
@@ -131,32 +177,49 @@ pub extern fn init_os(argc: isize, argv: *const *const u8) -> isize {
     Graphic::putfont_asc(210, 175, 0, "rio-os");
     keyboard::allow_pic1_keyboard_int();
     mouse::allow_mouse_init();
+    // allow_pic1_keyboard_intがPIC0のマスクを書き換えるので、その後にCOM1の割り込みを有効にする
+    match serial_result.and_then(|_| COM1.enable_interrupts()) {
+        Ok(_) => info!("serial console on com1"),
+        Err(message) => Graphic::putfont_asc(210, 190, 0, &message),
+    }
 
     let mouse: MouseGraphic = MouseGraphic::new();
     let mouse_state = mouse.init_mouse_cursor(14);
 
     let mut mouse_window: *mut Window = window_manager.create_window(mouse_state.1, mouse_state.2, mouse_state.3, mouse_state.4, mouse_state.0).unwrap();
 
-    pci::dump_vid_did();
-    // pci::dump_command_status();
-    pci::dump_bar();
+    drivers::init();
     // pci::test_nic_set();
     // pci::set_pci_intr_disable();
-    pci::set_bus_master_en();
-    pci::nic_init();
+    for (vid, ip_addr) in VLAN_TEST_INTERFACES.iter() {
+        vlan::add_vlan_interface(*vid, *ip_addr, 0).unwrap();
+    }
+    igmp::init();
     // pci::tx_init();
     // pci::dump_nic_ims();
 
     let mut idx: u32 = 10;
+    let mut mdns_socket: Option<udp::UdpSocket> = None;
 
     loop {
         asmfunc::io_cli();
 
-        let frame = pci::receive_frame();
+        let received_frame = pci::receive_rx_frame();
         let mut printer = Printer::new(10, 30, 0);
-        write!(printer, "{:?}", frame.len()).unwrap();
-        if frame.len() > 0 {
-            let parsed_ethernet_header = EthernetHdr::parse_from_frame(frame);
+        write!(printer, "{:?}", received_frame.data.len()).unwrap();
+        // delayされている送信フレームを送り出す
+        if let Err(message) = fault::poll_tx() {
+            Graphic::putfont_asc(600, 485, 0, &message);
+        }
+        // 送る時間になったIGMPのReportを送る
+        if let Err(message) = igmp::poll() {
+            Graphic::putfont_asc(300, 485, 0, &message);
+        }
+        // 検査を有効にしたHeapが壊れていないか、時々全体を確かめる
+        allocator::debug::poll_heap_walk(unsafe { &[&ALLOCATOR, &DMA_ALLOCATOR] });
+        // フォールトインジェクション層を通したフレームをethernet層で処理する
+        for frame in fault::receive_frames(received_frame) {
+            let parsed_ethernet_header = EthernetHdr::parse_from_rx_frame(frame);
             if let Some(ethernet_header) = parsed_ethernet_header {
                 let mut printer = Printer::new(600, 470, 0);
                 write!(printer, "{:x}", ethernet_header.get_type()).unwrap();
@@ -167,7 +230,7 @@ pub extern fn init_os(argc: isize, argv: *const *const u8) -> isize {
                         let mut printer = Printer::new((idx * 15) as u32, 60, 0);
                         write!(printer, "{:x}", b).unwrap();
                     }
-                    let parsed_arp = arp::receive_arp_packet(ethernet_header.get_data());
+                    let parsed_arp = arp::receive_arp_packet(ethernet_header.get_data(), ethernet_header.get_vlan());
                     match parsed_arp {
                         Some(arp) => {
                             for (idx, b) in arp.get_mac_addr().iter().enumerate() {
@@ -180,17 +243,29 @@ pub extern fn init_os(argc: isize, argv: *const *const u8) -> isize {
                 }
                 // ipだった場合の処理を書く
                 if ethernet_header.is_ip_type() {
-                    let parsed_ip_header = IpHdr::parsed_from_buf(ethernet_header.get_data());
-                    // icmpだった場合の処理を書く
-                    if parsed_ip_header.is_icmp() {
-                        receive_icmp(ethernet_header);
+                    // 不正なパケットはreceive_ip_packetの中で数えて捨てる
+                    if let Some(parsed_ip_header) = ip::receive_ip_packet(&ethernet_header) {
+                        // icmpだった場合の処理を書く
+                        if parsed_ip_header.is_icmp() {
+                            receive_icmp(ethernet_header);
+                        } else if parsed_ip_header.is_igmp() {
+                            igmp::receive_igmp(&parsed_ip_header);
+                        } else if parsed_ip_header.is_udp() {
+                            udp::receive_udp(&ethernet_header, &parsed_ip_header);
+                        }
+                        // tcpだった場合
                     }
-                    // tcpだった場合
-                    // udpだった場合
                 }
             }
         }
 
+        if let Some(socket) = &mdns_socket {
+            while let Some(datagram) = socket.recv_from() {
+                let mut writer = ScreenWriter::new(300, 515);
+                let _ = write!(writer, "{:?} {:?}", datagram.src_ip_addr, datagram.data.len());
+            }
+        }
+
         if !keyboard::is_existing() && !mouse::is_existing() {
             asmfunc::io_stihlt();
             continue;
@@ -208,6 +283,63 @@ pub extern fn init_os(argc: isize, argv: *const *const u8) -> isize {
                     if data == 4 {
                         icmp::send_icmp(&[192, 168, 56, 102]);
                         // icmp::send_icmp(&[8, 8, 8, 8]);
+                    } else if data == 5 {
+                        // フォールトインジェクションのOn/Off
+                        if fault::is_fault_enabled() {
+                            fault::set_fault_config(FaultConfig::disabled());
+                            Graphic::putfont_asc(600, 500, 0, "fault injection off");
+                        } else {
+                            fault::set_fault_config(FAULT_TEST_CONFIG);
+                            Graphic::putfont_asc(600, 500, 0, "fault injection on");
+                        }
+                    } else if data == 6 {
+                        // 受信時に捨てたパケットの数を理由ごとに表示
+                        net_stats::dump_rx_drop_counts(600, 520);
+                    } else if data == 7 {
+                        // mDNSのグループへの参加/離脱(dropでグループから抜ける)
+                        if mdns_socket.is_some() {
+                            mdns_socket = None;
+                            Graphic::putfont_asc(300, 500, 0, "left mdns group");
+                        } else {
+                            let joined = udp::UdpSocket::bind(MDNS_PORT)
+                                .and_then(|socket| socket.join_multicast_group(MDNS_GROUP).map(|_| socket));
+                            match joined {
+                                Ok(socket) => {
+                                    mdns_socket = Some(socket);
+                                    Graphic::putfont_asc(300, 500, 0, "joined mdns group");
+                                },
+                                Err(message) => Graphic::putfont_asc(300, 500, 0, &message),
+                            }
+                        }
+                    } else if data == 8 {
+                        // 見つかったPCIデバイスをlspci風に表示
+                        lspci::dump_pci_devices(10, 230);
+                    } else if data == 9 {
+                        // 仮想メモリの領域の一覧を表示
+                        vmm::dump_regions(10, 230);
+                    } else if data == 10 {
+                        // Heapのサイズごとの使用状況を表示
+                        dump_heap_stats(10, 230);
+                    } else if data == 11 {
+                        // 1回目で解放されていない確保の記録を始め、2回目以降は記録を表示する
+                        if unsafe { ALLOCATOR.is_tracking() } {
+                            dump_leak_reports(10, 230);
+                        } else {
+                            let result = unsafe {
+                                ALLOCATOR.enable_tracking().and_then(|_| DMA_ALLOCATOR.enable_tracking())
+                            };
+                            match result {
+                                Ok(_) => Graphic::putfont_asc(10, 230, 0, "heap tracking on"),
+                                Err(message) => Graphic::putfont_asc(10, 230, 0, &message),
+                            }
+                        }
+                    } else if data == 12 {
+                        // 最近のログをシリアルと画面に書き出す(dmesg)
+                        let result = log::ring::dmesg(&mut COM1.writer(), log::ring::LOG_RING_ENTRIES)
+                            .and_then(|_| log::ring::dmesg(&mut ScreenWriter::new(10, 230), DMESG_SCREEN_LINES));
+                        if result.is_err() {
+                            Graphic::putfont_asc(10, 230, 0, "dmesg failed");
+                        }
                     } else {
                         Graphic::putfont_asc_from_keyboard(idx, 15, 0, data); //
                     }
@@ -253,26 +385,35 @@ pub extern "C" fn eh_personality() {}
 #[panic_handler]
 #[no_mangle]
 pub extern "C" fn panic(_info: &PanicInfo) -> ! {
-    Graphic::putfont_asc(0, 100, 10, "panic!!!!!");
-    let mut printer = Printer::new(0, 120, 0);
-    write!(printer, "{:?}", _info.location().unwrap().file()).unwrap();
-    let mut printer = Printer::new(0, 140, 0);
-    write!(printer, "{:?}", _info.location().unwrap().line()).unwrap();
-    let mut printer = Printer::new(0, 160, 0);
-    write!(printer, "{:?}", _info.location().unwrap().column()).unwrap();
-    let mut printer = Printer::new(0, 180, 0);
-    write!(printer, "{:?}", _info.message().unwrap()).unwrap();
-    let mut printer = Printer::new(0, 300, 0);
-    write!(printer, "{:?}", _info.payload().downcast_ref::<&str>()).unwrap();
-    loop {
-        asmfunc::io_hlt();
-    }
+    // 場所・メッセージ・バックトレースとクラッシュまでのログをシリアルと画面に書き出す
+    exception::report::report_panic(_info);
+    exception::halt()
 }
 
 #[no_mangle]
 #[allow(non_snake_case)]
 pub extern "C" fn _Unwind_Resume(_ex_obj: *mut ()) { }
 
+fn dump_heap_stats(x: u32, y: u32) {
+    let mut writer = ScreenWriter::new(x, y);
+    if let Some(stats) = unsafe { ALLOCATOR.stats() } {
+        format_heap_stats(&mut writer, "heap", &stats).unwrap();
+    }
+    if let Some(stats) = unsafe { DMA_ALLOCATOR.stats() } {
+        format_heap_stats(&mut writer, "dma", &stats).unwrap();
+    }
+}
+
+fn dump_leak_reports(x: u32, y: u32) {
+    let mut writer = ScreenWriter::new(x, y);
+    if let Some(report) = unsafe { ALLOCATOR.leak_report() } {
+        format_leak_report(&mut writer, "heap", &report).unwrap();
+    }
+    if let Some(report) = unsafe { DMA_ALLOCATOR.leak_report() } {
+        format_leak_report(&mut writer, "dma", &report).unwrap();
+    }
+}
+
 #[alloc_error_handler]
 fn alloc_error_handler(layout: Layout) -> ! {
     Graphic::putfont_asc(0, 400, 0, "alloc_error_handler!!!!!");
@@ -280,6 +421,8 @@ fn alloc_error_handler(layout: Layout) -> ! {
     write!(printer, "{:?}", layout.size()).unwrap();
     let mut printer = Printer::new(0, 515, 0);
     write!(printer, "{:?}", layout.align()).unwrap();
+    // どのサイズが足りなくなったか分かるようにHeapの状態も出す(Heapを使わずに書く)
+    dump_heap_stats(0, 530);
 
     loop {
         asmfunc::io_hlt();
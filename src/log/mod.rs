@@ -0,0 +1,238 @@
+use core::fmt::{self, Write};
+use alloc::string::String;
+
+use crate::arch::asmfunc::{io_cli, io_load_eflags, io_store_eflags};
+use crate::arch::timer::{get_uptime, try_get_uptime};
+use crate::drivers::serial::COM1;
+use crate::spin::mutex::Mutex;
+
+pub mod ring;
+
+// カーネルのログ。log!/error!/warn!/info!/debug!/trace!とprint!/println!はCOM1に書く
+// QEMUの-serial stdioや-serial file:で取り出せる
+// log!などはringにも残すので、後からdmesgで読める
+// 割り込みハンドラやHeapが足りない時からも呼べるように、ここではHeapを使わない
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Error = 1,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl Level {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Level::Error => "ERROR",
+            Level::Warn => "WARN",
+            Level::Info => "INFO",
+            Level::Debug => "DEBUG",
+            Level::Trace => "TRACE",
+        }
+    }
+}
+
+const MAX_MODULE_FILTERS: usize = 16;
+const CRATE_PREFIX: &str = "rios::";
+// シリアルに書く1行の最大の長さ(改行を含む)。長いものは切って"..."を付ける
+const LINE_LEN: usize = 256;
+
+struct Filters {
+    max_level: Level,
+    // モジュールごとのレベル(モジュールのパスはdrivers::net のようにクレート名を除いたもの)
+    modules: [Option<(&'static str, Level)>; MAX_MODULE_FILTERS],
+}
+
+impl Filters {
+    // 一番長く一致したモジュールの設定。どれにも一致しなければ全体の設定
+    fn max_level_for(&self, module: &str) -> Level {
+        self.modules.iter()
+            .flatten()
+            .filter(|(m, _)| matches_module(m, module))
+            .max_by_key(|(m, _)| m.len())
+            .map_or(self.max_level, |(_, l)| *l)
+    }
+}
+
+static FILTERS: Mutex<Filters> = Mutex::new(Filters {
+    max_level: Level::Info,
+    modules: [None; MAX_MODULE_FILTERS],
+});
+
+// 割り込みハンドラから呼ばれても止まらないように、割り込みを禁止してからロックを取る
+fn without_interrupts<R>(f: impl FnOnce() -> R) -> R {
+    let eflags = io_load_eflags();
+    io_cli();
+    let result = f();
+    io_store_eflags(eflags);
+    result
+}
+
+fn strip_crate(module_path: &str) -> &str {
+    if module_path == "rios" { return ""; }
+    module_path.strip_prefix(CRATE_PREFIX).unwrap_or(module_path)
+}
+
+// filterがmoduleそのものか、moduleの親のモジュールならtrue
+fn matches_module(filter: &str, module: &str) -> bool {
+    module.starts_with(filter) && (module.len() == filter.len() || module[filter.len()..].starts_with("::"))
+}
+
+// モジュール別の設定を含めずに、全体で出すレベルを設定する
+pub fn set_max_level(level: Level) {
+    without_interrupts(|| FILTERS.lock().max_level = level);
+}
+
+// moduleとその下のモジュールで出すレベルを設定する。Noneなら設定を消して全体の設定に戻す
+pub fn set_module_level(module: &'static str, level: Option<Level>) -> Result<(), String> {
+    without_interrupts(|| {
+        let mut filters = FILTERS.lock();
+        let existing = filters.modules.iter().position(|f| f.map_or(false, |(m, _)| m == module));
+        match (existing, level) {
+            (Some(idx), Some(level)) => filters.modules[idx] = Some((module, level)),
+            (Some(idx), None) => filters.modules[idx] = None,
+            (None, Some(level)) => {
+                let idx = filters.modules.iter().position(|f| f.is_none())
+                    .ok_or(format!("log filters are full ({}).", MAX_MODULE_FILTERS))?;
+                filters.modules[idx] = Some((module, level));
+            },
+            (None, None) => {},
+        }
+        Ok(())
+    })
+}
+
+// 一番長く一致したモジュールの設定で決める。どれにも一致しなければ全体の設定
+pub fn enabled(level: Level, module_path: &str) -> bool {
+    let module = strip_crate(module_path);
+    without_interrupts(|| level <= FILTERS.lock().max_level_for(module))
+}
+
+// 1行をスタックの上に組み立てるWriter(改行はCRLFにする)
+// 他の割り込みなどのログと混ざらないように、組み立ててからシリアルに1回で書く
+struct LineBuffer {
+    buf: [u8; LINE_LEN],
+    len: usize,
+    truncated: bool,
+}
+
+impl LineBuffer {
+    const ELLIPSIS: &'static [u8] = b"...\r\n";
+
+    fn new() -> Self {
+        LineBuffer { buf: [0; LINE_LEN], len: 0, truncated: false }
+    }
+
+    // 最後に"..."と改行を入れられるだけ空けておく
+    fn push(&mut self, bytes: &[u8]) -> bool {
+        if self.truncated || self.len + bytes.len() > LINE_LEN - Self::ELLIPSIS.len() {
+            self.truncated = true;
+            return false;
+        }
+        self.buf[self.len..self.len + bytes.len()].copy_from_slice(bytes);
+        self.len += bytes.len();
+        true
+    }
+
+    fn finish(&mut self) -> &[u8] {
+        let end: &[u8] = if self.truncated { Self::ELLIPSIS } else { b"\r\n" };
+        self.buf[self.len..self.len + end.len()].copy_from_slice(end);
+        &self.buf[..self.len + end.len()]
+    }
+}
+
+impl Write for LineBuffer {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for ch in s.chars() {
+            let mut encoded = [0; 4];
+            let bytes: &[u8] = if ch == '\n' { b"\r\n" } else { ch.encode_utf8(&mut encoded).as_bytes() };
+            if !self.push(bytes) { break; }
+        }
+        Ok(())
+    }
+}
+
+// [秒.1/100秒] LEVEL module: message
+pub fn log(level: Level, module_path: &'static str, args: fmt::Arguments) {
+    if !enabled(level, module_path) { return; }
+    let module = strip_crate(module_path);
+    let ticks = without_interrupts(|| {
+        let ticks = get_uptime();
+        ring::record(ticks, level, module, args);
+        ticks
+    });
+    let mut line = LineBuffer::new();
+    // LineBufferへの書き込みは失敗しない(入りきらない分は捨てる)
+    let _ = write!(line, "[{:>5}.{:02}] {:<5} {}: {}", ticks / 100, ticks % 100, level.name(), module, args);
+    COM1.write_bytes(line.finish());
+}
+
+// 例外ハンドラなど、ログやシリアルのロックを持っている所に割り込んだかもしれない所から書く
+// ロックが取れない所(フィルタ・リングバッファ・シリアル)は待たずに飛ばす。フィルタが読めなければ書く
+pub fn try_log(level: Level, module_path: &'static str, args: fmt::Arguments) {
+    let module = strip_crate(module_path);
+    let ticks = without_interrupts(|| {
+        if let Some(filters) = FILTERS.try_lock() {
+            if level > filters.max_level_for(module) { return None; }
+        }
+        let ticks = try_get_uptime().unwrap_or(0);
+        ring::try_record(ticks, level, module, args);
+        Some(ticks)
+    });
+    let ticks = match ticks {
+        Some(ticks) => ticks,
+        None => return,
+    };
+    let mut line = LineBuffer::new();
+    let _ = write!(line, "[{:>5}.{:02}] {:<5} {}: {}", ticks / 100, ticks % 100, level.name(), module, args);
+    COM1.try_write_bytes(line.finish());
+}
+
+pub fn print(args: fmt::Arguments) {
+    let _ = COM1.writer().write_fmt(args);
+}
+
+#[macro_export]
+macro_rules! log {
+    ($level:expr, $($arg:tt)+) => {
+        $crate::log::log($level, module_path!(), format_args!($($arg)+))
+    };
+}
+
+#[macro_export]
+macro_rules! error {
+    ($($arg:tt)+) => { $crate::log!($crate::log::Level::Error, $($arg)+) };
+}
+
+#[macro_export]
+macro_rules! warn {
+    ($($arg:tt)+) => { $crate::log!($crate::log::Level::Warn, $($arg)+) };
+}
+
+#[macro_export]
+macro_rules! info {
+    ($($arg:tt)+) => { $crate::log!($crate::log::Level::Info, $($arg)+) };
+}
+
+#[macro_export]
+macro_rules! debug {
+    ($($arg:tt)+) => { $crate::log!($crate::log::Level::Debug, $($arg)+) };
+}
+
+#[macro_export]
+macro_rules! trace {
+    ($($arg:tt)+) => { $crate::log!($crate::log::Level::Trace, $($arg)+) };
+}
+
+#[macro_export]
+macro_rules! print {
+    ($($arg:tt)*) => { $crate::log::print(format_args!($($arg)*)) };
+}
+
+#[macro_export]
+macro_rules! println {
+    () => { $crate::print!("\n") };
+    ($($arg:tt)*) => { $crate::log::print(format_args!("{}\n", format_args!($($arg)*))) };
+}
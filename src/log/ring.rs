@@ -0,0 +1,182 @@
+use core::fmt::{self, Write};
+
+use crate::spin::mutex::{Mutex, MutexGuard};
+use super::Level;
+
+// カーネルのログを覚えておくリングバッファ(dmesg)
+// 一杯になったら古いものから上書きする。メッセージはMESSAGE_LEN bytesで切る
+// パニック中にも読むので、ここではHeapを使わない
+
+pub const LOG_RING_ENTRIES: usize = 64;
+const MESSAGE_LEN: usize = 112;
+
+#[derive(Copy, Clone)]
+pub struct LogRecord {
+    pub seq: u64,
+    pub ticks: usize,
+    pub level: Level,
+    pub module: &'static str,
+    message: [u8; MESSAGE_LEN],
+    len: usize,
+    truncated: bool,
+}
+
+impl LogRecord {
+    const EMPTY: LogRecord = LogRecord {
+        seq: 0,
+        ticks: 0,
+        level: Level::Info,
+        module: "",
+        message: [0; MESSAGE_LEN],
+        len: 0,
+        truncated: false,
+    };
+
+    pub fn message(&self) -> &str {
+        // 切った所が文字の途中にならないようにMessageWriterで書いているので、ここでは失敗しない
+        core::str::from_utf8(&self.message[..self.len]).unwrap_or("")
+    }
+
+    pub fn is_truncated(&self) -> bool {
+        self.truncated
+    }
+}
+
+impl fmt::Display for LogRecord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "[{:>5}.{:02}] {:<5} {}: {}", self.ticks / 100, self.ticks % 100, self.level.name(), self.module, self.message())?;
+        if self.truncated {
+            write!(f, "...")?;
+        }
+        Ok(())
+    }
+}
+
+// 入りきらない分は捨てるWriter(文字の途中では切らない)
+struct MessageWriter<'a> {
+    record: &'a mut LogRecord,
+}
+
+impl<'a> Write for MessageWriter<'a> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for ch in s.chars() {
+            let ch = if ch == '\n' || ch == '\r' { ' ' } else { ch };
+            let start = self.record.len;
+            if start + ch.len_utf8() > MESSAGE_LEN {
+                self.record.truncated = true;
+                break;
+            }
+            ch.encode_utf8(&mut self.record.message[start..]);
+            self.record.len += ch.len_utf8();
+        }
+        Ok(())
+    }
+}
+
+struct LogRing {
+    records: [LogRecord; LOG_RING_ENTRIES],
+    next_seq: u64, // 次に書くレコードの番号(番号 % LOG_RING_ENTRIES の所に書く)
+}
+
+impl LogRing {
+    fn oldest_seq(&self) -> u64 {
+        self.next_seq.saturating_sub(LOG_RING_ENTRIES as u64)
+    }
+
+    fn get(&self, seq: u64) -> &LogRecord {
+        &self.records[(seq % LOG_RING_ENTRIES as u64) as usize]
+    }
+}
+
+static LOG_RING: Mutex<LogRing> = Mutex::new(LogRing {
+    records: [LogRecord::EMPTY; LOG_RING_ENTRIES],
+    next_seq: 0,
+});
+
+// 呼ぶ側で割り込みを禁止してから呼ぶ
+pub(super) fn record(ticks: usize, level: Level, module: &'static str, args: fmt::Arguments) {
+    write_record(&mut LOG_RING.lock(), ticks, level, module, args);
+}
+
+// recordと同じだが、ロックが取れなければ(ログを書いている途中に割り込んだ例外など)残さずにfalse
+pub(super) fn try_record(ticks: usize, level: Level, module: &'static str, args: fmt::Arguments) -> bool {
+    match LOG_RING.try_lock() {
+        Some(mut ring) => {
+            write_record(&mut ring, ticks, level, module, args);
+            true
+        },
+        None => false,
+    }
+}
+
+fn write_record(ring: &mut LogRing, ticks: usize, level: Level, module: &'static str, args: fmt::Arguments) {
+    let seq = ring.next_seq;
+    let record = &mut ring.records[(seq % LOG_RING_ENTRIES as u64) as usize];
+    *record = LogRecord { seq, ticks, level, module, ..LogRecord::EMPTY };
+    let _ = MessageWriter { record }.write_fmt(args);
+    ring.next_seq += 1;
+}
+
+// 次に書かれるレコードの番号(records_sinceで続きから読むのに使う)
+pub fn next_seq() -> u64 {
+    super::without_interrupts(|| LOG_RING.lock().next_seq)
+}
+
+// seq以降に書かれたレコードを古い順にfに渡して、次に読む番号を返す
+// 上書きされて無くなった分は飛ばす
+pub fn records_since(seq: u64, mut f: impl FnMut(&LogRecord)) -> u64 {
+    super::without_interrupts(|| {
+        let ring = LOG_RING.lock();
+        for seq in seq.max(ring.oldest_seq())..ring.next_seq {
+            f(ring.get(seq));
+        }
+        ring.next_seq
+    })
+}
+
+fn format_last<W: Write>(w: &mut W, ring: &LogRing, count: usize) -> fmt::Result {
+    let from = ring.next_seq.saturating_sub(count as u64).max(ring.oldest_seq());
+    if from > 0 {
+        writeln!(w, "({} earlier lines)", from)?;
+    }
+    for seq in from..ring.next_seq {
+        writeln!(w, "{}", ring.get(seq))?;
+    }
+    Ok(())
+}
+
+// 最後のcount行を書き出す
+// シリアルなどへの書き込みは遅いので、レコードを1つずつ写してから、ロックを外して割り込みを許した状態で書く
+pub fn dmesg<W: Write>(w: &mut W, count: usize) -> fmt::Result {
+    let (from, end) = super::without_interrupts(|| {
+        let ring = LOG_RING.lock();
+        (ring.next_seq.saturating_sub(count as u64).max(ring.oldest_seq()), ring.next_seq)
+    });
+    if from > 0 {
+        writeln!(w, "({} earlier lines)", from)?;
+    }
+    for seq in from..end {
+        let record = super::without_interrupts(|| {
+            let ring = LOG_RING.lock();
+            // 書いている間に上書きされたものは飛ばす
+            if seq < ring.oldest_seq() { None } else { Some(*ring.get(seq)) }
+        });
+        if let Some(record) = record {
+            writeln!(w, "{}", record)?;
+        }
+    }
+    Ok(())
+}
+
+// パニックした時に最後のcount行を書き出す
+// ログを書いている途中でパニックした場合はロックが取れないので、外してから読む(途中のレコードは壊れているかもしれない)
+pub fn dmesg_on_panic<W: Write>(w: &mut W, count: usize) -> fmt::Result {
+    let ring: MutexGuard<LogRing> = match LOG_RING.try_lock() {
+        Some(ring) => ring,
+        None => {
+            LOG_RING.force_unlock();
+            LOG_RING.lock()
+        },
+    };
+    format_last(w, &ring, count)
+}
@@ -0,0 +1,198 @@
+use core::ptr;
+use alloc::string::String;
+use alloc::borrow::ToOwned;
+
+use crate::allocator::frame_allocator::FRAME_ALLOCATOR;
+use crate::arch::asmfunc::{load_cr3, store_cr3, flush_tlb};
+use crate::arch::paging::{
+    allocate_table_frame, kernel_page_dir, phys_to_virt, zero_frame, copy_frame,
+    KERNEL_BASE_ADDR, KERNEL_DIR_START, ADDRESS_MSK, PTE_PRESENT, PTE_RW, PTE_USER, PTE_OWNED,
+};
+
+// プロセスごとのアドレス空間
+// カーネル空間(0xc0000000~)のディレクトリのエントリはカーネルのページディレクトリからコピーして共有し、
+// ユーザー空間(0x00000000~0xbfffffff)のページテーブルとフレームはアドレス空間ごとに持つ
+// ディレクトリとページテーブルはストレートマッピングされたフレームに置くので、phys_to_virtで触れる
+// まだユーザーのプロセス(タスクの切り替え)が無いので、今はどこからも使っていない(プロセスを作る時のためのAPI)
+
+const NUM_OF_ENTRY: usize = 1024;
+
+type RawTable = [u32; NUM_OF_ENTRY];
+
+fn table(phys_address: u32) -> *mut RawTable {
+    phys_to_virt(phys_address & ADDRESS_MSK) as *mut RawTable
+}
+
+fn is_present(entry: u32) -> bool {
+    entry & PTE_PRESENT as u32 != 0
+}
+
+pub struct AddressSpace {
+    page_dir: u32, // ページディレクトリの物理アドレス(CR3に入れる値)
+}
+
+impl AddressSpace {
+    // カーネル空間だけがマッピングされた新しいアドレス空間
+    pub fn new() -> Result<Self, String> {
+        let page_dir = allocate_table_frame()?;
+        unsafe {
+            let kernel_dir = table(kernel_page_dir());
+            let dir = table(page_dir);
+            for idx in KERNEL_DIR_START..NUM_OF_ENTRY {
+                (*dir)[idx] = (*kernel_dir)[idx];
+            }
+        }
+        Ok(AddressSpace { page_dir })
+    }
+
+    pub fn page_dir(&self) -> u32 {
+        self.page_dir
+    }
+
+    pub fn is_active(&self) -> bool {
+        load_cr3() & ADDRESS_MSK == self.page_dir
+    }
+
+    // CR3を切り替えてこのアドレス空間に入る
+    pub fn activate(&self) {
+        store_cr3(self.page_dir);
+    }
+
+    fn check_user_address(vir_address: u32) -> Result<(), String> {
+        if vir_address >= KERNEL_BASE_ADDR {
+            return Err(format!("{:x} is not in user space.", vir_address));
+        }
+        Ok(())
+    }
+
+    // vir_addressのページテーブルエントリ。create_tableならページテーブルが無ければ作る
+    fn entry(&self, vir_address: u32, create_table: bool) -> Result<Option<*mut u32>, String> {
+        Self::check_user_address(vir_address)?;
+        let dir = table(self.page_dir);
+        let dir_idx = (vir_address >> 22) as usize;
+        unsafe {
+            if !is_present((*dir)[dir_idx]) {
+                if !create_table { return Ok(None); }
+                // ユーザー空間のテーブルは常にユーザーから触れるようにして、ページ単位のフラグで制御する
+                let table_address = allocate_table_frame()?;
+                (*dir)[dir_idx] = table_address | (PTE_PRESENT | PTE_RW | PTE_USER) as u32;
+            }
+            let page_table = table((*dir)[dir_idx]);
+            Ok(Some(&mut (*page_table)[(vir_address >> 12 & 0x3ff) as usize] as *mut u32))
+        }
+    }
+
+    fn flush(&self) {
+        if self.is_active() { flush_tlb(); }
+    }
+
+    // 決まった物理アドレスをマッピングする(このアドレス空間はフレームを解放しない)
+    pub fn map_page(&mut self, vir_address: u32, phys_address: u32, flags: u16) -> Result<(), String> {
+        let entry = self.entry(vir_address, true)?.unwrap();
+        unsafe {
+            if is_present(*entry) { return Err(format!("{:x} is already mapped.", vir_address)); }
+            *entry = phys_address & ADDRESS_MSK | (flags | PTE_PRESENT) as u32;
+        }
+        self.flush();
+        Ok(())
+    }
+
+    // 新しいフレームを確保して(0で埋めて)マッピングし、その物理アドレスを返す
+    pub fn map_new_page(&mut self, vir_address: u32, flags: u16) -> Result<u32, String> {
+        let phys_address = FRAME_ALLOCATOR.allocate_frame().ok_or("out of physical frames.".to_owned())?;
+        zero_frame(phys_address);
+        if let Err(e) = self.map_page(vir_address, phys_address, flags | PTE_OWNED) {
+            FRAME_ALLOCATOR.free_frame(phys_address);
+            return Err(e);
+        }
+        Ok(phys_address)
+    }
+
+    // マッピングを外す。このアドレス空間が確保したフレームなら解放する
+    pub fn unmap_page(&mut self, vir_address: u32) -> Option<u32> {
+        let entry = self.entry(vir_address, false).ok()??;
+        let old = unsafe { ptr::replace(entry, 0) };
+        if !is_present(old) { return None; }
+        if old & PTE_OWNED as u32 != 0 {
+            FRAME_ALLOCATOR.free_frame(old & ADDRESS_MSK);
+        }
+        self.flush();
+        Some(old & ADDRESS_MSK)
+    }
+
+    pub fn translate(&self, vir_address: u32) -> Option<u32> {
+        let entry = unsafe { *self.entry(vir_address, false).ok()?? };
+        if !is_present(entry) { return None; }
+        Some(entry & ADDRESS_MSK | vir_address & !ADDRESS_MSK)
+    }
+
+    // ユーザー空間のマッピングされているページ(仮想アドレス, エントリ)を順番に呼ぶ
+    fn for_each_user_page(&self, mut f: impl FnMut(u32, u32)) {
+        let dir = table(self.page_dir);
+        for dir_idx in 0..KERNEL_DIR_START {
+            let dir_entry = unsafe { (*dir)[dir_idx] };
+            if !is_present(dir_entry) { continue; }
+            let page_table = table(dir_entry);
+            for tbl_idx in 0..NUM_OF_ENTRY {
+                let entry = unsafe { (*page_table)[tbl_idx] };
+                if is_present(entry) {
+                    f((dir_idx << 22 | tbl_idx << 12) as u32, entry);
+                }
+            }
+        }
+    }
+
+    // forkのようにユーザー空間を複製する
+    // このアドレス空間が確保したフレームは中身をコピーした新しいフレームにし、それ以外(共有のマッピング)はそのまま共有する
+    pub fn fork(&self) -> Result<AddressSpace, String> {
+        let mut child = AddressSpace::new()?;
+        let mut result = Ok(());
+        self.for_each_user_page(|vir_address, entry| {
+            if result.is_err() { return; }
+            let flags = (entry & !ADDRESS_MSK) as u16 & !PTE_PRESENT;
+            result = if entry & PTE_OWNED as u32 != 0 {
+                match FRAME_ALLOCATOR.allocate_frame() {
+                    Some(phys_address) => {
+                        copy_frame(phys_address, entry & ADDRESS_MSK);
+                        child.map_page(vir_address, phys_address, flags).map_err(|e| {
+                            FRAME_ALLOCATOR.free_frame(phys_address);
+                            e
+                        })
+                    },
+                    None => Err("out of physical frames.".to_owned()),
+                }
+            } else {
+                child.map_page(vir_address, entry & ADDRESS_MSK, flags)
+            };
+        });
+        // 失敗した場合はchildのdropでここまでのフレームが解放される
+        result.map(|_| child)
+    }
+}
+
+impl Drop for AddressSpace {
+    // ユーザー空間のフレーム・ページテーブル・ディレクトリを全て解放する
+    fn drop(&mut self) {
+        if self.is_active() {
+            store_cr3(kernel_page_dir());
+        }
+        self.for_each_user_page(|_, entry| {
+            if entry & PTE_OWNED as u32 != 0 {
+                FRAME_ALLOCATOR.free_frame(entry & ADDRESS_MSK);
+            }
+        });
+        let dir = table(self.page_dir);
+        for dir_idx in 0..KERNEL_DIR_START {
+            let dir_entry = unsafe { (*dir)[dir_idx] };
+            if is_present(dir_entry) {
+                FRAME_ALLOCATOR.free_frame(dir_entry & ADDRESS_MSK);
+            }
+        }
+        FRAME_ALLOCATOR.free_frame(self.page_dir);
+    }
+}
+
+// カーネルのアドレス空間に戻る
+pub fn activate_kernel_space() {
+    store_cr3(kernel_page_dir());
+}
@@ -9,28 +9,184 @@ use core::ops::{Deref, DerefMut};
 use core::borrow;
 use core::iter::FusedIterator;
 use core::slice;
+use core::ops::{Index, IndexMut};
+use core::slice::SliceIndex;
 
 use alloc::alloc::handle_alloc_error;
 use alloc::string::{String, ToString};
+use alloc::vec::Vec;
 use alloc::borrow::ToOwned;
 
 use crate::allocator::LockedHeap;
+use crate::allocator::frame_allocator::{FRAME_ALLOCATOR, FRAME_SIZE};
+use crate::arch::paging::{
+    map_direct_uncached, unmap_direct_uncached, virt_to_phys, KERNEL_BASE_ADDR, KERNEL_HEAP_START,
+};
 use crate::spin::mutex::Mutex;
+use super::vmm::{self, Protection, RegionKind};
 
 use crate::arch::graphic::{Graphic, Printer, print_str};
 use core::fmt::Write;
 
-const DMA_START: usize = 0xb0000000;
-const DMA_END: usize = 0xb1000000;
+const DMA_SIZE: usize = 0x01000000;
+// カーネル空間のストレートマッピング(物理アドレス + KERNEL_BASE_ADDR)に置くので、
+// 起動時にマッピングした範囲の後ろからHeapの仮想アドレスに被らない範囲で取る
+const DMA_MIN_ADDRESS: u32 = 0x01000000;
+const DMA_MAX_ADDRESS: u32 = KERNEL_HEAP_START - KERNEL_BASE_ADDR;
+
+static DMA_REGION: Mutex<(u32, usize)> = Mutex::new((0, 0));
 
 pub static mut DMA_ALLOCATOR: LockedHeap = LockedHeap {
     heap: Mutex::new(None)
 };
 
+// 物理的に連続したフレームを確保してDMA用のHeapにする
+// デバイスと共有するのでキャッシュしないでマッピングする
 pub fn init_dma() {
-    let heap_start = DMA_START;
-    let heap_size: usize = DMA_END - DMA_START;
-    unsafe { DMA_ALLOCATOR.init(heap_start, heap_size) };
+    let phys_address = match FRAME_ALLOCATOR.allocate_contiguous_frames(DMA_SIZE / FRAME_SIZE, DMA_MIN_ADDRESS, DMA_MAX_ADDRESS) {
+        Some(phys_address) => phys_address,
+        None => panic!("Error in init_dma. no contiguous physical memory for dma."),
+    };
+    let heap_start = match map_direct_uncached(phys_address, DMA_SIZE) {
+        Ok(vir_address) => vir_address,
+        Err(e) => panic!("Error in init_dma. {:?}", e),
+    };
+    unsafe { DMA_ALLOCATOR.init(heap_start as usize, DMA_SIZE) };
+    *DMA_REGION.lock() = (heap_start, DMA_SIZE);
+}
+
+// DMA用に確保した領域(仮想アドレス, サイズ)。物理アドレスはpaging::virt_to_physで求める
+pub fn get_dma_region() -> (u32, usize) {
+    *DMA_REGION.lock()
+}
+
+// デバイスにDMAさせる領域の物理アドレスの条件
+#[derive(Copy, Clone, Debug)]
+pub struct DmaConstraints {
+    pub align: usize,     // 先頭の物理アドレスのアラインメント(2の累乗)
+    pub boundary: usize,  // この境界を跨がない(0なら制限なし。2の累乗でFRAME_SIZE以上)
+    pub max_address: u32, // 領域の終わりの物理アドレスがこれを超えない
+}
+
+impl DmaConstraints {
+    // ストレートマッピングできる範囲ならどこでもよい
+    pub const DEFAULT: DmaConstraints = DmaConstraints {
+        align: FRAME_SIZE,
+        boundary: 0,
+        max_address: DMA_MAX_ADDRESS,
+    };
+    // ISAのDMAコントローラは16MiBより下しか届かず、64KiBの境界を跨げない
+    pub const ISA: DmaConstraints = DmaConstraints {
+        align: FRAME_SIZE,
+        boundary: 0x10000,
+        max_address: 0x01000000,
+    };
+
+    fn check(&self, size: usize) -> Result<(), String> {
+        if size == 0 {
+            return Err("dma region size is 0.".to_owned());
+        }
+        if !self.align.is_power_of_two() {
+            return Err(format!("dma align {:x} is not a power of two.", self.align));
+        }
+        if self.boundary != 0 && (!self.boundary.is_power_of_two() || self.boundary < FRAME_SIZE) {
+            return Err(format!("dma boundary {:x} is not a power of two of at least a frame.", self.boundary));
+        }
+        if self.boundary != 0 && size > self.boundary {
+            return Err(format!("{:x} bytes do not fit in a {:x} byte boundary.", size, self.boundary));
+        }
+        if self.max_address > DMA_MAX_ADDRESS {
+            return Err(format!("dma max address {:x} is above {:x}.", self.max_address, DMA_MAX_ADDRESS));
+        }
+        Ok(())
+    }
+}
+
+// 物理的に連続した、キャッシュしないでマッピングしたDMA用の領域
+// デバイスにはphys_address()を、CPUからはvir_address()を使う。dropでフレームを返す
+// 起動時のストレートマッピングの外に置いたものはvmmの領域(RegionKind::Dma)として登録する
+pub struct DmaRegion {
+    vir_address: u32,
+    phys_address: u32,
+    frames: usize,
+    size: usize,
+    registered: bool,
+}
+
+impl DmaRegion {
+    // sizeバイトの領域を0で埋めて確保する
+    // 起動時にマッピングした範囲(DMA_MIN_ADDRESSより下)はページテーブルなどに残しておきたいので、その上から先に探す
+    pub fn allocate(size: usize, constraints: &DmaConstraints) -> Result<DmaRegion, String> {
+        constraints.check(size)?;
+        let frames = (size + FRAME_SIZE - 1) / FRAME_SIZE;
+        let align = constraints.align.max(FRAME_SIZE) / FRAME_SIZE;
+        let boundary = constraints.boundary / FRAME_SIZE;
+        let phys_address = FRAME_ALLOCATOR
+            .allocate_aligned_frames(frames, align, boundary, DMA_MIN_ADDRESS, constraints.max_address)
+            .or_else(|| FRAME_ALLOCATOR.allocate_aligned_frames(frames, align, boundary, 0, constraints.max_address.min(DMA_MIN_ADDRESS)))
+            .ok_or(format!("no contiguous physical memory for {:x} bytes of dma.", size))?;
+        let free_frames = || {
+            for idx in 0..frames {
+                FRAME_ALLOCATOR.free_frame(phys_address + (idx * FRAME_SIZE) as u32);
+            }
+        };
+        let vir_address = match map_direct_uncached(phys_address, frames * FRAME_SIZE) {
+            Ok(vir_address) => vir_address,
+            Err(e) => {
+                free_frames();
+                return Err(e);
+            },
+        };
+        // 下の方は起動時に登録した領域("kernel"や"page tables")に含まれている
+        let registered = phys_address >= DMA_MIN_ADDRESS;
+        if registered {
+            if let Err(e) = vmm::register_mapped_range(vir_address, phys_address, frames * FRAME_SIZE, RegionKind::Dma, Protection::KERNEL_RW, "dma region") {
+                unmap_direct_uncached(vir_address, frames * FRAME_SIZE);
+                free_frames();
+                return Err(e);
+            }
+        }
+        unsafe { ptr::write_bytes(vir_address as *mut u8, 0, frames * FRAME_SIZE) };
+        Ok(DmaRegion { vir_address, phys_address, frames, size, registered })
+    }
+
+    pub fn vir_address(&self) -> u32 {
+        self.vir_address
+    }
+
+    // デバイスのレジスタやディスクリプタに書くアドレス
+    pub fn phys_address(&self) -> u32 {
+        self.phys_address
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn as_ptr<T>(&self) -> *mut T {
+        self.vir_address as *mut T
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.vir_address as *const u8, self.size) }
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.vir_address as *mut u8, self.size) }
+    }
+}
+
+impl Drop for DmaRegion {
+    fn drop(&mut self) {
+        if self.registered {
+            // allocateで登録した範囲そのものなので失敗しない
+            let _ = vmm::unregister_range(self.vir_address, self.frames * FRAME_SIZE);
+        }
+        unmap_direct_uncached(self.vir_address, self.frames * FRAME_SIZE);
+        for idx in 0..self.frames {
+            FRAME_ALLOCATOR.free_frame(self.phys_address + (idx * FRAME_SIZE) as u32);
+        }
+    }
 }
 
 // ref: https://github.com/glandium/allocator_api/blob/master/src/liballoc/boxed.rs
@@ -124,6 +280,11 @@ impl<T: ?Sized> DmaBox<T> {
     pub fn into_pin(boxed: DmaBox<T>) -> Pin<DmaBox<T>> {
         unsafe { Pin::new_unchecked(boxed) }
     }
+
+    // デバイスに渡す物理アドレス(DMAのHeapは物理的に連続していて、ストレートマッピングされている)
+    pub fn phys_address(&self) -> u32 {
+        virt_to_phys(self.ptr.as_ptr() as *const u8 as u32)
+    }
 }
 
 impl<T: ?Sized> Drop for DmaBox<T> {
@@ -524,6 +685,11 @@ impl<T> DmaRawVec<T> {
         self.ptr.as_ptr()
     }
 
+    // デバイスに渡す物理アドレス(DMAのHeapは物理的に連続している)
+    pub fn phys_address(&self) -> u32 {
+        virt_to_phys(self.ptr.as_ptr() as u32)
+    }
+
     #[inline(always)]
     pub fn cap(&self) -> usize {
         if mem::size_of::<T>() == 0 {
@@ -561,6 +727,51 @@ impl<T> DmaRawVec<T> {
         output
     }
 
+    fn layout_for(&self, cap: usize) -> Layout {
+        let alloc_size = cap.checked_mul(mem::size_of::<T>()).unwrap_or_else(|| capacity_overflow());
+        alloc_guard(alloc_size).unwrap_or_else(|_| capacity_overflow());
+        Layout::from_size_align(alloc_size, mem::align_of::<T>()).unwrap()
+    }
+
+    // 先頭のused個の後ろにadditional個入るように広げる(広げる時は倍にして、pushのたびに確保し直さないようにする)
+    pub fn reserve(&mut self, used: usize, additional: usize) {
+        if self.cap().wrapping_sub(used) >= additional { return; }
+        let required = used.checked_add(additional).unwrap_or_else(|| capacity_overflow());
+        let new_cap = required.max(self.cap * 2).max(MIN_NON_ZERO_CAP);
+        let new_layout = self.layout_for(new_cap);
+        let result = unsafe {
+            match self.current_layout() {
+                Some(layout) => self.a.grow(self.ptr.cast(), layout, new_layout),
+                None => self.a.allocate(new_layout),
+            }
+        };
+        match result {
+            Ok(ptr) => self.ptr = ptr.cast(),
+            Err(_) => handle_alloc_error(new_layout),
+        }
+        self.cap = new_cap;
+    }
+
+    // capをamount個まで縮める(into_boxの前に長さとcapを合わせるのに使う)
+    pub fn shrink_to_fit(&mut self, amount: usize) {
+        if mem::size_of::<T>() == 0 || amount >= self.cap { return; }
+        let layout = match self.current_layout() {
+            Some(layout) => layout,
+            None => return,
+        };
+        if amount == 0 {
+            unsafe { self.dealloc_buffer() };
+            self.ptr = NonNull::dangling();
+        } else {
+            let new_layout = self.layout_for(amount);
+            match unsafe { self.a.shrink(self.ptr.cast(), layout, new_layout) } {
+                Ok(ptr) => self.ptr = ptr.cast(),
+                Err(_) => handle_alloc_error(new_layout),
+            }
+        }
+        self.cap = amount;
+    }
+
     pub unsafe fn dealloc_buffer(&mut self) {
         let elem_size = mem::size_of::<T>();
         if elem_size != 0 {
@@ -569,12 +780,323 @@ impl<T> DmaRawVec<T> {
             }
         }
     }
+}
 
+impl<T> Drop for DmaRawVec<T> {
+    // 中身はdropしない(DmaVecなど使う側がdropする)
+    // DmaVecのバッファはここで解放する。into_box/from_boxなど持ち主が変わる所ではmem::forgetしているので二重には解放しない
     fn drop(&mut self) {
         unsafe { self.dealloc_buffer() }
     }
 }
 
+const MIN_NON_ZERO_CAP: usize = 8;
+
+// DMAのHeapに置く伸び縮みする配列
+// 送るパケットをVec<u8>で組み立ててからDmaBoxにコピーする代わりに、最初からDMAのメモリで組み立てる
+pub struct DmaVec<T> {
+    buf: DmaRawVec<T>,
+    len: usize,
+}
+
+impl<T> DmaVec<T> {
+    pub fn new() -> Self {
+        DmaVec { buf: DmaRawVec::new(), len: 0 }
+    }
+
+    pub fn new_in(a: &'static LockedHeap) -> Self {
+        DmaVec { buf: DmaRawVec::new_in(a), len: 0 }
+    }
+
+    pub fn with_capacity(cap: usize) -> Self {
+        DmaVec { buf: DmaRawVec::with_capacity(cap), len: 0 }
+    }
+
+    pub fn with_capacity_in(cap: usize, a: &'static LockedHeap) -> Self {
+        DmaVec { buf: DmaRawVec::with_capacity_in(cap, a), len: 0 }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.buf.cap()
+    }
+
+    pub fn as_ptr(&self) -> *const T {
+        self.buf.ptr()
+    }
+
+    pub fn as_mut_ptr(&mut self) -> *mut T {
+        self.buf.ptr()
+    }
+
+    pub fn as_slice(&self) -> &[T] {
+        self
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [T] {
+        self
+    }
+
+    // 先頭の要素の物理アドレス(要素は物理的に連続している)
+    pub fn phys_address(&self) -> u32 {
+        self.buf.phys_address()
+    }
+
+    pub fn reserve(&mut self, additional: usize) {
+        self.buf.reserve(self.len, additional);
+    }
+
+    pub fn push(&mut self, value: T) {
+        if self.len == self.buf.cap() {
+            self.reserve(1);
+        }
+        unsafe { ptr::write(self.buf.ptr().add(self.len), value) };
+        self.len += 1;
+    }
+
+    pub fn pop(&mut self) -> Option<T> {
+        if self.len == 0 { return None; }
+        self.len -= 1;
+        unsafe { Some(ptr::read(self.buf.ptr().add(self.len))) }
+    }
+
+    // len個より後ろを捨てる
+    pub fn truncate(&mut self, len: usize) {
+        if len >= self.len { return; }
+        let remaining = self.len - len;
+        // dropの途中でpanicしても二重にdropしないように先に長さを縮める
+        self.len = len;
+        unsafe { ptr::drop_in_place(slice::from_raw_parts_mut(self.buf.ptr().add(len), remaining)) };
+    }
+
+    pub fn clear(&mut self) {
+        self.truncate(0);
+    }
+
+    pub unsafe fn set_len(&mut self, len: usize) {
+        self.len = len;
+    }
+
+    // 余っているcapを返して、長さの合ったDmaBoxにする
+    pub fn into_boxed_slice(mut self) -> DmaBox<[T]> {
+        self.buf.shrink_to_fit(self.len);
+        unsafe {
+            let buf = ptr::read(&self.buf);
+            mem::forget(self);
+            buf.into_box()
+        }
+    }
+}
+
+impl<T: Clone> DmaVec<T> {
+    pub fn extend_from_slice(&mut self, other: &[T]) {
+        self.reserve(other.len());
+        for item in other.iter() {
+            self.push(item.clone());
+        }
+    }
+
+    // 長さをnew_len個にする。伸ばす時はvalueで埋める
+    pub fn resize(&mut self, new_len: usize, value: T) {
+        if new_len <= self.len {
+            self.truncate(new_len);
+            return;
+        }
+        self.reserve(new_len - self.len);
+        while self.len < new_len {
+            self.push(value.clone());
+        }
+    }
+}
+
+impl<T> Drop for DmaVec<T> {
+    fn drop(&mut self) {
+        // バッファはDmaRawVecのdropで解放する
+        unsafe { ptr::drop_in_place(&mut self[..]) };
+    }
+}
+
+impl<T> Deref for DmaVec<T> {
+    type Target = [T];
+
+    fn deref(&self) -> &[T] {
+        unsafe { slice::from_raw_parts(self.buf.ptr(), self.len) }
+    }
+}
+
+impl<T> DerefMut for DmaVec<T> {
+    fn deref_mut(&mut self) -> &mut [T] {
+        unsafe { slice::from_raw_parts_mut(self.buf.ptr(), self.len) }
+    }
+}
+
+impl<T, I: SliceIndex<[T]>> Index<I> for DmaVec<T> {
+    type Output = I::Output;
+
+    fn index(&self, index: I) -> &Self::Output {
+        Index::index(&**self, index)
+    }
+}
+
+impl<T, I: SliceIndex<[T]>> IndexMut<I> for DmaVec<T> {
+    fn index_mut(&mut self, index: I) -> &mut Self::Output {
+        IndexMut::index_mut(&mut **self, index)
+    }
+}
+
+impl<T> Extend<T> for DmaVec<T> {
+    fn extend<I: IntoIterator<Item = T>>(&mut self, iter: I) {
+        let iter = iter.into_iter();
+        self.reserve(iter.size_hint().0);
+        for item in iter {
+            self.push(item);
+        }
+    }
+}
+
+impl<'a, T: Copy + 'a> Extend<&'a T> for DmaVec<T> {
+    fn extend<I: IntoIterator<Item = &'a T>>(&mut self, iter: I) {
+        self.extend(iter.into_iter().copied())
+    }
+}
+
+impl<T: Clone> Clone for DmaVec<T> {
+    fn clone(&self) -> Self {
+        let mut v = DmaVec::with_capacity_in(self.len, self.buf.a);
+        v.extend_from_slice(self);
+        v
+    }
+}
+
+impl<'a, T: Clone> From<&'a [T]> for DmaVec<T> {
+    fn from(slice: &'a [T]) -> Self {
+        let mut v = DmaVec::with_capacity(slice.len());
+        v.extend_from_slice(slice);
+        v
+    }
+}
+
+impl<T> From<DmaBox<[T]>> for DmaVec<T> {
+    fn from(boxed: DmaBox<[T]>) -> Self {
+        let len = boxed.len();
+        DmaVec { buf: DmaRawVec::from_box(boxed), len }
+    }
+}
+
+impl<T> From<DmaVec<T>> for DmaBox<[T]> {
+    fn from(v: DmaVec<T>) -> Self {
+        v.into_boxed_slice()
+    }
+}
+
+impl<T> AsRef<[T]> for DmaVec<T> {
+    fn as_ref(&self) -> &[T] {
+        self
+    }
+}
+
+impl<T> AsMut<[T]> for DmaVec<T> {
+    fn as_mut(&mut self) -> &mut [T] {
+        self
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for DmaVec<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+// DMAさせる区間(デバイスに渡す物理アドレスと長さ)
+#[derive(Copy, Clone, Debug)]
+pub struct DmaSegment {
+    pub phys_address: u32,
+    pub len: usize,
+}
+
+// 1つの区間の最大の長さ(ディスクリプタの長さは16bit)。これより長いバッファは複数の区間に分ける
+pub const MAX_SEGMENT_LEN: usize = 0xffff;
+
+// 1つのパケットを複数のDMAバッファの列として持つ
+// ヘッダとペイロードをつなげてコピーしなくても、ディスクリプタを並べてそのまま送れる
+pub struct ScatterGatherList {
+    buffers: Vec<DmaBox<[u8]>>,
+}
+
+impl ScatterGatherList {
+    pub fn new() -> Self {
+        ScatterGatherList { buffers: Vec::new() }
+    }
+
+    // 空のバッファはデバイスに渡せないので入れない
+    pub fn push(&mut self, buf: DmaBox<[u8]>) {
+        if !buf.is_empty() {
+            self.buffers.push(buf);
+        }
+    }
+
+    pub fn push_vec(&mut self, v: DmaVec<u8>) {
+        self.push(v.into_boxed_slice());
+    }
+
+    pub fn push_slice(&mut self, slice: &[u8]) {
+        self.push(DmaBox::from(slice));
+    }
+
+    // 全ての区間の合計のバイト数
+    pub fn len(&self) -> usize {
+        self.buffers.iter().map(|buf| buf.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buffers.is_empty()
+    }
+
+    pub fn segment_count(&self) -> usize {
+        self.buffers.iter().map(|buf| (buf.len() + MAX_SEGMENT_LEN - 1) / MAX_SEGMENT_LEN).sum()
+    }
+
+    // MAX_SEGMENT_LENより長いバッファは分けて返す(DMAのHeapは物理的に連続しているので、続きの物理アドレスから)
+    pub fn segments(&self) -> impl Iterator<Item = DmaSegment> + '_ {
+        self.buffers.iter().flat_map(|buf| {
+            let phys_address = buf.phys_address();
+            let len = buf.len();
+            (0..len).step_by(MAX_SEGMENT_LEN).map(move |offset| DmaSegment {
+                phys_address: phys_address + offset as u32,
+                len: (len - offset).min(MAX_SEGMENT_LEN),
+            })
+        })
+    }
+
+    pub fn buffers(&self) -> &[DmaBox<[u8]>] {
+        &self.buffers
+    }
+
+    // 区間を並べられないデバイス向けに、1つのバッファにつなげる
+    pub fn to_boxed_slice(&self) -> DmaBox<[u8]> {
+        let mut v = DmaVec::with_capacity(self.len());
+        for buf in self.buffers.iter() {
+            v.extend_from_slice(buf);
+        }
+        v.into_boxed_slice()
+    }
+}
+
+impl From<DmaBox<[u8]>> for ScatterGatherList {
+    fn from(buf: DmaBox<[u8]>) -> Self {
+        let mut sg = ScatterGatherList::new();
+        sg.push(buf);
+        sg
+    }
+}
+
 
 
 #[inline]
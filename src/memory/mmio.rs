@@ -0,0 +1,147 @@
+use core::marker::PhantomData;
+use core::ptr::{read_volatile, write_volatile};
+use alloc::string::String;
+
+use super::vmm::map_mmio;
+use crate::exception::fixup::probe_read32;
+
+// デバイスのレジスタ(MMIO)の領域
+// VMMでキャッシュ無効にマッピングした範囲を持ち、アクセスは全てvolatileで範囲とアラインメントを確かめる
+#[derive(Copy, Clone, Debug)]
+pub struct MmioRegion {
+    base: u32, // マッピングした仮想アドレス
+    size: usize,
+    name: &'static str,
+}
+
+impl MmioRegion {
+    // 物理アドレスの範囲をVMMでマッピングする
+    pub fn map(phys_address: u64, size: u64, name: &'static str) -> Result<MmioRegion, String> {
+        let base = map_mmio(phys_address, size, name)?;
+        Ok(MmioRegion { base, size: size as usize, name })
+    }
+
+    pub fn base(&self) -> u32 {
+        self.base
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    // 範囲外やアラインメントの合わないアクセスはドライバのバグなので止める
+    fn address(&self, offset: usize, width: usize) -> usize {
+        assert!(offset % width == 0, "{}: unaligned mmio access at {:x}.", self.name, offset);
+        assert!(
+            offset.checked_add(width).map_or(false, |end| end <= self.size),
+            "{}: mmio offset {:x} is out of range ({:x} bytes).", self.name, offset, self.size,
+        );
+        self.base as usize + offset
+    }
+
+    pub fn read32(&self, offset: usize) -> u32 {
+        unsafe { read_volatile(self.address(offset, 4) as *const u32) }
+    }
+
+    // デバイスが応答しない(マッピングが正しくない)かもしれない時に読む。フォルトしたらErr
+    pub fn probe_read32(&self, offset: usize) -> Result<u32, String> {
+        probe_read32(self.address(offset, 4) as u32)
+    }
+
+    pub fn write32(&self, offset: usize, value: u32) {
+        unsafe { write_volatile(self.address(offset, 4) as *mut u32, value) }
+    }
+
+    // 読んだ値をfで変えて書き戻す
+    pub fn modify32(&self, offset: usize, f: impl FnOnce(u32) -> u32) {
+        let value = self.read32(offset);
+        self.write32(offset, f(value));
+    }
+
+    pub fn read<R: RegisterValue>(&self, reg: Register<R>) -> R {
+        R::from_bits(self.read32(reg.offset))
+    }
+
+    pub fn write<R: RegisterValue>(&self, reg: Register<R>, value: R) {
+        self.write32(reg.offset, value.bits())
+    }
+
+    pub fn modify<R: RegisterValue>(&self, reg: Register<R>, f: impl FnOnce(R) -> R) {
+        self.modify32(reg.offset, |bits| f(R::from_bits(bits)).bits())
+    }
+}
+
+// レジスタの値の型(ビットフィールドのアクセサはmmio_bitfield!で作る)
+pub trait RegisterValue: Copy {
+    fn from_bits(bits: u32) -> Self;
+    fn bits(self) -> u32;
+}
+
+impl RegisterValue for u32 {
+    fn from_bits(bits: u32) -> Self { bits }
+    fn bits(self) -> u32 { self }
+}
+
+// 領域の先頭からoffsetにある、値の型がRの32bitレジスタ
+#[derive(Copy, Clone, Debug)]
+pub struct Register<R> {
+    offset: usize,
+    marker: PhantomData<R>,
+}
+
+impl<R> Register<R> {
+    pub const fn new(offset: usize) -> Self {
+        Register { offset, marker: PhantomData }
+    }
+
+    pub const fn offset(&self) -> usize {
+        self.offset
+    }
+
+    // 同じ型のレジスタが並んでいる場合(MTAなど)のidx番目
+    pub const fn at(&self, idx: usize) -> Self {
+        Register { offset: self.offset + idx * 4, marker: PhantomData }
+    }
+}
+
+// ビットフィールドを持つレジスタの値の型を作る
+// mmio_bitfield! {
+//     pub struct Rctl {
+//         en, set_en: 1, 1;          // 読む関数, 書く関数: 先頭のビット, ビット数
+//         bsize, set_bsize: 16, 2;
+//     }
+// }
+// 書く関数は値を変えたものを返すので、rctl.set_en(1).set_bam(1)のように続けられる
+#[macro_export]
+macro_rules! mmio_bitfield {
+    ($(#[$attr:meta])* $vis:vis struct $name:ident { $($getter:ident, $setter:ident: $shift:expr, $width:expr;)* }) => {
+        $(#[$attr])*
+        #[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+        $vis struct $name(pub u32);
+
+        #[allow(dead_code)]
+        impl $name {
+            $(
+                pub const fn $getter(&self) -> u32 {
+                    (self.0 >> $shift) & ((1u64 << $width) - 1) as u32
+                }
+
+                pub const fn $setter(self, value: u32) -> Self {
+                    let mask = (((1u64 << $width) - 1) as u32) << $shift;
+                    $name(self.0 & !mask | (value << $shift) & mask)
+                }
+            )*
+        }
+
+        impl $crate::memory::mmio::RegisterValue for $name {
+            fn from_bits(bits: u32) -> Self { $name(bits) }
+            fn bits(self) -> u32 { self.0 }
+        }
+    };
+}
+
+pub(crate) use mmio_bitfield;
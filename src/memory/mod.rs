@@ -1,4 +1,9 @@
 pub mod dma;
+pub mod vmm;
+pub mod address_space;
+
+#[macro_use]
+pub mod mmio;
 
 #[macro_use]
 pub mod volatile;
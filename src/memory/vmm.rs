@@ -0,0 +1,501 @@
+use core::fmt::Write;
+use core::ptr;
+use alloc::vec::Vec;
+use alloc::string::String;
+use alloc::borrow::ToOwned;
+
+use crate::allocator::frame_allocator::{FRAME_ALLOCATOR, FRAME_SIZE};
+use crate::arch::paging::{map_page, unmap_page, set_page_flags, translate, PTE_RW, PTE_USER, PTE_PCD, PTE_PWT};
+use crate::allocator::stats::ScreenWriter;
+use crate::arch::paging::{KERNEL_BASE_ADDR, KERNEL_HEAP_START, KERNEL_HEAP_MAX_SIZE, KERNEL_VRAM_ADDR, KERNEL_VRAM_SIZE};
+use super::dma::get_dma_region;
+
+#[macro_use]
+use crate::lazy_static;
+use crate::spin::mutex::Mutex;
+
+// 仮想アドレス空間の管理
+// どの範囲を何に使っているか(領域)を記録しておき、マッピング・解除・保護属性の変更は領域単位で行う
+// 予約だけして物理フレームを割り当てていない領域は、ページフォルトが起きた時に初めてフレームを割り当てる
+
+const PAGE_MASK: u32 = FRAME_SIZE as u32 - 1;
+const GUARD_SIZE: usize = FRAME_SIZE;
+
+// ユーザー空間より下のMMIOをマッピングする仮想アドレスの範囲(VRAMの後ろ)
+const MMIO_AREA_START: u32 = 0xf0400000;
+const MMIO_AREA_END: u32 = 0xf8000000;
+// カーネルスタックを割り当てる仮想アドレスの範囲(PCIの窓の手前)
+const KERNEL_STACK_AREA_START: u32 = 0xf8000000;
+const KERNEL_STACK_AREA_END: u32 = 0xfe000000;
+
+// ブート時のスタック(secondboot.asmでesp = 0xc03fffff)。一番下のページをガードページにする
+// カーネル本体(0xc0280000~)が0xc0300000を超えないことが前提(kernel.ldのASSERTで確かめている)
+const BOOT_STACK_GUARD: u32 = KERNEL_BASE_ADDR + 0x00300000;
+
+// ページフォルトのエラーコード
+const PF_PRESENT: u32 = 1 << 0; // 0: ページが無い, 1: 保護違反
+const PF_WRITE: u32 = 1 << 1;
+const PF_USER: u32 = 1 << 2;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum RegionKind {
+    Kernel,     // カーネル本体・ページテーブルなど
+    KernelHeap,
+    Mmio,
+    Dma,
+    Stack,
+    Guard,      // スタックなどの下に置くマッピングしないページ
+    User,
+}
+
+impl RegionKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            RegionKind::Kernel => "KERNEL",
+            RegionKind::KernelHeap => "HEAP",
+            RegionKind::Mmio => "MMIO",
+            RegionKind::Dma => "DMA",
+            RegionKind::Stack => "STACK",
+            RegionKind::Guard => "GUARD",
+            RegionKind::User => "USER",
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Protection {
+    pub writable: bool,
+    pub user: bool,
+}
+
+impl Protection {
+    pub const KERNEL_RO: Protection = Protection { writable: false, user: false };
+    pub const KERNEL_RW: Protection = Protection { writable: true, user: false };
+    pub const USER_RO: Protection = Protection { writable: false, user: true };
+    pub const USER_RW: Protection = Protection { writable: true, user: true };
+}
+
+// 領域のページをどの物理フレームで埋めるか
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Backing {
+    // 空いているフレームを割り当てる(解除したら解放する)
+    Anonymous,
+    // 決まった物理アドレス(領域の先頭に対応する物理アドレス)
+    Physical(u32),
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct VmRegion {
+    pub start: u32,
+    pub size: usize,
+    pub kind: RegionKind,
+    pub prot: Protection,
+    pub backing: Backing,
+    pub name: &'static str,
+}
+
+impl VmRegion {
+    pub fn end(&self) -> u64 {
+        self.start as u64 + self.size as u64
+    }
+
+    pub fn contains(&self, address: u32) -> bool {
+        self.start <= address && (address as u64) < self.end()
+    }
+
+    fn overlaps(&self, start: u32, size: usize) -> bool {
+        (start as u64) < self.end() && (self.start as u64) < start as u64 + size as u64
+    }
+
+    fn page_flags(&self) -> u16 {
+        let mut flags = 0;
+        if self.prot.writable { flags |= PTE_RW; }
+        if self.prot.user { flags |= PTE_USER; }
+        // MMIOはキャッシュさせない
+        if self.kind == RegionKind::Mmio { flags |= PTE_PCD | PTE_PWT; }
+        flags
+    }
+
+    fn phys_address(&self, page: u32) -> Option<u32> {
+        match self.backing {
+            Backing::Anonymous => None,
+            Backing::Physical(base) => Some(base + (page - self.start)),
+        }
+    }
+
+    // addressで2つに分ける(addressは領域の中でページ境界)
+    fn split(&self, address: u32) -> (VmRegion, VmRegion) {
+        let head_size = (address - self.start) as usize;
+        let backing = match self.backing {
+            Backing::Anonymous => Backing::Anonymous,
+            Backing::Physical(base) => Backing::Physical(base + head_size as u32),
+        };
+        (
+            VmRegion { size: head_size, ..*self },
+            VmRegion { start: address, size: self.size - head_size, backing, ..*self },
+        )
+    }
+}
+
+// ページフォルトを解決できなかった理由(割り込みの中なのでヒープを使わずに表す)
+#[derive(Copy, Clone, Debug)]
+pub enum PageFaultError {
+    NoRegion,
+    NullPointer,
+    GuardPage(VmRegion),
+    HeapPageNotMapped(VmRegion),
+    UserAccessToKernel(VmRegion),
+    WriteToReadOnly(VmRegion),
+    ProtectionViolation(VmRegion),
+    OutOfFrames(VmRegion),
+    MapFailed(VmRegion),
+}
+
+impl PageFaultError {
+    pub fn reason(&self) -> &'static str {
+        match self {
+            PageFaultError::NoRegion => "outside of any region",
+            PageFaultError::NullPointer => "null pointer dereference",
+            PageFaultError::GuardPage(_) => "guard page",
+            PageFaultError::HeapPageNotMapped(_) => "heap page not mapped (use after free?)",
+            PageFaultError::UserAccessToKernel(_) => "user access to kernel region",
+            PageFaultError::WriteToReadOnly(_) => "write to read-only region",
+            PageFaultError::ProtectionViolation(_) => "protection violation",
+            PageFaultError::OutOfFrames(_) => "out of physical frames",
+            PageFaultError::MapFailed(_) => "failed to map page",
+        }
+    }
+
+    pub fn region(&self) -> Option<VmRegion> {
+        match *self {
+            PageFaultError::NoRegion | PageFaultError::NullPointer => None,
+            PageFaultError::GuardPage(region)
+            | PageFaultError::HeapPageNotMapped(region)
+            | PageFaultError::UserAccessToKernel(region)
+            | PageFaultError::WriteToReadOnly(region)
+            | PageFaultError::ProtectionViolation(region)
+            | PageFaultError::OutOfFrames(region)
+            | PageFaultError::MapFailed(region) => Some(region),
+        }
+    }
+}
+
+lazy_static! {
+    // 開始アドレス順に並べておく
+    static ref VM_REGIONS: Mutex<Vec<VmRegion>> = Mutex::new(vec![]);
+}
+
+// ブート時にpaging::PageTableImpl::initializeなどで作ったマッピングを領域として登録する
+pub fn init() {
+    let boot_regions: [(u32, usize, RegionKind, &'static str); 5] = [
+        (KERNEL_BASE_ADDR, 0x00300000, RegionKind::Kernel, "kernel"),
+        (BOOT_STACK_GUARD + GUARD_SIZE as u32, 0x000ff000, RegionKind::Stack, "kernel stack"),
+        (KERNEL_BASE_ADDR + 0x00400000, 0x00c00000, RegionKind::Kernel, "page tables"), // ページテーブルとフレームのビットマップを含む
+        (KERNEL_VRAM_ADDR, KERNEL_VRAM_SIZE, RegionKind::Mmio, "vram"),
+        (0xfe000000, 0x01000000, RegionKind::Mmio, "pci"),
+    ];
+    for (start, size, kind, name) in boot_regions.iter() {
+        let phys_address = translate(*start).unwrap_or(*start);
+        register_mapped_range(*start, phys_address, *size, *kind, Protection::KERNEL_RW, name).unwrap();
+    }
+    unmap_page(BOOT_STACK_GUARD);
+    add_guard(BOOT_STACK_GUARD, "kernel stack").unwrap();
+    let (dma_start, dma_size) = get_dma_region();
+    register_mapped_range(dma_start, dma_start - KERNEL_BASE_ADDR, dma_size, RegionKind::Dma, Protection::KERNEL_RW, "dma").unwrap();
+    // Heapは必要になった時にallocator::page_sourceがページをマッピングするので、範囲全体を領域にしておく
+    add_region(VmRegion {
+        start: KERNEL_HEAP_START,
+        size: KERNEL_HEAP_MAX_SIZE,
+        kind: RegionKind::KernelHeap,
+        prot: Protection::KERNEL_RW,
+        backing: Backing::Anonymous,
+        name: "kernel heap",
+    }).unwrap();
+}
+
+fn check_range(start: u32, size: usize) -> Result<(), String> {
+    if size == 0 { return Err("size is zero.".to_owned()); }
+    if start & PAGE_MASK != 0 || size & PAGE_MASK as usize != 0 {
+        return Err(format!("{:x}+{:x} is not page aligned.", start, size));
+    }
+    if start as u64 + size as u64 > 0x1_0000_0000 { return Err(format!("{:x}+{:x} exceeds 4GiB.", start, size)); }
+    Ok(())
+}
+
+fn add_region(region: VmRegion) -> Result<(), String> {
+    check_range(region.start, region.size)?;
+    let mut regions = VM_REGIONS.lock();
+    if let Some(other) = regions.iter().find(|r| r.overlaps(region.start, region.size)) {
+        return Err(format!("{:x}+{:x} overlaps {}.", region.start, region.size, other.name));
+    }
+    let idx = regions.iter().position(|r| r.start > region.start).unwrap_or(regions.len());
+    regions.insert(idx, region);
+    Ok(())
+}
+
+fn add_guard(start: u32, name: &'static str) -> Result<(), String> {
+    add_region(VmRegion { start, size: GUARD_SIZE, kind: RegionKind::Guard, prot: Protection::KERNEL_RO, backing: Backing::Anonymous, name })
+}
+
+// [area_start, area_end)の中でsizeだけ空いている仮想アドレスを探す
+fn find_free_range(area_start: u32, area_end: u32, size: usize) -> Result<u32, String> {
+    let mut candidate = area_start as u64;
+    for region in VM_REGIONS.lock().iter() {
+        if region.end() <= candidate { continue; }
+        if region.start as u64 >= candidate + size as u64 { break; }
+        candidate = region.end();
+    }
+    if candidate + size as u64 > area_end as u64 {
+        return Err(format!("no free virtual address for {:x} bytes.", size));
+    }
+    Ok(candidate as u32)
+}
+
+// [start, start + size)の境界で領域を分けて、範囲に含まれる領域のindexを返す
+// 範囲の中に領域の無い穴があればErr
+fn split_range(regions: &mut Vec<VmRegion>, start: u32, size: usize) -> Result<(usize, usize), String> {
+    let end = start as u64 + size as u64;
+    for boundary in [start as u64, end].iter() {
+        if *boundary >= 0x1_0000_0000 { continue; }
+        let boundary = *boundary as u32;
+        if let Some(idx) = regions.iter().position(|r| r.contains(boundary) && r.start != boundary) {
+            let (head, tail) = regions[idx].split(boundary);
+            regions[idx] = head;
+            regions.insert(idx + 1, tail);
+        }
+    }
+    let first = match regions.iter().position(|r| r.start == start) {
+        Some(idx) => idx,
+        None => return Err(format!("{:x} is not in any region.", start)),
+    };
+    let mut last = first;
+    while regions[last].end() < end {
+        if last + 1 >= regions.len() || regions[last + 1].start as u64 != regions[last].end() {
+            return Err(format!("{:x} is not in any region.", regions[last].end()));
+        }
+        last += 1;
+    }
+    Ok((first, last + 1))
+}
+
+fn map_region_page(region: &VmRegion, page: u32) -> Result<(), PageFaultError> {
+    let (phys_address, anonymous) = match region.phys_address(page) {
+        Some(phys_address) => (phys_address, false),
+        None => (FRAME_ALLOCATOR.allocate_frame().ok_or(PageFaultError::OutOfFrames(*region))?, true),
+    };
+    if map_page(page, phys_address, region.page_flags()).is_err() {
+        if anonymous { FRAME_ALLOCATOR.free_frame(phys_address); }
+        return Err(PageFaultError::MapFailed(*region));
+    }
+    // 新しく割り当てたフレームには前の中身が残っているので消す
+    if anonymous {
+        unsafe { ptr::write_bytes(page as *mut u8, 0, FRAME_SIZE) };
+    }
+    Ok(())
+}
+
+// 登録したばかりの領域のページをマッピングする
+// 失敗したらこの呼び出しでマッピングしたページだけを外して領域を取り除く(ブート時などからあったマッピングは外さない)
+fn map_region_pages(region: &VmRegion, skip_mapped: bool) -> Result<(), String> {
+    let mut mapped: Vec<u32> = Vec::new();
+    for page in (region.start..(region.start + (region.size - 1) as u32)).step_by(FRAME_SIZE) {
+        // 既にマッピング済み(ブート時に作ったマッピングなど)ならそのまま使う
+        if skip_mapped && translate(page).is_some() { continue; }
+        if let Err(e) = map_region_page(region, page) {
+            for page in mapped.iter() {
+                if let Some(phys_address) = unmap_page(*page) {
+                    if region.backing == Backing::Anonymous { FRAME_ALLOCATOR.free_frame(phys_address); }
+                }
+            }
+            let message = format!("failed to map {:x}: {}.", page, e.reason());
+            return Err(with_rollback_error(message, unregister_range(region.start, region.size)));
+        }
+        mapped.push(page);
+    }
+    Ok(())
+}
+
+// 失敗を戻す処理も失敗した場合は、そのエラーも元のエラーに付け加える
+fn with_rollback_error(message: String, rollback: Result<(), String>) -> String {
+    match rollback {
+        Ok(()) => message,
+        Err(e) => format!("{} (rollback failed: {})", message, e),
+    }
+}
+
+// 領域を予約する。物理フレームは最初にアクセスされた時に割り当てる
+pub fn reserve_range(start: u32, size: usize, kind: RegionKind, prot: Protection, name: &'static str) -> Result<(), String> {
+    add_region(VmRegion { start, size, kind, prot, backing: Backing::Anonymous, name })
+}
+
+// 領域を予約して、すぐに物理フレームを割り当てる
+pub fn map_range(start: u32, size: usize, kind: RegionKind, prot: Protection, name: &'static str) -> Result<(), String> {
+    reserve_range(start, size, kind, prot, name)?;
+    map_region_pages(&VmRegion { start, size, kind, prot, backing: Backing::Anonymous, name }, false)
+}
+
+// 決まった物理アドレスをマッピングする(MMIO, DMAなど)
+pub fn map_phys_range(start: u32, phys_address: u32, size: usize, kind: RegionKind, prot: Protection, name: &'static str) -> Result<(), String> {
+    if phys_address & PAGE_MASK != 0 { return Err(format!("{:x} is not page aligned.", phys_address)); }
+    let region = VmRegion { start, size, kind, prot, backing: Backing::Physical(phys_address), name };
+    add_region(region)?;
+    map_region_pages(&region, true)
+}
+
+// 既にマッピングされている範囲を領域として登録する(ブート時に作ったマッピング)
+pub fn register_mapped_range(start: u32, phys_address: u32, size: usize, kind: RegionKind, prot: Protection, name: &'static str) -> Result<(), String> {
+    add_region(VmRegion { start, size, kind, prot, backing: Backing::Physical(phys_address), name })
+}
+
+// register_mapped_rangeで登録した範囲を領域から取り除く。マッピングとフレームはそのまま(呼び出し元が戻す)
+pub fn unregister_range(start: u32, size: usize) -> Result<(), String> {
+    check_range(start, size)?;
+    let mut regions = VM_REGIONS.lock();
+    let (first, last) = split_range(&mut regions, start, size)?;
+    regions.drain(first..last);
+    Ok(())
+}
+
+// MMIOをキャッシュ無効でマッピングして、アクセスに使う仮想アドレスを返す
+// 既にMMIOの領域(ブート時にマッピングした"pci"の窓など)に含まれていればそのまま使い、
+// それ以外はMMIO用の範囲の空いている所に置く(物理アドレスと同じ仮想アドレスはストレートマッピングやHeap、VRAMと被ることがある)
+pub fn map_mmio(phys_address: u64, size: u64, name: &'static str) -> Result<u32, String> {
+    if size == 0 { return Err("mmio size is zero.".to_owned()); }
+    let start = phys_address & !(PAGE_MASK as u64);
+    let end = (phys_address + size + PAGE_MASK as u64) & !(PAGE_MASK as u64);
+    // 32bitのアドレス空間に無い領域はマッピングできない
+    if end > 0x1_0000_0000 { return Err(format!("mmio {:x} is above 4GiB.", phys_address)); }
+    let offset = (phys_address - start) as u32;
+    let mapped = VM_REGIONS.lock().iter().find_map(|r| {
+        let region_phys = r.phys_address(r.start)? as u64;
+        if r.kind == RegionKind::Mmio && region_phys <= start && end <= region_phys + r.size as u64 {
+            Some(r.start + (start - region_phys) as u32)
+        } else {
+            None
+        }
+    });
+    if let Some(vir_address) = mapped {
+        return Ok(vir_address + offset);
+    }
+    let vir_address = find_free_range(MMIO_AREA_START, MMIO_AREA_END, (end - start) as usize)?;
+    map_phys_range(vir_address, start as u32, (end - start) as usize, RegionKind::Mmio, Protection::KERNEL_RW, name)?;
+    Ok(vir_address + offset)
+}
+
+// 範囲のマッピングを外して領域から取り除く。Anonymousのフレームは解放する
+pub fn unmap_range(start: u32, size: usize) -> Result<(), String> {
+    check_range(start, size)?;
+    let removed: Vec<VmRegion> = {
+        let mut regions = VM_REGIONS.lock();
+        let (first, last) = split_range(&mut regions, start, size)?;
+        regions.drain(first..last).collect()
+    };
+    for region in removed.iter() {
+        for page in (region.start..(region.start + (region.size - 1) as u32)).step_by(FRAME_SIZE) {
+            if let Some(phys_address) = unmap_page(page) {
+                if region.backing == Backing::Anonymous {
+                    FRAME_ALLOCATOR.free_frame(phys_address);
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+// 範囲の保護属性を変える。マッピング済みのページのフラグも書き換える
+pub fn protect(start: u32, size: usize, prot: Protection) -> Result<(), String> {
+    check_range(start, size)?;
+    let mut regions = VM_REGIONS.lock();
+    let (first, last) = split_range(&mut regions, start, size)?;
+    for region in regions[first..last].iter_mut() {
+        region.prot = prot;
+        for page in (region.start..(region.start + (region.size - 1) as u32)).step_by(FRAME_SIZE) {
+            if translate(page).is_some() {
+                set_page_flags(page, region.page_flags())?;
+            }
+        }
+    }
+    Ok(())
+}
+
+// カーネルスタック。[bottom, top)がマッピングされていて、bottomの下にガードページがある
+#[derive(Copy, Clone, Debug)]
+pub struct KernelStack {
+    pub bottom: u32,
+    pub top: u32,
+}
+
+// ガードページ付きのカーネルスタックを割り当てる
+// スタックでページフォルトが起きると例外を積めないので、遅延させずにすぐフレームを割り当てる
+pub fn allocate_kernel_stack(size: usize, name: &'static str) -> Result<KernelStack, String> {
+    let size = (size + PAGE_MASK as usize) & !(PAGE_MASK as usize);
+    let guard = find_free_range(KERNEL_STACK_AREA_START, KERNEL_STACK_AREA_END, GUARD_SIZE + size)?;
+    add_guard(guard, name)?;
+    let bottom = guard + GUARD_SIZE as u32;
+    if let Err(e) = map_range(bottom, size, RegionKind::Stack, Protection::KERNEL_RW, name) {
+        return Err(with_rollback_error(e, unmap_range(guard, GUARD_SIZE)));
+    }
+    Ok(KernelStack { bottom, top: bottom + size as u32 })
+}
+
+pub fn free_kernel_stack(stack: KernelStack) -> Result<(), String> {
+    unmap_range(stack.bottom - GUARD_SIZE as u32, GUARD_SIZE + (stack.top - stack.bottom) as usize)
+}
+
+// addressがスタックの下のガードページならそのスタックの領域を返す
+pub fn find_stack_guard(address: u32) -> Option<VmRegion> {
+    let guard = find_region(address).filter(|r| r.kind == RegionKind::Guard)?;
+    find_region(guard.end() as u32).filter(|r| r.kind == RegionKind::Stack)
+}
+
+// ダブルフォルトなど、VM_REGIONSのロックを持ったまま起きたかもしれない例外から使う
+// ロックが取れなければ(待つと止まってしまうので)Noneを返す
+pub fn try_find_stack_guard(address: u32) -> Option<VmRegion> {
+    let regions = VM_REGIONS.try_lock()?;
+    let guard = regions.iter().find(|r| r.contains(address) && r.kind == RegionKind::Guard)?;
+    regions.iter().find(|r| r.contains(guard.end() as u32) && r.kind == RegionKind::Stack).map(|r| *r)
+}
+
+pub fn find_region(address: u32) -> Option<VmRegion> {
+    VM_REGIONS.lock().iter().find(|r| r.contains(address)).map(|r| *r)
+}
+
+pub fn get_regions() -> Vec<VmRegion> {
+    VM_REGIONS.lock().clone()
+}
+
+// ページフォルトを解決する。予約済みでまだフレームの無いページならここで割り当てる
+pub fn handle_page_fault(address: u32, error_code: u32) -> Result<(), PageFaultError> {
+    let region = match VM_REGIONS.lock().iter().find(|r| r.contains(address)) {
+        Some(region) => *region,
+        None if address < FRAME_SIZE as u32 => return Err(PageFaultError::NullPointer),
+        None => return Err(PageFaultError::NoRegion),
+    };
+    if region.kind == RegionKind::Guard {
+        return Err(PageFaultError::GuardPage(region));
+    }
+    if error_code & PF_USER == PF_USER && !region.prot.user {
+        return Err(PageFaultError::UserAccessToKernel(region));
+    }
+    if error_code & PF_WRITE == PF_WRITE && !region.prot.writable {
+        return Err(PageFaultError::WriteToReadOnly(region));
+    }
+    if error_code & PF_PRESENT == PF_PRESENT {
+        return Err(PageFaultError::ProtectionViolation(region));
+    }
+    // Heapのページはallocatorが借りた時にだけマッピングされる。それ以外の場所は返したページか範囲外
+    if region.kind == RegionKind::KernelHeap {
+        return Err(PageFaultError::HeapPageNotMapped(region));
+    }
+    map_region_page(&region, address & !PAGE_MASK)
+}
+
+pub fn dump_regions(x: u32, y: u32) {
+    let mut writer = ScreenWriter::new(x, y);
+    for region in get_regions().iter() {
+        let _ = writeln!(writer, "{:08x}-{:08x} {} {}{} {}",
+                         region.start, region.end() - 1, region.kind.name(),
+                         if region.prot.writable { "rw" } else { "r-" }, if region.prot.user { "u" } else { "k" },
+                         region.name);
+    }
+}